@@ -18,10 +18,18 @@
 //! Normally you just crate a [`FileStore`](../enum.FileStore.html) from the
 //! backend and then everything else is done by calls to the `FileStore` which
 //! generally behave the same regardless of the backend.
+//!
+//! Backends implemented outside of this crate can be used through the
+//! [`custom`](custom/index.html) module.
 #[cfg(feature = "b2")]
 pub mod b2;
+pub mod custom;
 #[cfg(feature = "file")]
 pub mod file;
+#[cfg(feature = "memory")]
+pub mod memory;
+#[cfg(feature = "s3")]
+pub mod s3;
 
 use std::fmt;
 
@@ -34,6 +42,15 @@ pub enum Backend {
     #[cfg(feature = "b2")]
     /// The [b2 backend](b2/index.html). Included with the "b2" feature.
     B2,
+    #[cfg(feature = "memory")]
+    /// The [memory backend](memory/index.html). Included with the "memory"
+    /// feature.
+    Memory,
+    #[cfg(feature = "s3")]
+    /// The [s3 backend](s3/index.html). Included with the "s3" feature.
+    S3,
+    /// A [custom backend](custom/index.html) identified by its name.
+    Custom(&'static str),
 }
 
 impl fmt::Display for Backend {
@@ -43,6 +60,11 @@ impl fmt::Display for Backend {
             Backend::File => f.pad("file"),
             #[cfg(feature = "b2")]
             Backend::B2 => f.pad("b2"),
+            #[cfg(feature = "memory")]
+            Backend::Memory => f.pad("memory"),
+            #[cfg(feature = "s3")]
+            Backend::S3 => f.pad("s3"),
+            Backend::Custom(name) => f.pad(name),
         }
     }
 }
@@ -25,58 +25,133 @@
 //! used as the name of the bucket. The rest can be freeform though people
 //! generally use a regular path string separated by `/` characters to form
 //! a hierarchy. Attempting to write a file at the bucket level will fail
-//! however writing a file inside a bucket that does not yet exist will create
-//! the bucket (assuming the key has permission to do so).
+//! and so will writing a file inside a bucket that does not yet exist.
+//!
+//! Writes have never created missing buckets, earlier documentation claiming
+//! that they would was wrong. Buckets are only created by
+//! [`create_bucket`](struct.B2Backend.html#method.create_bucket).
+//!
+//! Buckets can be managed directly by name (ignoring any prefix) with
+//! [`create_bucket`](struct.B2Backend.html#method.create_bucket) and
+//! [`delete_bucket`](struct.B2Backend.html#method.delete_bucket). An existing
+//! bucket's lifecycle rules, CORS rules and info can also be replaced.
+//!
+//! Buckets are looked up by name and then remembered for a while, see
+//! [`cache_buckets`](struct.B2BackendBuilder.html#method.cache_buckets). Keys
+//! restricted to a single bucket never need to look that bucket up.
 //!
 //! In order to be compatible with other backends, but still include some useful
 //! functionality file versioning (if enabled for the bucket) is currently
 //! handled as follows:
 //! * Deleting a file will delete all of its versions.
 //! * Replacing a file will add a new version.
+//! * A file whose latest version hides it is treated as not existing, though
+//!   deleting it will still remove all of its versions.
+//!
+//! The versions themselves can be accessed with
+//! [`list_versions`](struct.B2Backend.html#method.list_versions), read with
+//! [`get_version_stream`](struct.B2Backend.html#method.get_version_stream) and
+//! deleted individually with [`delete_version`](struct.B2Backend.html#method.delete_version).
+//! [`hide_file`](struct.B2Backend.html#method.hide_file) removes a file while
+//! keeping its history.
 //!
-//! Setting a file's mimetype on upload is not currently supported. The backend
-//! will rely on B2's automatic mimetype detection to set the mimetype. This
-//! uses the file's extension to set a mimetype from a [list of mappings](https://www.backblaze.com/b2/docs/content-types.html)
+//! Copying or moving a file to a new path in the same bucket is performed by B2
+//! without the file's data passing through the client.
+//!
+//! Files are normally downloaded with a single request. Large files can instead
+//! be downloaded as ranges fetched in parallel, see
+//! [`parallel_downloads`](struct.B2BackendBuilder.html#method.parallel_downloads).
+//!
+//! If a file's mimetype is not set on upload the backend will rely on B2's
+//! automatic mimetype detection to set the mimetype. This uses the file's
+//! extension to set a mimetype from a [list of mappings](https://www.backblaze.com/b2/docs/content-types.html)
 //! and falls back to `application/octet-stream` in case of failure.
 //!
+//! Cache control, content disposition and custom metadata are stored in the
+//! file's info. B2 limits the number of entries in a file's info to 10 and one
+//! is used for the last modified time.
+//!
 //! The last modified time of an uploaded file will be set to the time that the
 //! upload began.
+//!
+//! B2 does not support conditional writes so a
+//! [`WritePrecondition`](../../enum.WritePrecondition.html) is enforced by
+//! listing the file's versions before and after the upload. If another version
+//! was uploaded in the meantime the new version is deleted and the write fails.
+//! A file's version is its B2 file id.
+//!
+//! Files larger than the small file cut-off are uploaded as B2 large files in
+//! fixed size parts. Only a few parts are held in memory at once, see
+//! [`limit_upload_parts`](struct.B2BackendBuilder.html#method.limit_upload_parts).
+//! If a large file upload fails the parts that were uploaded
+//! are kept by B2. [`list_unfinished_uploads`](struct.B2Backend.html#method.list_unfinished_uploads)
+//! finds these so that they can be resumed with
+//! [`resume_upload`](struct.B2Backend.html#method.resume_upload), which skips
+//! any parts that already match, or cancelled. Old abandoned uploads can be
+//! cleaned up with [`cancel_stale_uploads`](struct.B2Backend.html#method.cancel_stale_uploads).
+//!
+//! Requests that fail with a temporary error are retried with an exponential
+//! backoff, see [`RetryPolicy`](struct.RetryPolicy.html).
+//!
+//! Upload URLs are kept and reused by later uploads to the same bucket or large
+//! file. A URL that fails with an authorization, server or connection error is
+//! discarded and a new one requested.
 
 mod client;
 
+use std::cmp::{max, min};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
 use std::convert::{Infallible, TryInto};
 use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::ops::Range;
 use std::pin::Pin;
 use std::slice::Iter;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use bytes::IntoBuf;
+use bytes::{BytesMut, IntoBuf};
 use futures::channel::mpsc::{channel, Sender};
-use futures::future::{ready, TryFutureExt};
+use futures::future::{ready, FutureExt, TryFutureExt};
 use futures::sink::SinkExt;
-use futures::stream::{Stream, StreamExt, TryStreamExt};
+use futures::stream::{empty, iter, once, Stream, StreamExt, TryStreamExt};
 use hyper::client::connect::HttpConnector;
 use hyper::client::Client as HyperClient;
+use hyper::Chunk;
 use hyper_tls::HttpsConnector;
-use log::{error, trace};
+use log::{error, trace, warn};
 use sha1::Sha1;
-use tokio_executor::spawn;
+use tokio_executor::{DefaultExecutor, Executor};
 
 use storage_types::b2::v2::requests::*;
 use storage_types::b2::v2::responses::*;
-use storage_types::b2::v2::{FileAction, UserFileInfo, LAST_MODIFIED_KEY};
+use storage_types::b2::v2::{
+    UserFileInfo, CACHE_CONTROL_KEY, CONTENT_DISPOSITION_KEY, CONTENT_TYPE_AUTO,
+    LARGE_FILE_SHA1_KEY, LAST_MODIFIED_KEY, RESERVED_KEY_PREFIX,
+};
 
 use super::Backend;
-use crate::types::stream::{MergedStreams, ResultStreamPoll};
+use crate::types::stream::{MergedStreams, RangeStream, ResultStreamPoll};
 use crate::types::*;
-use crate::utils::{into_data_stream, Acquired, CloningPool, Pool};
+use crate::utils::{
+    delete_objects, into_data_stream, into_upload_stream, Acquired, CloningPool, PartTasks, Pool,
+};
 use crate::{FileStore, StorageBackend};
-use client::{B2APIState, B2Client, B2API};
+use client::{B2APIState, B2Client, BucketRef, B2API};
+
+pub use storage_types::b2::v2::responses::{Bucket, CorsRule, LifecycleRule};
+pub use storage_types::b2::v2::{BucketType, FileAction};
 
 const TOTAL_MAX_SMALL_FILE_SIZE: u64 = 5 * 1000 * 1000 * 1000;
 const DEFAULT_MAX_SMALL_FILE_SIZE: u64 = 200 * 1000 * 1000;
 const DEFAULT_REQUEST_LIMIT: usize = 20;
+const DEFAULT_UPLOAD_PART_LIMIT: usize = 4;
+const DEFAULT_BUCKET_CACHE_TTL: Duration = Duration::from_secs(300);
+const DEFAULT_MAX_ATTEMPTS: usize = 5;
+const DEFAULT_BASE_DELAY: Duration = Duration::from_millis(100);
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(30);
 
 type ClientPool = CloningPool<HyperClient<HttpsConnector<HttpConnector>>>;
 type Client = Acquired<
@@ -106,6 +181,76 @@ impl FileVersions {
     }
 }
 
+/// Gets the SHA1 hash of a file version's content.
+fn content_sha1(info: &FileInfo) -> Option<String> {
+    if info.action != FileAction::Upload {
+        return None;
+    }
+
+    // Large files have no content SHA1 so fall back to the one given when the
+    // upload was started.
+    info.content_sha1
+        .as_ref()
+        .map(|hash| hash.trim_start_matches("unverified:"))
+        .filter(|hash| *hash != "none")
+        .or_else(|| info.file_info.get(LARGE_FILE_SHA1_KEY).map(String::as_str))
+        .map(|hash| hash.to_lowercase())
+}
+
+/// A future that resolves to a B2 bucket.
+pub type BucketFuture = WrappedFuture<StorageResult<Bucket>>;
+
+/// A future that resolves to a list of file versions.
+pub type FileVersionsFuture = WrappedFuture<StorageResult<Vec<FileVersion>>>;
+
+/// A single version of a file stored in B2.
+///
+/// Uploading a file adds a new version. Hiding a file adds a version with the
+/// [`Hide`](enum.FileAction.html#variant.Hide) action and no content.
+#[derive(Clone, Debug)]
+pub struct FileVersion {
+    path: ObjectPath,
+    file_id: String,
+    info: FileInfo,
+}
+
+impl FileVersion {
+    /// The path of the file.
+    pub fn path(&self) -> ObjectPath {
+        self.path.clone()
+    }
+
+    /// The B2 file id of this version.
+    pub fn file_id(&self) -> &str {
+        &self.file_id
+    }
+
+    /// The time that this version was uploaded.
+    pub fn uploaded(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.info.upload_timestamp)
+    }
+
+    /// The size of this version in bytes.
+    pub fn len(&self) -> u64 {
+        self.info.content_length
+    }
+
+    /// Checks if this version is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// What created this version.
+    pub fn action(&self) -> &FileAction {
+        &self.info.action
+    }
+
+    /// The SHA1 hash of this version's content if known.
+    pub fn sha1(&self) -> Option<String> {
+        content_sha1(&self.info)
+    }
+}
+
 /// The B2 implementation for [`Object`](../../enum.Object.html).
 #[derive(Clone, Debug)]
 pub struct B2Object {
@@ -114,8 +259,18 @@ pub struct B2Object {
 }
 
 impl B2Object {
-    fn versions(&self) -> Iter<FileInfo> {
-        self.versions.iter()
+    /// Gets the versions of this file, oldest first.
+    pub fn versions(&self) -> Vec<FileVersion> {
+        self.versions
+            .iter()
+            .filter_map(|info| {
+                info.file_id.as_ref().map(|file_id| FileVersion {
+                    path: self.path.clone(),
+                    file_id: file_id.clone(),
+                    info: info.clone(),
+                })
+            })
+            .collect()
     }
 }
 
@@ -161,6 +316,58 @@ impl ObjectInfo for B2Object {
                 }
             })
     }
+
+    fn content_type(&self) -> Option<String> {
+        let version = self.versions.latest();
+        if version.action != FileAction::Upload {
+            return None;
+        }
+
+        version.content_type.clone()
+    }
+
+    fn version(&self) -> Option<String> {
+        let version = self.versions.latest();
+        if version.action != FileAction::Upload {
+            return None;
+        }
+
+        version.file_id.clone()
+    }
+
+    fn cache_control(&self) -> Option<String> {
+        self.versions
+            .latest()
+            .file_info
+            .get(CACHE_CONTROL_KEY)
+            .cloned()
+    }
+
+    fn content_disposition(&self) -> Option<String> {
+        self.versions
+            .latest()
+            .file_info
+            .get(CONTENT_DISPOSITION_KEY)
+            .cloned()
+    }
+
+    fn metadata(&self) -> HashMap<String, String> {
+        self.versions
+            .latest()
+            .file_info
+            .iter()
+            .filter(|(key, _)| {
+                *key != LAST_MODIFIED_KEY
+                    && *key != LARGE_FILE_SHA1_KEY
+                    && !key.starts_with(RESERVED_KEY_PREFIX)
+            })
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    fn checksum(&self) -> Option<Checksum> {
+        content_sha1(self.versions.latest()).map(Checksum::Sha1)
+    }
 }
 
 fn new_object(bucket: &str, versions: FileVersions, prefix: &ObjectPath) -> StorageResult<Object> {
@@ -184,9 +391,48 @@ struct B2Settings {
     host: String,
     prefix: ObjectPath,
     max_small_file_size: u64,
+    max_upload_parts: usize,
+    max_requests: usize,
+    stream_uploads: bool,
+    download_chunk_size: Option<u64>,
+    download_read_ahead: usize,
+    bucket_cache_ttl: Duration,
+    retry_policy: RetryPolicy,
+    request_timeout: Option<Duration>,
+    operation_timeout: Option<Duration>,
     user_agent: String,
 }
 
+/// Builds the file info to store for the file being uploaded.
+fn upload_file_info(info: &UploadInfo) -> UserFileInfo {
+    let mut file_info = info.metadata.clone();
+
+    if let Some(time) = info.modified {
+        if let Ok(duration) = time.duration_since(UNIX_EPOCH) {
+            file_info.insert(
+                LAST_MODIFIED_KEY.to_owned(),
+                duration.as_millis().to_string(),
+            );
+        }
+    }
+
+    if let Some(ref value) = info.cache_control {
+        file_info.insert(CACHE_CONTROL_KEY.to_owned(), value.clone());
+    }
+
+    if let Some(ref value) = info.content_disposition {
+        file_info.insert(CONTENT_DISPOSITION_KEY.to_owned(), value.clone());
+    }
+
+    file_info
+}
+
+fn upload_content_type(info: &UploadInfo) -> String {
+    info.content_type
+        .clone()
+        .unwrap_or_else(|| CONTENT_TYPE_AUTO.to_owned())
+}
+
 struct PartData {
     data: Vec<Data>,
     length: u64,
@@ -199,6 +445,7 @@ async fn part_upload(
     file_id: String,
     part: usize,
     part_data: PartData,
+    progress: Option<TransferObserver>,
     mut sender: Sender<Result<(), (usize, StorageError)>>,
 ) {
     trace!(
@@ -208,246 +455,620 @@ async fn part_upload(
         part_data.data.len()
     );
 
-    let part_url = match client
-        .b2_get_upload_part_url(path.clone(), GetUploadPartUrlRequest { file_id })
-        .await
-    {
-        Ok(p) => p,
-        Err(e) => {
-            return sender.send(Err((part, e))).await.unwrap();
-        }
-    };
+    let length = part_data.length;
+    if let Some(ref observer) = progress {
+        observer.notify(TransferEvent::PartStarted { part, length });
+    }
 
-    if let Err(e) = client
+    let result = client
         .b2_upload_part(
-            path,
-            part_url,
+            path.clone(),
+            file_id,
             part,
             part_data.length,
             part_data.hash,
             part_data.data,
+            progress.clone(),
         )
         .await
-    {
-        return sender.send(Err((part, e))).await.unwrap();
+        .map(|_| ())
+        .map_err(|e| (part, e));
+
+    if let Some(ref observer) = progress {
+        match result {
+            Ok(()) => observer.notify(TransferEvent::PartCompleted { part, length }),
+            Err((_, ref e)) => observer.notify(TransferEvent::PartFailed {
+                part,
+                error: e.clone(),
+            }),
+        }
     }
 
-    sender.send(Ok(())).await.unwrap();
+    // The upload stops waiting for parts if the transfer is dropped.
+    if let Err(e) = sender.send(result).await {
+        warn!(
+            "Unable to report part {} of large file upload to {}: {}",
+            part, path, e
+        );
+    }
 }
 
-async fn large_upload<S>(
-    client: B2API,
-    recommended_part_size: u64,
-    info: UploadInfo,
-    bucket_id: String,
-    file_name: String,
-    first_part: PartData,
-    mut stream: Pin<Box<S>>,
-) -> Result<(), TransferError>
+/// Reads the next part of a large file from the stream.
+///
+/// Parts are exactly `size` bytes long except for the final part which may be
+/// shorter. Any data read beyond the end of the part is kept in `remainder` to
+/// start the next part.
+async fn read_part<S>(
+    stream: &mut S,
+    remainder: &mut Option<Data>,
+    size: u64,
+) -> StorageResult<Option<PartData>>
 where
-    S: Stream<Item = StorageResult<Data>> + Send + 'static,
+    S: Stream<Item = StorageResult<Data>> + Unpin,
 {
-    trace!("Starting large file upload to {}.", info.path);
-    let mut part_count: usize = 1;
-    let (sender, mut receiver) = channel::<Result<(), (usize, StorageError)>>(0);
+    let mut hasher = Sha1::new();
+    let mut length: u64 = 0;
+    let mut data: Vec<Data> = Default::default();
+
+    while length < size {
+        let mut chunk = match remainder.take() {
+            Some(chunk) => chunk,
+            None => match stream.next().await {
+                Some(result) => result?,
+                None => break,
+            },
+        };
 
-    let mut file_info = UserFileInfo::new();
-    if let Some(time) = info.modified {
-        if let Ok(duration) = time.duration_since(UNIX_EPOCH) {
-            file_info.insert(
-                LAST_MODIFIED_KEY.to_owned(),
-                duration.as_millis().to_string(),
-            );
+        let wanted = (size - length) as usize;
+        if chunk.len() > wanted {
+            *remainder = Some(chunk.split_off(wanted));
         }
+
+        length += chunk.len() as u64;
+        hasher.update(&chunk);
+        data.push(chunk);
     }
 
-    let request = StartLargeFileRequest {
-        bucket_id,
-        file_name,
-        content_type: String::from("b2/x-auto"),
-        file_info: Some(file_info),
+    if length == 0 {
+        Ok(None)
+    } else {
+        Ok(Some(PartData {
+            data,
+            length,
+            hash: hasher.hexdigest(),
+        }))
+    }
+}
+
+/// Handles the result of a part upload, recording the first failure.
+fn check_part_result(
+    path: &ObjectPath,
+    result: Option<Result<(), (usize, StorageError)>>,
+    failure: &mut Option<TransferError>,
+) {
+    let error = match result {
+        Some(Ok(())) => return,
+        Some(Err((part_number, e))) => {
+            error!(
+                "Part {} of large file upload to {} failed: {}",
+                part_number, path, e
+            );
+            e
+        }
+        None => error::internal_error(Some("Part upload ended unexpectedly.")),
     };
 
-    let result = client
-        .b2_start_large_file(info.path.clone(), request)
-        .await
-        .map_err(TransferError::TargetError)?;
+    if failure.is_none() {
+        *failure = Some(TransferError::TargetError(error));
+    }
+}
 
-    let file_id = match result.file_id {
-        Some(s) => s,
-        None => {
-            return Err(TransferError::TargetError(error::invalid_data(Some(
-                "Attempt to request large file upload failed.",
-            ))))
+/// Cancels a large file on B2 if dropped before being disarmed. This cleans up
+/// after a large file transfer whose future is dropped before it completes.
+/// Uploads that fail are left unfinished so they can be resumed but copies
+/// cannot be resumed and so are cancelled.
+struct LargeFileGuard {
+    client: B2API,
+    path: ObjectPath,
+    file_id: Option<String>,
+}
+
+impl LargeFileGuard {
+    fn new(client: &B2API, path: &ObjectPath, file_id: &str) -> LargeFileGuard {
+        LargeFileGuard {
+            client: client.clone(),
+            path: path.clone(),
+            file_id: Some(file_id.to_owned()),
         }
-    };
+    }
 
-    let mut hashes = vec![first_part.hash.clone()];
+    fn disarm(mut self) {
+        self.file_id = None;
+    }
 
-    spawn(part_upload(
-        client.clone(),
-        info.path.clone(),
-        file_id.clone(),
-        part_count,
-        first_part,
-        sender.clone(),
-    ));
+    /// Cancels the large file immediately rather than when dropped.
+    async fn cancel(mut self) {
+        if let Some(file_id) = self.file_id.take() {
+            cancel_large_file(self.client.clone(), self.path.clone(), file_id).await;
+        }
+    }
+}
 
-    let mut hasher = Sha1::new();
-    let mut length: u64 = 0;
-    let mut buffers: Vec<Data> = Default::default();
+impl Drop for LargeFileGuard {
+    fn drop(&mut self) {
+        let file_id = match self.file_id.take() {
+            Some(id) => id,
+            None => return,
+        };
 
-    loop {
-        match stream.next().await {
-            Some(Ok(data)) => {
-                length += data.len() as u64;
-                hasher.update(&data);
-                buffers.push(data);
+        trace!("Cancelling dropped large file upload to {}.", self.path);
+        let cancel = cancel_large_file(self.client.clone(), self.path.clone(), file_id);
+        if let Err(e) = DefaultExecutor::current().spawn(Box::pin(cancel)) {
+            warn!(
+                "Failed to cancel large file upload to {}: {:?}",
+                self.path, e
+            );
+        }
+    }
+}
 
-                if length > recommended_part_size {
-                    // Start part upload.
-                    part_count += 1;
+async fn cancel_large_file(client: B2API, path: ObjectPath, file_id: String) {
+    client.forget_upload_part_urls(&file_id);
+    if let Err(e) = client
+        .b2_cancel_large_file(path.clone(), CancelLargeFileRequest { file_id })
+        .await
+    {
+        warn!("Failed to cancel large file upload to {}: {}", path, e);
+    }
+}
 
-                    let hash = hasher.hexdigest();
-                    hashes.push(hash.clone());
-                    spawn(part_upload(
-                        client.clone(),
-                        info.path.clone(),
-                        file_id.clone(),
-                        part_count,
-                        PartData {
-                            data: buffers.drain(..).collect(),
-                            length,
-                            hash,
-                        },
-                        sender.clone(),
-                    ));
+/// Uploads the parts of a started large file and then finishes it.
+///
+/// `existing` contains the SHA1 hashes of parts that have already been
+/// uploaded keyed by part number. Any of these that match the data from the
+/// stream are not uploaded again.
+///
+/// At most `max_parts` parts are read from the stream and not yet uploaded at
+/// any time so memory use is bounded by roughly `max_parts` times the part
+/// size.
+///
+/// If anything fails the large file is left unfinished, but only after any
+/// parts that were already in progress have completed. Dropping the future
+/// aborts any part uploads in progress.
+#[allow(clippy::too_many_arguments)]
+async fn upload_parts<S>(
+    client: B2API,
+    path: ObjectPath,
+    file_id: String,
+    part_size: u64,
+    max_parts: usize,
+    existing: HashMap<usize, String>,
+    progress: Option<TransferObserver>,
+    mut stream: S,
+) -> Result<FileInfo, TransferError>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + Unpin + 'static,
+{
+    trace!("Starting part uploads for large file upload to {}.", path);
+    let (sender, mut receiver) = channel::<Result<(), (usize, StorageError)>>(0);
+    let mut remainder: Option<Data> = None;
+    let mut hashes: Vec<String> = Default::default();
+    let mut pending: usize = 0;
+    let mut tasks = PartTasks::default();
 
-                    hasher = Sha1::new();
-                    length = 0;
-                }
-            }
-            Some(Err(e)) => return Err(TransferError::SourceError(e)),
-            None => {
-                // Got all data, finish uploads.
-                if length > 0 {
-                    // Start part upload.
-                    part_count += 1;
-
-                    let hash = hasher.hexdigest();
-                    hashes.push(hash.clone());
-                    spawn(part_upload(
-                        client.clone(),
-                        info.path.clone(),
-                        file_id.clone(),
-                        part_count,
-                        PartData {
-                            data: buffers.drain(..).collect(),
-                            length,
-                            hash,
-                        },
-                        sender.clone(),
-                    ));
-                }
+    let mut failure: Option<TransferError> = None;
+    loop {
+        // The part being read counts towards the limit so wait for an upload
+        // to complete before reading any more.
+        while pending >= max_parts && failure.is_none() {
+            let result = receiver.next().await;
+            pending -= 1;
+            check_part_result(&path, result, &mut failure);
+        }
 
+        if failure.is_some() {
+            break;
+        }
+
+        let part_data = match read_part(&mut stream, &mut remainder, part_size).await {
+            Ok(Some(p)) => p,
+            Ok(None) => break,
+            Err(e) => {
+                failure = Some(TransferError::SourceError(e));
                 break;
             }
+        };
+
+        hashes.push(part_data.hash.clone());
+        let part_number = hashes.len();
+        if existing.get(&part_number) == Some(&part_data.hash) {
+            trace!(
+                "Part {} of large file upload to {} is already uploaded.",
+                part_number,
+                path
+            );
+            continue;
         }
+
+        pending += 1;
+        tasks.spawn(part_upload(
+            client.clone(),
+            path.clone(),
+            file_id.clone(),
+            part_number,
+            part_data,
+            progress.clone(),
+            sender.clone(),
+        ));
     }
 
     trace!(
         "All parts ({}) started for large file upload to {}, waiting for completion.",
-        part_count,
-        info.path
+        hashes.len(),
+        path
     );
     // Wait for parts to finish uploading.
-    while part_count > 0 {
-        match receiver.next().await {
-            Some(Ok(())) => part_count -= 1,
-            Some(Err((part_number, e))) => {
-                error!(
-                    "Part {} of large file upload to {} failed: {}",
-                    part_number, info.path, e
-                );
-                return Err(TransferError::TargetError(e));
-            }
-            None => break,
-        }
+    while pending > 0 {
+        let result = receiver.next().await;
+        pending -= 1;
+        check_part_result(&path, result, &mut failure);
+    }
+    client.forget_upload_part_urls(&file_id);
+
+    if let Some(e) = failure {
+        return Err(e);
+    }
+
+    if existing
+        .keys()
+        .any(|part_number| *part_number > hashes.len())
+    {
+        return Err(TransferError::SourceError(error::invalid_data(Some(
+            "The data ended before the parts that were already uploaded.",
+        ))));
     }
 
     trace!(
         "All parts ({}) for large file upload to {} are complete.",
         hashes.len(),
-        info.path
+        path
     );
 
     client
         .b2_finish_large_file(
-            info.path,
+            path,
             FinishLargeFileRequest {
                 file_id,
                 part_sha1_array: hashes,
             },
         )
         .await
+        .map_err(TransferError::TargetError)
+}
+
+/// Starts a large file upload, returning the new file's id.
+async fn start_large_file(
+    client: &B2API,
+    info: &UploadInfo,
+    bucket_id: String,
+    file_name: String,
+) -> Result<String, TransferError> {
+    let mut file_info = upload_file_info(info);
+    if let Some(hash) = info.checksum.as_ref().and_then(Checksum::sha1) {
+        file_info.insert(LARGE_FILE_SHA1_KEY.to_owned(), hash.to_lowercase());
+    }
+
+    let request = StartLargeFileRequest {
+        bucket_id,
+        file_name,
+        content_type: upload_content_type(info),
+        file_info: Some(file_info),
+    };
+
+    let result = client
+        .b2_start_large_file(info.path.clone(), request)
+        .await
         .map_err(TransferError::TargetError)?;
 
-    Ok(())
+    match result.file_id {
+        Some(s) => Ok(s),
+        None => Err(TransferError::TargetError(error::invalid_data(Some(
+            "Attempt to request large file upload failed.",
+        )))),
+    }
 }
 
-async fn small_upload(
+/// Picks the size of the parts of a large file upload. This is B2's recommended
+/// part size but no larger than the small file cut-off, so that a large file
+/// always has at least two parts, and no smaller than B2 allows.
+fn upload_part_size(session: &AuthorizeAccountResponse, max_small_file_size: u64) -> u64 {
+    max(
+        min(session.recommended_part_size, max_small_file_size),
+        session.absolute_minimum_part_size,
+    )
+}
+
+async fn large_upload<S>(
     client: B2API,
+    part_size: u64,
+    max_parts: usize,
     info: UploadInfo,
     bucket_id: String,
     file_name: String,
-    part_data: PartData,
-) -> StorageResult<()> {
-    trace!(
-        "Starting regular file upload to {} with {} bytes in {} chunks.",
+    stream: S,
+) -> Result<FileInfo, TransferError>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + Unpin + 'static,
+{
+    trace!("Starting large file upload to {}.", info.path);
+    let file_id = start_large_file(&client, &info, bucket_id, file_name).await?;
+    let guard = LargeFileGuard::new(&client, &info.path, &file_id);
+
+    let result = upload_parts(
+        client,
         info.path,
-        part_data.length,
-        part_data.data.len()
-    );
-    let response = client
-        .clone()
-        .b2_get_upload_url(info.path.clone(), GetUploadUrlRequest { bucket_id })
-        .await?;
+        file_id,
+        part_size,
+        max_parts,
+        HashMap::new(),
+        info.progress,
+        stream,
+    )
+    .await;
+
+    guard.disarm();
+    result
+}
 
-    let mut user_info = UserFileInfo::new();
-    if let Some(time) = info.modified.as_ref() {
-        if let Ok(duration) = time.duration_since(UNIX_EPOCH) {
-            user_info.insert(
-                LAST_MODIFIED_KEY.to_owned(),
-                duration.as_millis().to_string(),
-            );
+fn copy_range(range: &Range<u64>) -> String {
+    format!("bytes={}-{}", range.start, range.end - 1)
+}
+
+async fn part_copy(
+    client: B2API,
+    path: ObjectPath,
+    source_id: String,
+    file_id: String,
+    part: usize,
+    range: Range<u64>,
+    mut sender: Sender<Result<(usize, String), (usize, StorageError)>>,
+) {
+    trace!(
+        "Starting large file part copy to {} of bytes {} to {}.",
+        path,
+        range.start,
+        range.end
+    );
+
+    let request = CopyPartRequest {
+        source_file_id: source_id,
+        large_file_id: file_id,
+        part_number: part,
+        range: Some(copy_range(&range)),
+    };
+
+    let result = client
+        .b2_copy_part(path.clone(), request)
+        .await
+        .map(|response| (part, response.content_sha1))
+        .map_err(|e| (part, e));
+
+    // The copy stops waiting for parts if the transfer is dropped.
+    if let Err(e) = sender.send(result).await {
+        warn!(
+            "Unable to report part {} of large file copy to {}: {}",
+            part, path, e
+        );
+    }
+}
+
+async fn large_copy(
+    client: B2API,
+    max_small_file_size: u64,
+    info: UploadInfo,
+    source: FileInfo,
+    source_id: String,
+    file_name: String,
+) -> Result<FileInfo, TransferError> {
+    trace!("Starting large file copy to {}.", info.path);
+    let session = client
+        .account_info()
+        .await
+        .map_err(TransferError::TargetError)?;
+
+    let request = StartLargeFileRequest {
+        bucket_id: source.bucket_id,
+        file_name,
+        content_type: upload_content_type(&info),
+        file_info: Some(upload_file_info(&info)),
+    };
+
+    let result = client
+        .b2_start_large_file(info.path.clone(), request)
+        .await
+        .map_err(TransferError::TargetError)?;
+
+    let file_id = match result.file_id {
+        Some(s) => s,
+        None => {
+            return Err(TransferError::TargetError(error::invalid_data(Some(
+                "Attempt to request large file copy failed.",
+            ))))
+        }
+    };
+
+    // No data passes through the client so use large parts, this also ensures
+    // that there are always at least two parts.
+    let part_size = max(session.recommended_part_size, max_small_file_size);
+
+    let guard = LargeFileGuard::new(&client, &info.path, &file_id);
+    let mut tasks = PartTasks::default();
+    let (sender, mut receiver) = channel::<Result<(usize, String), (usize, StorageError)>>(0);
+    let mut part_count: usize = 0;
+    let mut start: u64 = 0;
+    while start < source.content_length {
+        let end = min(start + part_size, source.content_length);
+        part_count += 1;
+
+        tasks.spawn(part_copy(
+            client.clone(),
+            info.path.clone(),
+            source_id.clone(),
+            file_id.clone(),
+            part_count,
+            start..end,
+            sender.clone(),
+        ));
+
+        start = end;
+    }
+
+    trace!(
+        "All parts ({}) started for large file copy to {}, waiting for completion.",
+        part_count,
+        info.path
+    );
+    let mut hashes: Vec<String> = vec![String::new(); part_count];
+    let mut remaining = part_count;
+    while remaining > 0 {
+        match receiver.next().await {
+            Some(Ok((part_number, hash))) => {
+                hashes[part_number - 1] = hash;
+                remaining -= 1;
+            }
+            Some(Err((part_number, e))) => {
+                error!(
+                    "Part {} of large file copy to {} failed: {}",
+                    part_number, info.path, e
+                );
+                drop(tasks);
+                guard.cancel().await;
+                return Err(TransferError::TargetError(e));
+            }
+            None => break,
+        }
+    }
+
+    let result = client
+        .b2_finish_large_file(
+            info.path,
+            FinishLargeFileRequest {
+                file_id,
+                part_sha1_array: hashes,
+            },
+        )
+        .await
+        .map_err(TransferError::TargetError);
+
+    if result.is_err() {
+        guard.cancel().await;
+    } else {
+        guard.disarm();
+    }
+    result
+}
+
+/// Copies a file within a bucket.
+///
+/// The upload info should already include the source file's metadata. Unless
+/// `replace` is set the source file's info is copied by B2 directly.
+async fn perform_copy(
+    client: B2API,
+    max_small_file_size: u64,
+    info: UploadInfo,
+    source: FileInfo,
+    file_name: String,
+    replace: bool,
+) -> Result<FileInfo, TransferError> {
+    let source_id = match source.file_id {
+        Some(ref id) => id.clone(),
+        None => {
+            return Err(TransferError::SourceError(error::internal_error(Some(
+                "Expected object to have a file id.",
+            ))));
         }
+    };
+
+    if source.content_length > max_small_file_size {
+        return large_copy(
+            client,
+            max_small_file_size,
+            info,
+            source,
+            source_id,
+            file_name,
+        )
+        .await;
     }
 
+    trace!("Starting regular file copy to {}.", info.path);
+    let request = if replace {
+        CopyFileRequest {
+            source_file_id: source_id,
+            destination_bucket_id: None,
+            file_name,
+            range: None,
+            metadata_directive: MetadataDirective::Replace,
+            content_type: Some(upload_content_type(&info)),
+            file_info: Some(upload_file_info(&info)),
+        }
+    } else {
+        CopyFileRequest {
+            source_file_id: source_id,
+            destination_bucket_id: None,
+            file_name,
+            range: None,
+            metadata_directive: MetadataDirective::Copy,
+            content_type: None,
+            file_info: None,
+        }
+    };
+
+    client
+        .b2_copy_file(info.path, request)
+        .await
+        .map_err(TransferError::TargetError)
+}
+
+async fn small_upload(
+    client: B2API,
+    info: UploadInfo,
+    bucket_id: String,
+    file_name: String,
+    part_data: PartData,
+) -> StorageResult<FileInfo> {
+    trace!(
+        "Starting regular file upload to {} with {} bytes in {} chunks.",
+        info.path,
+        part_data.length,
+        part_data.data.len()
+    );
+    let content_type = upload_content_type(&info);
+    let file_info = upload_file_info(&info);
+
     client
         .b2_upload_file(
             info.path,
-            response.upload_url,
-            response.authorization_token,
+            bucket_id,
             file_name,
-            String::from("b2/x-auto"),
-            user_info,
+            content_type,
+            file_info,
             part_data.length,
             part_data.hash,
             part_data.data,
         )
-        .await?;
-
-    Ok(())
+        .await
 }
 
 async fn perform_upload<S>(
     client: B2API,
     mut max_small_file_size: u64,
+    max_upload_parts: usize,
     info: UploadInfo,
     bucket_id: String,
     file_name: String,
     stream: S,
-) -> Result<(), TransferError>
+) -> Result<FileInfo, TransferError>
 where
     S: Stream<Item = StorageResult<Data>> + Send + 'static,
 {
@@ -473,18 +1094,17 @@ where
                 buffers.push(data);
 
                 if length > max_small_file_size {
-                    // Start large file upload.
+                    // Start large file upload. Parts are a fixed size so that
+                    // an interrupted upload splits the same way when resumed.
+                    let stream =
+                        iter(buffers.into_iter().map(Ok::<Data, StorageError>)).chain(stream);
                     return large_upload(
                         client,
-                        session.recommended_part_size,
+                        upload_part_size(&session, max_small_file_size),
+                        max_upload_parts,
                         info,
                         bucket_id,
                         file_name,
-                        PartData {
-                            data: buffers,
-                            length,
-                            hash: hasher.hexdigest(),
-                        },
                         stream,
                     )
                     .await;
@@ -511,6 +1131,333 @@ where
     }
 }
 
+/// Limits a stream of data to the next part of a large file.
+///
+/// Any data read beyond the end of the part is kept to start the next part.
+struct PartStream<S> {
+    stream: S,
+    remainder: Option<Data>,
+    remaining: u64,
+}
+
+impl<S> PartStream<S> {
+    fn new(stream: S) -> PartStream<S> {
+        PartStream {
+            stream,
+            remainder: None,
+            remaining: 0,
+        }
+    }
+
+    /// Starts the next part of `size` bytes.
+    fn next_part(&mut self, size: u64) {
+        self.remaining = size;
+    }
+}
+
+impl<S> Stream for PartStream<S>
+where
+    S: Stream<Item = StorageResult<Data>> + Unpin,
+{
+    type Item = StorageResult<Data>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> ResultStreamPoll<Data> {
+        if self.remaining == 0 {
+            return Poll::Ready(None);
+        }
+
+        let mut chunk = match self.remainder.take() {
+            Some(chunk) => chunk,
+            None => match self.stream.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(chunk))) => chunk,
+                result => return result,
+            },
+        };
+
+        if chunk.len() as u64 > self.remaining {
+            self.remainder = Some(chunk.split_off(self.remaining as usize));
+        }
+        self.remaining -= chunk.len() as u64;
+
+        Poll::Ready(Some(Ok(chunk)))
+    }
+}
+
+/// Uploads a file of a known size without buffering its data.
+///
+/// Large files use the same part size as buffered uploads so they can be
+/// resumed in the same way. Parts are uploaded one after another as they are
+/// read from the stream.
+async fn streaming_upload<S>(
+    client: B2API,
+    mut max_small_file_size: u64,
+    info: UploadInfo,
+    bucket_id: String,
+    file_name: String,
+    size: u64,
+    stream: S,
+) -> Result<FileInfo, TransferError>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + 'static,
+{
+    let session = client
+        .account_info()
+        .await
+        .map_err(TransferError::TargetError)?;
+    if session.absolute_minimum_part_size > max_small_file_size {
+        max_small_file_size = session.absolute_minimum_part_size
+    }
+
+    let stream = Box::pin(stream);
+    if size <= max_small_file_size {
+        trace!(
+            "Starting streaming file upload to {} of {} bytes.",
+            info.path,
+            size
+        );
+        return client
+            .b2_upload_file_streaming(
+                info.path.clone(),
+                bucket_id,
+                file_name,
+                upload_content_type(&info),
+                upload_file_info(&info),
+                size,
+                stream,
+            )
+            .await;
+    }
+
+    trace!(
+        "Starting streaming large file upload to {} of {} bytes.",
+        info.path,
+        size
+    );
+    let file_id = start_large_file(&client, &info, bucket_id, file_name).await?;
+    let guard = LargeFileGuard::new(&client, &info.path, &file_id);
+
+    let part_size = upload_part_size(&session, max_small_file_size);
+    let mut parts = PartStream::new(stream);
+    let mut hashes: Vec<String> = Default::default();
+    let mut start: u64 = 0;
+    let mut failure: Option<TransferError> = None;
+    while start < size {
+        let length = min(part_size, size - start);
+        let part = hashes.len() + 1;
+        parts.next_part(length);
+        if let Some(ref observer) = info.progress {
+            observer.notify(TransferEvent::PartStarted { part, length });
+        }
+
+        match client
+            .clone()
+            .b2_upload_part_streaming(
+                info.path.clone(),
+                file_id.clone(),
+                part,
+                length,
+                info.progress.clone(),
+                parts,
+            )
+            .await
+        {
+            Ok((response, stream)) => {
+                if let Some(ref observer) = info.progress {
+                    observer.notify(TransferEvent::PartCompleted { part, length });
+                }
+                hashes.push(response.content_sha1);
+                parts = stream;
+            }
+            Err(e) => {
+                error!(
+                    "Part {} of large file upload to {} failed: {}",
+                    part, info.path, e
+                );
+                if let Some(ref observer) = info.progress {
+                    let error = match e {
+                        TransferError::SourceError(ref e) | TransferError::TargetError(ref e) => {
+                            e.clone()
+                        }
+                    };
+                    observer.notify(TransferEvent::PartFailed { part, error });
+                }
+                failure = Some(e);
+                break;
+            }
+        }
+
+        start += length;
+    }
+    client.forget_upload_part_urls(&file_id);
+
+    if let Some(e) = failure {
+        guard.disarm();
+        return Err(e);
+    }
+
+    // The stream must end now, this also checks the data's hash and size.
+    if let Some(result) = parts.stream.next().await {
+        guard.disarm();
+        return Err(TransferError::SourceError(match result {
+            Ok(_) => error::invalid_data(Some("The data was longer than expected.")),
+            Err(e) => e,
+        }));
+    }
+
+    trace!(
+        "All parts ({}) for large file upload to {} are complete.",
+        hashes.len(),
+        info.path
+    );
+
+    let result = client
+        .b2_finish_large_file(
+            info.path,
+            FinishLargeFileRequest {
+                file_id,
+                part_sha1_array: hashes,
+            },
+        )
+        .await
+        .map_err(TransferError::TargetError);
+
+    guard.disarm();
+    result
+}
+
+/// Converts a downloaded body into a stream of data.
+fn data_stream<S>(body: S) -> DataStream
+where
+    S: Stream<Item = Result<Chunk, hyper::Error>> + Send + 'static,
+{
+    DataStream::from_stream(body.map(|result| match result {
+        Ok(chunk) => Result::<Data, StorageError>::Ok(chunk.into_bytes()),
+        Err(e) => Result::<Data, StorageError>::Err(e.into()),
+    }))
+}
+
+/// Lists all the versions of a file.
+async fn file_versions(
+    client: B2API,
+    path: ObjectPath,
+    bucket_id: String,
+    file_name: String,
+) -> StorageResult<Option<FileVersions>> {
+    let options = ListFileVersionsRequest {
+        bucket_id,
+        start_file_name: None,
+        start_file_id: None,
+        max_file_count: None,
+        prefix: Some(file_name.clone()),
+        delimiter: Some(String::from("/")),
+    };
+
+    let requestor = FileVersionsRequestor::new(client, path, options);
+    let mut files: Vec<FileVersions> = ListStream::new(requestor)
+        .try_filter(|versions| ready(versions.latest().file_name == file_name))
+        .try_collect()
+        .await?;
+
+    if files.len() == 1 {
+        Ok(Some(files.remove(0)))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Performs a write enforcing the upload's precondition.
+///
+/// B2 has no conditional writes so the file's versions are checked before the
+/// write and then again after. If any other version was written in between
+/// the new version is deleted and the write fails.
+async fn conditional_write<F>(
+    client: B2API,
+    info: &UploadInfo,
+    bucket_id: String,
+    file_name: String,
+    write: F,
+) -> Result<(), TransferError>
+where
+    F: Future<Output = Result<FileInfo, TransferError>>,
+{
+    if info.precondition == WritePrecondition::Always {
+        write.await?;
+        return Ok(());
+    }
+
+    let before = file_versions(
+        client.clone(),
+        info.path.clone(),
+        bucket_id.clone(),
+        file_name.clone(),
+    )
+    .await
+    .map_err(TransferError::TargetError)?;
+
+    let expected = before.as_ref().map(|versions| versions.latest().clone());
+    let current = before
+        .map(|versions| B2Object {
+            path: info.path.clone(),
+            versions,
+        })
+        .filter(|object| object.object_type() == ObjectType::File);
+    info.precondition
+        .check(&info.path, current.as_ref())
+        .map_err(TransferError::TargetError)?;
+
+    let uploaded = write.await?;
+
+    let after = file_versions(
+        client.clone(),
+        info.path.clone(),
+        bucket_id,
+        file_name.clone(),
+    )
+    .await
+    .map_err(TransferError::TargetError)?;
+
+    let since = expected.as_ref().map(|f| f.upload_timestamp).unwrap_or(0);
+    let expected_id = expected.and_then(|f| f.file_id);
+    let conflict = after
+        .iter()
+        .flat_map(|versions| versions.iter())
+        .any(|version| {
+            version.file_id != uploaded.file_id
+                && version.file_id != expected_id
+                && version.upload_timestamp >= since
+                && version.upload_timestamp <= uploaded.upload_timestamp
+        });
+
+    if !conflict {
+        return Ok(());
+    }
+
+    warn!(
+        "Another version of {} was written concurrently, removing this one.",
+        info.path
+    );
+    if let Some(file_id) = uploaded.file_id {
+        if let Err(e) = client
+            .b2_delete_file_version(
+                info.path.clone(),
+                DeleteFileVersionRequest { file_name, file_id },
+            )
+            .await
+        {
+            error!(
+                "Failed to remove the conflicting version of {}: {}",
+                info.path, e
+            );
+        }
+    }
+
+    let err = match info.precondition {
+        WritePrecondition::DoesNotExist => error::already_exists(info.path.clone(), None),
+        _ => error::precondition_failed(info.path.clone(), None),
+    };
+    Err(TransferError::TargetError(err))
+}
+
 trait ListRequestor<S>
 where
     S: Send + 'static,
@@ -685,11 +1632,554 @@ where
     }
 }
 
-/// The backend implementation for B2 storage.
-#[derive(Debug, Clone)]
-pub struct B2Backend {
-    state: B2APIState,
-}
+/// Reads an entire response body into a single buffer.
+async fn collect_body<S>(body: S) -> StorageResult<Data>
+where
+    S: Stream<Item = Result<Chunk, hyper::Error>>,
+{
+    let mut body = Box::pin(body);
+    let mut data = BytesMut::new();
+    while let Some(chunk) = body.next().await {
+        data.extend_from_slice(&chunk?);
+    }
+
+    Ok(data.freeze())
+}
+
+/// Downloads a single range of a specific version of a file for a parallel
+/// download.
+async fn download_range(
+    client: B2API,
+    path: ObjectPath,
+    file_id: String,
+    range: Range<u64>,
+) -> StorageResult<Data> {
+    let expected = range.end - range.start;
+    let (_, body) = client
+        .b2_download_file_by_id(path.clone(), file_id, Some(range))
+        .await?;
+    let data = collect_body(body).await?;
+
+    if data.len() as u64 != expected {
+        return Err(error::invalid_data(Some(&format!(
+            "Expected {} bytes from a range of {} but received {}.",
+            expected,
+            path,
+            data.len()
+        ))));
+    }
+
+    Ok(data)
+}
+
+/// Downloads a file, or a range of it, as a series of ranges fetched in
+/// parallel.
+///
+/// The first range is fetched by name to discover the size of the file and
+/// the id of its current version. After that up to `read_ahead` ranges of that
+/// same version are downloaded at once and returned in order.
+#[allow(clippy::too_many_arguments)]
+async fn parallel_download(
+    client: B2API,
+    path: ObjectPath,
+    bucket: String,
+    file: String,
+    range: Option<Range<u64>>,
+    chunk_size: u64,
+    read_ahead: usize,
+) -> StorageResult<DataStream> {
+    let (start, mut end) = match range {
+        Some(r) => (r.start, r.end),
+        None => (0, u64::max_value()),
+    };
+
+    let first = start..min(end, start.saturating_add(chunk_size));
+    trace!(
+        "Starting parallel download of {} with range {:?}.",
+        path,
+        first
+    );
+    let (headers, body) = client
+        .clone()
+        .b2_download_file_by_name(path.clone(), bucket, file, Some(first.clone()))
+        .await?;
+    let first_data = collect_body(body).await?;
+
+    if let Some(size) = headers.size {
+        end = min(end, size);
+    } else {
+        end = first.start + first_data.len() as u64;
+    }
+
+    let mut ranges: Vec<Range<u64>> = Default::default();
+    let mut next = first.start + first_data.len() as u64;
+    while next < end {
+        let range_end = min(next.saturating_add(chunk_size), end);
+        ranges.push(next..range_end);
+        next = range_end;
+    }
+
+    let file_id = match headers.file_id {
+        Some(id) => id,
+        None if ranges.is_empty() => String::new(),
+        None => {
+            return Err(error::invalid_data(Some(&format!(
+                "The download of {} did not include the file id.",
+                path
+            ))))
+        }
+    };
+
+    let rest = iter(ranges)
+        .map(move |range| download_range(client.clone(), path.clone(), file_id.clone(), range))
+        .buffered(read_ahead);
+
+    Ok(DataStream::from_stream(
+        once(ready(Ok(first_data))).chain(rest),
+    ))
+}
+
+/// A future that resolves to a list of unfinished large file uploads.
+pub type UnfinishedUploadsFuture = WrappedFuture<StorageResult<Vec<UnfinishedUpload>>>;
+
+/// A large file upload that was started but never finished.
+///
+/// These are left behind when a large file upload fails. They can be resumed
+/// with [`resume_upload`](struct.B2Backend.html#method.resume_upload) or
+/// cancelled to discard the parts that were uploaded.
+#[derive(Clone, Debug)]
+pub struct UnfinishedUpload {
+    path: ObjectPath,
+    file_id: String,
+    started: SystemTime,
+}
+
+impl UnfinishedUpload {
+    /// The path of the file being uploaded.
+    pub fn path(&self) -> ObjectPath {
+        self.path.clone()
+    }
+
+    /// The B2 file id of the upload.
+    pub fn file_id(&self) -> &str {
+        &self.file_id
+    }
+
+    /// The time that the upload was started.
+    pub fn started(&self) -> SystemTime {
+        self.started
+    }
+}
+
+/// Converts a bucket name into a path with a single part.
+fn bucket_path(name: &str) -> StorageResult<ObjectPath> {
+    let path = ObjectPath::new(name)?;
+    if path.parts().len() != 1 || path.is_dir_prefix() {
+        return Err(error::invalid_path(
+            path,
+            Some("Bucket names cannot be empty or contain a '/' character."),
+        ));
+    }
+
+    Ok(path)
+}
+
+/// Finds the buckets to search for files. When no bucket name is given every
+/// bucket in the account is included.
+async fn search_buckets(
+    client: &B2API,
+    path: ObjectPath,
+    bucket: Option<String>,
+) -> StorageResult<Vec<BucketRef>> {
+    if let Some(bucket_name) = bucket {
+        return match client.bucket_ref(path, &bucket_name).await {
+            Ok(bucket) => Ok(vec![bucket]),
+            Err(e) => match e.kind() {
+                StorageErrorKind::NotFound(_) => Ok(Vec::new()),
+                _ => Err(e),
+            },
+        };
+    }
+
+    let request = ListBucketsRequest {
+        account_id: client.account_info().await?.account_id,
+        bucket_id: None,
+        bucket_name: None,
+        bucket_types: Default::default(),
+    };
+
+    let buckets = client.b2_list_buckets(path, request).await?.buckets;
+    Ok(buckets
+        .into_iter()
+        .map(|bucket| {
+            client.cache_bucket(bucket.clone());
+            BucketRef {
+                bucket_id: bucket.bucket_id,
+                bucket_name: bucket.bucket_name,
+            }
+        })
+        .collect())
+}
+
+/// Lists the unfinished large file uploads for files prefixed by the given
+/// prefix.
+async fn unfinished_uploads(
+    client: B2API,
+    backend_prefix: ObjectPath,
+    prefix: ObjectPath,
+) -> StorageResult<Vec<UnfinishedUpload>> {
+    let mut file_part = backend_prefix.join(&prefix);
+    let bucket = file_part.unshift_part();
+
+    let path = ObjectPath::new(bucket.clone().unwrap_or_else(String::new))?;
+    let buckets = search_buckets(&client, path.clone(), bucket).await?;
+
+    let mut uploads: Vec<UnfinishedUpload> = Default::default();
+    for bucket in buckets {
+        let mut start_file_id = None;
+        loop {
+            let request = ListUnfinishedLargeFilesRequest {
+                bucket_id: bucket.bucket_id.clone(),
+                name_prefix: Some(file_part.to_string()),
+                start_file_id,
+                max_file_count: None,
+            };
+
+            let response = client
+                .b2_list_unfinished_large_files(path.clone(), request)
+                .await?;
+
+            for file in response.files {
+                let file_id = match file.file_id {
+                    Some(id) => id,
+                    None => continue,
+                };
+
+                let mut upload_path = ObjectPath::new(&file.file_name)?;
+                upload_path.shift_part(&bucket.bucket_name);
+                for _ in backend_prefix.parts() {
+                    upload_path.unshift_part();
+                }
+
+                uploads.push(UnfinishedUpload {
+                    path: upload_path,
+                    file_id,
+                    started: UNIX_EPOCH + Duration::from_millis(file.upload_timestamp),
+                });
+            }
+
+            match response.next_file_id {
+                Some(id) => start_file_id = Some(id),
+                None => break,
+            }
+        }
+    }
+
+    Ok(uploads)
+}
+
+/// Continues an unfinished large file upload with the entire file's data.
+async fn resume_large_upload<S>(
+    client: B2API,
+    max_small_file_size: u64,
+    max_upload_parts: usize,
+    upload: UnfinishedUpload,
+    stream: S,
+) -> Result<(), TransferError>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + 'static,
+{
+    trace!("Resuming large file upload to {}.", upload.path);
+    let session = client
+        .account_info()
+        .await
+        .map_err(TransferError::TargetError)?;
+
+    let mut existing: HashMap<usize, String> = HashMap::new();
+    let mut first_part_size: Option<u64> = None;
+    let mut start_part_number = None;
+    loop {
+        let request = ListPartsRequest {
+            file_id: upload.file_id.clone(),
+            start_part_number,
+            max_part_count: None,
+        };
+
+        let response = client
+            .b2_list_parts(upload.path.clone(), request)
+            .await
+            .map_err(TransferError::TargetError)?;
+
+        for part in response.parts {
+            if part.part_number == 1 {
+                first_part_size = Some(part.content_length);
+            }
+            existing.insert(part.part_number, part.content_sha1);
+        }
+
+        match response.next_part_number {
+            Some(number) => start_part_number = Some(number),
+            None => break,
+        }
+    }
+
+    // Only the final part can be shorter than the others so the first part
+    // gives the size the upload was originally split with.
+    let part_size = first_part_size.unwrap_or_else(|| {
+        upload_part_size(
+            &session,
+            max(max_small_file_size, session.absolute_minimum_part_size),
+        )
+    });
+
+    upload_parts(
+        client,
+        upload.path,
+        upload.file_id,
+        part_size,
+        max_upload_parts,
+        existing,
+        None,
+        Box::pin(stream),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Cancels the unfinished large file uploads that were started before the
+/// cut-off.
+async fn cancel_stale(
+    client: B2API,
+    backend_prefix: ObjectPath,
+    older_than: Duration,
+) -> StorageResult<Vec<UnfinishedUpload>> {
+    let cutoff = SystemTime::now()
+        .checked_sub(older_than)
+        .unwrap_or(UNIX_EPOCH);
+
+    let mut cancelled: Vec<UnfinishedUpload> = Default::default();
+    for upload in unfinished_uploads(client.clone(), backend_prefix, ObjectPath::empty()).await? {
+        if upload.started > cutoff {
+            continue;
+        }
+
+        trace!("Cancelling stale large file upload to {}.", upload.path);
+        client
+            .b2_cancel_large_file(
+                upload.path.clone(),
+                CancelLargeFileRequest {
+                    file_id: upload.file_id.clone(),
+                },
+            )
+            .await?;
+        cancelled.push(upload);
+    }
+
+    Ok(cancelled)
+}
+
+/// Gets the object for a path that has already been split into its bucket and
+/// file name.
+async fn get_expanded(
+    client: B2API,
+    backend_prefix: ObjectPath,
+    path: ObjectPath,
+    bucket: BucketRef,
+    file: String,
+) -> StorageResult<Object> {
+    match file_versions(client, path.clone(), bucket.bucket_id.clone(), file).await? {
+        Some(ref versions) if versions.latest().action == FileAction::Hide => {
+            Err(error::not_found(path, None))
+        }
+        Some(versions) => new_object(&bucket.bucket_name, versions, &backend_prefix),
+        None => Err(error::not_found(path, None)),
+    }
+}
+
+/// Copies a source object to a target whose path has already been split into
+/// its bucket and file name.
+async fn copy_expanded(
+    backend: B2Backend,
+    source: ObjectPath,
+    object: Object,
+    mut info: UploadInfo,
+    bucket: BucketRef,
+    file: String,
+) -> Result<(), TransferError> {
+    let object: B2Object = match object.try_into() {
+        Ok(o) => o,
+        Err(_) => {
+            return Err(TransferError::SourceError(error::internal_error(Some(
+                "Failed to convert retrieved object to the expected type.",
+            ))));
+        }
+    };
+
+    if object.object_type() != ObjectType::File {
+        return Err(TransferError::SourceError(error::not_found(source, None)));
+    }
+
+    let replace = info.has_metadata() || info.modified.is_some();
+    info.inherit_metadata(&object);
+
+    let latest = object.versions.latest().clone();
+    if bucket.bucket_id != latest.bucket_id {
+        let stream = DataStream::from_stream(backend.get_file_stream(source).try_flatten_stream());
+        return backend.write_file_from_stream(info, stream).await;
+    }
+
+    if info.modified.is_none() {
+        info.modified = object.modified();
+    }
+
+    let client = backend.client();
+    let write = perform_copy(
+        client.clone(),
+        backend.state.settings.max_small_file_size,
+        info.clone(),
+        latest,
+        file.clone(),
+        replace,
+    );
+    conditional_write(client, &info, bucket.bucket_id, file, write).await
+}
+
+/// The buckets used by a batch of operations. Each bucket is looked up once
+/// for the whole batch rather than once for every path in it, using the
+/// client's bucket cache.
+struct BatchBuckets {
+    prefix: ObjectPath,
+    buckets: HashMap<String, StorageResult<BucketRef>>,
+}
+
+impl BatchBuckets {
+    /// Looks up the buckets for the given paths. Each lookup is limited by the
+    /// operation timeout, a bucket that times out fails every path using it.
+    async fn lookup(
+        client: B2API,
+        prefix: ObjectPath,
+        paths: Vec<ObjectPath>,
+        timeout: Option<Duration>,
+    ) -> BatchBuckets {
+        let mut buckets: HashMap<String, StorageResult<BucketRef>> = HashMap::new();
+        for path in paths {
+            if let Some(name) = prefix.join(&path).unshift_part() {
+                if !buckets.contains_key(&name) {
+                    let lookup_client = client.clone();
+                    let lookup_name = name.clone();
+                    let bucket =
+                        WrappedFuture::<StorageResult<BucketRef>>::from_future(async move {
+                            lookup_client.bucket_ref(path, &lookup_name).await
+                        })
+                        .with_timeout(timeout)
+                        .await;
+                    buckets.insert(name, bucket);
+                }
+            }
+        }
+
+        BatchBuckets { prefix, buckets }
+    }
+
+    /// Splits a path into its bucket and file name in the same way as
+    /// `B2Backend::expand_path`.
+    fn expand(&self, path: &ObjectPath) -> StorageResult<(BucketRef, String)> {
+        if path.is_dir_prefix() {
+            return Err(error::invalid_path(
+                path.clone(),
+                Some("Object paths cannot be empty or end with a '/' character."),
+            ));
+        }
+
+        let mut file_part = self.prefix.join(path);
+        let bucket_name = match file_part.unshift_part() {
+            Some(b) => b,
+            None => return Err(error::not_found(path.clone(), None)),
+        };
+
+        if file_part.is_empty() {
+            return Err(error::not_found(path.clone(), None));
+        }
+
+        match self.buckets.get(&bucket_name) {
+            Some(Ok(bucket)) => Ok((bucket.clone(), file_part.to_string())),
+            // The error was for whichever path first used the bucket.
+            Some(Err(e)) => match e.kind() {
+                StorageErrorKind::NotFound(_) => Err(error::not_found(path.clone(), None)),
+                _ => Err(e.clone()),
+            },
+            None => Err(error::internal_error(Some(
+                "The bucket was not looked up for this batch.",
+            ))),
+        }
+    }
+}
+
+/// Deletes every version of the file with the given name, including the
+/// versions of a file that is currently hidden.
+async fn delete_file(
+    client: B2API,
+    path: ObjectPath,
+    bucket_id: String,
+    file_name: String,
+) -> StorageResult<()> {
+    match file_versions(client.clone(), path.clone(), bucket_id, file_name).await? {
+        Some(versions) => delete_versions(client, path, versions).await,
+        None => Err(error::not_found(path, None)),
+    }
+}
+
+/// Deletes all of the versions of a listed object, oldest first.
+async fn delete_object_versions(client: B2API, object: Object) -> StorageResult<()> {
+    let path = object.path();
+    let object: B2Object = match object.try_into() {
+        Ok(o) => o,
+        Err(_) => {
+            return Err(error::internal_error(Some(
+                "Failed to convert retrieved object to the expected type.",
+            )));
+        }
+    };
+
+    delete_versions(client, path, object.versions).await
+}
+
+/// Deletes all of the versions of a file, oldest first.
+async fn delete_versions(
+    client: B2API,
+    path: ObjectPath,
+    versions: FileVersions,
+) -> StorageResult<()> {
+    for info in versions.iter() {
+        match info.file_id {
+            Some(ref id) => {
+                client
+                    .b2_delete_file_version(
+                        path.clone(),
+                        DeleteFileVersionRequest {
+                            file_name: info.file_name.clone(),
+                            file_id: id.to_owned(),
+                        },
+                    )
+                    .await?;
+            }
+            None => {
+                return Err(error::internal_error(Some(
+                    "Expected object to have a file id.",
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The backend implementation for B2 storage.
+#[derive(Debug, Clone)]
+pub struct B2Backend {
+    state: B2APIState,
+}
 
 impl B2Backend {
     /// Creates a new [`FileStore`](../../enum.FileStore.html) instance using the
@@ -710,6 +2200,15 @@ impl B2Backend {
                 host: B2_API_HOST.to_owned(),
                 prefix: ObjectPath::empty(),
                 max_small_file_size: DEFAULT_MAX_SMALL_FILE_SIZE,
+                max_upload_parts: DEFAULT_UPLOAD_PART_LIMIT,
+                max_requests: DEFAULT_REQUEST_LIMIT,
+                stream_uploads: false,
+                download_chunk_size: None,
+                download_read_ahead: 1,
+                bucket_cache_ttl: DEFAULT_BUCKET_CACHE_TTL,
+                retry_policy: Default::default(),
+                request_timeout: None,
+                operation_timeout: None,
                 user_agent: format!(
                     "{}/{} ({})",
                     env!("CARGO_PKG_NAME"),
@@ -717,21 +2216,352 @@ impl B2Backend {
                     env!("CARGO_PKG_REPOSITORY")
                 ),
             },
-            max_requests: DEFAULT_REQUEST_LIMIT,
         }
-    }
+    }
+
+    /// Lists the large file uploads that were started for files prefixed by
+    /// the given prefix but never finished.
+    pub fn list_unfinished_uploads<P>(&self, prefix: P) -> UnfinishedUploadsFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let prefix = match prefix.try_into() {
+            Ok(p) => p,
+            Err(e) => return UnfinishedUploadsFuture::from_value(Err(e.into())),
+        };
+
+        UnfinishedUploadsFuture::from_future(unfinished_uploads(
+            self.client(),
+            self.state.settings.prefix.clone(),
+            prefix,
+        ))
+    }
+
+    /// Continues an unfinished large file upload.
+    ///
+    /// The stream must provide the entire file's data from the start. It is
+    /// split into parts the same way as the original upload and any parts
+    /// already uploaded with matching data are skipped. The file's metadata is
+    /// what was given when the upload started and the write's precondition is
+    /// not checked again.
+    pub fn resume_upload<S, I, E>(
+        &self,
+        upload: &UnfinishedUpload,
+        stream: S,
+    ) -> WriteCompleteFuture
+    where
+        S: Stream<Item = Result<I, E>> + Send + 'static,
+        I: IntoBuf + 'static,
+        E: Into<StorageError> + 'static,
+    {
+        WriteCompleteFuture::from_future(resume_large_upload(
+            self.client(),
+            self.state.settings.max_small_file_size,
+            self.state.settings.max_upload_parts,
+            upload.clone(),
+            into_data_stream(stream),
+        ))
+    }
+
+    /// Cancels an unfinished large file upload, deleting any uploaded parts.
+    pub fn cancel_upload(&self, upload: &UnfinishedUpload) -> OperationCompleteFuture {
+        let request = CancelLargeFileRequest {
+            file_id: upload.file_id.clone(),
+        };
+
+        OperationCompleteFuture::from_future(
+            self.client()
+                .b2_cancel_large_file(upload.path.clone(), request)
+                .map_ok(|_| ()),
+        )
+    }
+
+    /// Lists all of the versions of the file at the given path, oldest first.
+    ///
+    /// This includes files that are currently hidden.
+    pub fn list_versions<P>(&self, path: P) -> FileVersionsFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        async fn list(
+            client: B2API,
+            backend_prefix: ObjectPath,
+            path: ObjectPath,
+        ) -> StorageResult<Vec<FileVersion>> {
+            let (bucket, file) =
+                B2Backend::expand_path(client.clone(), backend_prefix, path.clone()).await?;
+
+            match file_versions(client, path.clone(), bucket.bucket_id, file).await? {
+                Some(versions) => Ok(B2Object { path, versions }.versions()),
+                None => Err(error::not_found(path, None)),
+            }
+        }
+
+        let path = match path.try_into() {
+            Ok(p) => p,
+            Err(e) => return FileVersionsFuture::from_value(Err(e.into())),
+        };
+
+        if path.is_dir_prefix() {
+            return FileVersionsFuture::from_value(Err(error::invalid_path(
+                path,
+                Some("Object paths cannot be empty or end with a '/' character."),
+            )));
+        }
+
+        FileVersionsFuture::from_future(list(
+            self.client(),
+            self.state.settings.prefix.clone(),
+            path,
+        ))
+    }
+
+    /// Gets a stream of data for a specific version of a file.
+    ///
+    /// This will return a [`NotFound`](../../enum.StorageErrorKind.html#variant.NotFound)
+    /// error if the version has no content, for example if it hides the file.
+    pub fn get_version_stream(&self, version: &FileVersion) -> DataStreamFuture {
+        if version.info.action != FileAction::Upload {
+            return DataStreamFuture::from_value(Err(error::not_found(version.path(), None)));
+        }
+
+        DataStreamFuture::from_future(
+            self.client()
+                .b2_download_file_by_id(version.path(), version.file_id.clone(), None)
+                .map_ok(|(_, body)| data_stream(body)),
+        )
+    }
+
+    /// Deletes a single version of a file.
+    ///
+    /// Deleting the latest version makes the previous version current again.
+    pub fn delete_version(&self, version: &FileVersion) -> OperationCompleteFuture {
+        let request = DeleteFileVersionRequest {
+            file_name: version.info.file_name.clone(),
+            file_id: version.file_id.clone(),
+        };
+
+        OperationCompleteFuture::from_future(
+            self.client()
+                .b2_delete_file_version(version.path(), request)
+                .map_ok(|_| ()),
+        )
+    }
+
+    /// Hides the file at the given path.
+    ///
+    /// This adds a new version that hides the file so that it appears to have
+    /// been deleted while keeping its previous versions. Deleting the hiding
+    /// version with [`delete_version`](#method.delete_version) restores the
+    /// file.
+    pub fn hide_file<P>(&self, path: P) -> OperationCompleteFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        async fn hide(
+            client: B2API,
+            backend_prefix: ObjectPath,
+            path: ObjectPath,
+        ) -> StorageResult<()> {
+            let (bucket, file_name) =
+                B2Backend::expand_path(client.clone(), backend_prefix, path.clone()).await?;
+
+            let request = HideFileRequest {
+                bucket_id: bucket.bucket_id,
+                file_name,
+            };
+            client.b2_hide_file(path, request).await?;
+            Ok(())
+        }
+
+        let path = match path.try_into() {
+            Ok(p) => p,
+            Err(e) => return OperationCompleteFuture::from_value(Err(e.into())),
+        };
+
+        if path.is_dir_prefix() {
+            return OperationCompleteFuture::from_value(Err(error::invalid_path(
+                path,
+                Some("Object paths cannot be empty or end with a '/' character."),
+            )));
+        }
+
+        OperationCompleteFuture::from_future(hide(
+            self.client(),
+            self.state.settings.prefix.clone(),
+            path,
+        ))
+    }
+
+    /// Creates a new bucket with the given name.
+    ///
+    /// Bucket names are not affected by this backend's prefix.
+    pub fn create_bucket(&self, name: &str, bucket_type: BucketType) -> BucketFuture {
+        async fn create(
+            client: B2API,
+            path: ObjectPath,
+            bucket_type: BucketType,
+        ) -> StorageResult<Bucket> {
+            let request = CreateBucketRequest {
+                account_id: client.account_info().await?.account_id,
+                bucket_name: path.to_string(),
+                bucket_type,
+                bucket_info: None,
+                cors_rules: None,
+                lifecycle_rules: None,
+            };
+
+            let bucket = client.b2_create_bucket(path, request).await?;
+            client.cache_bucket(bucket.clone());
+            Ok(bucket)
+        }
+
+        match bucket_path(name) {
+            Ok(path) => BucketFuture::from_future(create(self.client(), path, bucket_type)),
+            Err(e) => BucketFuture::from_value(Err(e)),
+        }
+    }
+
+    /// Replaces the lifecycle rules of the named bucket.
+    pub fn set_lifecycle_rules(&self, name: &str, rules: Vec<LifecycleRule>) -> BucketFuture {
+        self.update_bucket(name, |request| request.lifecycle_rules = Some(rules))
+    }
+
+    /// Replaces the CORS rules of the named bucket.
+    pub fn set_cors_rules(&self, name: &str, rules: Vec<CorsRule>) -> BucketFuture {
+        self.update_bucket(name, |request| request.cors_rules = Some(rules))
+    }
+
+    /// Replaces the info of the named bucket.
+    pub fn set_bucket_info(&self, name: &str, info: HashMap<String, String>) -> BucketFuture {
+        let info = info
+            .into_iter()
+            .map(|(key, value)| (key, serde_json::Value::String(value)))
+            .collect();
+        self.update_bucket(name, |request| request.bucket_info = Some(info))
+    }
+
+    /// Deletes the named bucket.
+    ///
+    /// B2 will only delete a bucket that contains no file versions, otherwise
+    /// this fails with a precondition failed error.
+    pub fn delete_bucket(&self, name: &str) -> OperationCompleteFuture {
+        async fn delete(client: B2API, path: ObjectPath) -> StorageResult<()> {
+            let bucket = client.bucket(path.clone(), &path.to_string()).await?;
+            let request = DeleteBucketRequest {
+                account_id: bucket.account_id,
+                bucket_id: bucket.bucket_id,
+            };
+
+            client.forget_bucket(&bucket.bucket_name);
+            client.b2_delete_bucket(path, request).await?;
+            Ok(())
+        }
+
+        match bucket_path(name) {
+            Ok(path) => OperationCompleteFuture::from_future(delete(self.client(), path)),
+            Err(e) => OperationCompleteFuture::from_value(Err(e)),
+        }
+    }
+
+    /// Cancels all of the unfinished large file uploads beneath this backend's
+    /// prefix that were started more than `older_than` ago.
+    ///
+    /// Resolves to the uploads that were cancelled.
+    pub fn cancel_stale_uploads(&self, older_than: Duration) -> UnfinishedUploadsFuture {
+        UnfinishedUploadsFuture::from_future(cancel_stale(
+            self.client(),
+            self.state.settings.prefix.clone(),
+            older_than,
+        ))
+    }
+
+    /// Creates a new [`B2API`](struct.B2API.html) that can be used for
+    /// making B2 API calls.
+    fn client(&self) -> B2API {
+        B2API::new(&self.state)
+    }
+
+    /// Applies some changes to the named bucket.
+    fn update_bucket<F>(&self, name: &str, change: F) -> BucketFuture
+    where
+        F: FnOnce(&mut UpdateBucketRequest) + Send + 'static,
+    {
+        async fn update<F>(client: B2API, path: ObjectPath, change: F) -> StorageResult<Bucket>
+        where
+            F: FnOnce(&mut UpdateBucketRequest),
+        {
+            let bucket = client.bucket(path.clone(), &path.to_string()).await?;
+            let mut request = UpdateBucketRequest {
+                account_id: bucket.account_id,
+                bucket_id: bucket.bucket_id,
+                bucket_type: None,
+                bucket_info: None,
+                cors_rules: None,
+                lifecycle_rules: None,
+                if_revision_is: None,
+            };
+            change(&mut request);
+
+            let bucket = client.b2_update_bucket(path, request).await?;
+            client.cache_bucket(bucket.clone());
+            Ok(bucket)
+        }
+
+        match bucket_path(name) {
+            Ok(path) => BucketFuture::from_future(update(self.client(), path, change)),
+            Err(e) => BucketFuture::from_value(Err(e)),
+        }
+    }
+
+    /// Downloads the file at the given path, optionally only a range of it.
+    fn download(&self, path: ObjectPath, range: Option<Range<u64>>) -> DataStreamFuture {
+        if path.is_dir_prefix() {
+            return DataStreamFuture::from_value(Err(error::invalid_path(
+                path,
+                Some("Object paths cannot be empty or end with a '/' character."),
+            )));
+        }
+
+        let mut file_name = self.state.settings.prefix.join(&path);
+        let bucket = match file_name.unshift_part() {
+            Some(b) => b,
+            _ => {
+                return DataStreamFuture::from_value(Err(error::invalid_path(
+                    path,
+                    Some("Object paths cannot be empty."),
+                )));
+            }
+        };
+
+        if let Some(chunk_size) = self.state.settings.download_chunk_size {
+            return DataStreamFuture::from_future(parallel_download(
+                self.client(),
+                path,
+                bucket,
+                file_name.to_string(),
+                range,
+                chunk_size,
+                self.state.settings.download_read_ahead,
+            ));
+        }
 
-    /// Creates a new [`B2API`](struct.B2API.html) that can be used for
-    /// making B2 API calls.
-    fn client(&self) -> B2API {
-        B2API::new(&self.state)
+        let future = self
+            .client()
+            .b2_download_file_by_name(path, bucket, file_name.to_string(), range)
+            .map_ok(|(_, body)| data_stream(body));
+
+        DataStreamFuture::from_future(future)
     }
 
     async fn expand_path(
         client: B2API,
         prefix: ObjectPath,
         path: ObjectPath,
-    ) -> StorageResult<(Bucket, String)> {
+    ) -> StorageResult<(BucketRef, String)> {
         let mut file_part = prefix.join(&path);
         let bucket_name = match file_part.unshift_part() {
             Some(b) => b,
@@ -742,19 +2572,93 @@ impl B2Backend {
             return Err(error::not_found(path, None));
         }
 
-        let request = ListBucketsRequest {
-            account_id: client.account_info().await?.account_id,
-            bucket_id: None,
-            bucket_name: Some(bucket_name),
-            bucket_types: Default::default(),
-        };
+        let bucket = client.bucket_ref(path, &bucket_name).await?;
+        Ok((bucket, file_part.to_string()))
+    }
+}
 
-        let mut buckets = client.b2_list_buckets(path.clone(), request).await?.buckets;
-        if buckets.len() != 1 {
-            return Err(error::not_found(path, None));
+/// Controls how requests to B2 that fail are retried.
+///
+/// Only failures that B2 says are temporary are retried, for example
+/// `429 too_many_requests` or `503` responses and connection failures. The
+/// delay before each retry doubles from the base delay up to the maximum delay.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: usize,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: bool,
+    honor_retry_after: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            base_delay: DEFAULT_BASE_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            jitter: true,
+            honor_retry_after: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Creates the default policy. This makes up to 5 attempts with delays
+    /// starting at 100ms and growing to at most 30 seconds, with jitter and
+    /// honoring the `Retry-After` header.
+    pub fn new() -> RetryPolicy {
+        Default::default()
+    }
+
+    /// Sets the most attempts made for a request, including the first.
+    pub fn max_attempts(mut self, attempts: usize) -> RetryPolicy {
+        self.max_attempts = max(attempts, 1);
+        self
+    }
+
+    /// Sets the delay before the first retry and the most that the delay can
+    /// grow to.
+    pub fn backoff(mut self, base: Duration, max_delay: Duration) -> RetryPolicy {
+        self.base_delay = base;
+        self.max_delay = max(base, max_delay);
+        self
+    }
+
+    /// Sets whether delays are randomized. A randomized delay is somewhere
+    /// between half and all of the full delay which stops many clients that
+    /// failed at the same time from all retrying at the same time.
+    pub fn jitter(mut self, jitter: bool) -> RetryPolicy {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Sets whether to wait for at least as long as a response's `Retry-After`
+    /// header asks. This may be longer than the maximum delay.
+    pub fn honor_retry_after(mut self, honor: bool) -> RetryPolicy {
+        self.honor_retry_after = honor;
+        self
+    }
+
+    /// Gets the delay before making the given retry, counting from 1.
+    fn delay(&self, retry: usize, retry_after: Option<Duration>) -> Duration {
+        let exponent = min(retry.saturating_sub(1), 31) as u32;
+        let mut delay = self
+            .base_delay
+            .checked_mul(1 << exponent)
+            .map_or(self.max_delay, |delay| min(delay, self.max_delay));
+
+        if self.jitter {
+            let half = delay / 2;
+            let random = RandomState::new().build_hasher().finish();
+            let range = half.as_nanos() as u64 + 1;
+            delay = half + Duration::from_nanos(random % range);
         }
 
-        Ok((buckets.remove(0), file_part.to_string()))
+        match retry_after {
+            Some(wait) if self.honor_retry_after => max(delay, wait),
+            _ => delay,
+        }
     }
 }
 
@@ -763,7 +2667,6 @@ impl B2Backend {
 /// settings.
 pub struct B2BackendBuilder {
     settings: B2Settings,
-    max_requests: usize,
 }
 
 impl B2BackendBuilder {
@@ -812,7 +2715,86 @@ impl B2BackendBuilder {
     /// This also limits the number of parallel threads for downloads and
     /// uploads.
     pub fn limit_requests(mut self, requests: usize) -> B2BackendBuilder {
-        self.max_requests = requests;
+        self.settings.max_requests = requests;
+        self
+    }
+
+    /// Limits the number of parts of each large file upload that can be held
+    /// in memory at once, whether waiting to upload or uploading.
+    ///
+    /// Each part is B2's recommended part size (100MB at the time of writing),
+    /// or the small file cut-off if that is smaller, so a large file upload
+    /// uses roughly this many times that much memory. Reading from the source
+    /// pauses until a part has finished uploading. Defaults to 4.
+    pub fn limit_upload_parts(mut self, parts: usize) -> B2BackendBuilder {
+        self.settings.max_upload_parts = max(parts, 1);
+        self
+    }
+
+    /// Streams the data for uploads of a known [`size`](../../struct.UploadInfo.html#structfield.size)
+    /// directly to B2 rather than buffering it.
+    ///
+    /// The SHA1 of each file or part is sent after its data so memory use
+    /// depends only on the size of the chunks of data being written. Parts of
+    /// large files are uploaded one at a time and a request that fails once it
+    /// has started sending data cannot be retried, leaving the large file
+    /// unfinished. Uploads without a size are buffered as normal.
+    pub fn stream_uploads(mut self, stream: bool) -> B2BackendBuilder {
+        self.settings.stream_uploads = stream;
+        self
+    }
+
+    /// Downloads files as a series of ranges that are fetched in parallel.
+    ///
+    /// Files are split into ranges of `chunk_size` bytes and up to
+    /// `read_ahead` ranges are downloaded ahead of the data being read, each
+    /// held in memory until it is read. Files no larger than `chunk_size` are
+    /// downloaded in a single request as normal.
+    ///
+    /// Only the first range is requested by the file's name, the rest are
+    /// requested by the id of the version it returned so replacing the file
+    /// during the download cannot mix data from both versions.
+    pub fn parallel_downloads(mut self, chunk_size: u64, read_ahead: usize) -> B2BackendBuilder {
+        self.settings.download_chunk_size = Some(max(chunk_size, 1));
+        self.settings.download_read_ahead = max(read_ahead, 1);
+        self
+    }
+
+    /// Sets how long the id of a bucket is remembered after looking it up by
+    /// name. Defaults to 5 minutes, zero disables caching.
+    ///
+    /// A bucket that is deleted and recreated by another client in this time
+    /// will fail to be found until the cached id expires.
+    pub fn cache_buckets(mut self, ttl: Duration) -> B2BackendBuilder {
+        self.settings.bucket_cache_ttl = ttl;
+        self
+    }
+
+    /// Sets how requests that fail with a temporary error are retried.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> B2BackendBuilder {
+        self.settings.retry_policy = policy;
+        self
+    }
+
+    /// Sets how long to wait for B2 to respond to a request. A request that
+    /// times out is retried according to the [`retry_policy`](#method.retry_policy).
+    ///
+    /// This covers sending the request and waiting for the response to start.
+    /// Requests that upload file data are not limited by it, and neither is
+    /// the time taken to receive a file's data. These are only limited by the
+    /// [`operation_timeout`](#method.operation_timeout).
+    pub fn request_timeout(mut self, timeout: Duration) -> B2BackendBuilder {
+        self.settings.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how long an operation may take, including any retries, before it
+    /// fails with [`TimedOut`](../../enum.StorageErrorKind.html#variant.TimedOut).
+    ///
+    /// For streams this covers getting the stream, not reading from it. An
+    /// operation that times out is cancelled as if its future was dropped.
+    pub fn operation_timeout(mut self, timeout: Duration) -> B2BackendBuilder {
+        self.settings.operation_timeout = Some(timeout);
         self
     }
 
@@ -838,11 +2820,11 @@ impl B2BackendBuilder {
             };
 
             let client = HyperClient::builder().build(connector);
-            let clients = ClientPool::new(client, Some(self.max_requests));
+            let clients = ClientPool::new(client, Some(self.settings.max_requests));
 
             let auth_tokens = Pool::new(
                 (self.settings.clone(), clients.clone()),
-                Some(self.max_requests / 2),
+                Some(self.settings.max_requests / 2),
                 |(settings, clients)| {
                     WrappedFuture::<StorageResult<AuthorizeAccountResponse>>::from_future(
                         B2Client::authorize(settings.clone(), clients.clone()),
@@ -856,6 +2838,8 @@ impl B2BackendBuilder {
                     next_id: Default::default(),
                     clients,
                     auth_tokens,
+                    buckets: Default::default(),
+                    upload_urls: Default::default(),
                 },
             };
 
@@ -868,35 +2852,22 @@ impl B2BackendBuilder {
     }
 }
 
+/// Lists the objects that start with a prefix. Files whose latest version
+/// hides them are only included if `include_hidden` is set.
 async fn object_list(
     client: B2API,
     backend_prefix: ObjectPath,
     prefix: ObjectPath,
     delimiter: Option<String>,
+    include_hidden: bool,
 ) -> StorageResult<ObjectStream> {
     let mut file_part = backend_prefix.join(&prefix);
     let bucket = file_part.unshift_part();
 
-    let mut request = ListBucketsRequest {
-        account_id: client.account_info().await?.account_id,
-        bucket_id: None,
-        bucket_name: None,
-        bucket_types: Default::default(),
-    };
-
-    if let Some(ref bucket_name) = bucket {
-        // Only include the bucket named `bucket`.
-        request.bucket_name = Some(bucket_name.clone());
-    }
-
-    let bucket_name = bucket.unwrap_or_else(String::new);
-    let path = ObjectPath::new(bucket_name.clone())?;
-    let listers = client
-        .b2_list_buckets(path, request)
+    let path = ObjectPath::new(bucket.clone().unwrap_or_else(String::new))?;
+    let listers = search_buckets(&client, path, bucket)
         .await?
-        .buckets
-        .drain(..)
-        .filter(|b| b.bucket_name.starts_with(&bucket_name))
+        .into_iter()
         .map(move |b| {
             let options = ListFileVersionsRequest {
                 bucket_id: b.bucket_id.clone(),
@@ -910,6 +2881,9 @@ async fn object_list(
             let requestor = FileVersionsRequestor::new(client.clone(), prefix.clone(), options);
             let temp_prefix = backend_prefix.clone();
             ListStream::new(requestor)
+                .try_filter(move |versions| {
+                    ready(include_hidden || versions.latest().action != FileAction::Hide)
+                })
                 .and_then(move |i| ready(new_object(&b.bucket_name, i, &temp_prefix)))
         })
         .fold(MergedStreams::new(), |mut m, s| {
@@ -940,7 +2914,9 @@ impl StorageBackend for B2Backend {
             self.state.settings.prefix.clone(),
             prefix,
             None,
+            false,
         ))
+        .with_timeout(self.state.settings.operation_timeout)
     }
 
     fn list_directory<P>(&self, dir: P) -> ObjectStreamFuture
@@ -962,7 +2938,9 @@ impl StorageBackend for B2Backend {
             self.state.settings.prefix.clone(),
             path,
             Some(String::from("/")),
+            false,
         ))
+        .with_timeout(self.state.settings.operation_timeout)
     }
 
     fn get_object<P>(&self, path: P) -> ObjectFuture
@@ -978,26 +2956,7 @@ impl StorageBackend for B2Backend {
             let (bucket, file) =
                 B2Backend::expand_path(client.clone(), backend_prefix.clone(), path.clone())
                     .await?;
-
-            let options = ListFileVersionsRequest {
-                bucket_id: bucket.bucket_id.clone(),
-                start_file_name: None,
-                start_file_id: None,
-                max_file_count: None,
-                prefix: Some(file.clone()),
-                delimiter: Some(String::from("/")),
-            };
-
-            let requestor = FileVersionsRequestor::new(client.clone(), path.clone(), options);
-            let mut files: Vec<FileVersions> = ListStream::new(requestor)
-                .try_filter(|versions| ready(versions.latest().file_name == file))
-                .try_collect()
-                .await?;
-            if files.len() != 1 {
-                return Err(error::not_found(path, None));
-            }
-
-            new_object(&bucket.bucket_name, files.remove(0), &backend_prefix)
+            get_expanded(client, backend_prefix, path, bucket, file).await
         }
 
         let path = match path.try_into() {
@@ -1015,9 +2974,23 @@ impl StorageBackend for B2Backend {
         let client = self.client();
         let prefix = self.state.settings.prefix.clone();
         ObjectFuture::from_future(get(client, prefix, path))
+            .with_timeout(self.state.settings.operation_timeout)
     }
 
     fn get_file_stream<P>(&self, path: P) -> DataStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        match path.try_into() {
+            Ok(p) => self
+                .download(p, None)
+                .with_timeout(self.state.settings.operation_timeout),
+            Err(e) => DataStreamFuture::from_value(Err(e.into())),
+        }
+    }
+
+    fn get_file_range<P>(&self, path: P, range: Range<u64>) -> DataStreamFuture
     where
         P: TryInto<ObjectPath>,
         P::Error: Into<StorageError>,
@@ -1027,35 +3000,84 @@ impl StorageBackend for B2Backend {
             Err(e) => return DataStreamFuture::from_value(Err(e.into())),
         };
 
-        if path.is_dir_prefix() {
-            return DataStreamFuture::from_value(Err(error::invalid_path(
-                path,
-                Some("Object paths cannot be empty or end with a '/' character."),
-            )));
+        // An empty range cannot be expressed in a Range header so just check
+        // that the file exists.
+        if range.start >= range.end {
+            return DataStreamFuture::from_future(
+                self.get_object(path)
+                    .map_ok(|_| DataStream::from_stream(empty::<StorageResult<Data>>())),
+            );
         }
 
-        let mut file_name = self.state.settings.prefix.join(&path);
-        let bucket = match file_name.unshift_part() {
-            Some(b) => b,
-            _ => {
-                return DataStreamFuture::from_value(Err(error::invalid_path(
-                    path,
-                    Some("Object paths cannot be empty."),
-                )));
+        let length = range.end - range.start;
+        DataStreamFuture::from_future(
+            self.download(path, Some(range))
+                .map_ok(move |stream| DataStream::from_stream(RangeStream::new(stream, 0..length))),
+        )
+        .with_timeout(self.state.settings.operation_timeout)
+    }
+
+    /// Copies a file from one path to another.
+    ///
+    /// When both paths are in the same bucket the file is copied by B2 without
+    /// any data passing through this client, larger files being copied in
+    /// parts. Otherwise the data is downloaded and uploaded to the new path.
+    ///
+    /// The file's metadata and last modification time are copied to the new
+    /// file unless the target sets its own.
+    fn copy_file<P, I>(&self, source: P, target: I) -> CopyCompleteFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+        I: TryInto<UploadInfo>,
+        I::Error: Into<StorageError>,
+    {
+        async fn copy(
+            backend: B2Backend,
+            source: ObjectPath,
+            info: UploadInfo,
+        ) -> Result<(), TransferError> {
+            let object = backend
+                .get_object(source.clone())
+                .await
+                .map_err(TransferError::SourceError)?;
+
+            let (bucket, file) = B2Backend::expand_path(
+                backend.client(),
+                backend.state.settings.prefix.clone(),
+                info.path.clone(),
+            )
+            .await
+            .map_err(TransferError::TargetError)?;
+
+            copy_expanded(backend, source, object, info, bucket, file).await
+        }
+
+        let source = match source.try_into() {
+            Ok(p) => p,
+            Err(e) => {
+                return CopyCompleteFuture::from_value(Err(TransferError::SourceError(e.into())))
             }
         };
 
-        let future = self
-            .client()
-            .b2_download_file_by_name(path, bucket, file_name.to_string())
-            .map_ok(|body| {
-                DataStream::from_stream(body.map(|result| match result {
-                    Ok(chunk) => Result::<Data, StorageError>::Ok(chunk.into_bytes()),
-                    Err(e) => Result::<Data, StorageError>::Err(e.into()),
-                }))
-            });
+        let info: UploadInfo = match target.try_into() {
+            Ok(i) => i,
+            Err(e) => {
+                return CopyCompleteFuture::from_value(Err(TransferError::TargetError(e.into())))
+            }
+        };
 
-        DataStreamFuture::from_future(future)
+        if info.path.is_dir_prefix() {
+            return CopyCompleteFuture::from_value(Err(TransferError::TargetError(
+                error::invalid_path(
+                    info.path,
+                    Some("Object paths cannot be empty or end with a '/' character."),
+                ),
+            )));
+        }
+
+        CopyCompleteFuture::from_future(copy(self.clone(), source, info))
+            .with_timeout(self.state.settings.operation_timeout)
     }
 
     fn delete_object<P>(&self, path: P) -> OperationCompleteFuture
@@ -1063,47 +3085,246 @@ impl StorageBackend for B2Backend {
         P: TryInto<ObjectPath>,
         P::Error: Into<StorageError>,
     {
-        async fn delete(backend: B2Backend, path: ObjectPath) -> StorageResult<()> {
-            let object: B2Object = match backend.clone().get_object(path.clone()).await?.try_into()
-            {
-                Ok(o) => o,
-                Err(_) => {
-                    return Err(error::internal_error(Some(
-                        "Failed to convert retrieved object to the expected type.",
-                    )));
-                }
-            };
+        async fn delete(
+            client: B2API,
+            backend_prefix: ObjectPath,
+            path: ObjectPath,
+        ) -> StorageResult<()> {
+            let (bucket, file) =
+                B2Backend::expand_path(client.clone(), backend_prefix, path.clone()).await?;
+            delete_file(client, path, bucket.bucket_id, file).await
+        }
 
-            for info in object.versions() {
-                match info.file_id {
-                    Some(ref id) => {
-                        backend
-                            .client()
-                            .b2_delete_file_version(
-                                path.clone(),
-                                DeleteFileVersionRequest {
-                                    file_name: info.file_name.clone(),
-                                    file_id: id.to_owned(),
-                                },
-                            )
-                            .await?;
-                    }
-                    None => {
-                        return Err(error::internal_error(Some(
-                            "Expected object to have a file id.",
-                        )));
+        let path = match path.try_into() {
+            Ok(p) => p,
+            Err(e) => return OperationCompleteFuture::from_value(Err(e.into())),
+        };
+
+        if path.is_dir_prefix() {
+            return OperationCompleteFuture::from_value(Err(error::invalid_path(
+                path,
+                Some("Object paths cannot be empty or end with a '/' character."),
+            )));
+        }
+
+        OperationCompleteFuture::from_future(delete(
+            self.client(),
+            self.state.settings.prefix.clone(),
+            path,
+        ))
+        .with_timeout(self.state.settings.operation_timeout)
+    }
+
+    /// Deletes every version of every file whose path starts with the given
+    /// prefix, including files that are hidden. Up to the
+    /// [request limit](struct.B2BackendBuilder.html#method.limit_requests)
+    /// files are deleted at once, the versions of each file being deleted in
+    /// turn.
+    fn delete_prefix<P>(&self, prefix: P) -> DeleteStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        async fn delete(
+            client: B2API,
+            backend_prefix: ObjectPath,
+            prefix: ObjectPath,
+            limit: usize,
+        ) -> StorageResult<DeleteStream> {
+            let objects =
+                object_list(client.clone(), backend_prefix, prefix.clone(), None, true).await?;
+            Ok(DeleteStream::from_stream(delete_objects(
+                objects,
+                prefix,
+                limit,
+                move |object| {
+                    OperationCompleteFuture::from_future(delete_object_versions(
+                        client.clone(),
+                        object,
+                    ))
+                },
+            )))
+        }
+
+        let prefix = match prefix.try_into() {
+            Ok(p) => p,
+            Err(e) => return DeleteStreamFuture::from_value(Err(e.into())),
+        };
+
+        DeleteStreamFuture::from_future(delete(
+            self.client(),
+            self.state.settings.prefix.clone(),
+            prefix,
+            self.state.settings.max_requests,
+        ))
+        .with_timeout(self.state.settings.operation_timeout)
+    }
+
+    /// Gets info about the objects at each of the given paths, running up to
+    /// the [request limit](struct.B2BackendBuilder.html#method.limit_requests)
+    /// lookups at once. Each bucket is only looked up once for the batch.
+    fn get_objects<I>(&self, paths: I) -> GetStream
+    where
+        I: IntoIterator<Item = ObjectPath>,
+    {
+        async fn get(
+            client: B2API,
+            buckets: Arc<BatchBuckets>,
+            path: ObjectPath,
+            timeout: Option<Duration>,
+        ) -> GetResult {
+            let (bucket, file) = buckets.expand(&path).map_err(|e| (path.clone(), e))?;
+            ObjectFuture::from_future(get_expanded(
+                client,
+                buckets.prefix.clone(),
+                path.clone(),
+                bucket,
+                file,
+            ))
+            .with_timeout(timeout)
+            .await
+            .map_err(|e| (path, e))
+        }
+
+        let paths: Vec<ObjectPath> = paths.into_iter().collect();
+        let client = self.client();
+        let prefix = self.state.settings.prefix.clone();
+        let timeout = self.state.settings.operation_timeout;
+        let gets = async move {
+            let buckets = Arc::new(
+                BatchBuckets::lookup(client.clone(), prefix, paths.clone(), timeout).await,
+            );
+            iter(paths).map(move |path| get(client.clone(), buckets.clone(), path, timeout))
+        };
+
+        GetStream::from_stream(
+            once(gets)
+                .flatten()
+                .buffer_unordered(max(self.state.settings.max_requests, 1)),
+        )
+    }
+
+    /// Deletes every version of the files at each of the given paths, running
+    /// up to the [request limit](struct.B2BackendBuilder.html#method.limit_requests)
+    /// deletes at once. Each bucket is only looked up once for the batch.
+    fn delete_objects<I>(&self, paths: I) -> DeleteStream
+    where
+        I: IntoIterator<Item = ObjectPath>,
+    {
+        async fn delete(
+            client: B2API,
+            buckets: Arc<BatchBuckets>,
+            path: ObjectPath,
+        ) -> StorageResult<()> {
+            let (bucket, file) = buckets.expand(&path)?;
+            delete_file(client, path, bucket.bucket_id, file).await
+        }
+
+        let paths: Vec<ObjectPath> = paths.into_iter().collect();
+        let client = self.client();
+        let prefix = self.state.settings.prefix.clone();
+        let timeout = self.state.settings.operation_timeout;
+        let deletes = async move {
+            let buckets = Arc::new(
+                BatchBuckets::lookup(client.clone(), prefix, paths.clone(), timeout).await,
+            );
+            iter(paths).map(move |path| {
+                let future = OperationCompleteFuture::from_future(delete(
+                    client.clone(),
+                    buckets.clone(),
+                    path.clone(),
+                ))
+                .with_timeout(timeout);
+
+                async move {
+                    match future.await {
+                        Ok(()) => Ok(path),
+                        Err(e) => Err((path, e)),
                     }
                 }
-            }
+            })
+        };
 
-            Ok(())
+        DeleteStream::from_stream(
+            once(deletes)
+                .flatten()
+                .buffer_unordered(max(self.state.settings.max_requests, 1)),
+        )
+    }
+
+    /// Copies each of the given source paths to its target path, running up
+    /// to the [request limit](struct.B2BackendBuilder.html#method.limit_requests)
+    /// copies at once. Each bucket is only looked up once for the batch.
+    fn copy_files<I>(&self, paths: I) -> CopyStream
+    where
+        I: IntoIterator<Item = (ObjectPath, ObjectPath)>,
+    {
+        async fn copy(
+            backend: B2Backend,
+            buckets: Arc<BatchBuckets>,
+            source: ObjectPath,
+            target: ObjectPath,
+        ) -> Result<(), TransferError> {
+            let (bucket, file) = buckets
+                .expand(&source)
+                .map_err(TransferError::SourceError)?;
+            let object = get_expanded(
+                backend.client(),
+                buckets.prefix.clone(),
+                source.clone(),
+                bucket,
+                file,
+            )
+            .await
+            .map_err(TransferError::SourceError)?;
+
+            let (bucket, file) = buckets
+                .expand(&target)
+                .map_err(TransferError::TargetError)?;
+            copy_expanded(backend, source, object, target.into(), bucket, file).await
         }
 
-        let path = match path.try_into() {
-            Ok(p) => p,
-            Err(e) => return OperationCompleteFuture::from_value(Err(e.into())),
+        let paths: Vec<(ObjectPath, ObjectPath)> = paths.into_iter().collect();
+        let backend = self.clone();
+        let timeout = self.state.settings.operation_timeout;
+        let copies = async move {
+            let all_paths = paths
+                .iter()
+                .flat_map(|(source, target)| vec![source.clone(), target.clone()])
+                .collect();
+            let buckets = Arc::new(
+                BatchBuckets::lookup(
+                    backend.client(),
+                    backend.state.settings.prefix.clone(),
+                    all_paths,
+                    timeout,
+                )
+                .await,
+            );
+
+            iter(paths).map(move |(source, target)| {
+                let future = CopyCompleteFuture::from_future(copy(
+                    backend.clone(),
+                    buckets.clone(),
+                    source.clone(),
+                    target.clone(),
+                ))
+                .with_timeout(timeout);
+
+                async move {
+                    match future.await {
+                        Ok(()) => Ok((source, target)),
+                        Err(e) => Err((source, target, e)),
+                    }
+                }
+            })
         };
-        OperationCompleteFuture::from_future(delete(self.clone(), path))
+
+        CopyStream::from_stream(
+            once(copies)
+                .flatten()
+                .buffer_unordered(max(self.state.settings.max_requests, 1)),
+        )
     }
 
     fn write_file_from_stream<S, I, E, P>(&self, info: P, stream: S) -> WriteCompleteFuture
@@ -1116,8 +3337,7 @@ impl StorageBackend for B2Backend {
     {
         async fn upload<S>(
             client: B2API,
-            max_small_file_size: u64,
-            prefix: ObjectPath,
+            settings: B2Settings,
             info: UploadInfo,
             stream: S,
         ) -> Result<(), TransferError>
@@ -1125,19 +3345,36 @@ impl StorageBackend for B2Backend {
             S: Stream<Item = StorageResult<Data>> + Send + 'static,
         {
             let (bucket, file) =
-                B2Backend::expand_path(client.clone(), prefix.clone(), info.path.clone())
+                B2Backend::expand_path(client.clone(), settings.prefix, info.path.clone())
                     .await
                     .map_err(TransferError::SourceError)?;
 
-            perform_upload(
-                client,
-                max_small_file_size,
-                info,
-                bucket.bucket_id,
-                file,
-                stream,
-            )
-            .await
+            match info.size {
+                Some(size) if settings.stream_uploads => {
+                    let write = streaming_upload(
+                        client.clone(),
+                        settings.max_small_file_size,
+                        info.clone(),
+                        bucket.bucket_id.clone(),
+                        file.clone(),
+                        size,
+                        stream,
+                    );
+                    conditional_write(client, &info, bucket.bucket_id, file, write).await
+                }
+                _ => {
+                    let write = perform_upload(
+                        client.clone(),
+                        settings.max_small_file_size,
+                        settings.max_upload_parts,
+                        info.clone(),
+                        bucket.bucket_id.clone(),
+                        file.clone(),
+                        stream,
+                    );
+                    conditional_write(client, &info, bucket.bucket_id, file, write).await
+                }
+            }
         }
 
         let info = match info.try_into() {
@@ -1157,12 +3394,17 @@ impl StorageBackend for B2Backend {
             )));
         }
 
+        let stream = match into_upload_stream(&info, stream) {
+            Ok(s) => s,
+            Err(e) => return WriteCompleteFuture::from_value(Err(e)),
+        };
+
         WriteCompleteFuture::from_future(upload(
             self.client(),
-            self.state.settings.max_small_file_size,
-            self.state.settings.prefix.clone(),
+            self.state.settings.clone(),
             info,
-            into_data_stream(stream),
+            stream,
         ))
+        .with_timeout(self.state.settings.operation_timeout)
     }
 }
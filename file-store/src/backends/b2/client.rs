@@ -16,43 +16,61 @@
 //!
 //! Mainly split out to ensure that only the expected methods can be called
 //! to ensure that limits are enforced correctly.
+use std::collections::HashMap;
 use std::fmt;
 use std::future::Future;
-use std::io::Read;
-use std::ops::Deref;
+use std::ops::{Deref, Range};
+use std::pin::Pin;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use base64::encode;
+use chrono::DateTime;
+use futures::future::{select, Either};
 use futures::stream::{iter, Stream, StreamExt};
 use http::header;
 use http::method::Method;
+use http::StatusCode;
 use hyper::body::Body;
 use hyper::Chunk;
 use hyper::{Request, Response};
 use log::{error, trace, warn};
 use serde::de::DeserializeOwned;
 use serde_json::{from_str, to_string};
+use sha1::Sha1;
+use tokio_timer::Delay;
 
 use storage_types::b2::v2::requests::*;
 use storage_types::b2::v2::responses::*;
 use storage_types::b2::v2::{
-    percent_encode, UserFileInfo, B2_HEADER_CONTENT_SHA1, B2_HEADER_FILE_INFO_PREFIX,
-    B2_HEADER_FILE_NAME, B2_HEADER_PART_NUMBER,
+    percent_encode, UserFileInfo, B2_HEADER_CONTENT_SHA1, B2_HEADER_FILE_ID,
+    B2_HEADER_FILE_INFO_PREFIX, B2_HEADER_FILE_NAME, B2_HEADER_PART_NUMBER, CONTENT_SHA1_AT_END,
 };
 
 use super::{B2Settings, Client, ClientPool};
 use crate::types::stream::AfterStream;
 use crate::types::*;
-use crate::utils::Pool;
+use crate::utils::{Acquired, Pool};
 
-const MAX_API_RETRIES: usize = 5;
+/// The largest API response body that will be read into memory.
+const MAX_RESPONSE_SIZE: usize = 16 * 1024 * 1024;
+/// The length of the hex encoded SHA1 sent after the data of a streaming upload.
+const SHA1_HEX_LENGTH: u64 = 40;
+/// Uploads send the file's data as part of the request so they can take far
+/// longer than the configured request timeout allows for.
+const UPLOAD_REQUEST_TIMEOUT: Option<Duration> = None;
 
 #[derive(Debug)]
 struct B2Error {
     error: StorageError,
     needs_auth: bool,
     can_retry: bool,
+    /// Whether the request used a bucket id that no longer exists.
+    bad_bucket: bool,
+    /// How long the server asked for the client to wait before retrying.
+    retry_after: Option<Duration>,
 }
 
 impl From<B2Error> for StorageError {
@@ -70,6 +88,8 @@ impl From<hyper::error::Error> for B2Error {
                 error,
                 needs_auth: can_retry,
                 can_retry,
+                bad_bucket: false,
+                retry_after: None,
             }
         }
 
@@ -123,6 +143,8 @@ fn generate_error(method: &str, client_id: usize, path: &ObjectPath, response: &
             error,
             needs_auth: false,
             can_retry: false,
+            bad_bucket: false,
+            retry_after: None,
         }
     }
 
@@ -146,17 +168,22 @@ fn generate_error(method: &str, client_id: usize, path: &ObjectPath, response: &
 
     match (error_info.status, error_info.code.as_str()) {
         (400, "bad_request") => error(error::internal_error(Some(&error_info.message))),
-        (400, "bad_bucket_id") => {
-            error(error::not_found(path.to_owned(), Some(&error_info.message)))
-        }
-        (400, "invalid_bucket_id") => {
-            error(error::not_found(path.to_owned(), Some(&error_info.message)))
-        }
+        (400, "bad_bucket_id") | (400, "invalid_bucket_id") => B2Error {
+            error: error::not_found(path.to_owned(), Some(&error_info.message)),
+            needs_auth: false,
+            can_retry: false,
+            bad_bucket: true,
+            retry_after: None,
+        },
         (400, "too_many_buckets") => error(error::over_quota(Some(&error_info.code))),
         (400, "duplicate_bucket_name") => error(error::already_exists(
             path.to_owned(),
             Some(&error_info.code),
         )),
+        (400, "cannot_delete_non_empty_bucket") => error(error::precondition_failed(
+            path.to_owned(),
+            Some(&error_info.message),
+        )),
         (400, "file_not_present") => {
             error(error::not_found(path.to_owned(), Some(&error_info.message)))
         }
@@ -169,11 +196,15 @@ fn generate_error(method: &str, client_id: usize, path: &ObjectPath, response: &
             error: error::access_expired(Some(&error_info.message)),
             needs_auth: true,
             can_retry: true,
+            bad_bucket: false,
+            retry_after: None,
         },
         (401, "expired_auth_token") => B2Error {
             error: error::access_expired(Some(&error_info.message)),
             needs_auth: true,
             can_retry: true,
+            bad_bucket: false,
+            retry_after: None,
         },
 
         (403, "cap_exceeded") => error(error::over_quota(Some(&error_info.code))),
@@ -186,6 +217,8 @@ fn generate_error(method: &str, client_id: usize, path: &ObjectPath, response: &
             error: error::connection_closed(Some(&error_info.message)),
             needs_auth: true,
             can_retry: true,
+            bad_bucket: false,
+            retry_after: None,
         },
 
         (416, "range_not_satisfiable") => error(error::internal_error(Some(&error_info.message))),
@@ -194,17 +227,23 @@ fn generate_error(method: &str, client_id: usize, path: &ObjectPath, response: &
             error: error::over_quota(Some(&error_info.message)),
             needs_auth: true,
             can_retry: true,
+            bad_bucket: false,
+            retry_after: None,
         },
 
         (500, "internal_error") => B2Error {
             error: error::service_error(Some(&error_info.message)),
             needs_auth: true,
             can_retry: true,
+            bad_bucket: false,
+            retry_after: None,
         },
         (503, "bad_request") => B2Error {
             error: error::service_error(Some(&error_info.message)),
             needs_auth: true,
             can_retry: true,
+            bad_bucket: false,
+            retry_after: None,
         },
 
         (status, _) => {
@@ -215,24 +254,81 @@ fn generate_error(method: &str, client_id: usize, path: &ObjectPath, response: &
                     error: error::access_expired(Some(&error_info.message)),
                     needs_auth: true,
                     can_retry: true,
+                    bad_bucket: false,
+                    retry_after: None,
                 }
             } else if status >= 500 && status < 600 {
                 B2Error {
                     error: error::service_error(Some(&error_info.message)),
                     needs_auth: true,
                     can_retry: true,
+                    bad_bucket: false,
+                    retry_after: None,
                 }
             } else {
                 B2Error {
                     error: error::other_error(Some(&error_info.message)),
                     needs_auth: true,
                     can_retry: true,
+                    bad_bucket: false,
+                    retry_after: None,
                 }
             }
         }
     }
 }
 
+/// Reads how long to wait from a `Retry-After` header. This is either a number
+/// of seconds or an HTTP date to wait until.
+fn retry_after(headers: &header::HeaderMap) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    let until = UNIX_EPOCH + Duration::from_secs(date.timestamp().max(0) as u64);
+    Some(
+        until
+            .duration_since(SystemTime::now())
+            .unwrap_or_else(|_| Duration::from_secs(0)),
+    )
+}
+
+/// Details of a downloaded file from the response headers.
+#[derive(Clone, Debug, Default)]
+pub(super) struct DownloadHeaders {
+    /// The total size of the file.
+    pub size: Option<u64>,
+    /// The id of the version of the file that was downloaded.
+    pub file_id: Option<String>,
+}
+
+impl DownloadHeaders {
+    fn from_parts(parts: &http::response::Parts) -> DownloadHeaders {
+        DownloadHeaders {
+            size: file_size(parts),
+            file_id: parts
+                .headers
+                .get(B2_HEADER_FILE_ID)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_owned()),
+        }
+    }
+}
+
+/// Finds the total size of a downloaded file from the response headers.
+fn file_size(parts: &http::response::Parts) -> Option<u64> {
+    if parts.status == StatusCode::OK {
+        let length = parts.headers.get(header::CONTENT_LENGTH)?.to_str().ok()?;
+        length.parse().ok()
+    } else {
+        // Either `bytes <first>-<last>/<size>` or `bytes */<size>`.
+        let range = parts.headers.get(header::CONTENT_RANGE)?.to_str().ok()?;
+        range.rsplit('/').next()?.parse().ok()
+    }
+}
+
 macro_rules! b2_api {
     ($method:ident, $request:ident, $response:ident) => {
         #[allow(dead_code)]
@@ -260,9 +356,39 @@ impl B2Client {
         path: ObjectPath,
         client: &Client,
         request: Request<Body>,
+        timeout: Option<Duration>,
     ) -> B2Result<Response<Body>> {
         trace!("Client {:04}: Requesting {}", id, request.uri());
-        let response = match client.request(request).await {
+        let ranged = request.headers().contains_key(header::RANGE);
+        let result = match timeout {
+            Some(duration) => {
+                match select(
+                    client.request(request),
+                    Delay::new(Instant::now() + duration),
+                )
+                .await
+                {
+                    Either::Left((result, _)) => result,
+                    Either::Right(_) => {
+                        error!("Client {:04}: {} b2 api call timed out", id, method);
+                        return Err(B2Error {
+                            error: error::timed_out(Some(&format!(
+                                "No response to {} after {}ms.",
+                                method,
+                                duration.as_millis()
+                            ))),
+                            needs_auth: false,
+                            can_retry: true,
+                            bad_bucket: false,
+                            retry_after: None,
+                        });
+                    }
+                }
+            }
+            None => client.request(request).await,
+        };
+
+        let response = match result {
             Ok(r) => {
                 trace!("Client {:04}: {} b2 api call succeeded", id, method);
                 r
@@ -273,36 +399,87 @@ impl B2Client {
             }
         };
 
-        if response.status().is_success() {
+        // A range starting beyond the end of the file simply has no content.
+        if response.status().is_success()
+            || (ranged && response.status() == StatusCode::RANGE_NOT_SATISFIABLE)
+        {
             Ok(response)
         } else {
-            let (_, body) = response.into_parts();
+            let (parts, body) = response.into_parts();
 
-            let mut data: String = String::new();
-            BlockingStreamReader::from_stream(body)
-                .read_to_string(&mut data)
-                .unwrap();
-            Err(generate_error(method, id, &path, &data))
+            let data = B2Client::read_body(id, method, body).await?;
+            let mut error = generate_error(method, id, &path, &data);
+            error.retry_after = retry_after(&parts.headers);
+            Err(error)
         }
     }
 
+    /// Collects a response body into a string, failing if it grows beyond
+    /// `MAX_RESPONSE_SIZE`.
+    async fn read_body(id: usize, method: &str, mut body: Body) -> B2Result<String> {
+        fn error(error: StorageError) -> B2Error {
+            B2Error {
+                error,
+                needs_auth: false,
+                can_retry: true,
+                bad_bucket: false,
+                retry_after: None,
+            }
+        }
+
+        let mut data: Vec<u8> = Vec::new();
+        while let Some(result) = body.next().await {
+            let chunk = match result {
+                Ok(c) => c,
+                Err(e) => {
+                    error!(
+                        "Client {:04}: Failed reading {} response: {}",
+                        id, method, e
+                    );
+                    return Err(e.into());
+                }
+            };
+
+            if data.len() + chunk.len() > MAX_RESPONSE_SIZE {
+                error!("Client {:04}: {} response was too large", id, method);
+                let mut error = error(error::invalid_data(Some(&format!(
+                    "Response from {} exceeded {} bytes.",
+                    method, MAX_RESPONSE_SIZE
+                ))));
+                error.can_retry = false;
+                return Err(error);
+            }
+
+            data.extend_from_slice(&chunk);
+        }
+
+        String::from_utf8(data).map_err(|e| {
+            error!(
+                "Client {:04}: {} response was not valid UTF-8: {}",
+                id, method, e
+            );
+            error(error::invalid_data(Some(&format!(
+                "Response from {} was not valid UTF-8.",
+                method
+            ))))
+        })
+    }
+
     async fn basic_request<R>(
         id: usize,
         method: &str,
         path: ObjectPath,
         mut client: Client,
         request: Request<Body>,
+        timeout: Option<Duration>,
     ) -> B2Result<R>
     where
         R: DeserializeOwned + fmt::Debug,
     {
-        let response = B2Client::request(id, method, path, &client, request).await?;
+        let response = B2Client::request(id, method, path, &client, request, timeout).await?;
         let (_, body) = response.into_parts();
 
-        let mut data: String = String::new();
-        BlockingStreamReader::from_stream(body)
-            .read_to_string(&mut data)
-            .unwrap();
+        let data = B2Client::read_body(id, method, body).await?;
 
         // Make sure that client stays alive until the request is complete.
         client.release();
@@ -321,6 +498,8 @@ impl B2Client {
                     ))),
                     needs_auth: false,
                     can_retry: true,
+                    bad_bucket: false,
+                    retry_after: None,
                 })
             }
         }
@@ -349,7 +528,178 @@ impl B2Client {
 
         let empty = ObjectPath::empty();
         let client = clients.acquire().await;
-        Ok(B2Client::basic_request(0, "b2_authorize_account", empty, client, request).await?)
+        Ok(B2Client::basic_request(
+            0,
+            "b2_authorize_account",
+            empty,
+            client,
+            request,
+            settings.request_timeout,
+        )
+        .await?)
+    }
+}
+
+/// The id and name of a bucket.
+#[derive(Debug, Clone)]
+pub(super) struct BucketRef {
+    pub bucket_id: String,
+    pub bucket_name: String,
+}
+
+/// Remembers the buckets that have been looked up by name.
+#[derive(Debug, Clone, Default)]
+pub(super) struct BucketCache {
+    buckets: Arc<Mutex<HashMap<String, (Instant, Bucket)>>>,
+}
+
+impl BucketCache {
+    fn get(&self, name: &str, ttl: Duration) -> Option<Bucket> {
+        let mut buckets = self.buckets.lock().unwrap();
+        match buckets.get(name) {
+            Some((cached, bucket)) if cached.elapsed() < ttl => Some(bucket.clone()),
+            Some(_) => {
+                buckets.remove(name);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, bucket: Bucket) {
+        self.buckets
+            .lock()
+            .unwrap()
+            .insert(bucket.bucket_name.clone(), (Instant::now(), bucket));
+    }
+
+    fn remove(&self, name: &str) {
+        self.buckets.lock().unwrap().remove(name);
+    }
+
+    fn clear(&self) {
+        self.buckets.lock().unwrap().clear();
+    }
+}
+
+type UploadUrlPool = Pool<(B2API, String), GetUploadUrlResponse, StorageError>;
+type UploadPartUrlPool = Pool<(B2API, String), GetUploadPartUrlResponse, StorageError>;
+
+/// Upload URLs that are kept for reuse by later uploads. File upload URLs are
+/// kept per bucket id and part upload URLs per large file id.
+///
+/// B2 only allows one upload at a time to use a URL so a new URL is requested
+/// whenever all of the existing ones are in use.
+#[derive(Debug, Clone, Default)]
+pub(super) struct UploadUrls {
+    files: Arc<Mutex<HashMap<String, UploadUrlPool>>>,
+    parts: Arc<Mutex<HashMap<String, UploadPartUrlPool>>>,
+}
+
+struct StreamingState<S> {
+    stream: Option<S>,
+    hasher: Sha1,
+    attempt: usize,
+    started: bool,
+    finished: bool,
+    error: Option<StorageError>,
+}
+
+/// The data for a streaming upload.
+///
+/// The data is read from the stream as it is sent so it can only be sent
+/// again if the previous attempt failed before reading any.
+struct StreamingUpload<S> {
+    state: Arc<Mutex<StreamingState<S>>>,
+}
+
+impl<S> StreamingUpload<S>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + Unpin + 'static,
+{
+    fn new(stream: S) -> StreamingUpload<S> {
+        StreamingUpload {
+            state: Arc::new(Mutex::new(StreamingState {
+                stream: Some(stream),
+                hasher: Sha1::new(),
+                attempt: 0,
+                started: false,
+                finished: false,
+                error: None,
+            })),
+        }
+    }
+
+    /// Creates the body for a new attempt at sending the data. Any body from
+    /// an earlier attempt stops sending data.
+    fn body(&self) -> Body {
+        let mut state = self.state.lock().unwrap();
+        state.attempt += 1;
+        Body::wrap_stream(StreamingBody {
+            state: self.state.clone(),
+            attempt: state.attempt,
+        })
+    }
+
+    fn started(&self) -> bool {
+        self.state.lock().unwrap().started
+    }
+
+    /// Takes the error from the data stream if reading it failed.
+    fn source_error(&self) -> Option<StorageError> {
+        self.state.lock().unwrap().error.take()
+    }
+
+    fn into_inner(self) -> Option<S> {
+        self.state.lock().unwrap().stream.take()
+    }
+}
+
+/// A request body that sends the data from a stream followed by its hex
+/// encoded SHA1.
+struct StreamingBody<S> {
+    state: Arc<Mutex<StreamingState<S>>>,
+    attempt: usize,
+}
+
+impl<S> Stream for StreamingBody<S>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + Unpin + 'static,
+{
+    type Item = StorageResult<Data>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<StorageResult<Data>>> {
+        let mut state = self.state.lock().unwrap();
+        if state.attempt != self.attempt || state.finished {
+            return Poll::Ready(None);
+        }
+
+        let result = match state.stream {
+            Some(ref mut stream) => stream.poll_next_unpin(cx),
+            None => Poll::Ready(None),
+        };
+
+        match result {
+            Poll::Ready(Some(Ok(data))) => {
+                state.started = true;
+                state.hasher.update(&data);
+                Poll::Ready(Some(Ok(data)))
+            }
+            Poll::Ready(Some(Err(e))) => {
+                state.started = true;
+                state.finished = true;
+                state.error = Some(e);
+                Poll::Ready(Some(Err(error::cancelled(Some(
+                    "Reading the data to upload failed.",
+                )))))
+            }
+            Poll::Ready(None) => {
+                state.started = true;
+                state.finished = true;
+                Poll::Ready(Some(Ok(Data::from(state.hasher.hexdigest()))))
+            }
+            Poll::Pending => Poll::Pending,
+        }
     }
 }
 
@@ -359,6 +709,8 @@ pub(super) struct B2APIState {
     pub clients: ClientPool,
     pub next_id: Arc<AtomicUsize>,
     pub auth_tokens: Pool<(B2Settings, ClientPool), AuthorizeAccountResponse, StorageError>,
+    pub buckets: BucketCache,
+    pub upload_urls: UploadUrls,
 }
 
 impl Clone for B2APIState {
@@ -368,6 +720,8 @@ impl Clone for B2APIState {
             clients: self.clients.clone(),
             next_id: self.next_id.clone(),
             auth_tokens: self.auth_tokens.clone(),
+            buckets: self.buckets.clone(),
+            upload_urls: self.upload_urls.clone(),
         }
     }
 }
@@ -419,16 +773,30 @@ impl B2API {
 
             let client = self.state.clients.acquire().await;
 
-            match B2Client::basic_request(self.id, method, path.clone(), client, request).await {
+            match B2Client::basic_request(
+                self.id,
+                method,
+                path.clone(),
+                client,
+                request,
+                self.state.settings.request_timeout,
+            )
+            .await
+            {
                 Ok(response) => return Ok(response),
                 Err(e) => {
                     if e.needs_auth {
                         auth_info.destroy();
                     }
+                    auth_info.release();
+
+                    if e.bad_bucket {
+                        self.state.buckets.clear();
+                    }
 
                     tries += 1;
 
-                    if !e.can_retry || tries >= MAX_API_RETRIES {
+                    if !self.wait_to_retry(tries, &e).await {
                         return Err(e.into());
                     }
                 }
@@ -436,6 +804,30 @@ impl B2API {
         }
     }
 
+    /// Waits before retrying a request that failed. Returns false if the
+    /// request should not be retried.
+    async fn wait_to_retry(&self, tries: usize, error: &B2Error) -> bool {
+        let policy = &self.state.settings.retry_policy;
+        if !error.can_retry || tries >= policy.max_attempts {
+            return false;
+        }
+
+        // Expired authorization is fixed by authorizing again.
+        if error.error.kind() == StorageErrorKind::AccessExpired {
+            return true;
+        }
+
+        let delay = policy.delay(tries, error.retry_after);
+        trace!(
+            "Client {:04}: Retrying after {}ms (attempt {}).",
+            self.id,
+            delay.as_millis(),
+            tries + 1
+        );
+        Delay::new(Instant::now() + delay).await;
+        true
+    }
+
     pub async fn account_info(&self) -> StorageResult<AuthorizeAccountResponse> {
         let auth_info = self.state.auth_tokens.acquire().await?;
         let mut account_info = auth_info.deref().clone();
@@ -443,12 +835,117 @@ impl B2API {
         Ok(account_info)
     }
 
+    /// Finds the bucket with the given name, using a cached copy if it is
+    /// recent enough.
+    pub async fn bucket(&self, path: ObjectPath, name: &str) -> StorageResult<Bucket> {
+        let ttl = self.state.settings.bucket_cache_ttl;
+        if let Some(bucket) = self.state.buckets.get(name, ttl) {
+            return Ok(bucket);
+        }
+
+        let request = ListBucketsRequest {
+            account_id: self.account_info().await?.account_id,
+            bucket_id: None,
+            bucket_name: Some(name.to_owned()),
+            bucket_types: Default::default(),
+        };
+
+        let mut buckets = self.b2_list_buckets(path.clone(), request).await?.buckets;
+        if buckets.len() != 1 {
+            return Err(error::not_found(path, None));
+        }
+
+        let bucket = buckets.remove(0);
+        self.cache_bucket(bucket.clone());
+        Ok(bucket)
+    }
+
+    /// Finds the id of the bucket with the given name.
+    ///
+    /// Keys restricted to a single bucket are told its id when authorizing so
+    /// no lookup is needed for that bucket.
+    pub async fn bucket_ref(&self, path: ObjectPath, name: &str) -> StorageResult<BucketRef> {
+        let allowed = self.account_info().await?.allowed;
+        if let (Some(bucket_id), Some(bucket_name)) = (allowed.bucket_id, allowed.bucket_name) {
+            if bucket_name == name {
+                return Ok(BucketRef {
+                    bucket_id,
+                    bucket_name,
+                });
+            }
+        }
+
+        let bucket = self.bucket(path, name).await?;
+        Ok(BucketRef {
+            bucket_id: bucket.bucket_id,
+            bucket_name: bucket.bucket_name,
+        })
+    }
+
+    /// Adds a bucket to the cache.
+    pub fn cache_bucket(&self, bucket: Bucket) {
+        if self.state.settings.bucket_cache_ttl > Duration::from_secs(0) {
+            self.state.buckets.insert(bucket);
+        }
+    }
+
+    /// Removes a bucket from the cache.
+    pub fn forget_bucket(&self, name: &str) {
+        self.state.buckets.remove(name);
+    }
+
+    /// Downloads a file, or part of a file. Also returns the total size of the
+    /// file and the id of the version downloaded if the response included them.
     pub async fn b2_download_file_by_name(
         self,
         path: ObjectPath,
         bucket: String,
         file: String,
-    ) -> StorageResult<impl Stream<Item = Result<Chunk, hyper::Error>>> {
+        range: Option<Range<u64>>,
+    ) -> StorageResult<(
+        DownloadHeaders,
+        impl Stream<Item = Result<Chunk, hyper::Error>>,
+    )> {
+        let location = format!(
+            "/file/{}/{}",
+            percent_encode(&bucket),
+            percent_encode(&file)
+        );
+        self.download("b2_download_file_by_name", path, location, range)
+            .await
+    }
+
+    /// Downloads a specific version of a file, or part of it. Also returns the
+    /// total size of the file if the response included it.
+    pub async fn b2_download_file_by_id(
+        self,
+        path: ObjectPath,
+        file_id: String,
+        range: Option<Range<u64>>,
+    ) -> StorageResult<(
+        DownloadHeaders,
+        impl Stream<Item = Result<Chunk, hyper::Error>>,
+    )> {
+        let location = format!(
+            "/b2api/{}/b2_download_file_by_id?fileId={}",
+            B2_VERSION,
+            percent_encode(&file_id)
+        );
+        self.download("b2_download_file_by_id", path, location, range)
+            .await
+    }
+
+    /// Downloads from a location relative to the account's download url.
+    async fn download(
+        self,
+        method: &'static str,
+        path: ObjectPath,
+        location: String,
+        range: Option<Range<u64>>,
+    ) -> StorageResult<(
+        DownloadHeaders,
+        impl Stream<Item = Result<Chunk, hyper::Error>>,
+    )> {
         let mut tries: usize = 0;
         loop {
             let mut auth_info = self.state.auth_tokens.acquire().await?;
@@ -456,47 +953,57 @@ impl B2API {
             trace!(
                 "Client {:04}: Starting {} api call (attempt {})",
                 self.id,
-                "b2_download_file_by_name",
+                method,
                 tries + 1,
             );
 
-            let request = Request::builder()
+            let mut builder = Request::builder();
+            builder
                 .method(Method::GET)
                 .header(header::AUTHORIZATION, &auth_info.authorization_token)
                 .header(header::USER_AGENT, &self.state.settings.user_agent)
-                .uri(format!(
-                    "{}/file/{}/{}",
-                    auth_info.download_url,
-                    percent_encode(&bucket),
-                    percent_encode(&file)
-                ))
-                .body(Body::empty())?;
+                .uri(format!("{}{}", auth_info.download_url, location));
+            if let Some(ref range) = range {
+                builder.header(
+                    header::RANGE,
+                    format!("bytes={}-{}", range.start, range.end - 1),
+                );
+            }
+            let request = builder.body(Body::empty())?;
 
             let mut client = self.state.clients.acquire().await;
             match B2Client::request(
                 self.id,
-                "b2_download_file_by_name",
+                method,
                 path.clone(),
                 &client,
                 request,
+                self.state.settings.request_timeout,
             )
             .await
             {
                 Ok(response) => {
-                    let (_, body) = response.into_parts();
+                    let (parts, body) = response.into_parts();
+                    let headers = DownloadHeaders::from_parts(&parts);
+                    let body = if parts.status == StatusCode::RANGE_NOT_SATISFIABLE {
+                        Body::empty()
+                    } else {
+                        body
+                    };
                     let stream = AfterStream::after(body, move || client.release());
 
-                    return Ok(stream);
+                    return Ok((headers, stream));
                 }
                 Err(e) => {
                     client.release();
                     if e.needs_auth {
                         auth_info.destroy();
                     }
+                    auth_info.release();
 
                     tries += 1;
 
-                    if !e.can_retry || tries >= MAX_API_RETRIES {
+                    if !self.wait_to_retry(tries, &e).await {
                         return Err(e.into());
                     }
                 }
@@ -504,12 +1011,88 @@ impl B2API {
         }
     }
 
+    /// Creates a client that does not share this client's upload URLs. The
+    /// upload URL pools use this to request new URLs without holding a
+    /// reference to themselves.
+    fn detached(&self) -> B2API {
+        let mut state = self.state.clone();
+        state.upload_urls = Default::default();
+        B2API::new(&state)
+    }
+
+    /// Gets a URL to upload a file to the bucket with.
+    async fn upload_url(
+        &self,
+        bucket_id: &str,
+    ) -> StorageResult<Acquired<(B2API, String), GetUploadUrlResponse, StorageError>> {
+        let pool = self
+            .state
+            .upload_urls
+            .files
+            .lock()
+            .unwrap()
+            .entry(bucket_id.to_owned())
+            .or_insert_with(|| {
+                Pool::new(
+                    (self.detached(), bucket_id.to_owned()),
+                    None,
+                    |(client, bucket_id)| {
+                        let request = GetUploadUrlRequest {
+                            bucket_id: bucket_id.clone(),
+                        };
+                        WrappedFuture::from_future(
+                            client.b2_get_upload_url(ObjectPath::empty(), request),
+                        )
+                    },
+                )
+            })
+            .clone();
+
+        pool.acquire().await
+    }
+
+    /// Gets a URL to upload a part of the large file with.
+    async fn upload_part_url(
+        &self,
+        file_id: &str,
+    ) -> StorageResult<Acquired<(B2API, String), GetUploadPartUrlResponse, StorageError>> {
+        let pool = self
+            .state
+            .upload_urls
+            .parts
+            .lock()
+            .unwrap()
+            .entry(file_id.to_owned())
+            .or_insert_with(|| {
+                Pool::new(
+                    (self.detached(), file_id.to_owned()),
+                    None,
+                    |(client, file_id)| {
+                        let request = GetUploadPartUrlRequest {
+                            file_id: file_id.clone(),
+                        };
+                        WrappedFuture::from_future(
+                            client.b2_get_upload_part_url(ObjectPath::empty(), request),
+                        )
+                    },
+                )
+            })
+            .clone();
+
+        pool.acquire().await
+    }
+
+    /// Drops the part upload URLs for a large file that is no longer being
+    /// uploaded.
+    pub fn forget_upload_part_urls(&self, file_id: &str) {
+        self.state.upload_urls.parts.lock().unwrap().remove(file_id);
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub async fn b2_upload_file(
         self,
         path: ObjectPath,
-        url: String,
-        auth: String,
+        bucket_id: String,
         file_name: String,
         content_type: String,
         info: UserFileInfo,
@@ -520,11 +1103,13 @@ impl B2API {
         let mut tries: usize = 0;
 
         loop {
+            let mut upload_url = self.upload_url(&bucket_id).await?;
+
             let mut builder = Request::builder();
             builder
                 .method(Method::POST)
-                .uri(&url)
-                .header(header::AUTHORIZATION, &auth)
+                .uri(&upload_url.upload_url)
+                .header(header::AUTHORIZATION, &upload_url.authorization_token)
                 .header(header::USER_AGENT, &self.state.settings.user_agent)
                 .header(B2_HEADER_FILE_NAME, percent_encode(&file_name))
                 .header(header::CONTENT_TYPE, &content_type)
@@ -532,7 +1117,10 @@ impl B2API {
                 .header(B2_HEADER_CONTENT_SHA1, &hash);
 
             for (key, value) in info.iter() {
-                builder.header(&format!("{}{}", B2_HEADER_FILE_INFO_PREFIX, key), value);
+                builder.header(
+                    &format!("{}{}", B2_HEADER_FILE_INFO_PREFIX, key),
+                    percent_encode(value),
+                );
             }
 
             let request = builder.body(Body::wrap_stream(
@@ -540,14 +1128,27 @@ impl B2API {
             ))?;
 
             let client = self.state.clients.acquire().await;
-            match B2Client::basic_request(self.id, "b2_upload_file", path.clone(), client, request)
-                .await
+            match B2Client::basic_request(
+                self.id,
+                "b2_upload_file",
+                path.clone(),
+                client,
+                request,
+                UPLOAD_REQUEST_TIMEOUT,
+            )
+            .await
             {
                 Ok(response) => return Ok(response),
                 Err(e) => {
+                    // The URL may be broken so use a different one next time.
+                    if e.needs_auth {
+                        upload_url.destroy();
+                    }
+                    upload_url.release();
+
                     tries += 1;
 
-                    if !e.can_retry || tries >= MAX_API_RETRIES {
+                    if !self.wait_to_retry(tries, &e).await {
                         return Err(e.into());
                     }
                 }
@@ -555,18 +1156,23 @@ impl B2API {
         }
     }
 
+    /// Uploads a part of a large file. Retries are reported to the observer.
+    #[allow(clippy::too_many_arguments)]
     pub async fn b2_upload_part(
         self,
         path: ObjectPath,
-        upload_url: GetUploadPartUrlResponse,
+        file_id: String,
         part: usize,
         length: u64,
         hash: String,
         data: Vec<Data>,
+        progress: Option<TransferObserver>,
     ) -> StorageResult<UploadPartResponse> {
         let mut tries: usize = 0;
 
         loop {
+            let mut upload_url = self.upload_part_url(&file_id).await?;
+
             let request = Request::builder()
                 .method(Method::POST)
                 .uri(&upload_url.upload_url)
@@ -575,27 +1181,214 @@ impl B2API {
                 .header(B2_HEADER_PART_NUMBER, part)
                 .header(header::CONTENT_LENGTH, length)
                 .header(B2_HEADER_CONTENT_SHA1, &hash)
+                // Cloning only copies the reference counted handles to the
+                // part's buffers, not the data itself.
                 .body(Body::wrap_stream(
                     iter(data.clone()).map(Ok::<_, StorageError>),
                 ))?;
 
             let client = self.state.clients.acquire().await;
-            match B2Client::basic_request(self.id, "b2_upload_part", path.clone(), client, request)
-                .await
+            match B2Client::basic_request(
+                self.id,
+                "b2_upload_part",
+                path.clone(),
+                client,
+                request,
+                UPLOAD_REQUEST_TIMEOUT,
+            )
+            .await
             {
                 Ok(response) => return Ok(response),
                 Err(e) => {
+                    // The URL may be broken so use a different one next time.
+                    if e.needs_auth {
+                        upload_url.destroy();
+                    }
+                    upload_url.release();
+
                     tries += 1;
 
-                    if !e.can_retry || tries >= MAX_API_RETRIES {
+                    if !self.wait_to_retry(tries, &e).await {
                         return Err(e.into());
                     }
+
+                    if let Some(ref observer) = progress {
+                        observer.notify(TransferEvent::PartRetried {
+                            part,
+                            error: e.into(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Uploads a file while reading its data from a stream of exactly `length`
+    /// bytes. The data's SHA1 is sent after the data so nothing is buffered,
+    /// but the upload cannot be retried once any data has been read.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn b2_upload_file_streaming<S>(
+        self,
+        path: ObjectPath,
+        bucket_id: String,
+        file_name: String,
+        content_type: String,
+        info: UserFileInfo,
+        length: u64,
+        stream: S,
+    ) -> Result<UploadFileResponse, TransferError>
+    where
+        S: Stream<Item = StorageResult<Data>> + Send + Unpin + 'static,
+    {
+        let upload = StreamingUpload::new(stream);
+        let mut tries: usize = 0;
+
+        loop {
+            let mut upload_url = self
+                .upload_url(&bucket_id)
+                .await
+                .map_err(TransferError::TargetError)?;
+
+            let mut builder = Request::builder();
+            builder
+                .method(Method::POST)
+                .uri(&upload_url.upload_url)
+                .header(header::AUTHORIZATION, &upload_url.authorization_token)
+                .header(header::USER_AGENT, &self.state.settings.user_agent)
+                .header(B2_HEADER_FILE_NAME, percent_encode(&file_name))
+                .header(header::CONTENT_TYPE, &content_type)
+                .header(header::CONTENT_LENGTH, length + SHA1_HEX_LENGTH)
+                .header(B2_HEADER_CONTENT_SHA1, CONTENT_SHA1_AT_END);
+
+            for (key, value) in info.iter() {
+                builder.header(
+                    &format!("{}{}", B2_HEADER_FILE_INFO_PREFIX, key),
+                    percent_encode(value),
+                );
+            }
+
+            let request = builder
+                .body(upload.body())
+                .map_err(|e| TransferError::TargetError(e.into()))?;
+
+            let client = self.state.clients.acquire().await;
+            match B2Client::basic_request(
+                self.id,
+                "b2_upload_file",
+                path.clone(),
+                client,
+                request,
+                UPLOAD_REQUEST_TIMEOUT,
+            )
+            .await
+            {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    if e.needs_auth {
+                        upload_url.destroy();
+                    }
+                    upload_url.release();
+
+                    if let Some(error) = upload.source_error() {
+                        return Err(TransferError::SourceError(error));
+                    }
+
+                    tries += 1;
+
+                    if upload.started() || !self.wait_to_retry(tries, &e).await {
+                        return Err(TransferError::TargetError(e.into()));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Uploads a part of a large file while reading its data from a stream of
+    /// exactly `length` bytes, returning the stream once the part is complete.
+    /// The upload cannot be retried once any data has been read. Retries are
+    /// reported to the observer.
+    pub async fn b2_upload_part_streaming<S>(
+        self,
+        path: ObjectPath,
+        file_id: String,
+        part: usize,
+        length: u64,
+        progress: Option<TransferObserver>,
+        stream: S,
+    ) -> Result<(UploadPartResponse, S), TransferError>
+    where
+        S: Stream<Item = StorageResult<Data>> + Send + Unpin + 'static,
+    {
+        let upload = StreamingUpload::new(stream);
+        let mut tries: usize = 0;
+
+        loop {
+            let mut upload_url = self
+                .upload_part_url(&file_id)
+                .await
+                .map_err(TransferError::TargetError)?;
+
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(&upload_url.upload_url)
+                .header(header::AUTHORIZATION, &upload_url.authorization_token)
+                .header(header::USER_AGENT, &self.state.settings.user_agent)
+                .header(B2_HEADER_PART_NUMBER, part)
+                .header(header::CONTENT_LENGTH, length + SHA1_HEX_LENGTH)
+                .header(B2_HEADER_CONTENT_SHA1, CONTENT_SHA1_AT_END)
+                .body(upload.body())
+                .map_err(|e| TransferError::TargetError(e.into()))?;
+
+            let client = self.state.clients.acquire().await;
+            match B2Client::basic_request(
+                self.id,
+                "b2_upload_part",
+                path.clone(),
+                client,
+                request,
+                UPLOAD_REQUEST_TIMEOUT,
+            )
+            .await
+            {
+                Ok(response) => {
+                    return match upload.into_inner() {
+                        Some(stream) => Ok((response, stream)),
+                        None => Err(TransferError::SourceError(error::internal_error(Some(
+                            "The data for the upload was lost.",
+                        )))),
+                    };
+                }
+                Err(e) => {
+                    if e.needs_auth {
+                        upload_url.destroy();
+                    }
+                    upload_url.release();
+
+                    if let Some(error) = upload.source_error() {
+                        return Err(TransferError::SourceError(error));
+                    }
+
+                    tries += 1;
+
+                    if upload.started() || !self.wait_to_retry(tries, &e).await {
+                        return Err(TransferError::TargetError(e.into()));
+                    }
+
+                    if let Some(ref observer) = progress {
+                        observer.notify(TransferEvent::PartRetried {
+                            part,
+                            error: e.into(),
+                        });
+                    }
                 }
             }
         }
     }
 
     b2_api!(b2_list_buckets, ListBucketsRequest, ListBucketsResponse);
+    b2_api!(b2_create_bucket, CreateBucketRequest, CreateBucketResponse);
+    b2_api!(b2_update_bucket, UpdateBucketRequest, UpdateBucketResponse);
+    b2_api!(b2_delete_bucket, DeleteBucketRequest, DeleteBucketResponse);
     b2_api!(b2_get_file_info, GetFileInfoRequest, GetFileInfoResponse);
     b2_api!(
         b2_list_file_names,
@@ -612,6 +1405,7 @@ impl B2API {
         DeleteFileVersionRequest,
         DeleteFileVersionResponse
     );
+    b2_api!(b2_hide_file, HideFileRequest, HideFileResponse);
     b2_api!(b2_get_upload_url, GetUploadUrlRequest, GetUploadUrlResponse);
     b2_api!(
         b2_start_large_file,
@@ -628,4 +1422,17 @@ impl B2API {
         FinishLargeFileRequest,
         FinishLargeFileResponse
     );
+    b2_api!(b2_copy_file, CopyFileRequest, CopyFileResponse);
+    b2_api!(b2_copy_part, CopyPartRequest, CopyPartResponse);
+    b2_api!(
+        b2_list_unfinished_large_files,
+        ListUnfinishedLargeFilesRequest,
+        ListUnfinishedLargeFilesResponse
+    );
+    b2_api!(b2_list_parts, ListPartsRequest, ListPartsResponse);
+    b2_api!(
+        b2_cancel_large_file,
+        CancelLargeFileRequest,
+        CancelLargeFileResponse
+    );
 }
@@ -0,0 +1,379 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for storage backends implemented outside of this crate.
+//!
+//! [`StorageBackend`](../../trait.StorageBackend.html) is generic and so cannot
+//! be used as a trait object. Instead implement
+//! [`DynStorageBackend`](trait.DynStorageBackend.html) which takes concrete
+//! paths and streams and then wrap it in an `Arc` to create a
+//! [`FileStore`](../../enum.FileStore.html):
+//!
+//! ```ignore
+//! let fs = FileStore::from(Arc::new(MyBackend::new()) as Arc<dyn DynStorageBackend>);
+//! ```
+//!
+//! Objects can be returned from any backend but
+//! [`CustomObject`](struct.CustomObject.html) is available for backends that
+//! have no object type of their own. Backends that override
+//! [`copy_file`](trait.DynStorageBackend.html#method.copy_file) should use
+//! [`UploadInfo::inherit_metadata`](../../struct.UploadInfo.html#method.inherit_metadata)
+//! to keep the source file's metadata.
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use bytes::IntoBuf;
+use futures::future::TryFutureExt;
+use futures::stream::Stream;
+
+use super::Backend;
+use crate::types::stream::RangeStream;
+use crate::types::*;
+use crate::utils::{delete_objects, into_upload_stream};
+use crate::{StorageBackend, DEFAULT_CONCURRENCY};
+
+/// An object-safe version of [`StorageBackend`](../../trait.StorageBackend.html).
+///
+/// Each method behaves exactly as the method of the same name on
+/// `StorageBackend` but takes already parsed paths and upload information and
+/// a [`DataStream`](../../type.DataStream.html) for writes.
+pub trait DynStorageBackend: fmt::Debug + Send + Sync + 'static {
+    /// Retrieves the type of this backend. Generally this will be
+    /// [`Backend::Custom`](../enum.Backend.html#variant.Custom).
+    fn backend_type(&self) -> Backend;
+
+    /// Lists the objects that are prefixed by the given prefix.
+    fn list_objects(&self, prefix: ObjectPath) -> ObjectStreamFuture;
+
+    /// Lists the objects that exist in the given (possibly virtual) directory.
+    fn list_directory(&self, dir: ObjectPath) -> ObjectStreamFuture;
+
+    /// Gets info about the object at the given path.
+    fn get_object(&self, path: ObjectPath) -> ObjectFuture;
+
+    /// Gets a stream of data for the file at the given path.
+    fn get_file_stream(&self, path: ObjectPath) -> DataStreamFuture;
+
+    /// Gets a stream of data for a range of bytes in the file at the given path.
+    ///
+    /// The default implementation discards the data outside of the range from
+    /// a stream of the entire file.
+    fn get_file_range(&self, path: ObjectPath, range: Range<u64>) -> DataStreamFuture {
+        DataStreamFuture::from_future(
+            self.get_file_stream(path)
+                .map_ok(move |stream| DataStream::from_stream(RangeStream::new(stream, range))),
+        )
+    }
+
+    /// Copies a file from one path to another within this backend.
+    ///
+    /// The default implementation reads the entire file and writes it to the
+    /// new location.
+    fn copy_file(
+        self: Arc<Self>,
+        source: ObjectPath,
+        mut target: UploadInfo,
+    ) -> CopyCompleteFuture {
+        CopyCompleteFuture::from_future(async move {
+            let object = self
+                .get_object(source.clone())
+                .await
+                .map_err(TransferError::SourceError)?;
+            target.inherit_metadata(&object);
+
+            let data = DataStream::from_stream(self.get_file_stream(source).try_flatten_stream());
+            self.write_file_from_stream(target, data).await
+        })
+    }
+
+    /// Moves a file from one path to another within this backend.
+    ///
+    /// The default implementation copies the file and then deletes the source.
+    fn move_file(self: Arc<Self>, source: ObjectPath, target: UploadInfo) -> MoveCompleteFuture {
+        MoveCompleteFuture::from_future(async move {
+            self.clone().copy_file(source.clone(), target).await?;
+            self.delete_object(source)
+                .await
+                .map_err(TransferError::SourceError)
+        })
+    }
+
+    /// Deletes the object at the given path.
+    fn delete_object(&self, path: ObjectPath) -> OperationCompleteFuture;
+
+    /// Deletes every object whose path starts with the given prefix.
+    ///
+    /// The default implementation lists the objects and deletes each with
+    /// `delete_object`, directories last.
+    fn delete_prefix(self: Arc<Self>, prefix: ObjectPath) -> DeleteStreamFuture {
+        DeleteStreamFuture::from_future(async move {
+            let objects = self.list_objects(prefix.clone()).await?;
+            Ok(DeleteStream::from_stream(delete_objects(
+                objects,
+                prefix,
+                DEFAULT_CONCURRENCY,
+                move |object| self.delete_object(object.path()),
+            )))
+        })
+    }
+
+    /// Writes a stream of data to the file at the given path.
+    fn write_file_from_stream(&self, info: UploadInfo, stream: DataStream) -> WriteCompleteFuture;
+}
+
+impl StorageBackend for Arc<dyn DynStorageBackend> {
+    fn backend_type(&self) -> Backend {
+        (**self).backend_type()
+    }
+
+    fn list_objects<P>(&self, prefix: P) -> ObjectStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        match prefix.try_into() {
+            Ok(p) => (**self).list_objects(p),
+            Err(e) => ObjectStreamFuture::from_value(Err(e.into())),
+        }
+    }
+
+    fn list_directory<P>(&self, dir: P) -> ObjectStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        match dir.try_into() {
+            Ok(p) => (**self).list_directory(p),
+            Err(e) => ObjectStreamFuture::from_value(Err(e.into())),
+        }
+    }
+
+    fn get_object<P>(&self, path: P) -> ObjectFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        match path.try_into() {
+            Ok(p) => (**self).get_object(p),
+            Err(e) => ObjectFuture::from_value(Err(e.into())),
+        }
+    }
+
+    fn get_file_stream<P>(&self, path: P) -> DataStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        match path.try_into() {
+            Ok(p) => (**self).get_file_stream(p),
+            Err(e) => DataStreamFuture::from_value(Err(e.into())),
+        }
+    }
+
+    fn get_file_range<P>(&self, path: P, range: Range<u64>) -> DataStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        match path.try_into() {
+            Ok(p) => (**self).get_file_range(p, range),
+            Err(e) => DataStreamFuture::from_value(Err(e.into())),
+        }
+    }
+
+    fn copy_file<P, I>(&self, source: P, target: I) -> CopyCompleteFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+        I: TryInto<UploadInfo>,
+        I::Error: Into<StorageError>,
+    {
+        let source = match source.try_into() {
+            Ok(p) => p,
+            Err(e) => {
+                return CopyCompleteFuture::from_value(Err(TransferError::SourceError(e.into())))
+            }
+        };
+
+        let info = match target.try_into() {
+            Ok(i) => i,
+            Err(e) => {
+                return CopyCompleteFuture::from_value(Err(TransferError::TargetError(e.into())))
+            }
+        };
+
+        DynStorageBackend::copy_file(self.clone(), source, info)
+    }
+
+    fn move_file<P, I>(&self, source: P, target: I) -> MoveCompleteFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+        I: TryInto<UploadInfo>,
+        I::Error: Into<StorageError>,
+    {
+        let source = match source.try_into() {
+            Ok(p) => p,
+            Err(e) => {
+                return MoveCompleteFuture::from_value(Err(TransferError::SourceError(e.into())))
+            }
+        };
+
+        let info = match target.try_into() {
+            Ok(i) => i,
+            Err(e) => {
+                return MoveCompleteFuture::from_value(Err(TransferError::TargetError(e.into())))
+            }
+        };
+
+        DynStorageBackend::move_file(self.clone(), source, info)
+    }
+
+    fn delete_object<P>(&self, path: P) -> OperationCompleteFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        match path.try_into() {
+            Ok(p) => (**self).delete_object(p),
+            Err(e) => OperationCompleteFuture::from_value(Err(e.into())),
+        }
+    }
+
+    fn delete_prefix<P>(&self, prefix: P) -> DeleteStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        match prefix.try_into() {
+            Ok(p) => DynStorageBackend::delete_prefix(self.clone(), p),
+            Err(e) => DeleteStreamFuture::from_value(Err(e.into())),
+        }
+    }
+
+    fn write_file_from_stream<S, I, E, P>(&self, info: P, stream: S) -> WriteCompleteFuture
+    where
+        S: Stream<Item = Result<I, E>> + Send + 'static,
+        I: IntoBuf + 'static,
+        E: Into<StorageError> + 'static,
+        P: TryInto<UploadInfo>,
+        P::Error: Into<StorageError>,
+    {
+        let info = match info.try_into() {
+            Ok(i) => i,
+            Err(e) => {
+                return WriteCompleteFuture::from_value(Err(TransferError::TargetError(e.into())))
+            }
+        };
+
+        let stream = match into_upload_stream(&info, stream) {
+            Ok(s) => s,
+            Err(e) => return WriteCompleteFuture::from_value(Err(e)),
+        };
+
+        (**self).write_file_from_stream(info, DataStream::from_stream(stream))
+    }
+}
+
+/// An [`Object`](../../enum.Object.html) for custom backends to return.
+///
+/// All of the object's properties are set directly.
+#[derive(Clone, Debug)]
+pub struct CustomObject {
+    /// The object's path.
+    pub path: ObjectPath,
+    /// The object's type.
+    pub object_type: ObjectType,
+    /// The object's size in bytes.
+    pub len: u64,
+    /// The last modification time for the object.
+    pub modified: Option<SystemTime>,
+    /// The object's mimetype.
+    pub content_type: Option<String>,
+    /// The `Cache-Control` value stored with the object.
+    pub cache_control: Option<String>,
+    /// The `Content-Disposition` value stored with the object.
+    pub content_disposition: Option<String>,
+    /// Custom metadata stored with the object.
+    pub metadata: HashMap<String, String>,
+    /// An opaque identifier for the current version of the object.
+    pub version: Option<String>,
+    /// A checksum of the object's content.
+    pub checksum: Option<Checksum>,
+}
+
+impl CustomObject {
+    /// Creates a new empty object of the given type. Other properties can be
+    /// set once created.
+    pub fn new(path: ObjectPath, object_type: ObjectType) -> CustomObject {
+        CustomObject {
+            path,
+            object_type,
+            len: 0,
+            modified: None,
+            content_type: None,
+            cache_control: None,
+            content_disposition: None,
+            metadata: HashMap::new(),
+            version: None,
+            checksum: None,
+        }
+    }
+}
+
+impl ObjectInfo for CustomObject {
+    fn path(&self) -> ObjectPath {
+        self.path.clone()
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn object_type(&self) -> ObjectType {
+        self.object_type
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    fn content_type(&self) -> Option<String> {
+        self.content_type.clone()
+    }
+
+    fn cache_control(&self) -> Option<String> {
+        self.cache_control.clone()
+    }
+
+    fn content_disposition(&self) -> Option<String> {
+        self.content_disposition.clone()
+    }
+
+    fn metadata(&self) -> HashMap<String, String> {
+        self.metadata.clone()
+    }
+
+    fn version(&self) -> Option<String> {
+        self.version.clone()
+    }
+
+    fn checksum(&self) -> Option<Checksum> {
+        self.checksum.clone()
+    }
+}
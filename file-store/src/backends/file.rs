@@ -25,28 +25,70 @@
 //! [`delete_object`](../../enum.FileStore.html#method.delete_object) and
 //! [`write_file_from_stream`](../../enum.FileStore.html#method.write_file_from_stream)
 //! will remove these (in the directory case recursively).
+//!
+//! Paths are always confined to the root directory. Path parts that are empty,
+//! `.` or `..`, or that contain `\` or NUL characters are rejected. Symlinks
+//! within the root are followed wherever they point unless disabled with
+//! [`FileBackendBuilder::follow_external_symlinks`](struct.FileBackendBuilder.html#method.follow_external_symlinks).
+//!
+//! A file's metadata (content type, cache control, content disposition and any
+//! custom metadata) is stored in the file's extended attributes. On platforms
+//! or filesystems that do not support them (Windows or tmpfs for example) the
+//! metadata is discarded with a warning and files are still written. The
+//! attributes are only read from disk when first requested from an
+//! [`Object`](../../enum.Object.html) so listings don't read them for every
+//! file.
+//!
+//! Files are written to a hidden temporary file in the target directory which
+//! is only moved into place once it has been completely written and synced to
+//! disk, so a failed write leaves any existing file untouched. Temporary files
+//! are never included in listings. Use
+//! [`FileBackendBuilder::sync_directory`](struct.FileBackendBuilder.html#method.sync_directory)
+//! to also sync the directory after the file is moved into place.
+//!
+//! Files written with [`WritePrecondition::DoesNotExist`](../../enum.WritePrecondition.html#variant.DoesNotExist)
+//! are linked into place exclusively so only one of many concurrent writers
+//! will succeed.
+//! A file's version is compared with
+//! [`WritePrecondition::MatchesVersion`](../../enum.WritePrecondition.html#variant.MatchesVersion)
+//! before writing starts and again immediately before the new file is moved
+//! into place. Versioned writes through the same backend are serialized around
+//! that final check but another process changing the file between the check
+//! and the move will be overwritten, so across processes the check is only
+//! best-effort.
+use std::cmp::max;
+use std::collections::HashMap;
 use std::convert::TryInto;
+use std::ffi::OsStr;
 use std::fs::Metadata;
 use std::io;
-use std::path::{Path, PathBuf};
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
 use std::pin::Pin;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use bytes::IntoBuf;
 use filetime::{set_file_mtime, FileTime};
 use futures::future::{ready, Future, FutureExt, TryFutureExt};
 use futures::stream::{empty, once, Stream, StreamExt, TryStreamExt};
 use log::{trace, warn};
+use sha1::Sha1;
 use tokio_fs::DirEntry;
 use tokio_io::AsyncWriteExt;
 
 use super::Backend;
 use crate::types::error;
-use crate::types::stream::{MergedStreams, ResultStreamPoll};
+use crate::types::stream::{MergedStreams, RangeStream, ResultStreamPoll};
 use crate::types::*;
-use crate::utils::{into_data_stream, ReaderStream};
-use crate::{FileStore, Object, ObjectInfo, StorageBackend};
+use crate::utils::{
+    copy_each, delete_each, delete_objects, get_each, into_upload_stream, ReaderStream,
+};
+use crate::{FileStore, Object, ObjectInfo, StorageBackend, DEFAULT_CONCURRENCY};
 
 // When reading from a file we start requesting INITIAL_BUFFER_SIZE bytes. As
 // data is read the available space is reduced until it reaches MIN_BUFFER_SIZE
@@ -55,6 +97,76 @@ const MB: usize = 1024 * 1024;
 const INITIAL_BUFFER_SIZE: usize = 20 * MB;
 const MIN_BUFFER_SIZE: usize = MB;
 
+// Files are written to temporary files named `.file-store.<name>.<id>.tmp` in
+// the target directory before being moved into place.
+const TEMP_PREFIX: &str = ".file-store.";
+const TEMP_SUFFIX: &str = ".tmp";
+static NEXT_TEMP_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Generates a temporary file path next to the target that no other write,
+/// from this or any other process, will use.
+fn temp_path(target: &Path) -> PathBuf {
+    let name = target
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let id = NEXT_TEMP_ID.fetch_add(1, Ordering::SeqCst);
+    target.with_file_name(format!(
+        "{}{}.{}-{}{}",
+        TEMP_PREFIX,
+        name,
+        process::id(),
+        id,
+        TEMP_SUFFIX
+    ))
+}
+
+/// Removes a temporary file when dropped unless it has been moved into place.
+/// This cleans up after writes that fail or are cancelled part way through.
+struct TempFile {
+    path: PathBuf,
+    armed: bool,
+}
+
+impl TempFile {
+    fn new(target: &Path) -> TempFile {
+        TempFile {
+            path: temp_path(target),
+            armed: true,
+        }
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Failed to remove temporary file: {}", e);
+            }
+        }
+    }
+}
+
+/// Checks whether a file name is one used for a temporary file.
+fn is_temp_file(name: &OsStr) -> bool {
+    match name.to_str() {
+        Some(n) => n.starts_with(TEMP_PREFIX) && n.ends_with(TEMP_SUFFIX),
+        None => false,
+    }
+}
+
 async fn read_dir<P>(path: P) -> io::Result<tokio_fs::ReadDir>
 where
     P: AsRef<Path> + Send + 'static,
@@ -97,6 +209,68 @@ where
     result
 }
 
+async fn rename<P, Q>(from: P, to: Q) -> io::Result<()>
+where
+    P: AsRef<Path> + Send + 'static,
+    Q: AsRef<Path> + Send + 'static,
+{
+    let from = from.as_ref().to_owned();
+    let to = to.as_ref().to_owned();
+    let result = tokio_fs::rename(from.clone(), to.clone()).await;
+    match result {
+        Ok(_) => trace!(
+            "tokio_fs::rename {} {} success",
+            from.display(),
+            to.display()
+        ),
+        Err(ref e) => trace!(
+            "tokio_fs::rename {} {} failed: {}",
+            from.display(),
+            to.display(),
+            e
+        ),
+    }
+
+    result
+}
+
+async fn hard_link<P, Q>(src: P, dst: Q) -> io::Result<()>
+where
+    P: AsRef<Path> + Send + 'static,
+    Q: AsRef<Path> + Send + 'static,
+{
+    let src = src.as_ref().to_owned();
+    let dst = dst.as_ref().to_owned();
+    let result = tokio_fs::hard_link(src.clone(), dst.clone()).await;
+    match result {
+        Ok(_) => trace!(
+            "tokio_fs::hard_link {} {} success",
+            src.display(),
+            dst.display()
+        ),
+        Err(ref e) => trace!(
+            "tokio_fs::hard_link {} {} failed: {}",
+            src.display(),
+            dst.display(),
+            e
+        ),
+    }
+
+    result
+}
+
+/// Syncs a directory's entries to disk.
+#[cfg(unix)]
+fn sync_directory(path: &Path) -> io::Result<()> {
+    std::fs::File::open(path)?.sync_all()
+}
+
+/// Directories cannot be opened for syncing on this platform.
+#[cfg(not(unix))]
+fn sync_directory(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
 async fn symlink_metadata<P>(path: P) -> io::Result<Metadata>
 where
     P: AsRef<Path> + Send + 'static,
@@ -132,15 +306,24 @@ impl File {
         result
     }
 
-    pub async fn create<P>(path: P) -> io::Result<tokio_fs::File>
+    /// Creates a file, failing if something already exists at the path.
+    pub async fn create_new<P>(path: P) -> io::Result<tokio_fs::File>
     where
         P: AsRef<Path> + 'static,
     {
         let path = path.as_ref().to_owned();
-        let result = tokio_fs::File::create(path.clone()).await;
+        let result = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map(tokio_fs::File::from_std);
         match result {
-            Ok(_) => trace!("tokio_fs::File::create {} success", path.display()),
-            Err(ref e) => trace!("tokio_fs::File::create {} failed: {}", path.display(), e),
+            Ok(_) => trace!("std::fs::OpenOptions::open {} success", path.display()),
+            Err(ref e) => trace!(
+                "std::fs::OpenOptions::open {} failed: {}",
+                path.display(),
+                e
+            ),
         }
 
         result
@@ -150,6 +333,7 @@ impl File {
 fn get_storage_error(error: io::Error, path: ObjectPath) -> StorageError {
     match error.kind() {
         io::ErrorKind::NotFound => error::not_found(path, Some(&error.to_string())),
+        io::ErrorKind::AlreadyExists => error::already_exists(path, Some(&error.to_string())),
         _ => error::other_error(Some(&error.to_string())),
     }
 }
@@ -168,11 +352,186 @@ where
     stream.map_err(move |e| get_storage_error(e, path.clone()))
 }
 
+const XATTR_PREFIX: &str = "user.file-store.";
+const XATTR_CONTENT_TYPE: &str = "user.file-store.content-type";
+const XATTR_CACHE_CONTROL: &str = "user.file-store.cache-control";
+const XATTR_CONTENT_DISPOSITION: &str = "user.file-store.content-disposition";
+const XATTR_METADATA_PREFIX: &str = "user.file-store.meta.";
+const XATTR_SHA1: &str = "user.file-store.sha1";
+
+/// The file metadata stored in a file's extended attributes.
+#[derive(Clone, Debug, Default)]
+struct FileAttributes {
+    content_type: Option<String>,
+    cache_control: Option<String>,
+    content_disposition: Option<String>,
+    metadata: HashMap<String, String>,
+    sha1: Option<String>,
+}
+
+impl FileAttributes {
+    /// Reads the attributes for a file. Attributes that cannot be read are
+    /// ignored.
+    fn read(target: &Path) -> FileAttributes {
+        let mut attributes = FileAttributes::default();
+
+        let names = match xattr::list(target) {
+            Ok(names) => names,
+            Err(_) => return attributes,
+        };
+
+        for name in names {
+            let name = match name.into_string() {
+                Ok(n) => n,
+                Err(_) => continue,
+            };
+
+            if !name.starts_with(XATTR_PREFIX) {
+                continue;
+            }
+
+            let value = match xattr::get(target, &name) {
+                Ok(Some(v)) => match String::from_utf8(v) {
+                    Ok(s) => s,
+                    Err(_) => continue,
+                },
+                _ => continue,
+            };
+
+            match name.as_str() {
+                XATTR_CONTENT_TYPE => attributes.content_type = Some(value),
+                XATTR_CACHE_CONTROL => attributes.cache_control = Some(value),
+                XATTR_CONTENT_DISPOSITION => attributes.content_disposition = Some(value),
+                XATTR_SHA1 => attributes.sha1 = Some(value),
+                _ => {
+                    if name.starts_with(XATTR_METADATA_PREFIX) {
+                        let key = name[XATTR_METADATA_PREFIX.len()..].to_owned();
+                        attributes.metadata.insert(key, value);
+                    }
+                }
+            }
+        }
+
+        attributes
+    }
+
+    /// Stores the metadata from the upload info in the file's attributes. Fails
+    /// if the platform or filesystem doesn't support extended attributes.
+    fn write(target: &Path, info: &UploadInfo) -> io::Result<()> {
+        let values = [
+            (XATTR_CONTENT_TYPE, &info.content_type),
+            (XATTR_CACHE_CONTROL, &info.cache_control),
+            (XATTR_CONTENT_DISPOSITION, &info.content_disposition),
+        ];
+
+        for (name, value) in values.iter() {
+            if let Some(value) = value {
+                xattr::set(target, name, value.as_bytes())?;
+            }
+        }
+
+        for (key, value) in info.metadata.iter() {
+            let name = format!("{}{}", XATTR_METADATA_PREFIX, key);
+            xattr::set(target, name, value.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Stores the SHA1 hash of the file's current content. This must be called
+    /// after the file's content and modification time are final.
+    fn write_sha1(target: &Path, hash: &str) -> io::Result<()> {
+        let metadata = std::fs::metadata(target)?;
+        let value = format!("{} {}", checksum_stamp(&metadata), hash);
+        xattr::set(target, XATTR_SHA1, value.as_bytes())
+    }
+
+    /// Gets the stored SHA1 hash if it still applies to the file's content.
+    fn sha1(&self, metadata: &Metadata) -> Option<String> {
+        let value = self.sha1.as_ref()?;
+        let mut parts = value.splitn(2, ' ');
+        let stamp = parts.next()?;
+        let hash = parts.next()?;
+
+        if stamp == checksum_stamp(metadata) {
+            Some(hash.to_owned())
+        } else {
+            None
+        }
+    }
+}
+
+/// Identifies the content of a file that a stored checksum was generated for.
+/// The stamp only changes if something modifies the file without updating the
+/// checksum. The change time cannot be used as storing the checksum updates it.
+fn checksum_stamp(metadata: &Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_nanos())
+        .unwrap_or(0);
+
+    format!("{}-{}", modified, metadata.len())
+}
+
+/// Builds a version for a file from its metadata. The change time is updated
+/// on every write and cannot be set directly.
+#[cfg(unix)]
+fn file_version(metadata: &Metadata) -> String {
+    use std::os::unix::fs::MetadataExt;
+
+    format!(
+        "{}-{}.{}-{}",
+        metadata.ino(),
+        metadata.ctime(),
+        metadata.ctime_nsec(),
+        metadata.len()
+    )
+}
+
+/// Builds a version for a file from its metadata.
+#[cfg(not(unix))]
+fn file_version(metadata: &Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_nanos())
+        .unwrap_or(0);
+
+    format!("{}-{}", modified, metadata.len())
+}
+
 /// The File implementation for [`Object`](../../enum.Object.html).
 #[derive(Clone, Debug)]
 pub struct FileObject {
     path: ObjectPath,
     metadata: Option<Metadata>,
+    /// The file to read attributes from, `None` for anything but files.
+    target: Option<PathBuf>,
+    /// The file's attributes once they have been read.
+    attributes: Arc<Mutex<Option<FileAttributes>>>,
+}
+
+impl FileObject {
+    /// Calls the callback with the file's attributes, reading them if this is
+    /// the first time they have been needed.
+    fn with_attributes<F, R>(&self, callback: F) -> R
+    where
+        F: FnOnce(&FileAttributes) -> R,
+    {
+        let mut attributes = match self.attributes.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let target = &self.target;
+        callback(attributes.get_or_insert_with(|| match target {
+            Some(target) => FileAttributes::read(target),
+            None => FileAttributes::default(),
+        }))
+    }
 }
 
 impl ObjectInfo for FileObject {
@@ -210,26 +569,142 @@ impl ObjectInfo for FileObject {
             .as_ref()
             .and_then(|m| if m.is_file() { m.modified().ok() } else { None })
     }
+
+    fn content_type(&self) -> Option<String> {
+        self.with_attributes(|a| a.content_type.clone())
+    }
+
+    fn cache_control(&self) -> Option<String> {
+        self.with_attributes(|a| a.cache_control.clone())
+    }
+
+    fn content_disposition(&self) -> Option<String> {
+        self.with_attributes(|a| a.content_disposition.clone())
+    }
+
+    fn metadata(&self) -> HashMap<String, String> {
+        self.with_attributes(|a| a.metadata.clone())
+    }
+
+    fn version(&self) -> Option<String> {
+        self.metadata.as_ref().and_then(|m| {
+            if m.is_file() {
+                Some(file_version(m))
+            } else {
+                None
+            }
+        })
+    }
+
+    fn checksum(&self) -> Option<Checksum> {
+        self.metadata.as_ref().and_then(|m| {
+            if m.is_file() {
+                self.with_attributes(|a| a.sha1(m)).map(Checksum::Sha1)
+            } else {
+                None
+            }
+        })
+    }
 }
 
-fn get_object(path: ObjectPath, metadata: Option<Metadata>) -> Object {
-    Object::from(FileObject { path, metadata })
+fn get_object(space: &FileSpace, path: ObjectPath, metadata: Option<Metadata>) -> Object {
+    let is_file = metadata.as_ref().map(|m| m.is_file()).unwrap_or(false);
+    let target = if is_file {
+        space.get_std_path(&path).ok()
+    } else {
+        None
+    };
+
+    Object::from(FileObject {
+        path,
+        metadata,
+        target,
+        attributes: Default::default(),
+    })
+}
+
+/// Checks that a path part names a single entry within its directory.
+fn is_valid_part(part: &str) -> bool {
+    if part.is_empty() || part == "." || part == ".." {
+        return false;
+    }
+
+    if part.contains('\0') || part.contains('\\') {
+        return false;
+    }
+
+    // Catches anything the platform would treat as a root or prefix.
+    let mut components = Path::new(part).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => true,
+        _ => false,
+    }
 }
 
 #[derive(Clone, Debug)]
 struct FileSpace {
     base: PathBuf,
+    /// The canonical form of the base when symlinks are not allowed to point
+    /// outside of it.
+    canonical_base: Option<PathBuf>,
 }
 
 impl FileSpace {
     fn get_std_path(&self, path: &ObjectPath) -> StorageResult<PathBuf> {
         let mut result = self.base.clone();
         for part in path.parts() {
+            if !is_valid_part(part) {
+                return Err(error::invalid_path(
+                    path.clone(),
+                    Some(
+                        "Path parts cannot be empty, '.', '..' or contain '\\' or NUL characters.",
+                    ),
+                ));
+            }
             result.push(part);
         }
 
+        if let Some(ref canonical_base) = self.canonical_base {
+            self.check_resolved(path, &result, canonical_base)?;
+        }
+
         Ok(result)
     }
+
+    /// Checks that the path, or the closest of its ancestors that exists, does
+    /// not resolve to somewhere outside of the base.
+    fn check_resolved(
+        &self,
+        path: &ObjectPath,
+        target: &Path,
+        canonical_base: &Path,
+    ) -> StorageResult<()> {
+        let mut existing = target;
+        let resolved = loop {
+            match existing.canonicalize() {
+                Ok(resolved) => break resolved,
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        return Err(get_storage_error(e, path.clone()));
+                    }
+
+                    existing = match existing.parent() {
+                        Some(parent) => parent,
+                        None => return Ok(()),
+                    };
+                }
+            }
+        };
+
+        if resolved.starts_with(canonical_base) {
+            Ok(())
+        } else {
+            Err(error::invalid_path(
+                path.clone(),
+                Some("Path resolves to outside of the root directory."),
+            ))
+        }
+    }
 }
 
 fn directory_stream(
@@ -294,14 +769,18 @@ struct FileLister {
     stream: Pin<Box<MergedStreams<FileList>>>,
     space: FileSpace,
     prefix: ObjectPath,
+    include_temp: bool,
 }
 
 impl FileLister {
-    fn list(space: FileSpace, mut prefix: ObjectPath) -> FileLister {
+    /// Lists everything beneath the prefix. Temporary files are only included
+    /// if requested.
+    fn list(space: FileSpace, mut prefix: ObjectPath, include_temp: bool) -> FileLister {
         let mut lister = FileLister {
             stream: Box::pin(MergedStreams::new()),
             space,
             prefix: prefix.clone(),
+            include_temp,
         };
 
         prefix.pop_part();
@@ -322,14 +801,20 @@ impl Stream for FileLister {
         loop {
             match self.stream.as_mut().poll_next(cx) {
                 Poll::Ready(Some(Ok((path, maybe_metadata)))) => {
-                    if path.starts_with(&self.prefix) {
+                    let is_temp = path
+                        .parts()
+                        .last()
+                        .map(|name| is_temp_file(OsStr::new(name)))
+                        .unwrap_or(false);
+                    if path.starts_with(&self.prefix) && (self.include_temp || !is_temp) {
                         if let Some(ref metadata) = maybe_metadata {
                             if metadata.is_dir() {
                                 self.add_directory(path.clone());
                             }
                         }
 
-                        return Poll::Ready(Some(Ok(get_object(path, maybe_metadata))));
+                        let object = get_object(&self.space, path, maybe_metadata);
+                        return Poll::Ready(Some(Ok(object)));
                     }
                 }
                 Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
@@ -340,29 +825,47 @@ impl Stream for FileLister {
     }
 }
 
+/// Deletes everything beneath the prefix, including temporary files, and
+/// returns a stream of the result for each object deleted. Up to `limit`
+/// files are deleted at once.
+fn delete_listed(
+    space: FileSpace,
+    prefix: ObjectPath,
+    limit: usize,
+) -> impl Stream<Item = DeleteResult> + Send + 'static {
+    let lister = ObjectStream::from_stream(FileLister::list(space.clone(), prefix.clone(), true));
+    delete_objects(lister, prefix, limit, move |object| {
+        let path = object.path();
+        let target = match space.get_std_path(&path) {
+            Ok(t) => t,
+            Err(e) => return OperationCompleteFuture::from_value(Err(e)),
+        };
+
+        if object.object_type() == ObjectType::Directory {
+            OperationCompleteFuture::from_future(wrap_future(remove_dir(target), path))
+        } else {
+            OperationCompleteFuture::from_future(wrap_future(remove_file(target), path))
+        }
+    })
+}
+
 #[allow(clippy::needless_lifetimes)]
 async fn delete_directory(space: FileSpace, path: ObjectPath) -> StorageResult<()> {
     let mut dir_path = path.clone();
     dir_path.push_part("");
 
-    let allfiles = FileLister::list(space.clone(), dir_path)
-        .try_collect::<Vec<Object>>()
-        .await?;
-    let nondirectories = allfiles
-        .iter()
-        .filter(|file| file.object_type() != ObjectType::Directory);
-    let directories = allfiles
-        .iter()
-        .filter(|file| file.object_type() == ObjectType::Directory);
-
-    for file in nondirectories {
-        let target = space.get_std_path(&file.path())?;
-        wrap_future(remove_file(target), file.path()).await?;
+    // Let the deletes that are already running finish before reporting the
+    // first failure.
+    let mut failure: Option<StorageError> = None;
+    let mut results = Box::pin(delete_listed(space.clone(), dir_path, DEFAULT_CONCURRENCY));
+    while let Some(result) = results.next().await {
+        if let Err((_, e)) = result {
+            failure = failure.or(Some(e));
+        }
     }
 
-    for dir in directories {
-        let target = space.get_std_path(&dir.path())?;
-        wrap_future(remove_dir(target), dir.path()).await?;
+    if let Some(e) = failure {
+        return Err(e);
     }
 
     let target = space.get_std_path(&path)?;
@@ -374,6 +877,12 @@ async fn delete_directory(space: FileSpace, path: ObjectPath) -> StorageResult<(
 #[derive(Clone, Debug)]
 pub struct FileBackend {
     space: FileSpace,
+    sync_directory: bool,
+    max_concurrency: usize,
+    operation_timeout: Option<Duration>,
+    /// Held while checking a file's version and moving its replacement into
+    /// place.
+    version_lock: Arc<Mutex<()>>,
 }
 
 impl FileBackend {
@@ -383,19 +892,108 @@ impl FileBackend {
     /// The root path provided must be a directory and is used as the base of
     /// the visible storage.
     pub fn connect(root: &Path) -> ConnectFuture {
-        let target = root.to_owned();
+        FileBackend::builder(root).connect()
+    }
+
+    /// Creates a new [`FileBackendBuilder`](struct.FileBackendBuilder.html).
+    pub fn builder(root: &Path) -> FileBackendBuilder {
+        FileBackendBuilder {
+            root: root.to_owned(),
+            sync_directory: false,
+            follow_external_symlinks: true,
+            max_concurrency: DEFAULT_CONCURRENCY,
+            operation_timeout: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// Used to build a [`FileBackend`](struct.FileBackend.html) with some custom
+/// settings.
+pub struct FileBackendBuilder {
+    root: PathBuf,
+    sync_directory: bool,
+    follow_external_symlinks: bool,
+    max_concurrency: usize,
+    operation_timeout: Option<Duration>,
+}
+
+impl FileBackendBuilder {
+    /// Sets whether to sync a file's directory after writing the file.
+    ///
+    /// Written files are always synced to disk before being moved into place
+    /// but on some filesystems the move itself may not survive a crash unless
+    /// the directory is synced too. This is off by default as it makes writes
+    /// slower.
+    pub fn sync_directory(mut self, sync_directory: bool) -> FileBackendBuilder {
+        self.sync_directory = sync_directory;
+        self
+    }
+
+    /// Sets whether symlinks that point outside of the root directory can be
+    /// followed.
+    ///
+    /// When disabled any path that passes through or ends at such a symlink is
+    /// rejected with [`StorageErrorKind::InvalidPath`](../../enum.StorageErrorKind.html#variant.InvalidPath).
+    /// This should be disabled when the paths used come from untrusted
+    /// sources and the root directory may contain symlinks.
+    pub fn follow_external_symlinks(mut self, follow: bool) -> FileBackendBuilder {
+        self.follow_external_symlinks = follow;
+        self
+    }
+
+    /// Limits how many operations a batch, or deleting everything under a
+    /// prefix, runs at once. Defaults to 10.
+    pub fn limit_concurrency(mut self, operations: usize) -> FileBackendBuilder {
+        self.max_concurrency = max(operations, 1);
+        self
+    }
+
+    /// Sets how long an operation may take before it fails with
+    /// [`TimedOut`](../../enum.StorageErrorKind.html#variant.TimedOut).
+    ///
+    /// For streams this covers getting the stream, not reading from it. An
+    /// operation that times out is cancelled as if its future was dropped.
+    pub fn operation_timeout(mut self, timeout: Duration) -> FileBackendBuilder {
+        self.operation_timeout = Some(timeout);
+        self
+    }
+
+    /// Creates a new file based [`FileStore`](../../enum.FileStore.html) using
+    /// this builder's settings.
+    ///
+    /// The root path must be a directory and is used as the base of the
+    /// visible storage.
+    pub fn connect(self) -> ConnectFuture {
         ConnectFuture::from_future(async move {
             let metadata =
-                wrap_future(symlink_metadata(target.clone()), ObjectPath::empty()).await?;
+                wrap_future(symlink_metadata(self.root.clone()), ObjectPath::empty()).await?;
             if !metadata.is_dir() {
-                Err(error::invalid_settings(Some(
+                return Err(error::invalid_settings(Some(
                     "Root path is not a directory.",
-                )))
-            } else {
-                Ok(FileStore::from(FileBackend {
-                    space: FileSpace { base: target },
-                }))
+                )));
             }
+
+            let canonical_base = if self.follow_external_symlinks {
+                None
+            } else {
+                Some(
+                    self.root
+                        .canonicalize()
+                        .map_err(|e| get_storage_error(e, ObjectPath::empty()))?,
+                )
+            };
+
+            Ok(FileStore::from(FileBackend {
+                space: FileSpace {
+                    base: self.root,
+                    canonical_base,
+                },
+                sync_directory: self.sync_directory,
+                max_concurrency: self.max_concurrency,
+                operation_timeout: self.operation_timeout,
+                version_lock: Default::default(),
+            }))
         })
     }
 }
@@ -411,7 +1009,9 @@ impl StorageBackend for FileBackend {
         P::Error: Into<StorageError>,
     {
         async fn list(space: FileSpace, prefix: ObjectPath) -> StorageResult<ObjectStream> {
-            Ok(ObjectStream::from_stream(FileLister::list(space, prefix)))
+            Ok(ObjectStream::from_stream(FileLister::list(
+                space, prefix, false,
+            )))
         }
 
         let path = match prefix.try_into() {
@@ -420,6 +1020,7 @@ impl StorageBackend for FileBackend {
         };
 
         ObjectStreamFuture::from_future(list(self.space.clone(), path))
+            .with_timeout(self.operation_timeout)
     }
 
     fn list_directory<P>(&self, dir: P) -> ObjectStreamFuture
@@ -440,7 +1041,9 @@ impl StorageBackend for FileBackend {
                     wrap_future(read_dir(path.clone()), directory.clone()).await?,
                     directory.clone(),
                 )
+                .try_filter(|entry| ready(!is_temp_file(&entry.file_name())))
                 .and_then(move |entry| {
+                    let space = space.clone();
                     let path_base = directory.clone();
                     wrap_future(symlink_metadata(entry.path()), directory.clone()).map(
                         move |result| match result {
@@ -456,7 +1059,7 @@ impl StorageBackend for FileBackend {
 
                                 let mut path = path_base.clone();
                                 path.push_part(&file_name);
-                                Ok(get_object(path, Some(metadata)))
+                                Ok(get_object(&space, path, Some(metadata)))
                             }
                             Err(e) => Err(e),
                         },
@@ -475,6 +1078,7 @@ impl StorageBackend for FileBackend {
         }
 
         ObjectStreamFuture::from_future(list(self.space.clone(), path))
+            .with_timeout(self.operation_timeout)
     }
 
     fn get_object<P>(&self, path: P) -> ObjectFuture
@@ -486,12 +1090,12 @@ impl StorageBackend for FileBackend {
             let target = space.get_std_path(&path)?;
 
             match symlink_metadata(target.clone()).await {
-                Ok(m) => Ok(get_object(path, Some(m))),
+                Ok(m) => Ok(get_object(&space, path, Some(m))),
                 Err(e) => {
                     if e.kind() == io::ErrorKind::NotFound {
                         Err(error::not_found(path, Some(&e.to_string())))
                     } else {
-                        Ok(get_object(path, None))
+                        Ok(get_object(&space, path, None))
                     }
                 }
             }
@@ -510,6 +1114,7 @@ impl StorageBackend for FileBackend {
         }
 
         ObjectFuture::from_future(get(self.space.clone(), path))
+            .with_timeout(self.operation_timeout)
     }
 
     fn get_file_stream<P>(&self, path: P) -> DataStreamFuture
@@ -533,7 +1138,43 @@ impl StorageBackend for FileBackend {
         }
 
         match path.try_into() {
-            Ok(p) => DataStreamFuture::from_future(read(self.space.clone(), p)),
+            Ok(p) => DataStreamFuture::from_future(read(self.space.clone(), p))
+                .with_timeout(self.operation_timeout),
+            Err(e) => DataStreamFuture::from_value(Err(e.into())),
+        }
+    }
+
+    fn get_file_range<P>(&self, path: P, range: Range<u64>) -> DataStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        async fn read(
+            space: FileSpace,
+            path: ObjectPath,
+            range: Range<u64>,
+        ) -> StorageResult<DataStream> {
+            let target = space.get_std_path(&path)?;
+
+            let metadata = wrap_future(symlink_metadata(target.clone()), path.clone()).await?;
+            if !metadata.is_file() {
+                return Err(error::not_found(path, None));
+            }
+
+            let mut file = wrap_future(File::open(target), path.clone()).await?;
+            wrap_future(file.seek(SeekFrom::Start(range.start)), path.clone()).await?;
+
+            let length = range.end.saturating_sub(range.start);
+            Ok(DataStream::from_stream(RangeStream::new(
+                ReaderStream::<tokio_fs::File>::stream(file, INITIAL_BUFFER_SIZE, MIN_BUFFER_SIZE)
+                    .map_err(move |e| get_storage_error(e, path.clone())),
+                0..length,
+            )))
+        }
+
+        match path.try_into() {
+            Ok(p) => DataStreamFuture::from_future(read(self.space.clone(), p, range))
+                .with_timeout(self.operation_timeout),
             Err(e) => DataStreamFuture::from_value(Err(e.into())),
         }
     }
@@ -555,11 +1196,65 @@ impl StorageBackend for FileBackend {
         }
 
         match path.try_into() {
-            Ok(p) => OperationCompleteFuture::from_future(delete(self.space.clone(), p)),
+            Ok(p) => OperationCompleteFuture::from_future(delete(self.space.clone(), p))
+                .with_timeout(self.operation_timeout),
             Err(e) => OperationCompleteFuture::from_value(Err(e.into())),
         }
     }
 
+    /// Deletes every file, symlink and directory whose path starts with the
+    /// given prefix. Directories are removed once everything inside them has
+    /// been deleted and a directory whose contents are being deleted by a
+    /// prefix ending in `/` is left in place. Errors listing the directories
+    /// are reported in the stream.
+    fn delete_prefix<P>(&self, prefix: P) -> DeleteStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        match prefix.try_into() {
+            Ok(p) => DeleteStreamFuture::from_value(Ok(DeleteStream::from_stream(delete_listed(
+                self.space.clone(),
+                p,
+                self.max_concurrency,
+            )))),
+            Err(e) => DeleteStreamFuture::from_value(Err(e.into())),
+        }
+    }
+
+    fn get_objects<I>(&self, paths: I) -> GetStream
+    where
+        I: IntoIterator<Item = ObjectPath>,
+    {
+        get_each(
+            self.clone(),
+            paths.into_iter().collect(),
+            self.max_concurrency,
+        )
+    }
+
+    fn delete_objects<I>(&self, paths: I) -> DeleteStream
+    where
+        I: IntoIterator<Item = ObjectPath>,
+    {
+        delete_each(
+            self.clone(),
+            paths.into_iter().collect(),
+            self.max_concurrency,
+        )
+    }
+
+    fn copy_files<I>(&self, paths: I) -> CopyStream
+    where
+        I: IntoIterator<Item = (ObjectPath, ObjectPath)>,
+    {
+        copy_each(
+            self.clone(),
+            paths.into_iter().collect(),
+            self.max_concurrency,
+        )
+    }
+
     fn write_file_from_stream<S, I, E, P>(&self, info: P, stream: S) -> WriteCompleteFuture
     where
         S: Stream<Item = Result<I, E>> + Send + 'static,
@@ -568,8 +1263,108 @@ impl StorageBackend for FileBackend {
         P: TryInto<UploadInfo>,
         P::Error: Into<StorageError>,
     {
+        /// Writes the stream to the temporary file, setting its attributes and
+        /// syncing it to disk.
+        async fn write_temp<S>(
+            temp: &Path,
+            info: &UploadInfo,
+            stream: &mut S,
+        ) -> Result<(), TransferError>
+        where
+            S: Stream<Item = StorageResult<Data>> + Send + Unpin + 'static,
+        {
+            let storage_error =
+                |e| TransferError::TargetError(get_storage_error(e, info.path.clone()));
+
+            // The temporary file is created exclusively so nothing else can be
+            // clobbered.
+            let mut file = File::create_new(temp.to_owned())
+                .await
+                .map_err(storage_error)?;
+
+            let mut hasher = Sha1::new();
+            while let Some(result) = stream.next().await {
+                let data = result.map_err(TransferError::SourceError)?;
+                hasher.update(&data);
+                file.write_all(&data).await.map_err(storage_error)?;
+            }
+
+            file.flush().await.map_err(storage_error)?;
+
+            if let Err(e) = FileAttributes::write(temp, info) {
+                warn!("Failed to store file metadata: {}", e);
+            }
+
+            if let Some(time) = info.modified {
+                if let Err(e) = set_file_mtime(temp, FileTime::from_system_time(time)) {
+                    warn!("Failed to set file modification time: {}", e);
+                }
+            }
+
+            if let Err(e) = FileAttributes::write_sha1(temp, &hasher.hexdigest()) {
+                warn!("Failed to store file checksum: {}", e);
+            }
+
+            file.sync_all().await.map_err(storage_error)?;
+            file.shutdown().await.map_err(storage_error)?;
+
+            Ok(())
+        }
+
+        /// Moves the written temporary file into place.
+        async fn replace(
+            space: FileSpace,
+            version_lock: &Mutex<()>,
+            temp: PathBuf,
+            target: PathBuf,
+            info: &UploadInfo,
+            existing: Option<Metadata>,
+        ) -> StorageResult<()> {
+            if let Some(m) = existing {
+                if m.is_dir() {
+                    delete_directory(space, info.path.clone()).await?;
+                }
+            }
+
+            match info.precondition {
+                // Linking fails if anything has been created at the target
+                // since the precondition was checked.
+                WritePrecondition::DoesNotExist => {
+                    wrap_future(hard_link(temp.clone(), target), info.path.clone()).await?;
+                    if let Err(e) = remove_file(temp).await {
+                        warn!("Failed to remove temporary file: {}", e);
+                    }
+                    Ok(())
+                }
+                // Check that the file hasn't changed while the new file was
+                // written. Nothing here waits so the lock is never held across
+                // a yield.
+                WritePrecondition::MatchesVersion(_) => {
+                    let _guard = match version_lock.lock() {
+                        Ok(guard) => guard,
+                        Err(poisoned) => poisoned.into_inner(),
+                    };
+
+                    let current = match std::fs::symlink_metadata(&target) {
+                        Ok(m) => Some(get_object(&space, info.path.clone(), Some(m))),
+                        Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                        Err(e) => return Err(get_storage_error(e, info.path.clone())),
+                    };
+                    info.precondition.check(&info.path, current.as_ref())?;
+
+                    std::fs::rename(&temp, &target)
+                        .map_err(|e| get_storage_error(e, info.path.clone()))
+                }
+                WritePrecondition::Always => {
+                    wrap_future(rename(temp, target), info.path.clone()).await
+                }
+            }
+        }
+
         async fn write<S>(
             space: FileSpace,
+            sync_dir: bool,
+            version_lock: Arc<Mutex<()>>,
             info: UploadInfo,
             mut stream: S,
         ) -> Result<(), TransferError>
@@ -580,57 +1375,45 @@ impl StorageBackend for FileBackend {
                 .get_std_path(&info.path)
                 .map_err(TransferError::TargetError)?;
 
-            match symlink_metadata(target.clone()).await {
-                Ok(m) => {
-                    if m.is_dir() {
-                        delete_directory(space, info.path.clone())
-                            .await
-                            .map_err(TransferError::TargetError)?;
-                    } else {
-                        wrap_future(remove_file(target.clone()), info.path.clone())
-                            .await
-                            .map_err(TransferError::TargetError)?;
-                    }
-                }
+            let existing = match symlink_metadata(target.clone()).await {
+                Ok(m) => Some(m),
                 Err(e) => {
                     if e.kind() != io::ErrorKind::NotFound {
                         return Err(TransferError::TargetError(get_storage_error(e, info.path)));
                     }
+                    None
                 }
             };
 
-            let mut file = wrap_future(File::create(target.clone()), info.path.clone())
-                .await
-                .map_err(TransferError::TargetError)?;
-
-            loop {
-                let option = stream.next().await;
-                if let Some(result) = option {
-                    let data = result.map_err(TransferError::SourceError)?;
-                    match file.write_all(&data).await {
-                        Ok(()) => (),
-                        Err(e) => {
-                            return Err(TransferError::TargetError(get_storage_error(e, info.path)))
-                        }
-                    };
-                } else {
-                    break;
-                }
+            if info.precondition != WritePrecondition::Always {
+                let current = existing
+                    .clone()
+                    .map(|m| get_object(&space, info.path.clone(), Some(m)));
+                info.precondition
+                    .check(&info.path, current.as_ref())
+                    .map_err(TransferError::TargetError)?;
             }
 
-            match file.flush().await {
-                Ok(()) => (),
-                Err(e) => return Err(TransferError::TargetError(get_storage_error(e, info.path))),
-            }
-
-            match file.shutdown().await {
-                Ok(()) => (),
-                Err(e) => return Err(TransferError::TargetError(get_storage_error(e, info.path))),
-            }
-
-            if let Some(time) = info.modified {
-                if let Err(e) = set_file_mtime(&target, FileTime::from_system_time(time)) {
-                    warn!("Failed to set file modification time: {}", e);
+            // Dropping the guard removes the temporary file if anything fails
+            // or this future is dropped before the file is moved into place.
+            let temp = TempFile::new(&target);
+            write_temp(temp.path(), &info, &mut stream).await?;
+            replace(
+                space,
+                &version_lock,
+                temp.path().to_owned(),
+                target.clone(),
+                &info,
+                existing,
+            )
+            .await
+            .map_err(TransferError::TargetError)?;
+            temp.disarm();
+
+            if sync_dir {
+                if let Some(parent) = target.parent() {
+                    sync_directory(parent)
+                        .map_err(|e| TransferError::TargetError(get_storage_error(e, info.path)))?;
                 }
             }
 
@@ -644,10 +1427,18 @@ impl StorageBackend for FileBackend {
             }
         };
 
+        let stream = match into_upload_stream(&info, stream) {
+            Ok(s) => s,
+            Err(e) => return WriteCompleteFuture::from_value(Err(e)),
+        };
+
         WriteCompleteFuture::from_future(write(
             self.space.clone(),
+            self.sync_directory,
+            self.version_lock.clone(),
             info,
-            Box::pin(into_data_stream(stream)),
+            Box::pin(stream),
         ))
+        .with_timeout(self.operation_timeout)
     }
 }
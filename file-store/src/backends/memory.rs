@@ -0,0 +1,530 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Stores files in memory. Included with the feature "memory".
+//!
+//! The [`MemoryBackend`](struct.MemoryBackend.html) keeps all of its files in
+//! a map held in memory. Nothing is persisted and all of the files are lost
+//! once the last clone of the [`FileStore`](../../enum.FileStore.html) is
+//! dropped. This makes it mostly useful for testing code that uses a
+//! `FileStore` without needing to touch the disk or the network.
+//!
+//! The backend behaves like the [file backend](../file/index.html) in that it
+//! has real directories. Writing a file creates any missing parent directories
+//! and deleting a directory deletes everything inside it. Directories cannot be
+//! created directly.
+use std::cmp::min;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use bytes::{BytesMut, IntoBuf};
+use futures::stream::{iter, Stream, StreamExt};
+use sha1::Sha1;
+
+use super::Backend;
+use crate::types::error;
+use crate::types::*;
+use crate::utils::into_upload_stream;
+use crate::{FileStore, Object, ObjectInfo, StorageBackend};
+
+// Data is returned from get_file_stream in chunks of at most this size.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+// Every file written is given a new version from this counter.
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(1);
+
+/// The metadata stored alongside a file.
+#[derive(Clone, Debug)]
+struct FileMetadata {
+    content_type: Option<String>,
+    cache_control: Option<String>,
+    content_disposition: Option<String>,
+    metadata: HashMap<String, String>,
+}
+
+impl From<&UploadInfo> for FileMetadata {
+    fn from(info: &UploadInfo) -> FileMetadata {
+        FileMetadata {
+            content_type: info.content_type.clone(),
+            cache_control: info.cache_control.clone(),
+            content_disposition: info.content_disposition.clone(),
+            metadata: info.metadata.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum MemoryEntry {
+    Directory,
+    File {
+        data: Data,
+        modified: SystemTime,
+        metadata: FileMetadata,
+        version: u64,
+        sha1: String,
+    },
+}
+
+impl MemoryEntry {
+    fn data(&self) -> Data {
+        match self {
+            MemoryEntry::File { data, .. } => data.clone(),
+            MemoryEntry::Directory => Data::new(),
+        }
+    }
+
+    fn file_metadata(&self) -> Option<&FileMetadata> {
+        match self {
+            MemoryEntry::File { metadata, .. } => Some(metadata),
+            MemoryEntry::Directory => None,
+        }
+    }
+}
+
+/// The Memory implementation for [`Object`](../../enum.Object.html).
+#[derive(Clone, Debug)]
+pub struct MemoryObject {
+    path: ObjectPath,
+    entry: MemoryEntry,
+}
+
+impl ObjectInfo for MemoryObject {
+    fn path(&self) -> ObjectPath {
+        self.path.clone()
+    }
+
+    fn len(&self) -> u64 {
+        match self.entry {
+            MemoryEntry::File { ref data, .. } => data.len() as u64,
+            MemoryEntry::Directory => 0,
+        }
+    }
+
+    fn object_type(&self) -> ObjectType {
+        match self.entry {
+            MemoryEntry::File { .. } => ObjectType::File,
+            MemoryEntry::Directory => ObjectType::Directory,
+        }
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        match self.entry {
+            MemoryEntry::File { modified, .. } => Some(modified),
+            MemoryEntry::Directory => None,
+        }
+    }
+
+    fn content_type(&self) -> Option<String> {
+        self.entry
+            .file_metadata()
+            .and_then(|m| m.content_type.clone())
+    }
+
+    fn cache_control(&self) -> Option<String> {
+        self.entry
+            .file_metadata()
+            .and_then(|m| m.cache_control.clone())
+    }
+
+    fn content_disposition(&self) -> Option<String> {
+        self.entry
+            .file_metadata()
+            .and_then(|m| m.content_disposition.clone())
+    }
+
+    fn metadata(&self) -> HashMap<String, String> {
+        self.entry
+            .file_metadata()
+            .map(|m| m.metadata.clone())
+            .unwrap_or_default()
+    }
+
+    fn version(&self) -> Option<String> {
+        match self.entry {
+            MemoryEntry::File { version, .. } => Some(version.to_string()),
+            MemoryEntry::Directory => None,
+        }
+    }
+
+    fn checksum(&self) -> Option<Checksum> {
+        match self.entry {
+            MemoryEntry::File { ref sha1, .. } => Some(Checksum::Sha1(sha1.clone())),
+            MemoryEntry::Directory => None,
+        }
+    }
+}
+
+fn get_object(path: &ObjectPath, entry: &MemoryEntry) -> Object {
+    Object::from(MemoryObject {
+        path: path.clone(),
+        entry: entry.clone(),
+    })
+}
+
+fn chunked_stream(data: Data) -> DataStream {
+    let mut chunks: Vec<StorageResult<Data>> = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let end = min(start + CHUNK_SIZE, data.len());
+        chunks.push(Ok(data.slice(start, end)));
+        start = end;
+    }
+
+    DataStream::from_stream(iter(chunks))
+}
+
+fn is_child(parent: &ObjectPath, path: &ObjectPath) -> bool {
+    let mut dir = path.clone();
+    dir.pop_part();
+    &dir == parent
+}
+
+fn is_descendant(parent: &ObjectPath, path: &ObjectPath) -> bool {
+    let mut prefix = parent.clone();
+    prefix.push_part("");
+    path.starts_with(&prefix)
+}
+
+type EntryMap = BTreeMap<ObjectPath, MemoryEntry>;
+
+#[derive(Clone, Debug, Default)]
+struct MemorySpace {
+    entries: Arc<Mutex<EntryMap>>,
+}
+
+impl MemorySpace {
+    fn lock(&self) -> MutexGuard<EntryMap> {
+        self.entries.lock().unwrap()
+    }
+}
+
+/// Removes the entry at the given path and anything beneath it.
+fn remove_entry(entries: &mut EntryMap, path: &ObjectPath) {
+    entries.remove(path);
+
+    let descendants: Vec<ObjectPath> = entries
+        .keys()
+        .filter(|p| is_descendant(path, p))
+        .cloned()
+        .collect();
+    for descendant in descendants {
+        entries.remove(&descendant);
+    }
+}
+
+/// Stores a file at the given path replacing anything that was already there
+/// and creating any missing parent directories.
+fn insert_file(entries: &mut EntryMap, info: &UploadInfo, data: Data) -> StorageResult<()> {
+    let path = &info.path;
+    let mut parent = path.clone();
+    parent.pop_part();
+
+    let mut ancestors: Vec<ObjectPath> = Vec::new();
+    while !parent.is_empty() {
+        ancestors.push(parent.clone());
+        parent.pop_part();
+    }
+
+    for ancestor in ancestors.iter() {
+        if let Some(MemoryEntry::File { .. }) = entries.get(ancestor) {
+            return Err(error::invalid_path(
+                path.clone(),
+                Some("A parent of this path is a file."),
+            ));
+        }
+    }
+
+    // Nothing may change if the precondition fails.
+    let current = entries.get(path).map(|entry| get_object(path, entry));
+    info.precondition.check(path, current.as_ref())?;
+
+    for ancestor in ancestors {
+        entries.entry(ancestor).or_insert(MemoryEntry::Directory);
+    }
+
+    let mut hasher = Sha1::new();
+    hasher.update(&data);
+
+    remove_entry(entries, path);
+    entries.insert(
+        path.clone(),
+        MemoryEntry::File {
+            data,
+            modified: info.modified.unwrap_or_else(SystemTime::now),
+            metadata: FileMetadata::from(info),
+            version: NEXT_VERSION.fetch_add(1, Ordering::SeqCst),
+            sha1: hasher.hexdigest(),
+        },
+    );
+
+    Ok(())
+}
+
+/// The backend implementation for in-memory storage. Only included when the
+/// `memory` feature is enabled.
+#[derive(Clone, Debug)]
+pub struct MemoryBackend {
+    space: MemorySpace,
+}
+
+impl MemoryBackend {
+    /// Creates a new [`FileStore`](../../enum.FileStore.html) instance using the
+    /// memory backend.
+    ///
+    /// The store starts out empty. Clones of the returned `FileStore` share the
+    /// same files.
+    pub fn connect() -> ConnectFuture {
+        ConnectFuture::from_value(Ok(FileStore::from(MemoryBackend {
+            space: Default::default(),
+        })))
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn backend_type(&self) -> Backend {
+        Backend::Memory
+    }
+
+    fn list_objects<P>(&self, prefix: P) -> ObjectStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let prefix = match prefix.try_into() {
+            Ok(p) => p,
+            Err(e) => return ObjectStreamFuture::from_value(Err(e.into())),
+        };
+
+        let objects: Vec<StorageResult<Object>> = self
+            .space
+            .lock()
+            .iter()
+            .filter(|(path, _)| path.starts_with(&prefix))
+            .map(|(path, entry)| Ok(get_object(path, entry)))
+            .collect();
+
+        ObjectStreamFuture::from_value(Ok(ObjectStream::from_stream(iter(objects))))
+    }
+
+    fn list_directory<P>(&self, dir: P) -> ObjectStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let mut path = match dir.try_into() {
+            Ok(p) => p,
+            Err(e) => return ObjectStreamFuture::from_value(Err(e.into())),
+        };
+
+        if !path.is_empty() && path.is_dir_prefix() {
+            path.pop_part();
+        }
+
+        let entries = self.space.lock();
+        if !path.is_empty() {
+            match entries.get(&path) {
+                Some(MemoryEntry::Directory) => (),
+                Some(MemoryEntry::File { .. }) => {
+                    return ObjectStreamFuture::from_value(Ok(ObjectStream::from_stream(iter(
+                        Vec::<StorageResult<Object>>::new(),
+                    ))));
+                }
+                None => return ObjectStreamFuture::from_value(Err(error::not_found(path, None))),
+            }
+        }
+
+        let objects: Vec<StorageResult<Object>> = entries
+            .iter()
+            .filter(|(p, _)| is_child(&path, p))
+            .map(|(p, entry)| Ok(get_object(p, entry)))
+            .collect();
+
+        ObjectStreamFuture::from_value(Ok(ObjectStream::from_stream(iter(objects))))
+    }
+
+    fn get_object<P>(&self, path: P) -> ObjectFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let path = match path.try_into() {
+            Ok(p) => p,
+            Err(e) => return ObjectFuture::from_value(Err(e.into())),
+        };
+
+        if path.is_dir_prefix() {
+            return ObjectFuture::from_value(Err(error::invalid_path(
+                path,
+                Some("Object paths cannot be empty or end with a '/' character."),
+            )));
+        }
+
+        let result = match self.space.lock().get(&path) {
+            Some(entry) => Ok(get_object(&path, entry)),
+            None => Err(error::not_found(path, None)),
+        };
+
+        ObjectFuture::from_value(result)
+    }
+
+    fn get_file_stream<P>(&self, path: P) -> DataStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let path = match path.try_into() {
+            Ok(p) => p,
+            Err(e) => return DataStreamFuture::from_value(Err(e.into())),
+        };
+
+        match self.space.lock().get(&path) {
+            Some(MemoryEntry::File { data, .. }) => {
+                DataStreamFuture::from_value(Ok(chunked_stream(data.clone())))
+            }
+            _ => DataStreamFuture::from_value(Err(error::not_found(path, None))),
+        }
+    }
+
+    fn get_file_range<P>(&self, path: P, range: Range<u64>) -> DataStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let path = match path.try_into() {
+            Ok(p) => p,
+            Err(e) => return DataStreamFuture::from_value(Err(e.into())),
+        };
+
+        match self.space.lock().get(&path) {
+            Some(MemoryEntry::File { data, .. }) => {
+                let end = min(range.end, data.len() as u64) as usize;
+                let start = min(range.start as usize, end);
+                DataStreamFuture::from_value(Ok(chunked_stream(data.slice(start, end))))
+            }
+            _ => DataStreamFuture::from_value(Err(error::not_found(path, None))),
+        }
+    }
+
+    fn copy_file<P, I>(&self, source: P, target: I) -> CopyCompleteFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+        I: TryInto<UploadInfo>,
+        I::Error: Into<StorageError>,
+    {
+        let source = match source.try_into() {
+            Ok(p) => p,
+            Err(e) => {
+                return CopyCompleteFuture::from_value(Err(TransferError::SourceError(e.into())))
+            }
+        };
+
+        let mut info: UploadInfo = match target.try_into() {
+            Ok(i) => i,
+            Err(e) => {
+                return CopyCompleteFuture::from_value(Err(TransferError::TargetError(e.into())))
+            }
+        };
+
+        let mut entries = self.space.lock();
+        let data = match entries.get(&source) {
+            Some(entry @ MemoryEntry::File { .. }) => {
+                info.inherit_metadata(&get_object(&source, entry));
+                entry.data()
+            }
+            _ => {
+                return CopyCompleteFuture::from_value(Err(TransferError::SourceError(
+                    error::not_found(source, None),
+                )))
+            }
+        };
+
+        CopyCompleteFuture::from_value(
+            insert_file(&mut entries, &info, data).map_err(TransferError::TargetError),
+        )
+    }
+
+    fn delete_object<P>(&self, path: P) -> OperationCompleteFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let path = match path.try_into() {
+            Ok(p) => p,
+            Err(e) => return OperationCompleteFuture::from_value(Err(e.into())),
+        };
+
+        let mut entries = self.space.lock();
+        if !entries.contains_key(&path) {
+            return OperationCompleteFuture::from_value(Err(error::not_found(path, None)));
+        }
+
+        remove_entry(&mut entries, &path);
+        OperationCompleteFuture::from_value(Ok(()))
+    }
+
+    fn write_file_from_stream<S, I, E, P>(&self, info: P, stream: S) -> WriteCompleteFuture
+    where
+        S: Stream<Item = Result<I, E>> + Send + 'static,
+        I: IntoBuf + 'static,
+        E: Into<StorageError> + 'static,
+        P: TryInto<UploadInfo>,
+        P::Error: Into<StorageError>,
+    {
+        async fn write<S>(
+            space: MemorySpace,
+            info: UploadInfo,
+            mut stream: S,
+        ) -> Result<(), TransferError>
+        where
+            S: Stream<Item = StorageResult<Data>> + Send + Unpin + 'static,
+        {
+            let mut buffer = BytesMut::new();
+            while let Some(result) = stream.next().await {
+                let data = result.map_err(TransferError::SourceError)?;
+                buffer.extend_from_slice(&data);
+            }
+
+            insert_file(&mut space.lock(), &info, buffer.freeze())
+                .map_err(TransferError::TargetError)
+        }
+
+        let info: UploadInfo = match info.try_into() {
+            Ok(i) => i,
+            Err(e) => {
+                return WriteCompleteFuture::from_value(Err(TransferError::TargetError(e.into())))
+            }
+        };
+
+        if info.path.is_dir_prefix() {
+            return WriteCompleteFuture::from_value(Err(TransferError::TargetError(
+                error::invalid_path(
+                    info.path,
+                    Some("Object paths cannot be empty or end with a '/' character."),
+                ),
+            )));
+        }
+
+        let stream = match into_upload_stream(&info, stream) {
+            Ok(s) => s,
+            Err(e) => return WriteCompleteFuture::from_value(Err(e)),
+        };
+
+        WriteCompleteFuture::from_future(write(self.space.clone(), info, Box::pin(stream)))
+    }
+}
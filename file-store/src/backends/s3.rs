@@ -0,0 +1,1234 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Accesses files in Amazon S3 or any S3 compatible storage. Included with the
+//! feature "s3".
+//!
+//! The [`S3Backend`](struct.S3Backend.html) can be initialized with as little
+//! as an access key id and secret key. It also supports a
+//! [`builder`](struct.S3Backend.html#method.builder) pattern to set the region,
+//! a custom endpoint for S3 compatible services, temporary credentials and a
+//! path prefix to restrict the files visible.
+//!
+//! [`ObjectPath`](../../struct.ObjectPath.html)'s represent the names of files.
+//! The first directory part of a path (the string up until the first `/`) is
+//! used as the name of the bucket. The rest is used as the object's key.
+//! Attempting to write a file at the bucket level will fail. Buckets are never
+//! created by this backend.
+//!
+//! All requests are signed with AWS Signature Version 4. Files larger than
+//! the configured small file size are uploaded with a multipart upload with
+//! the parts being uploaded in parallel. Only a limited number of parts are
+//! held in memory at once and a failed upload waits for its running parts to
+//! finish before aborting the multipart upload.
+//!
+//! The last modified time of an uploaded file is stored in the object's
+//! metadata. When listing files S3 does not return metadata so the time that
+//! the file was uploaded is used instead. For the same reason an object's
+//! content type, cache control, content disposition and custom metadata are
+//! only available from [`get_object`](../../trait.StorageBackend.html#method.get_object).
+//!
+//! Write preconditions are sent to S3 as `If-None-Match` and `If-Match` headers
+//! and a file's version is its ETag. S3 compatible services that do not support
+//! conditional writes will ignore them.
+
+mod client;
+
+use std::collections::HashMap;
+use std::convert::{Infallible, TryInto};
+use std::future::Future;
+use std::ops::Range;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::IntoBuf;
+use futures::channel::mpsc::{channel, Sender};
+use futures::future::{ready, TryFutureExt};
+use futures::sink::SinkExt;
+use futures::stream::{empty, iter, Stream, StreamExt};
+use http::header;
+use http::header::HeaderMap;
+use hyper::client::connect::HttpConnector;
+use hyper::client::Client as HyperClient;
+use hyper_tls::HttpsConnector;
+use log::{error, trace, warn};
+
+use storage_types::s3::requests::*;
+use storage_types::s3::responses::*;
+use storage_types::s3::signing::PayloadHasher;
+use storage_types::s3::{parse_http_date, parse_iso8601, LAST_MODIFIED_KEY, S3_HEADER_META_PREFIX};
+
+use super::Backend;
+use crate::types::stream::{MergedStreams, RangeStream, ResultStreamPoll};
+use crate::types::*;
+use crate::utils::{into_upload_stream, Acquired, CloningPool, PartTasks};
+use crate::{FileStore, StorageBackend};
+use client::{stream_error, ObjectHeaders, S3APIState, S3API};
+
+const TOTAL_MAX_SMALL_FILE_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+const DEFAULT_MAX_SMALL_FILE_SIZE: u64 = 100 * 1024 * 1024;
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+const MAX_PART_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+const DEFAULT_PART_SIZE: u64 = 16 * 1024 * 1024;
+const MAX_PARTS: usize = 10_000;
+const DEFAULT_UPLOAD_PART_LIMIT: usize = 4;
+const DEFAULT_REGION: &str = "us-east-1";
+const DEFAULT_REQUEST_LIMIT: usize = 20;
+
+type ClientPool = CloningPool<HyperClient<HttpsConnector<HttpConnector>>>;
+type Client = Acquired<
+    HyperClient<HttpsConnector<HttpConnector>>,
+    HyperClient<HttpsConnector<HttpConnector>>,
+    Infallible,
+>;
+
+/// The S3 implementation for [`Object`](../../enum.Object.html).
+#[derive(Clone, Debug)]
+pub struct S3Object {
+    path: ObjectPath,
+    object_type: ObjectType,
+    len: u64,
+    modified: Option<SystemTime>,
+    etag: Option<String>,
+    headers: ObjectHeaders,
+}
+
+impl ObjectInfo for S3Object {
+    fn path(&self) -> ObjectPath {
+        self.path.clone()
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn object_type(&self) -> ObjectType {
+        self.object_type
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    fn content_type(&self) -> Option<String> {
+        self.headers.content_type.clone()
+    }
+
+    fn cache_control(&self) -> Option<String> {
+        self.headers.cache_control.clone()
+    }
+
+    fn content_disposition(&self) -> Option<String> {
+        self.headers.content_disposition.clone()
+    }
+
+    fn metadata(&self) -> HashMap<String, String> {
+        self.headers.metadata.clone()
+    }
+
+    fn version(&self) -> Option<String> {
+        self.etag.clone()
+    }
+
+    fn checksum(&self) -> Option<Checksum> {
+        // The ETag is only the MD5 of the content for objects that were not
+        // uploaded in multiple parts. Multipart ETags contain a `-`.
+        let etag = self.etag.as_ref()?.trim_matches('"');
+        if etag.len() == 32 && etag.chars().all(|c| c.is_ascii_hexdigit()) {
+            Some(Checksum::Md5(etag.to_lowercase()))
+        } else {
+            None
+        }
+    }
+}
+
+/// Converts a bucket and key back into a path relative to the backend prefix.
+fn object_path(bucket: &str, key: &str, prefix: &ObjectPath) -> StorageResult<ObjectPath> {
+    let mut path = ObjectPath::new(key)?;
+    path.shift_part(bucket);
+    if path.is_dir_prefix() {
+        path.pop_part();
+    }
+
+    for _ in prefix.parts() {
+        path.unshift_part();
+    }
+
+    Ok(path)
+}
+
+fn file_object(bucket: &str, contents: Contents, prefix: &ObjectPath) -> StorageResult<Object> {
+    let path = object_path(bucket, &contents.key, prefix)?;
+
+    // Some tools create empty objects ending in `/` to represent directories.
+    let object = if contents.key.ends_with('/') {
+        S3Object {
+            path,
+            object_type: ObjectType::Directory,
+            len: 0,
+            modified: None,
+            etag: None,
+            headers: Default::default(),
+        }
+    } else {
+        S3Object {
+            path,
+            object_type: ObjectType::File,
+            len: contents.size,
+            modified: parse_iso8601(&contents.last_modified),
+            etag: contents.e_tag,
+            headers: Default::default(),
+        }
+    };
+
+    Ok(Object::from(object))
+}
+
+fn directory_object(bucket: &str, key: &str, prefix: &ObjectPath) -> StorageResult<Object> {
+    Ok(Object::from(S3Object {
+        path: object_path(bucket, key, prefix)?,
+        object_type: ObjectType::Directory,
+        len: 0,
+        modified: None,
+        etag: None,
+        headers: Default::default(),
+    }))
+}
+
+fn header_object(path: ObjectPath, headers: &HeaderMap) -> StorageResult<Object> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    let len = match header(header::CONTENT_LENGTH.as_str()).and_then(|l| l.parse::<u64>().ok()) {
+        Some(l) => l,
+        None => {
+            return Err(error::invalid_data(Some(
+                "HeadObject response had no valid Content-Length.",
+            )))
+        }
+    };
+
+    let modified = header(&format!("{}{}", S3_HEADER_META_PREFIX, LAST_MODIFIED_KEY))
+        .and_then(|s| s.parse::<u64>().ok())
+        .map(|t| UNIX_EPOCH + Duration::from_millis(t))
+        .or_else(|| header(header::LAST_MODIFIED.as_str()).and_then(parse_http_date));
+
+    let metadata = headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let name = name.as_str();
+            if !name.starts_with(S3_HEADER_META_PREFIX) {
+                return None;
+            }
+
+            let key = &name[S3_HEADER_META_PREFIX.len()..];
+            if key == LAST_MODIFIED_KEY {
+                return None;
+            }
+
+            value.to_str().ok().map(|v| (key.to_owned(), v.to_owned()))
+        })
+        .collect();
+
+    Ok(Object::from(S3Object {
+        path,
+        object_type: ObjectType::File,
+        len,
+        modified,
+        etag: header(header::ETAG.as_str()).map(String::from),
+        headers: ObjectHeaders {
+            content_type: header(header::CONTENT_TYPE.as_str()).map(String::from),
+            cache_control: header(header::CACHE_CONTROL.as_str()).map(String::from),
+            content_disposition: header(header::CONTENT_DISPOSITION.as_str()).map(String::from),
+            metadata,
+        },
+    }))
+}
+
+/// S3 reports all failed preconditions the same way, this converts the error
+/// for uploads that should only create new objects.
+fn precondition_error(info: &UploadInfo, error: StorageError) -> StorageError {
+    match (&info.precondition, error.kind()) {
+        (WritePrecondition::DoesNotExist, StorageErrorKind::PreconditionFailed(path)) => {
+            error::already_exists(path, Some(&error.to_string()))
+        }
+        _ => error,
+    }
+}
+
+fn upload_headers(info: &UploadInfo) -> ObjectHeaders {
+    let mut metadata = info.metadata.clone();
+    if let Some(time) = info.modified {
+        if let Ok(duration) = time.duration_since(UNIX_EPOCH) {
+            metadata.insert(
+                LAST_MODIFIED_KEY.to_owned(),
+                duration.as_millis().to_string(),
+            );
+        }
+    }
+
+    ObjectHeaders {
+        content_type: info.content_type.clone(),
+        cache_control: info.cache_control.clone(),
+        content_disposition: info.content_disposition.clone(),
+        metadata,
+    }
+}
+
+#[derive(Clone, Debug)]
+struct S3Settings {
+    access_key_id: String,
+    secret_key: String,
+    session_token: Option<String>,
+    region: String,
+    endpoint: Option<String>,
+    path_style: bool,
+    prefix: ObjectPath,
+    max_small_file_size: u64,
+    part_size: u64,
+    max_upload_parts: usize,
+    user_agent: String,
+}
+
+impl S3Settings {
+    fn endpoint(&self) -> String {
+        match self.endpoint {
+            Some(ref endpoint) => endpoint.clone(),
+            None => format!("https://s3.{}.amazonaws.com", self.region),
+        }
+    }
+}
+
+struct PartData {
+    data: Vec<Data>,
+    length: u64,
+    hash: String,
+}
+
+struct UploadTarget {
+    path: ObjectPath,
+    bucket: String,
+    key: String,
+}
+
+type PartResult = Result<(usize, String), (usize, StorageError)>;
+
+#[allow(clippy::too_many_arguments)]
+async fn part_upload(
+    client: S3API,
+    path: ObjectPath,
+    bucket: String,
+    key: String,
+    upload_id: String,
+    part: usize,
+    part_data: PartData,
+    mut sender: Sender<PartResult>,
+) {
+    trace!(
+        "Starting multipart upload part {} to {} with {} bytes in {} chunks.",
+        part,
+        path,
+        part_data.length,
+        part_data.data.len()
+    );
+
+    let result = client
+        .upload_part(
+            path.clone(),
+            bucket,
+            key,
+            upload_id,
+            part,
+            part_data.length,
+            part_data.hash,
+            part_data.data,
+        )
+        .await
+        .map(|etag| (part, etag))
+        .map_err(|e| (part, e));
+
+    // The upload gives up waiting for parts once any part has failed.
+    if let Err(e) = sender.send(result).await {
+        warn!(
+            "Unable to report part {} of multipart upload to {}: {}",
+            part, path, e
+        );
+    }
+}
+
+/// Reads up to `size` bytes from the stream for the next part of a multipart
+/// upload. Returns `None` once the stream has ended.
+async fn read_part<S>(
+    stream: &mut S,
+    remainder: &mut Option<Data>,
+    size: u64,
+) -> StorageResult<Option<PartData>>
+where
+    S: Stream<Item = StorageResult<Data>> + Unpin,
+{
+    let mut hasher = PayloadHasher::new();
+    let mut length: u64 = 0;
+    let mut data: Vec<Data> = Default::default();
+
+    while length < size {
+        let mut chunk = match remainder.take() {
+            Some(chunk) => chunk,
+            None => match stream.next().await {
+                Some(result) => result?,
+                None => break,
+            },
+        };
+
+        let wanted = (size - length) as usize;
+        if chunk.len() > wanted {
+            *remainder = Some(chunk.split_off(wanted));
+        }
+
+        length += chunk.len() as u64;
+        hasher.update(&chunk);
+        data.push(chunk);
+    }
+
+    if length == 0 {
+        Ok(None)
+    } else {
+        Ok(Some(PartData {
+            data,
+            length,
+            hash: hasher.hexdigest(),
+        }))
+    }
+}
+
+/// Picks the part size for a multipart upload. When the size of the file is
+/// known the part size is increased if needed to fit within S3's part limit.
+fn multipart_part_size(part_size: u64, first_part: u64, size: Option<u64>) -> u64 {
+    let remaining = match size {
+        Some(size) if size > first_part => size - first_part,
+        _ => return part_size,
+    };
+
+    let other_parts = (MAX_PARTS - 1) as u64;
+    let needed = (remaining + other_parts - 1) / other_parts;
+    part_size.max(needed).min(MAX_PART_SIZE)
+}
+
+/// Handles the result of a part upload, recording the first failure.
+fn check_part_result(
+    path: &ObjectPath,
+    result: Option<PartResult>,
+    parts: &mut Vec<CompletedPart>,
+    failure: &mut Option<TransferError>,
+) {
+    let error = match result {
+        Some(Ok((part_number, e_tag))) => {
+            parts.push(CompletedPart {
+                part_number: part_number as u64,
+                e_tag,
+            });
+            return;
+        }
+        Some(Err((part_number, e))) => {
+            error!(
+                "Part {} of multipart upload to {} failed: {}",
+                part_number, path, e
+            );
+            e
+        }
+        None => error::internal_error(Some("Part upload ended unexpectedly.")),
+    };
+
+    if failure.is_none() {
+        *failure = Some(TransferError::TargetError(error));
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn multipart_parts<S>(
+    client: S3API,
+    part_size: u64,
+    max_parts: usize,
+    target: &UploadTarget,
+    upload_id: &str,
+    first_part: PartData,
+    mut stream: Pin<Box<S>>,
+) -> Result<CompleteMultipartUpload, TransferError>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + 'static,
+{
+    let (sender, mut receiver) = channel::<PartResult>(0);
+    let mut remainder: Option<Data> = None;
+    let mut parts: Vec<CompletedPart> = Default::default();
+    let mut part_count: usize = 0;
+    let mut pending: usize = 0;
+    let mut tasks = PartTasks::default();
+
+    let mut failure: Option<TransferError> = None;
+    let mut next_part = Some(first_part);
+    loop {
+        // The part being read counts towards the limit so wait for an upload
+        // to complete before reading any more.
+        while pending >= max_parts && failure.is_none() {
+            let result = receiver.next().await;
+            pending -= 1;
+            check_part_result(&target.path, result, &mut parts, &mut failure);
+        }
+
+        if failure.is_some() {
+            break;
+        }
+
+        let part_data = match next_part.take() {
+            Some(p) => p,
+            None => match read_part(&mut stream, &mut remainder, part_size).await {
+                Ok(Some(p)) => p,
+                Ok(None) => break,
+                Err(e) => {
+                    failure = Some(TransferError::SourceError(e));
+                    break;
+                }
+            },
+        };
+
+        if part_count == MAX_PARTS {
+            failure = Some(TransferError::TargetError(error::invalid_settings(Some(
+                "Multipart uploads cannot have more than 10,000 parts, use a larger part size.",
+            ))));
+            break;
+        }
+
+        part_count += 1;
+        pending += 1;
+        tasks.spawn(part_upload(
+            client.clone(),
+            target.path.clone(),
+            target.bucket.clone(),
+            target.key.clone(),
+            upload_id.to_owned(),
+            part_count,
+            part_data,
+            sender.clone(),
+        ));
+    }
+
+    trace!(
+        "All parts ({}) started for multipart upload to {}, waiting for completion.",
+        part_count,
+        target.path
+    );
+    // Wait for running parts to finish so none complete after an abort.
+    while pending > 0 {
+        let result = receiver.next().await;
+        pending -= 1;
+        check_part_result(&target.path, result, &mut parts, &mut failure);
+    }
+
+    if let Some(e) = failure {
+        return Err(e);
+    }
+
+    parts.sort_by_key(|p| p.part_number);
+    Ok(CompleteMultipartUpload { parts })
+}
+
+async fn multipart_upload<S>(
+    client: S3API,
+    part_size: u64,
+    max_parts: usize,
+    info: UploadInfo,
+    target: UploadTarget,
+    first_part: PartData,
+    stream: Pin<Box<S>>,
+) -> Result<(), TransferError>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + 'static,
+{
+    trace!("Starting multipart upload to {}.", info.path);
+
+    let upload_id = client
+        .clone()
+        .create_multipart_upload(
+            target.path.clone(),
+            target.bucket.clone(),
+            target.key.clone(),
+            upload_headers(&info),
+        )
+        .await
+        .map_err(TransferError::TargetError)?
+        .upload_id;
+
+    let part_size = multipart_part_size(part_size, first_part.length, info.size);
+    let result = match multipart_parts(
+        client.clone(),
+        part_size,
+        max_parts,
+        &target,
+        &upload_id,
+        first_part,
+        stream,
+    )
+    .await
+    {
+        Ok(parts) => client
+            .clone()
+            .complete_multipart_upload(
+                target.path.clone(),
+                target.bucket.clone(),
+                target.key.clone(),
+                upload_id.clone(),
+                parts,
+                info.precondition.clone(),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|e| TransferError::TargetError(precondition_error(&info, e))),
+        Err(e) => Err(e),
+    };
+
+    if result.is_err() {
+        // Don't leave the parts that were uploaded taking up space.
+        if let Err(e) = client
+            .abort_multipart_upload(target.path, target.bucket, target.key, upload_id)
+            .await
+        {
+            warn!("Failed to abort multipart upload to {}: {}", info.path, e);
+        }
+    }
+
+    result
+}
+
+async fn perform_upload<S>(
+    client: S3API,
+    max_small_file_size: u64,
+    part_size: u64,
+    max_parts: usize,
+    info: UploadInfo,
+    target: UploadTarget,
+    stream: S,
+) -> Result<(), TransferError>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + 'static,
+{
+    trace!("Starting file upload to {}", info.path);
+
+    let mut hasher = PayloadHasher::new();
+    let mut length: u64 = 0;
+    let mut buffers: Vec<Data> = Default::default();
+    let mut stream = Box::pin(stream);
+
+    loop {
+        match stream.next().await {
+            Some(Ok(data)) => {
+                length += data.len() as u64;
+                hasher.update(&data);
+                buffers.push(data);
+
+                if length > max_small_file_size {
+                    return multipart_upload(
+                        client,
+                        part_size,
+                        max_parts,
+                        info,
+                        target,
+                        PartData {
+                            data: buffers,
+                            length,
+                            hash: hasher.hexdigest(),
+                        },
+                        stream,
+                    )
+                    .await;
+                }
+            }
+            Some(Err(e)) => return Err(TransferError::SourceError(e)),
+            None => {
+                trace!(
+                    "Starting regular file upload to {} with {} bytes in {} chunks.",
+                    info.path,
+                    length,
+                    buffers.len()
+                );
+
+                return client
+                    .put_object(
+                        target.path,
+                        target.bucket,
+                        target.key,
+                        upload_headers(&info),
+                        info.precondition.clone(),
+                        length,
+                        hasher.hexdigest(),
+                        buffers,
+                    )
+                    .await
+                    .map_err(|e| TransferError::TargetError(precondition_error(&info, e)));
+            }
+        }
+    }
+}
+
+/// A stream of objects from a single S3 bucket.
+struct ListStream {
+    client: S3API,
+    path: ObjectPath,
+    backend_prefix: ObjectPath,
+    bucket: String,
+    prefix: String,
+    delimiter: Option<String>,
+    continuation_token: Option<String>,
+    done: bool,
+    results: Vec<StorageResult<Object>>,
+    future: Option<Pin<Box<WrappedFuture<StorageResult<ListBucketResult>>>>>,
+}
+
+impl ListStream {
+    fn new(
+        client: S3API,
+        path: ObjectPath,
+        backend_prefix: ObjectPath,
+        bucket: String,
+        prefix: String,
+        delimiter: Option<String>,
+    ) -> ListStream {
+        ListStream {
+            client,
+            path,
+            backend_prefix,
+            bucket,
+            prefix,
+            delimiter,
+            continuation_token: None,
+            done: false,
+            results: Vec::new(),
+            future: None,
+        }
+    }
+
+    fn take_response(&mut self, response: ListBucketResult) {
+        if response.is_truncated && response.next_continuation_token.is_some() {
+            self.continuation_token = response.next_continuation_token;
+        } else {
+            self.done = true;
+        }
+
+        for prefix in response.common_prefixes {
+            self.results.push(directory_object(
+                &self.bucket,
+                &prefix.prefix,
+                &self.backend_prefix,
+            ));
+        }
+
+        for contents in response.contents {
+            // Don't include a directory marker for the directory being listed.
+            if self.delimiter.is_some() && contents.key == self.prefix {
+                continue;
+            }
+
+            self.results
+                .push(file_object(&self.bucket, contents, &self.backend_prefix));
+        }
+    }
+}
+
+impl Stream for ListStream {
+    type Item = StorageResult<Object>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> ResultStreamPoll<Object> {
+        loop {
+            if !self.results.is_empty() {
+                return Poll::Ready(Some(self.results.remove(0)));
+            } else if let Some(ref mut fut) = self.future {
+                match fut.as_mut().poll(cx) {
+                    Poll::Ready(Ok(response)) => {
+                        self.future = None;
+                        self.take_response(response);
+                    }
+                    Poll::Ready(Err(e)) => {
+                        self.future = None;
+                        self.done = true;
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                    Poll::Pending => return Poll::Pending,
+                }
+            } else if self.done {
+                return Poll::Ready(None);
+            } else {
+                let future = self.client.clone().list_objects(
+                    self.path.clone(),
+                    self.bucket.clone(),
+                    self.prefix.clone(),
+                    self.delimiter.clone(),
+                    self.continuation_token.clone(),
+                );
+                self.future = Some(Box::pin(
+                    WrappedFuture::<StorageResult<ListBucketResult>>::from_future(future),
+                ));
+            }
+        }
+    }
+}
+
+/// The backend implementation for S3 storage.
+#[derive(Debug, Clone)]
+pub struct S3Backend {
+    state: S3APIState,
+}
+
+impl S3Backend {
+    /// Creates a new [`FileStore`](../../enum.FileStore.html) instance using the
+    /// s3 backend in the default region.
+    ///
+    /// When constructed in this manner the root for all paths will be at the
+    /// account level.
+    pub fn connect(access_key_id: &str, secret_key: &str) -> ConnectFuture {
+        S3Backend::builder(access_key_id, secret_key).connect()
+    }
+
+    /// Creates a new [`S3BackendBuilder`](struct.S3BackendBuilder.html).
+    pub fn builder(access_key_id: &str, secret_key: &str) -> S3BackendBuilder {
+        S3BackendBuilder {
+            settings: S3Settings {
+                access_key_id: access_key_id.to_owned(),
+                secret_key: secret_key.to_owned(),
+                session_token: None,
+                region: DEFAULT_REGION.to_owned(),
+                endpoint: None,
+                path_style: false,
+                prefix: ObjectPath::empty(),
+                max_small_file_size: DEFAULT_MAX_SMALL_FILE_SIZE,
+                part_size: DEFAULT_PART_SIZE,
+                max_upload_parts: DEFAULT_UPLOAD_PART_LIMIT,
+                user_agent: format!(
+                    "{}/{} ({})",
+                    env!("CARGO_PKG_NAME"),
+                    env!("CARGO_PKG_VERSION"),
+                    env!("CARGO_PKG_REPOSITORY")
+                ),
+            },
+            max_requests: DEFAULT_REQUEST_LIMIT,
+        }
+    }
+
+    /// Creates a new [`S3API`](struct.S3API.html) that can be used for
+    /// making S3 API calls.
+    fn client(&self) -> S3API {
+        S3API::new(&self.state)
+    }
+
+    /// Downloads the object at the given path, optionally only a range of it.
+    fn download(&self, path: ObjectPath, range: Option<Range<u64>>) -> DataStreamFuture {
+        if path.is_dir_prefix() {
+            return DataStreamFuture::from_value(Err(error::invalid_path(
+                path,
+                Some("Object paths cannot be empty or end with a '/' character."),
+            )));
+        }
+
+        let (bucket, key) = match S3Backend::expand_path(&self.state.settings.prefix, &path) {
+            Ok(t) => t,
+            Err(e) => return DataStreamFuture::from_value(Err(e)),
+        };
+
+        let future = self
+            .client()
+            .get_object(path, bucket, key, range)
+            .map_ok(|body| {
+                DataStream::from_stream(body.map(|result| match result {
+                    Ok(chunk) => Result::<Data, StorageError>::Ok(chunk.into_bytes()),
+                    Err(e) => Result::<Data, StorageError>::Err(stream_error(e)),
+                }))
+            });
+
+        DataStreamFuture::from_future(future)
+    }
+
+    /// Splits a path into the bucket name and object key.
+    fn expand_path(prefix: &ObjectPath, path: &ObjectPath) -> StorageResult<(String, String)> {
+        let mut key = prefix.join(path);
+        let bucket = match key.unshift_part() {
+            Some(b) => b,
+            None => return Err(error::not_found(path.clone(), None)),
+        };
+
+        if key.is_empty() {
+            return Err(error::not_found(path.clone(), None));
+        }
+
+        Ok((bucket, key.to_string()))
+    }
+}
+
+#[derive(Debug, Clone)]
+/// Used to build a [`S3Backend`](struct.S3Backend.html) with some custom
+/// settings.
+pub struct S3BackendBuilder {
+    settings: S3Settings,
+    max_requests: usize,
+}
+
+impl S3BackendBuilder {
+    /// Sets the region to use. Defaults to `us-east-1`.
+    pub fn region(mut self, region: &str) -> S3BackendBuilder {
+        self.settings.region = region.to_owned();
+        self
+    }
+
+    /// Sets the endpoint to send requests to, e.g. `https://s3.example.com`.
+    ///
+    /// By default the AWS endpoint for the region is used. This allows using
+    /// other services that are compatible with S3.
+    pub fn endpoint(mut self, endpoint: &str) -> S3BackendBuilder {
+        self.settings.endpoint = Some(endpoint.trim_end_matches('/').to_owned());
+        self
+    }
+
+    /// Sets the session token used with temporary credentials.
+    pub fn session_token(mut self, token: &str) -> S3BackendBuilder {
+        self.settings.session_token = Some(token.to_owned());
+        self
+    }
+
+    /// Sets whether to include the bucket name in the path of requests rather
+    /// than as part of the host name.
+    ///
+    /// Many S3 compatible services only support path style requests.
+    pub fn path_style(mut self, path_style: bool) -> S3BackendBuilder {
+        self.settings.path_style = path_style;
+        self
+    }
+
+    /// Sets a path prefix for this storage.
+    ///
+    /// Essentially sets the 'root directory' for this storage, any paths
+    /// requested will be joined with this with a `/` character in between, so
+    /// this can be either the name of a bucket or a bucket followed by some
+    /// directory parts within that bucket.
+    pub fn prefix(mut self, prefix: ObjectPath) -> S3BackendBuilder {
+        self.settings.prefix = prefix;
+        self
+    }
+
+    /// Sets the cutoff between single request uploads and multipart uploads.
+    ///
+    /// Files up to this size are uploaded in a single request. Anything larger
+    /// is uploaded in multiple parts which are uploaded in parallel. This is
+    /// clamped between the minimum part size allowed by S3 (5MB) and the
+    /// largest single request upload allowed (5GB).
+    pub fn limit_small_file_size(mut self, size: u64) -> S3BackendBuilder {
+        self.settings.max_small_file_size = size.max(MIN_PART_SIZE).min(TOTAL_MAX_SMALL_FILE_SIZE);
+        self
+    }
+
+    /// Sets the size of the parts used for multipart uploads.
+    ///
+    /// This is clamped between the minimum (5MB) and maximum (5GB) part sizes
+    /// that S3 allows. S3 allows at most 10,000 parts so when the size of the
+    /// file being uploaded is known the part size is increased to fit. Uploads
+    /// of unknown size fail once they would need more parts than that.
+    pub fn part_size(mut self, size: u64) -> S3BackendBuilder {
+        self.settings.part_size = size.max(MIN_PART_SIZE).min(MAX_PART_SIZE);
+        self
+    }
+
+    /// Limits the number of parts of each multipart upload that can be held in
+    /// memory at once, whether waiting to upload or uploading.
+    ///
+    /// Reading from the source pauses until a part has finished uploading so a
+    /// multipart upload uses roughly this many times the part size in memory.
+    /// Defaults to 4.
+    pub fn limit_upload_parts(mut self, parts: usize) -> S3BackendBuilder {
+        self.settings.max_upload_parts = parts.max(1);
+        self
+    }
+
+    /// Limits the number of API requests that can be called in parallel.
+    ///
+    /// This also limits the number of parallel threads for downloads and
+    /// uploads.
+    pub fn limit_requests(mut self, requests: usize) -> S3BackendBuilder {
+        self.max_requests = requests;
+        self
+    }
+
+    /// Sets the User-Agent for all requests to S3.
+    pub fn user_agent(mut self, user_agent: &str) -> S3BackendBuilder {
+        self.settings.user_agent = user_agent.to_owned();
+        self
+    }
+
+    /// Creates a new S3 based [`FileStore`](../../enum.FileStore.html) using
+    /// this builder's settings.
+    ///
+    /// S3 has no session to start so this does not contact the server, bad
+    /// credentials will only be reported when the first request is made.
+    pub fn connect(self) -> ConnectFuture {
+        ConnectFuture::from_future(async {
+            trace!("Connecting to S3 with settings {:?}", self.settings);
+            let connector = match HttpsConnector::new() {
+                Ok(c) => c,
+                Err(e) => {
+                    return Err(error::connection_failed(Some(&format!(
+                        "Could not create http connection: {}.",
+                        e
+                    ))))
+                }
+            };
+
+            let client = HyperClient::builder().build(connector);
+            let clients = ClientPool::new(client, Some(self.max_requests));
+
+            let backend = S3Backend {
+                state: S3APIState {
+                    settings: self.settings,
+                    next_id: Default::default(),
+                    clients,
+                },
+            };
+
+            Ok(FileStore::from(backend))
+        })
+    }
+}
+
+async fn object_list(
+    client: S3API,
+    backend_prefix: ObjectPath,
+    prefix: ObjectPath,
+    delimiter: Option<String>,
+) -> StorageResult<ObjectStream> {
+    let mut key_prefix = backend_prefix.join(&prefix);
+
+    let buckets: Vec<String> = match key_prefix.unshift_part() {
+        Some(bucket) => vec![bucket],
+        None => {
+            let buckets = client
+                .clone()
+                .list_buckets(prefix.clone())
+                .await?
+                .buckets
+                .buckets;
+
+            if delimiter.is_some() {
+                // Listing the root directory just lists the buckets.
+                let objects = buckets
+                    .iter()
+                    .map(|b| directory_object(&b.name, "", &backend_prefix))
+                    .collect::<Vec<StorageResult<Object>>>();
+                return Ok(ObjectStream::from_stream(iter(objects)));
+            }
+
+            buckets.into_iter().map(|b| b.name).collect()
+        }
+    };
+
+    let listers = buckets
+        .into_iter()
+        .map(|bucket| {
+            ListStream::new(
+                client.clone(),
+                prefix.clone(),
+                backend_prefix.clone(),
+                bucket,
+                key_prefix.to_string(),
+                delimiter.clone(),
+            )
+        })
+        .fold(MergedStreams::new(), |mut m, s| {
+            m.push(s);
+            m
+        });
+
+    Ok(ObjectStream::from_stream(listers))
+}
+
+impl StorageBackend for S3Backend {
+    fn backend_type(&self) -> Backend {
+        Backend::S3
+    }
+
+    fn list_objects<P>(&self, prefix: P) -> ObjectStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let prefix = match prefix.try_into() {
+            Ok(p) => p,
+            Err(e) => return ObjectStreamFuture::from_value(Err(e.into())),
+        };
+
+        ObjectStreamFuture::from_future(object_list(
+            self.client(),
+            self.state.settings.prefix.clone(),
+            prefix,
+            None,
+        ))
+    }
+
+    fn list_directory<P>(&self, dir: P) -> ObjectStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let mut path = match dir.try_into() {
+            Ok(p) => p,
+            Err(e) => return ObjectStreamFuture::from_value(Err(e.into())),
+        };
+
+        if !path.is_empty() && !path.is_dir_prefix() {
+            path.push_part("");
+        }
+
+        ObjectStreamFuture::from_future(object_list(
+            self.client(),
+            self.state.settings.prefix.clone(),
+            path,
+            Some(String::from("/")),
+        ))
+    }
+
+    fn get_object<P>(&self, path: P) -> ObjectFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let path = match path.try_into() {
+            Ok(p) => p,
+            Err(e) => return ObjectFuture::from_value(Err(e.into())),
+        };
+
+        if path.is_dir_prefix() {
+            return ObjectFuture::from_value(Err(error::invalid_path(
+                path,
+                Some("Object paths cannot be empty or end with a '/' character."),
+            )));
+        }
+
+        let (bucket, key) = match S3Backend::expand_path(&self.state.settings.prefix, &path) {
+            Ok(t) => t,
+            Err(e) => return ObjectFuture::from_value(Err(e)),
+        };
+
+        let future = self
+            .client()
+            .head_object(path.clone(), bucket, key)
+            .and_then(move |headers| ready(header_object(path, &headers)));
+
+        ObjectFuture::from_future(future)
+    }
+
+    fn get_file_stream<P>(&self, path: P) -> DataStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        match path.try_into() {
+            Ok(p) => self.download(p, None),
+            Err(e) => DataStreamFuture::from_value(Err(e.into())),
+        }
+    }
+
+    fn get_file_range<P>(&self, path: P, range: Range<u64>) -> DataStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let path = match path.try_into() {
+            Ok(p) => p,
+            Err(e) => return DataStreamFuture::from_value(Err(e.into())),
+        };
+
+        // An empty range cannot be expressed in a Range header so just check
+        // that the object exists.
+        if range.start >= range.end {
+            return DataStreamFuture::from_future(
+                self.get_object(path)
+                    .map_ok(|_| DataStream::from_stream(empty::<StorageResult<Data>>())),
+            );
+        }
+
+        let length = range.end - range.start;
+        DataStreamFuture::from_future(
+            self.download(path, Some(range))
+                .map_ok(move |stream| DataStream::from_stream(RangeStream::new(stream, 0..length))),
+        )
+    }
+
+    fn delete_object<P>(&self, path: P) -> OperationCompleteFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        async fn delete(backend: S3Backend, path: ObjectPath) -> StorageResult<()> {
+            // S3 happily deletes objects that don't exist so check first.
+            backend.get_object(path.clone()).await?;
+
+            let (bucket, key) = S3Backend::expand_path(&backend.state.settings.prefix, &path)?;
+            backend.client().delete_object(path, bucket, key).await
+        }
+
+        let path = match path.try_into() {
+            Ok(p) => p,
+            Err(e) => return OperationCompleteFuture::from_value(Err(e.into())),
+        };
+        OperationCompleteFuture::from_future(delete(self.clone(), path))
+    }
+
+    fn write_file_from_stream<S, I, E, P>(&self, info: P, stream: S) -> WriteCompleteFuture
+    where
+        S: Stream<Item = Result<I, E>> + Send + 'static,
+        I: IntoBuf + 'static,
+        E: Into<StorageError> + 'static,
+        P: TryInto<UploadInfo>,
+        P::Error: Into<StorageError>,
+    {
+        let info = match info.try_into() {
+            Ok(i) => i,
+            Err(e) => {
+                return WriteCompleteFuture::from_value(Err(TransferError::TargetError(e.into())))
+            }
+        };
+
+        let path = info.path.clone();
+        if path.is_dir_prefix() {
+            return WriteCompleteFuture::from_value(Err(TransferError::TargetError(
+                error::invalid_path(
+                    path,
+                    Some("Object paths cannot be empty or end with a '/' character."),
+                ),
+            )));
+        }
+
+        let (bucket, key) = match S3Backend::expand_path(&self.state.settings.prefix, &path) {
+            Ok(t) => t,
+            Err(e) => return WriteCompleteFuture::from_value(Err(TransferError::TargetError(e))),
+        };
+
+        let stream = match into_upload_stream(&info, stream) {
+            Ok(s) => s,
+            Err(e) => return WriteCompleteFuture::from_value(Err(e)),
+        };
+
+        WriteCompleteFuture::from_future(perform_upload(
+            self.client(),
+            self.state.settings.max_small_file_size,
+            self.state.settings.part_size,
+            self.state.settings.max_upload_parts,
+            info,
+            UploadTarget { path, bucket, key },
+            stream,
+        ))
+    }
+}
@@ -0,0 +1,763 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The main S3 client API.
+//!
+//! Handles signing requests, mapping errors and retrying failed requests.
+use std::fmt;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use futures::stream::{iter, Stream, StreamExt};
+use http::header;
+use http::header::HeaderMap;
+use http::method::Method;
+use http::uri::Uri;
+use http::StatusCode;
+use hyper::body::Body;
+use hyper::Chunk;
+use hyper::{Request, Response};
+use log::{error, trace, warn};
+use serde::de::DeserializeOwned;
+use serde_xml_rs::from_str;
+
+use storage_types::s3::requests::*;
+use storage_types::s3::responses::*;
+use storage_types::s3::signing::{authorization, canonical_request, sha256_hex, signature};
+use storage_types::s3::{
+    canonical_query, format_amz_date, uri_encode_path, UserMetadata, S3_HEADER_CONTENT_SHA256,
+    S3_HEADER_DATE, S3_HEADER_META_PREFIX, S3_HEADER_SECURITY_TOKEN,
+};
+
+use super::{Client, ClientPool, S3Settings};
+use crate::types::stream::AfterStream;
+use crate::types::*;
+
+const MAX_API_RETRIES: usize = 5;
+/// The largest API response body that will be read into memory.
+const MAX_RESPONSE_SIZE: usize = 16 * 1024 * 1024;
+
+/// The headers to store with an object when it is created.
+#[derive(Clone, Debug, Default)]
+pub(super) struct ObjectHeaders {
+    pub content_type: Option<String>,
+    pub cache_control: Option<String>,
+    pub content_disposition: Option<String>,
+    pub metadata: UserMetadata,
+}
+
+#[derive(Debug)]
+struct S3Error {
+    error: StorageError,
+    can_retry: bool,
+}
+
+impl From<S3Error> for StorageError {
+    fn from(error: S3Error) -> StorageError {
+        error.error
+    }
+}
+
+type S3Result<T> = Result<T, S3Error>;
+
+fn hyper_error(hyper_error: hyper::Error) -> S3Error {
+    fn error(error: StorageError, can_retry: bool) -> S3Error {
+        S3Error { error, can_retry }
+    }
+
+    if hyper_error.is_parse() || hyper_error.is_user() {
+        error(error::invalid_data(Some(&hyper_error.to_string())), false)
+    } else if hyper_error.is_canceled() {
+        error(error::cancelled(Some(&hyper_error.to_string())), true)
+    } else if hyper_error.is_closed() || hyper_error.is_incomplete_message() {
+        error(
+            error::connection_closed(Some(&hyper_error.to_string())),
+            true,
+        )
+    } else if hyper_error.is_connect() {
+        error(
+            error::connection_failed(Some(&hyper_error.to_string())),
+            true,
+        )
+    } else {
+        error(error::invalid_data(Some(&hyper_error.to_string())), true)
+    }
+}
+
+fn http_error(error: http::Error) -> StorageError {
+    error::other_error(Some(&error.to_string()))
+}
+
+/// Converts an error from a body stream.
+pub(super) fn stream_error(error: hyper::Error) -> StorageError {
+    hyper_error(error).into()
+}
+
+fn generate_error(
+    method: &str,
+    client_id: usize,
+    path: &ObjectPath,
+    status: StatusCode,
+    response: &str,
+) -> S3Error {
+    fn error(error: StorageError) -> S3Error {
+        S3Error {
+            error,
+            can_retry: false,
+        }
+    }
+
+    fn retry(error: StorageError) -> S3Error {
+        S3Error {
+            error,
+            can_retry: true,
+        }
+    }
+
+    // Some responses (HEAD requests in particular) have no body.
+    let error_info: ErrorResponse = if response.is_empty() {
+        ErrorResponse {
+            code: String::new(),
+            message: status.to_string(),
+            resource: None,
+            request_id: None,
+        }
+    } else {
+        match from_str(response) {
+            Ok(r) => r,
+            Err(e) => {
+                error!(
+                    "Client {:04}: Unable to parse ErrorResponse structure from {}.",
+                    client_id, response
+                );
+                return error(error::invalid_data(Some(&format!(
+                    "Unable to parse error response from {}: {}.",
+                    method, e
+                ))));
+            }
+        }
+    };
+    warn!(
+        "Client {:04}: The API call {} failed with {} {:?}",
+        client_id, method, status, error_info
+    );
+
+    let message = Some(error_info.message.as_str());
+    match error_info.code.as_str() {
+        "NoSuchKey" | "NoSuchBucket" | "NoSuchUpload" => {
+            error(error::not_found(path.to_owned(), message))
+        }
+        "BucketAlreadyExists" | "BucketAlreadyOwnedByYou" => {
+            error(error::already_exists(path.to_owned(), message))
+        }
+        "PreconditionFailed" | "ConditionalRequestConflict" => {
+            error(error::precondition_failed(path.to_owned(), message))
+        }
+        "AccessDenied"
+        | "AccountProblem"
+        | "AllAccessDisabled"
+        | "InvalidAccessKeyId"
+        | "SignatureDoesNotMatch" => error(error::access_denied(message)),
+        "ExpiredToken" | "TokenRefreshRequired" => error(error::access_expired(message)),
+        "TooManyBuckets" | "QuotaExceeded" => error(error::over_quota(message)),
+        "RequestTimeout" => retry(error::connection_closed(message)),
+        "InternalError" | "ServiceUnavailable" | "SlowDown" => retry(error::service_error(message)),
+        "EntityTooSmall" | "EntityTooLarge" | "InvalidArgument" | "InvalidPart"
+        | "InvalidPartOrder" | "InvalidRequest" | "MalformedXML" => {
+            error(error::internal_error(message))
+        }
+        _ => {
+            if status == StatusCode::NOT_FOUND {
+                error(error::not_found(path.to_owned(), message))
+            } else if status == StatusCode::FORBIDDEN {
+                error(error::access_denied(message))
+            } else if status.is_server_error() {
+                retry(error::service_error(message))
+            } else {
+                error(error::other_error(message))
+            }
+        }
+    }
+}
+
+/// A request to S3 that has not yet been signed.
+#[derive(Debug, Clone)]
+struct S3Request {
+    method: Method,
+    bucket: Option<String>,
+    key: String,
+    query: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+    length: u64,
+    payload_hash: String,
+    data: Vec<Data>,
+}
+
+impl S3Request {
+    fn new(method: Method, bucket: Option<&str>, key: &str) -> S3Request {
+        S3Request {
+            method,
+            bucket: bucket.map(|b| b.to_owned()),
+            key: key.to_owned(),
+            query: Default::default(),
+            headers: Default::default(),
+            length: 0,
+            payload_hash: sha256_hex(b""),
+            data: Default::default(),
+        }
+    }
+
+    fn query(mut self, key: &str, value: &str) -> S3Request {
+        self.query.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    fn header(mut self, key: &str, value: &str) -> S3Request {
+        self.headers.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    fn object_headers(mut self, headers: &ObjectHeaders) -> S3Request {
+        if let Some(ref value) = headers.content_type {
+            self = self.header(header::CONTENT_TYPE.as_str(), value);
+        }
+        if let Some(ref value) = headers.cache_control {
+            self = self.header(header::CACHE_CONTROL.as_str(), value);
+        }
+        if let Some(ref value) = headers.content_disposition {
+            self = self.header(header::CONTENT_DISPOSITION.as_str(), value);
+        }
+        for (key, value) in headers.metadata.iter() {
+            self = self.header(&format!("{}{}", S3_HEADER_META_PREFIX, key), value);
+        }
+        self
+    }
+
+    fn precondition(self, precondition: &WritePrecondition) -> S3Request {
+        match precondition {
+            WritePrecondition::Always => self,
+            WritePrecondition::DoesNotExist => self.header(header::IF_NONE_MATCH.as_str(), "*"),
+            WritePrecondition::MatchesVersion(etag) => self.header(header::IF_MATCH.as_str(), etag),
+        }
+    }
+
+    fn body(mut self, data: Vec<Data>, length: u64, payload_hash: String) -> S3Request {
+        self.data = data;
+        self.length = length;
+        self.payload_hash = payload_hash;
+        self
+    }
+
+    /// Signs the request with the current time.
+    fn build(&self, settings: &S3Settings) -> StorageResult<Request<Body>> {
+        let endpoint: Uri = match settings.endpoint().parse() {
+            Ok(uri) => uri,
+            Err(e) => {
+                return Err(error::invalid_settings(Some(&format!(
+                    "Invalid endpoint: {}",
+                    e
+                ))))
+            }
+        };
+        let scheme = endpoint.scheme_str().unwrap_or("https");
+        let authority = match endpoint.authority_part() {
+            Some(a) => a.as_str(),
+            None => return Err(error::invalid_settings(Some("Endpoint has no host."))),
+        };
+
+        let (host, path) = match self.bucket {
+            Some(ref bucket) if settings.path_style => {
+                let mut path = format!("/{}", uri_encode_path(bucket));
+                if !self.key.is_empty() {
+                    path.push('/');
+                    path.push_str(&uri_encode_path(&self.key));
+                }
+                (authority.to_owned(), path)
+            }
+            Some(ref bucket) => (
+                format!("{}.{}", bucket, authority),
+                format!("/{}", uri_encode_path(&self.key)),
+            ),
+            None => (authority.to_owned(), String::from("/")),
+        };
+
+        let query = canonical_query(&self.query);
+        let amz_date = format_amz_date(SystemTime::now());
+
+        let mut headers = self.headers.clone();
+        headers.push((String::from("host"), host.clone()));
+        headers.push((S3_HEADER_DATE.to_owned(), amz_date.clone()));
+        headers.push((
+            S3_HEADER_CONTENT_SHA256.to_owned(),
+            self.payload_hash.clone(),
+        ));
+        if let Some(ref token) = settings.session_token {
+            headers.push((S3_HEADER_SECURITY_TOKEN.to_owned(), token.clone()));
+        }
+
+        let (canonical, signed_headers) = canonical_request(
+            self.method.as_str(),
+            &path,
+            &query,
+            &headers,
+            &self.payload_hash,
+        );
+        let signature = signature(
+            &settings.secret_key,
+            &amz_date,
+            &settings.region,
+            &canonical,
+        );
+
+        let uri = if query.is_empty() {
+            format!("{}://{}{}", scheme, host, path)
+        } else {
+            format!("{}://{}{}?{}", scheme, host, path, query)
+        };
+
+        let mut builder = Request::builder();
+        builder
+            .method(self.method.clone())
+            .uri(uri)
+            .header(
+                header::AUTHORIZATION,
+                authorization(
+                    &settings.access_key_id,
+                    &amz_date,
+                    &settings.region,
+                    &signed_headers,
+                    &signature,
+                ),
+            )
+            .header(header::USER_AGENT, &settings.user_agent);
+
+        for (key, value) in headers.iter() {
+            builder.header(key.as_str(), value.as_str());
+        }
+
+        if self.method == Method::PUT || self.method == Method::POST {
+            builder.header(header::CONTENT_LENGTH, self.length);
+        }
+
+        let body = if self.data.is_empty() {
+            Body::empty()
+        } else {
+            Body::wrap_stream(iter(self.data.clone()).map(Ok::<_, StorageError>))
+        };
+
+        builder.body(body).map_err(http_error)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct S3Client {}
+
+impl S3Client {
+    /// Collects a response body into a string, failing if it grows beyond
+    /// `MAX_RESPONSE_SIZE`.
+    async fn read_body(id: usize, method: &str, mut body: Body) -> S3Result<String> {
+        let mut data: Vec<u8> = Vec::new();
+        while let Some(result) = body.next().await {
+            let chunk = match result {
+                Ok(c) => c,
+                Err(e) => {
+                    error!(
+                        "Client {:04}: Failed reading {} response: {}",
+                        id, method, e
+                    );
+                    return Err(hyper_error(e));
+                }
+            };
+
+            if data.len() + chunk.len() > MAX_RESPONSE_SIZE {
+                error!("Client {:04}: {} response was too large", id, method);
+                return Err(S3Error {
+                    error: error::invalid_data(Some(&format!(
+                        "Response from {} exceeded {} bytes.",
+                        method, MAX_RESPONSE_SIZE
+                    ))),
+                    can_retry: false,
+                });
+            }
+
+            data.extend_from_slice(&chunk);
+        }
+
+        String::from_utf8(data).map_err(|e| {
+            error!(
+                "Client {:04}: {} response was not valid UTF-8: {}",
+                id, method, e
+            );
+            S3Error {
+                error: error::invalid_data(Some(&format!(
+                    "Response from {} was not valid UTF-8.",
+                    method
+                ))),
+                can_retry: true,
+            }
+        })
+    }
+
+    async fn request(
+        id: usize,
+        method: &str,
+        path: ObjectPath,
+        client: &Client,
+        request: Request<Body>,
+    ) -> S3Result<Response<Body>> {
+        trace!("Client {:04}: Requesting {}", id, request.uri());
+        let ranged = request.headers().contains_key(header::RANGE);
+        let response = match client.request(request).await {
+            Ok(r) => {
+                trace!("Client {:04}: {} s3 api call succeeded", id, method);
+                r
+            }
+            Err(e) => {
+                error!("Client {:04}: {} s3 api call failed: {}", id, method, e);
+                return Err(hyper_error(e));
+            }
+        };
+
+        // A range starting beyond the end of the object simply has no content.
+        if response.status().is_success()
+            || (ranged && response.status() == StatusCode::RANGE_NOT_SATISFIABLE)
+        {
+            Ok(response)
+        } else {
+            let (head, body) = response.into_parts();
+            let data = S3Client::read_body(id, method, body).await?;
+            Err(generate_error(method, id, &path, head.status, &data))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct S3APIState {
+    pub settings: S3Settings,
+    pub clients: ClientPool,
+    pub next_id: Arc<AtomicUsize>,
+}
+
+#[derive(Debug)]
+pub(super) struct S3API {
+    id: usize,
+    state: S3APIState,
+}
+
+impl Clone for S3API {
+    fn clone(&self) -> S3API {
+        S3API::new(&self.state)
+    }
+}
+
+impl S3API {
+    pub fn new(state: &S3APIState) -> S3API {
+        S3API {
+            id: state.next_id.fetch_add(1, Ordering::SeqCst),
+            state: state.clone(),
+        }
+    }
+
+    /// Sends a request, retrying when possible. On success the client used is
+    /// returned so the caller can keep it alive while reading the body.
+    async fn send(
+        &self,
+        method: &str,
+        path: ObjectPath,
+        request: S3Request,
+    ) -> StorageResult<(Response<Body>, Client)> {
+        let mut tries: usize = 0;
+        loop {
+            trace!(
+                "Client {:04}: Starting {} api call (attempt {})",
+                self.id,
+                method,
+                tries + 1,
+            );
+
+            let http_request = request.build(&self.state.settings)?;
+            let mut client = self.state.clients.acquire().await;
+
+            match S3Client::request(self.id, method, path.clone(), &client, http_request).await {
+                Ok(response) => return Ok((response, client)),
+                Err(e) => {
+                    client.release();
+                    tries += 1;
+
+                    if !e.can_retry || tries >= MAX_API_RETRIES {
+                        return Err(e.into());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends a request and returns the response body.
+    async fn text_call(
+        &self,
+        method: &str,
+        path: ObjectPath,
+        request: S3Request,
+    ) -> StorageResult<String> {
+        let (response, mut client) = self.send(method, path, request).await?;
+        let (_, body) = response.into_parts();
+        let data = S3Client::read_body(self.id, method, body).await?;
+
+        // Make sure that client stays alive until the request is complete.
+        client.release();
+
+        Ok(data)
+    }
+
+    /// Sends a request and parses the XML response body.
+    async fn xml_call<R>(
+        &self,
+        method: &str,
+        path: ObjectPath,
+        request: S3Request,
+    ) -> StorageResult<R>
+    where
+        R: DeserializeOwned + fmt::Debug,
+    {
+        let data = self.text_call(method, path, request).await?;
+
+        match from_str(&data) {
+            Ok(r) => {
+                trace!(
+                    "Client {:04}: {} api method returned {:?}",
+                    self.id,
+                    method,
+                    r
+                );
+                Ok(r)
+            }
+            Err(e) => {
+                error!("Client {:04}: {} api method failed: {}", self.id, method, e);
+                Err(error::invalid_data(Some(&format!(
+                    "Unable to parse response from {}: {}.",
+                    method, e
+                ))))
+            }
+        }
+    }
+
+    /// Sends a request and returns the response headers.
+    async fn header_call(
+        &self,
+        method: &str,
+        path: ObjectPath,
+        request: S3Request,
+    ) -> StorageResult<HeaderMap> {
+        let (response, mut client) = self.send(method, path, request).await?;
+        let (head, body) = response.into_parts();
+        S3Client::read_body(self.id, method, body).await?;
+        client.release();
+
+        Ok(head.headers)
+    }
+
+    pub async fn list_buckets(self, path: ObjectPath) -> StorageResult<ListAllMyBucketsResult> {
+        let request = S3Request::new(Method::GET, None, "");
+        self.xml_call("ListBuckets", path, request).await
+    }
+
+    pub async fn list_objects(
+        self,
+        path: ObjectPath,
+        bucket: String,
+        prefix: String,
+        delimiter: Option<String>,
+        continuation_token: Option<String>,
+    ) -> StorageResult<ListBucketResult> {
+        let mut request = S3Request::new(Method::GET, Some(&bucket), "")
+            .query("list-type", "2")
+            .query("prefix", &prefix);
+
+        if let Some(ref delimiter) = delimiter {
+            request = request.query("delimiter", delimiter);
+        }
+
+        if let Some(ref token) = continuation_token {
+            request = request.query("continuation-token", token);
+        }
+
+        self.xml_call("ListObjectsV2", path, request).await
+    }
+
+    pub async fn head_object(
+        self,
+        path: ObjectPath,
+        bucket: String,
+        key: String,
+    ) -> StorageResult<HeaderMap> {
+        let request = S3Request::new(Method::HEAD, Some(&bucket), &key);
+        self.header_call("HeadObject", path, request).await
+    }
+
+    pub async fn get_object(
+        self,
+        path: ObjectPath,
+        bucket: String,
+        key: String,
+        range: Option<Range<u64>>,
+    ) -> StorageResult<impl Stream<Item = Result<Chunk, hyper::Error>>> {
+        let mut request = S3Request::new(Method::GET, Some(&bucket), &key);
+        if let Some(range) = range {
+            request = request.header(
+                header::RANGE.as_str(),
+                &format!("bytes={}-{}", range.start, range.end - 1),
+            );
+        }
+        let (response, mut client) = self.send("GetObject", path, request).await?;
+
+        let (head, body) = response.into_parts();
+        let body = if head.status == StatusCode::RANGE_NOT_SATISFIABLE {
+            Body::empty()
+        } else {
+            body
+        };
+        Ok(AfterStream::after(body, move || client.release()))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn put_object(
+        self,
+        path: ObjectPath,
+        bucket: String,
+        key: String,
+        headers: ObjectHeaders,
+        precondition: WritePrecondition,
+        length: u64,
+        hash: String,
+        data: Vec<Data>,
+    ) -> StorageResult<()> {
+        let request = S3Request::new(Method::PUT, Some(&bucket), &key)
+            .object_headers(&headers)
+            .precondition(&precondition)
+            .body(data, length, hash);
+
+        self.header_call("PutObject", path, request).await?;
+        Ok(())
+    }
+
+    pub async fn create_multipart_upload(
+        self,
+        path: ObjectPath,
+        bucket: String,
+        key: String,
+        headers: ObjectHeaders,
+    ) -> StorageResult<InitiateMultipartUploadResult> {
+        let request = S3Request::new(Method::POST, Some(&bucket), &key)
+            .query("uploads", "")
+            .object_headers(&headers);
+
+        self.xml_call("CreateMultipartUpload", path, request).await
+    }
+
+    /// Uploads a part of a multipart upload, returning the part's ETag.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn upload_part(
+        self,
+        path: ObjectPath,
+        bucket: String,
+        key: String,
+        upload_id: String,
+        part: usize,
+        length: u64,
+        hash: String,
+        data: Vec<Data>,
+    ) -> StorageResult<String> {
+        let request = S3Request::new(Method::PUT, Some(&bucket), &key)
+            .query("partNumber", &part.to_string())
+            .query("uploadId", &upload_id)
+            .body(data, length, hash);
+
+        let headers = self.header_call("UploadPart", path, request).await?;
+        match headers.get(header::ETAG).and_then(|v| v.to_str().ok()) {
+            Some(etag) => Ok(etag.to_owned()),
+            None => Err(error::invalid_data(Some(
+                "UploadPart response contained no ETag.",
+            ))),
+        }
+    }
+
+    pub async fn complete_multipart_upload(
+        self,
+        path: ObjectPath,
+        bucket: String,
+        key: String,
+        upload_id: String,
+        parts: CompleteMultipartUpload,
+        precondition: WritePrecondition,
+    ) -> StorageResult<CompleteMultipartUploadResult> {
+        let method = "CompleteMultipartUpload";
+        let xml = parts.to_xml();
+        let length = xml.len() as u64;
+        let hash = sha256_hex(xml.as_bytes());
+        let request = S3Request::new(Method::POST, Some(&bucket), &key)
+            .query("uploadId", &upload_id)
+            .header(header::CONTENT_TYPE.as_str(), "application/xml")
+            .precondition(&precondition)
+            .body(vec![Data::from(xml)], length, hash);
+
+        let mut tries: usize = 0;
+        loop {
+            let data = self
+                .text_call(method, path.clone(), request.clone())
+                .await?;
+
+            // This can fail after the server has already sent a 200 response
+            // so the error appears in the body.
+            if let Ok(result) = from_str::<CompleteMultipartUploadResult>(&data) {
+                return Ok(result);
+            }
+
+            let e = generate_error(method, self.id, &path, StatusCode::OK, &data);
+            tries += 1;
+
+            if !e.can_retry || tries >= MAX_API_RETRIES {
+                return Err(e.into());
+            }
+        }
+    }
+
+    pub async fn abort_multipart_upload(
+        self,
+        path: ObjectPath,
+        bucket: String,
+        key: String,
+        upload_id: String,
+    ) -> StorageResult<()> {
+        let request =
+            S3Request::new(Method::DELETE, Some(&bucket), &key).query("uploadId", &upload_id);
+
+        self.header_call("AbortMultipartUpload", path, request)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_object(
+        self,
+        path: ObjectPath,
+        bucket: String,
+        key: String,
+    ) -> StorageResult<()> {
+        let request = S3Request::new(Method::DELETE, Some(&bucket), &key);
+
+        self.header_call("DeleteObject", path, request).await?;
+        Ok(())
+    }
+}
@@ -31,16 +31,23 @@
 //!
 //! The [`FileStore`](enum.FileStore.html) is the main way to access storage. A
 //! [`FileStore`](enum.FileStore.html) is created from one of the backends.
+//!
+//! The [`sync`](sync/index.html) module can be used to copy files between
+//! two `FileStore`s, for example to back up a local directory to a remote
+//! backend.
 #![warn(missing_docs)]
 
 #[macro_use]
 pub mod backends;
+pub mod sync;
 mod types;
 pub mod utils;
 
 pub use types::*;
 
 use std::convert::TryInto;
+use std::ops::Range;
+use std::sync::Arc;
 
 use bytes::IntoBuf;
 use enum_dispatch::enum_dispatch;
@@ -48,7 +55,18 @@ use futures::future::TryFutureExt;
 use futures::stream::Stream;
 
 use backends::b2::B2Backend;
+use backends::custom::DynStorageBackend;
 use backends::file::FileBackend;
+#[cfg(feature = "memory")]
+use backends::memory::MemoryBackend;
+#[cfg(feature = "s3")]
+use backends::s3::S3Backend;
+use types::stream::{ChecksumStream, ProgressStream, RangeStream};
+use utils::{copy_each, delete_each, delete_objects, get_each};
+
+/// How many operations to run at once for batches and deleting everything
+/// under a prefix, unless the backend sets its own limit.
+pub(crate) const DEFAULT_CONCURRENCY: usize = 10;
 
 /// The trait that every storage backend must implement at a minimum.
 #[enum_dispatch]
@@ -101,6 +119,94 @@ pub trait StorageBackend: Clone + Send + 'static {
         P: TryInto<ObjectPath>,
         P::Error: Into<StorageError>;
 
+    /// Gets a stream of data for the file at the given path, verifying it
+    /// against the file's stored checksum.
+    ///
+    /// If the object has a SHA1 [`checksum`](trait.ObjectInfo.html#tymethod.checksum)
+    /// and the data read does not match it the stream emits an
+    /// [`InvalidData`](enum.StorageErrorKind.html#variant.InvalidData) error
+    /// after the last of the data. Objects with no checksum or a checksum of
+    /// another type are returned unverified.
+    ///
+    /// The object's information and its data are retrieved separately so if the
+    /// file is replaced in between the two the stream will fail verification.
+    fn get_verified_file_stream<P>(&self, path: P) -> DataStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let path = match path.try_into() {
+            Ok(p) => p,
+            Err(e) => return DataStreamFuture::from_value(Err(e.into())),
+        };
+
+        let backend = self.clone();
+        DataStreamFuture::from_future(async move {
+            let object = backend.get_object(path.clone()).await?;
+            let expected = object
+                .checksum()
+                .and_then(|checksum| checksum.sha1().map(String::from));
+            let stream = backend.get_file_stream(path).await?;
+            Ok(DataStream::from_stream(ChecksumStream::new(
+                stream, expected, None,
+            )))
+        })
+    }
+
+    /// Gets a stream of data for the file at the given path, reporting the
+    /// download's progress to the observer.
+    ///
+    /// The object's size is looked up first so that the total is known.
+    fn get_file_stream_with_progress<P>(
+        &self,
+        path: P,
+        observer: TransferObserver,
+    ) -> DataStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let path = match path.try_into() {
+            Ok(p) => p,
+            Err(e) => return DataStreamFuture::from_value(Err(e.into())),
+        };
+
+        let backend = self.clone();
+        DataStreamFuture::from_future(async move {
+            let object = backend.get_object(path.clone()).await?;
+            let stream = backend.get_file_stream(path).await?;
+            Ok(DataStream::from_stream(ProgressStream::new(
+                stream,
+                Some(observer),
+                Some(object.len()),
+            )))
+        })
+    }
+
+    /// Gets a stream of data for a range of bytes in the file at the given path.
+    ///
+    /// The range is measured in bytes from the start of the file and does not
+    /// include its end. Any part of the range that extends beyond the end of
+    /// the file is ignored so a range starting at or after the end of the file
+    /// returns no data.
+    ///
+    /// The default implementation discards the data outside of the range from
+    /// a stream of the entire file. Backends that can read from an offset
+    /// directly should override it.
+    ///
+    /// This will return a [`NotFound`](enum.StorageErrorKind.html#variant.NotFound)
+    /// error if the object at the path does not exist or is not a file.
+    fn get_file_range<P>(&self, path: P, range: Range<u64>) -> DataStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        DataStreamFuture::from_future(
+            self.get_file_stream(path)
+                .map_ok(move |stream| DataStream::from_stream(RangeStream::new(stream, range))),
+        )
+    }
+
     /// Copies a file from one path to another within this `Backend`.
     ///
     /// Normally this will be an efficient operation but in some cases it will
@@ -108,7 +214,8 @@ pub trait StorageBackend: Clone + Send + 'static {
     /// location.
     ///
     /// Various properties of the file such as last modification time may not be
-    /// copied to the new file.
+    /// copied to the new file. The file's metadata is copied unless the target
+    /// sets some of its own.
     fn copy_file<P, I>(&self, source: P, target: I) -> CopyCompleteFuture
     where
         P: TryInto<ObjectPath>,
@@ -116,8 +223,32 @@ pub trait StorageBackend: Clone + Send + 'static {
         I: TryInto<UploadInfo>,
         I::Error: Into<StorageError>,
     {
-        let source = DataStream::from_stream(self.get_file_stream(source).try_flatten_stream());
-        self.write_file_from_stream(target, source)
+        let source = match source.try_into() {
+            Ok(p) => p,
+            Err(e) => {
+                return CopyCompleteFuture::from_value(Err(TransferError::SourceError(e.into())))
+            }
+        };
+
+        let mut info: UploadInfo = match target.try_into() {
+            Ok(i) => i,
+            Err(e) => {
+                return CopyCompleteFuture::from_value(Err(TransferError::TargetError(e.into())))
+            }
+        };
+
+        let backend = self.clone();
+        CopyCompleteFuture::from_future(async move {
+            let object = backend
+                .get_object(source.clone())
+                .await
+                .map_err(TransferError::SourceError)?;
+            info.inherit_metadata(&object);
+
+            let data =
+                DataStream::from_stream(backend.get_file_stream(source).try_flatten_stream());
+            backend.write_file_from_stream(info, data).await
+        })
     }
 
     /// Moves a file from one path to another within this `Backend`.
@@ -162,15 +293,110 @@ pub trait StorageBackend: Clone + Send + 'static {
         P: TryInto<ObjectPath>,
         P::Error: Into<StorageError>;
 
+    /// Deletes every object whose path starts with the given prefix.
+    ///
+    /// Objects are deleted concurrently and the stream returned yields the
+    /// result of deleting each one. A failure to delete one object does not
+    /// stop the others from being deleted. The future only fails if the
+    /// objects under the prefix cannot be listed.
+    ///
+    /// The default implementation lists the objects and deletes each with
+    /// [`delete_object`](#tymethod.delete_object), up to ten at a time.
+    /// Directories are deleted after everything else, deepest first.
+    fn delete_prefix<P>(&self, prefix: P) -> DeleteStreamFuture
+    where
+        P: TryInto<ObjectPath>,
+        P::Error: Into<StorageError>,
+    {
+        let prefix = match prefix.try_into() {
+            Ok(p) => p,
+            Err(e) => return DeleteStreamFuture::from_value(Err(e.into())),
+        };
+
+        let backend = self.clone();
+        DeleteStreamFuture::from_future(async move {
+            let objects = backend.list_objects(prefix.clone()).await?;
+            Ok(DeleteStream::from_stream(delete_objects(
+                objects,
+                prefix,
+                DEFAULT_CONCURRENCY,
+                move |object| backend.delete_object(object.path()),
+            )))
+        })
+    }
+
+    /// Gets info about the objects at each of the given paths.
+    ///
+    /// Lookups run concurrently, up to a limit set by the backend, and the
+    /// stream returned yields the result for each path as it completes so the
+    /// results may be in a different order to the paths.
+    ///
+    /// The default implementation calls [`get_object`](#tymethod.get_object)
+    /// for each path, up to ten at a time.
+    fn get_objects<I>(&self, paths: I) -> GetStream
+    where
+        I: IntoIterator<Item = ObjectPath>,
+    {
+        get_each(
+            self.clone(),
+            paths.into_iter().collect(),
+            DEFAULT_CONCURRENCY,
+        )
+    }
+
+    /// Deletes the objects at each of the given paths.
+    ///
+    /// Deletes run concurrently, up to a limit set by the backend, and the
+    /// stream returned yields the result for each path as it completes. A
+    /// failure to delete one object does not stop the others from being
+    /// deleted.
+    ///
+    /// The default implementation calls [`delete_object`](#tymethod.delete_object)
+    /// for each path, up to ten at a time.
+    fn delete_objects<I>(&self, paths: I) -> DeleteStream
+    where
+        I: IntoIterator<Item = ObjectPath>,
+    {
+        delete_each(
+            self.clone(),
+            paths.into_iter().collect(),
+            DEFAULT_CONCURRENCY,
+        )
+    }
+
+    /// Copies each of the given source paths to its target path.
+    ///
+    /// Copies run concurrently, up to a limit set by the backend, and the
+    /// stream returned yields the result for each pair of paths as it
+    /// completes. A failure to copy one file does not stop the others from
+    /// being copied.
+    ///
+    /// The default implementation calls [`copy_file`](#method.copy_file) for
+    /// each pair of paths, up to ten at a time.
+    fn copy_files<I>(&self, paths: I) -> CopyStream
+    where
+        I: IntoIterator<Item = (ObjectPath, ObjectPath)>,
+    {
+        copy_each(
+            self.clone(),
+            paths.into_iter().collect(),
+            DEFAULT_CONCURRENCY,
+        )
+    }
+
     /// Writes a stream of data to the file at the given path.
     ///
-    /// Calling this will overwrite anything at the given path (notably on
-    /// backends that support symlinks or directories those will be deleted
-    /// along with their contents and replaced with a file). The rationale for
-    /// this is that for network based backends not overwriting generally
-    /// involves more API calls to check if something is there first. If you
-    /// care about overwriting, call [`get_object`](trait.StorageBackend.html#method.get_file)
-    /// first and check the result.
+    /// Unless the upload's [`precondition`](struct.UploadInfo.html#structfield.precondition)
+    /// says otherwise calling this will overwrite anything at the given path
+    /// (notably on backends that support symlinks or directories those will be
+    /// deleted along with their contents and replaced with a file). The
+    /// rationale for this is that for network based backends not overwriting
+    /// generally involves more API calls to check if something is there first.
+    ///
+    /// A [`WritePrecondition`](enum.WritePrecondition.html) can be used to only
+    /// create new files or to only replace a file that still matches a known
+    /// version. How strongly this is enforced against concurrent writers
+    /// depends on the backend.
     ///
     /// If this operation fails there are no guarantees about the state of the
     /// file. If that is an issue then you should consider always calling
@@ -183,6 +409,16 @@ pub trait StorageBackend: Clone + Send + 'static {
     /// written.
     ///
     /// Any error emitted by the stream will cause this operation to fail.
+    ///
+    /// Dropping the future before it resolves cancels the write, stopping any
+    /// transfers in progress and cleaning up partially written data where the
+    /// backend can.
+    ///
+    /// If the upload has an expected [`checksum`](struct.UploadInfo.html#structfield.checksum)
+    /// then the data is verified before the file is committed to storage.
+    ///
+    /// If the upload has a [`progress`](struct.UploadInfo.html#structfield.progress)
+    /// observer it receives events as the data is written.
     fn write_file_from_stream<S, I, E, P>(&self, info: P, stream: S) -> WriteCompleteFuture
     where
         S: Stream<Item = Result<I, E>> + Send + 'static,
@@ -200,7 +436,9 @@ pub trait StorageBackend: Clone + Send + 'static {
 /// Avoid using the backends directly if you want to keep your code compatible
 /// with all backends.
 ///
-/// You create a `FileStore` from one of the [backend implementations](backends/index.html).
+/// You create a `FileStore` from one of the [backend implementations](backends/index.html)
+/// or from a [`DynStorageBackend`](backends/custom/trait.DynStorageBackend.html)
+/// for backends implemented outside of this crate.
 #[allow(clippy::large_enum_variant, missing_docs)]
 #[derive(Clone, Debug)]
 pub enum FileStore {
@@ -210,4 +448,12 @@ pub enum FileStore {
     #[doc(hidden)]
     #[cfg(feature = "b2")]
     B2(B2Backend),
+    #[doc(hidden)]
+    #[cfg(feature = "memory")]
+    Memory(MemoryBackend),
+    #[doc(hidden)]
+    #[cfg(feature = "s3")]
+    S3(S3Backend),
+    /// A backend implemented outside of this crate.
+    Custom(Arc<dyn DynStorageBackend>),
 }
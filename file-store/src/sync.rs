@@ -0,0 +1,392 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Synchronizes files from one [`FileStore`](../enum.FileStore.html) to
+//! another.
+//!
+//! A [`SyncBuilder`](struct.SyncBuilder.html) lists all of the files beneath a
+//! prefix in the source and beneath a prefix in the target and matches them up
+//! by their path relative to the prefix. Files missing from the target are
+//! uploaded and files that differ are replaced. Files only in the target can
+//! optionally be deleted.
+//!
+//! A file is considered to differ if its size differs or if the source was
+//! modified more recently than the target (compared to the millisecond as not
+//! all backends store times more precisely). Files with no known modification
+//! time are only compared by size unless
+//! [`compare_contents`](struct.SyncBuilder.html#method.compare_contents) is
+//! enabled.
+//!
+//! Only files are synchronized, directories in either store are ignored.
+use std::cmp::min;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::future::FutureExt;
+use futures::stream::{iter, StreamExt};
+
+use crate::types::*;
+use crate::{FileStore, StorageBackend};
+
+const DEFAULT_CONCURRENCY: usize = 5;
+
+/// A future that resolves to a [`SyncReport`](struct.SyncReport.html) once
+/// the synchronization is complete.
+pub type SyncFuture = WrappedFuture<StorageResult<SyncReport>>;
+
+/// An action taken, or in a dry run that would have been taken, to bring the
+/// target in line with the source.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SyncAction {
+    /// A file missing from the target was uploaded.
+    Create {
+        /// The path of the file in the source.
+        source: ObjectPath,
+        /// The path of the file in the target.
+        target: ObjectPath,
+    },
+    /// A file that differed in the target was replaced.
+    Update {
+        /// The path of the file in the source.
+        source: ObjectPath,
+        /// The path of the file in the target.
+        target: ObjectPath,
+    },
+    /// A file that does not exist in the source was deleted from the target.
+    Delete {
+        /// The path of the file in the target.
+        target: ObjectPath,
+    },
+}
+
+/// The results of a synchronization.
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// The actions that were successfully taken. For a dry run these are the
+    /// actions that would have been taken.
+    pub actions: Vec<SyncAction>,
+    /// The actions that failed along with the reason for the failure.
+    pub failures: Vec<(SyncAction, TransferError)>,
+    /// The number of files that were already the same in the target.
+    pub unchanged: usize,
+}
+
+impl SyncReport {
+    /// Returns whether every action succeeded.
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+enum Outcome {
+    Unchanged,
+    Done(SyncAction),
+    Failed(SyncAction, TransferError),
+}
+
+/// Used to configure and then run a synchronization between two stores.
+#[derive(Clone, Debug)]
+pub struct SyncBuilder {
+    source: FileStore,
+    source_prefix: ObjectPath,
+    target: FileStore,
+    target_prefix: ObjectPath,
+    dry_run: bool,
+    delete_extra: bool,
+    compare_contents: bool,
+    concurrency: usize,
+}
+
+impl SyncBuilder {
+    /// Creates a new builder that will synchronize the files beneath
+    /// `source_prefix` in the source store to beneath `target_prefix` in the
+    /// target store.
+    ///
+    /// Prefixes are treated as directories so `backups` and `backups/` are
+    /// the same. An empty prefix covers the entire store.
+    pub fn new(
+        source: &FileStore,
+        source_prefix: ObjectPath,
+        target: &FileStore,
+        target_prefix: ObjectPath,
+    ) -> SyncBuilder {
+        SyncBuilder {
+            source: source.clone(),
+            source_prefix: as_directory(source_prefix),
+            target: target.clone(),
+            target_prefix: as_directory(target_prefix),
+            dry_run: false,
+            delete_extra: false,
+            compare_contents: false,
+            concurrency: DEFAULT_CONCURRENCY,
+        }
+    }
+
+    /// Sets whether to only report the actions that would be taken without
+    /// changing the target.
+    pub fn dry_run(mut self, dry_run: bool) -> SyncBuilder {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Sets whether files in the target that do not exist in the source should
+    /// be deleted.
+    pub fn delete_extra(mut self, delete_extra: bool) -> SyncBuilder {
+        self.delete_extra = delete_extra;
+        self
+    }
+
+    /// Sets whether to compare the contents of files that appear to be the
+    /// same.
+    ///
+    /// When both stores report a SHA1 checksum for a file those are compared.
+    /// Otherwise this requires downloading the file from both stores so is slow
+    /// but will catch changes that the size and modification time miss.
+    pub fn compare_contents(mut self, compare_contents: bool) -> SyncBuilder {
+        self.compare_contents = compare_contents;
+        self
+    }
+
+    /// Limits the number of files that are compared, uploaded or deleted in
+    /// parallel.
+    pub fn limit_concurrency(mut self, concurrency: usize) -> SyncBuilder {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Performs the synchronization.
+    ///
+    /// Failing to list either store fails the whole synchronization. Failures
+    /// for individual files are included in the report.
+    pub fn run(self) -> SyncFuture {
+        SyncFuture::from_future(synchronize(self))
+    }
+}
+
+/// Makes a non-empty prefix end with a `/` character.
+fn as_directory(mut prefix: ObjectPath) -> ObjectPath {
+    if !prefix.is_dir_prefix() {
+        prefix.push_part("");
+    }
+    prefix
+}
+
+/// Lists the files beneath a prefix keyed by their path relative to it.
+async fn list_files(fs: FileStore, prefix: ObjectPath) -> StorageResult<HashMap<String, Object>> {
+    let prefix_len = prefix.to_string().len();
+    let mut stream = fs.list_objects(prefix).await?;
+
+    let mut files = HashMap::new();
+    while let Some(result) = stream.next().await {
+        let object = result?;
+        if object.object_type() == ObjectType::File {
+            let relative = object.path().to_string()[prefix_len..].to_owned();
+            files.insert(relative, object);
+        }
+    }
+
+    Ok(files)
+}
+
+fn as_millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0)
+}
+
+/// Checks whether the target file is out of date compared to the source.
+fn needs_update(source: &Object, target: &Object) -> bool {
+    if source.len() != target.len() {
+        return true;
+    }
+
+    match (source.modified(), target.modified()) {
+        (Some(source), Some(target)) => as_millis(source) > as_millis(target),
+        _ => false,
+    }
+}
+
+/// Compares the SHA1 checksums of both files if both stores report them.
+fn checksums_match(source: &Object, target: &Object) -> Option<bool> {
+    let source = source.checksum()?;
+    let target = target.checksum()?;
+    Some(source.sha1()?.eq_ignore_ascii_case(target.sha1()?))
+}
+
+/// Streams both files comparing their contents.
+async fn contents_match(
+    source: FileStore,
+    source_path: ObjectPath,
+    target: FileStore,
+    target_path: ObjectPath,
+) -> Result<bool, TransferError> {
+    let mut source_stream = source
+        .get_file_stream(source_path)
+        .await
+        .map_err(TransferError::SourceError)?;
+    let mut target_stream = target
+        .get_file_stream(target_path)
+        .await
+        .map_err(TransferError::TargetError)?;
+
+    let mut source_data = Data::new();
+    let mut target_data = Data::new();
+    loop {
+        while source_data.is_empty() {
+            match source_stream.next().await {
+                Some(result) => source_data = result.map_err(TransferError::SourceError)?,
+                None => break,
+            }
+        }
+
+        while target_data.is_empty() {
+            match target_stream.next().await {
+                Some(result) => target_data = result.map_err(TransferError::TargetError)?,
+                None => break,
+            }
+        }
+
+        if source_data.is_empty() || target_data.is_empty() {
+            return Ok(source_data.is_empty() && target_data.is_empty());
+        }
+
+        let count = min(source_data.len(), target_data.len());
+        if source_data[..count] != target_data[..count] {
+            return Ok(false);
+        }
+
+        source_data.advance(count);
+        target_data.advance(count);
+    }
+}
+
+async fn sync_file(
+    settings: SyncBuilder,
+    relative: String,
+    source: Object,
+    target: Option<Object>,
+) -> Outcome {
+    let target_path = match ObjectPath::new(format!("{}{}", settings.target_prefix, relative)) {
+        Ok(p) => p,
+        Err(e) => {
+            let action = SyncAction::Create {
+                source: source.path(),
+                target: settings.target_prefix.clone(),
+            };
+            return Outcome::Failed(action, TransferError::TargetError(e));
+        }
+    };
+
+    let action = if target.is_some() {
+        SyncAction::Update {
+            source: source.path(),
+            target: target_path.clone(),
+        }
+    } else {
+        SyncAction::Create {
+            source: source.path(),
+            target: target_path.clone(),
+        }
+    };
+
+    if let Some(existing) = target {
+        if !needs_update(&source, &existing) {
+            if !settings.compare_contents {
+                return Outcome::Unchanged;
+            }
+
+            let matched = match checksums_match(&source, &existing) {
+                Some(matched) => Ok(matched),
+                None => {
+                    contents_match(
+                        settings.source.clone(),
+                        source.path(),
+                        settings.target.clone(),
+                        target_path.clone(),
+                    )
+                    .await
+                }
+            };
+
+            match matched {
+                Ok(true) => return Outcome::Unchanged,
+                Ok(false) => (),
+                Err(e) => return Outcome::Failed(action, e),
+            }
+        }
+    }
+
+    if settings.dry_run {
+        return Outcome::Done(action);
+    }
+
+    let mut info = UploadInfo::from(source.clone());
+    info.path = target_path;
+
+    let data = match settings.source.get_file_stream(source.path()).await {
+        Ok(d) => d,
+        Err(e) => return Outcome::Failed(action, TransferError::SourceError(e)),
+    };
+
+    match settings.target.write_file_from_stream(info, data).await {
+        Ok(()) => Outcome::Done(action),
+        Err(e) => Outcome::Failed(action, e),
+    }
+}
+
+async fn delete_file(settings: SyncBuilder, target: Object) -> Outcome {
+    let action = SyncAction::Delete {
+        target: target.path(),
+    };
+
+    if settings.dry_run {
+        return Outcome::Done(action);
+    }
+
+    match settings.target.delete_object(target.path()).await {
+        Ok(()) => Outcome::Done(action),
+        Err(e) => Outcome::Failed(action, TransferError::TargetError(e)),
+    }
+}
+
+async fn synchronize(settings: SyncBuilder) -> StorageResult<SyncReport> {
+    let source_files = list_files(settings.source.clone(), settings.source_prefix.clone()).await?;
+    let mut target_files =
+        list_files(settings.target.clone(), settings.target_prefix.clone()).await?;
+
+    let mut work = Vec::new();
+    for (relative, source) in source_files {
+        let target = target_files.remove(&relative);
+        work.push(sync_file(settings.clone(), relative, source, target).left_future());
+    }
+
+    if settings.delete_extra {
+        for (_, target) in target_files {
+            work.push(delete_file(settings.clone(), target).right_future());
+        }
+    }
+
+    let mut outcomes = iter(work).buffer_unordered(settings.concurrency);
+
+    let mut report = SyncReport::default();
+    while let Some(outcome) = outcomes.next().await {
+        match outcome {
+            Outcome::Unchanged => report.unchanged += 1,
+            Outcome::Done(action) => report.actions.push(action),
+            Outcome::Failed(action, error) => report.failures.push((action, error)),
+        }
+    }
+
+    Ok(report)
+}
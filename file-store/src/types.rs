@@ -17,20 +17,17 @@ pub(crate) mod error;
 pub(crate) mod future;
 pub(crate) mod objects;
 pub(crate) mod path;
+pub(crate) mod progress;
 pub(crate) mod stream;
 
-use std::io;
-
-use bytes::buf::Buf;
-use bytes::{Bytes, IntoBuf};
-use futures::executor::{block_on_stream, BlockingStream};
-use futures::stream::Stream;
+use bytes::Bytes;
 
 use super::FileStore;
 pub use error::{StorageError, StorageErrorKind, StorageResult, TransferError};
 pub use future::WrappedFuture;
-pub use objects::{Object, ObjectInfo, ObjectType, UploadInfo};
+pub use objects::{Checksum, Object, ObjectInfo, ObjectType, UploadInfo, WritePrecondition};
 pub use path::ObjectPath;
+pub use progress::{TransferEvent, TransferObserver};
 pub use stream::WrappedStream;
 
 /// The data type used for streaming data from and to files.
@@ -56,47 +53,23 @@ pub type DataStreamFuture = WrappedFuture<StorageResult<DataStream>>;
 pub type CopyCompleteFuture = WrappedFuture<Result<(), TransferError>>;
 /// A future that resolves when the move is complete.
 pub type MoveCompleteFuture = WrappedFuture<Result<(), TransferError>>;
-
-pub(crate) struct BlockingStreamReader<S>
-where
-    S: Unpin + Stream,
-{
-    stream: BlockingStream<S>,
-    reader: Option<Box<dyn io::Read>>,
-}
-
-impl<S> BlockingStreamReader<S>
-where
-    S: Unpin + Stream,
-{
-    pub fn from_stream(stream: S) -> BlockingStreamReader<S> {
-        BlockingStreamReader {
-            stream: block_on_stream(stream),
-            reader: None,
-        }
-    }
-}
-
-impl<S, O, E> io::Read for BlockingStreamReader<S>
-where
-    S: Unpin + Stream<Item = Result<O, E>>,
-    O: IntoBuf,
-    <O as IntoBuf>::Buf: 'static,
-    E: Into<StorageError>,
-{
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match self.reader {
-            Some(ref mut r) => r.read(buf),
-            None => match self.stream.next() {
-                Some(Ok(d)) => {
-                    let mut reader = d.into_buf().reader();
-                    let count = reader.read(buf)?;
-                    self.reader = Some(Box::new(reader));
-                    Ok(count)
-                }
-                Some(Err(e)) => Err(e.into().into()),
-                None => Ok(0),
-            },
-        }
-    }
-}
+/// The result of deleting a single object as part of a larger delete. A
+/// failure includes the path of the object that could not be deleted.
+pub type DeleteResult = Result<ObjectPath, (ObjectPath, StorageError)>;
+/// A stream that returns a [`DeleteResult`](type.DeleteResult.html) for each
+/// object deleted.
+pub type DeleteStream = WrappedStream<DeleteResult>;
+/// A future that resolves to a [`DeleteStream`](type.DeleteStream.html).
+pub type DeleteStreamFuture = WrappedFuture<StorageResult<DeleteStream>>;
+/// The result of getting a single object as part of a batch. A failure
+/// includes the path that was looked up.
+pub type GetResult = Result<Object, (ObjectPath, StorageError)>;
+/// A stream that returns a [`GetResult`](type.GetResult.html) for each path in
+/// a batch.
+pub type GetStream = WrappedStream<GetResult>;
+/// The result of copying a single file as part of a batch, identified by its
+/// source and target paths.
+pub type CopyResult = Result<(ObjectPath, ObjectPath), (ObjectPath, ObjectPath, TransferError)>;
+/// A stream that returns a [`CopyResult`](type.CopyResult.html) for each copy
+/// in a batch.
+pub type CopyStream = WrappedStream<CopyResult>;
@@ -32,8 +32,12 @@ pub enum StorageErrorKind {
     NotFound(ObjectPath),
     /// The object already exists.
     AlreadyExists(ObjectPath),
+    /// The object did not match the version expected by a write.
+    PreconditionFailed(ObjectPath),
     /// The operation was cancelled.
     Cancelled,
+    /// The operation did not complete in the time allowed.
+    TimedOut,
     /// The connection to storage failed.
     ConnectionFailed,
     /// The connection to storage was closed.
@@ -59,7 +63,7 @@ pub enum StorageErrorKind {
 
 /// Errors hit while interacting with storage backends. Generally wrapped by an
 /// `io::Error`. Can be reached with `TryFrom`.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct StorageError {
     kind: StorageErrorKind,
     detail: Option<String>,
@@ -118,8 +122,13 @@ impl fmt::Display for StorageError {
             StorageErrorKind::AlreadyExists(p) => {
                 self.default_write(f, format!("The path '{}' already exists", p))
             }
+            StorageErrorKind::PreconditionFailed(p) => self.default_write(
+                f,
+                format!("The path '{}' did not match the expected version", p),
+            ),
             StorageErrorKind::InvalidData => self.default_write(f, "Invalid data"),
             StorageErrorKind::Cancelled => self.default_write(f, "The operation was cancelled"),
+            StorageErrorKind::TimedOut => self.default_write(f, "The operation timed out"),
             StorageErrorKind::ConnectionFailed => {
                 self.default_write(f, "The storage connection failed")
             }
@@ -150,9 +159,11 @@ impl From<StorageError> for io::Error {
             StorageErrorKind::InvalidPath(_) => io::ErrorKind::InvalidData,
             StorageErrorKind::NotFound(_) => io::ErrorKind::NotFound,
             StorageErrorKind::AlreadyExists(_) => io::ErrorKind::AlreadyExists,
+            StorageErrorKind::PreconditionFailed(_) => io::ErrorKind::Other,
             StorageErrorKind::InvalidData => io::ErrorKind::InvalidData,
             StorageErrorKind::InvalidSettings => io::ErrorKind::InvalidInput,
             StorageErrorKind::Cancelled => io::ErrorKind::ConnectionAborted,
+            StorageErrorKind::TimedOut => io::ErrorKind::TimedOut,
             StorageErrorKind::ConnectionFailed => io::ErrorKind::ConnectionRefused,
             StorageErrorKind::ConnectionClosed => io::ErrorKind::NotConnected,
             StorageErrorKind::InternalError => io::ErrorKind::Other,
@@ -180,6 +191,7 @@ impl From<io::Error> for StorageError {
             io::ErrorKind::BrokenPipe => StorageErrorKind::ConnectionClosed,
             io::ErrorKind::InvalidInput => StorageErrorKind::InvalidData,
             io::ErrorKind::InvalidData => StorageErrorKind::InvalidData,
+            io::ErrorKind::TimedOut => StorageErrorKind::TimedOut,
             _ => StorageErrorKind::Other,
         };
 
@@ -224,6 +236,10 @@ pub fn already_exists(path: ObjectPath, detail: Option<&str>) -> StorageError {
     StorageError::new(StorageErrorKind::AlreadyExists(path), detail)
 }
 
+pub fn precondition_failed(path: ObjectPath, detail: Option<&str>) -> StorageError {
+    StorageError::new(StorageErrorKind::PreconditionFailed(path), detail)
+}
+
 pub fn over_quota(detail: Option<&str>) -> StorageError {
     StorageError::new(StorageErrorKind::OverQuota, detail)
 }
@@ -252,6 +268,10 @@ pub fn cancelled(detail: Option<&str>) -> StorageError {
     StorageError::new(StorageErrorKind::Cancelled, detail)
 }
 
+pub fn timed_out(detail: Option<&str>) -> StorageError {
+    StorageError::new(StorageErrorKind::TimedOut, detail)
+}
+
 pub fn connection_failed(detail: Option<&str>) -> StorageError {
     StorageError::new(StorageErrorKind::ConnectionFailed, detail)
 }
@@ -17,13 +17,42 @@
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+#[cfg(any(feature = "file", feature = "b2"))]
+use std::time::{Duration, Instant};
 
 use futures::future::ready;
+#[cfg(any(feature = "file", feature = "b2"))]
+use futures::future::{select, Either};
+#[cfg(any(feature = "file", feature = "b2"))]
+use tokio_timer::Delay;
+
+#[cfg(any(feature = "file", feature = "b2"))]
+use super::{error, StorageError, TransferError};
 
 pub(crate) type FuturePoll<R> = Poll<R>;
 
 pub(crate) type PinnedFuture<R> = Pin<Box<dyn Future<Output = R> + Send + 'static>>;
 
+/// A result that can represent an operation that timed out.
+#[cfg(any(feature = "file", feature = "b2"))]
+pub(crate) trait TimeoutResult {
+    fn timed_out() -> Self;
+}
+
+#[cfg(any(feature = "file", feature = "b2"))]
+impl<T> TimeoutResult for Result<T, StorageError> {
+    fn timed_out() -> Self {
+        Err(error::timed_out(None))
+    }
+}
+
+#[cfg(any(feature = "file", feature = "b2"))]
+impl TimeoutResult for Result<(), TransferError> {
+    fn timed_out() -> Self {
+        Err(TransferError::TargetError(error::timed_out(None)))
+    }
+}
+
 /// Wraps a future of an unknown type into a concrete type.
 pub struct WrappedFuture<R>
 where
@@ -52,6 +81,26 @@ where
         }
     }
 
+    /// Fails with a [`TimedOut`](enum.StorageErrorKind.html#variant.TimedOut)
+    /// error if the future does not complete within the timeout. The timeout
+    /// starts when the future is first polled. Whatever the future was doing
+    /// is dropped when it times out.
+    #[cfg(any(feature = "file", feature = "b2"))]
+    pub(crate) fn with_timeout(self, timeout: Option<Duration>) -> WrappedFuture<R>
+    where
+        R: TimeoutResult,
+    {
+        match timeout {
+            Some(duration) => WrappedFuture::from_future(async move {
+                match select(self, Delay::new(Instant::now() + duration)).await {
+                    Either::Left((result, _)) => result,
+                    Either::Right(_) => R::timed_out(),
+                }
+            }),
+            None => self,
+        }
+    }
+
     pub(crate) fn poll_inner(&mut self, cx: &mut Context) -> FuturePoll<R> {
         self.base.as_mut().poll(cx)
     }
@@ -15,6 +15,7 @@
 //! Object types.
 
 use std::cmp::{Ordering, PartialOrd};
+use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 use std::fmt;
 use std::time::SystemTime;
@@ -23,7 +24,12 @@ use enum_dispatch::enum_dispatch;
 
 use super::*;
 use crate::backends::b2::B2Object;
+use crate::backends::custom::CustomObject;
 use crate::backends::file::FileObject;
+#[cfg(feature = "memory")]
+use crate::backends::memory::MemoryObject;
+#[cfg(feature = "s3")]
+use crate::backends::s3::S3Object;
 
 /// An object's type. For most backends this will just be File.
 ///
@@ -102,6 +108,11 @@ impl fmt::Display for ObjectType {
 pub enum Object {
     B2(B2Object),
     File(FileObject),
+    #[cfg(feature = "memory")]
+    Memory(MemoryObject),
+    #[cfg(feature = "s3")]
+    S3(S3Object),
+    Custom(CustomObject),
 }
 
 impl PartialEq for Object {
@@ -152,6 +163,41 @@ pub trait ObjectInfo {
     /// Gets the last modification time for the object.
     fn modified(&self) -> Option<SystemTime>;
 
+    /// Gets the object's mimetype if known.
+    fn content_type(&self) -> Option<String> {
+        None
+    }
+
+    /// Gets the `Cache-Control` value stored with the object.
+    fn cache_control(&self) -> Option<String> {
+        None
+    }
+
+    /// Gets the `Content-Disposition` value stored with the object.
+    fn content_disposition(&self) -> Option<String> {
+        None
+    }
+
+    /// Gets any custom metadata stored with the object.
+    fn metadata(&self) -> HashMap<String, String> {
+        HashMap::new()
+    }
+
+    /// Gets an opaque identifier for the current version of the object. This
+    /// can be used with [`WritePrecondition::MatchesVersion`](enum.WritePrecondition.html#variant.MatchesVersion)
+    /// to only replace the object if it hasn't changed.
+    fn version(&self) -> Option<String> {
+        None
+    }
+
+    /// Gets a checksum of the object's content if the backend knows one.
+    ///
+    /// Backends return the strongest hash they have available without having
+    /// to read the object's data.
+    fn checksum(&self) -> Option<Checksum> {
+        None
+    }
+
     /// Creates an [`UploadInfo`](struct.UploadInfo.html) for uploading this
     /// object to a new path.
     fn as_upload<P>(&self, path: P) -> StorageResult<UploadInfo>
@@ -167,6 +213,84 @@ pub trait ObjectInfo {
     }
 }
 
+/// A checksum of an object's content.
+///
+/// Hashes are always stored as lowercase hexadecimal strings. Only SHA1
+/// checksums can currently be verified by this crate, the others are just
+/// reported when a backend happens to know them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Checksum {
+    /// A SHA1 hash.
+    Sha1(String),
+    /// A SHA256 hash.
+    Sha256(String),
+    /// An MD5 hash.
+    Md5(String),
+}
+
+impl Checksum {
+    /// Gets the SHA1 hash if this is a SHA1 checksum.
+    pub fn sha1(&self) -> Option<&str> {
+        match self {
+            Checksum::Sha1(hash) => Some(hash),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Checksum::Sha1(hash) => write!(f, "sha1:{}", hash),
+            Checksum::Sha256(hash) => write!(f, "sha256:{}", hash),
+            Checksum::Md5(hash) => write!(f, "md5:{}", hash),
+        }
+    }
+}
+
+/// A condition that must hold for a write to go ahead.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WritePrecondition {
+    /// Always write the file, replacing anything already at the path.
+    Always,
+    /// Only write the file if nothing exists at the path. Fails with
+    /// [`StorageErrorKind::AlreadyExists`](enum.StorageErrorKind.html#variant.AlreadyExists)
+    /// otherwise.
+    DoesNotExist,
+    /// Only replace the file if its current [`version`](trait.ObjectInfo.html#tymethod.version)
+    /// matches. Fails with [`StorageErrorKind::PreconditionFailed`](enum.StorageErrorKind.html#variant.PreconditionFailed)
+    /// otherwise.
+    MatchesVersion(String),
+}
+
+impl Default for WritePrecondition {
+    fn default() -> WritePrecondition {
+        WritePrecondition::Always
+    }
+}
+
+impl WritePrecondition {
+    /// Checks the precondition against the object currently at the path.
+    pub(crate) fn check<O>(&self, path: &ObjectPath, current: Option<&O>) -> StorageResult<()>
+    where
+        O: ObjectInfo,
+    {
+        match self {
+            WritePrecondition::Always => Ok(()),
+            WritePrecondition::DoesNotExist => match current {
+                Some(_) => Err(error::already_exists(path.clone(), None)),
+                None => Ok(()),
+            },
+            WritePrecondition::MatchesVersion(expected) => {
+                match current.and_then(|object| object.version()) {
+                    Some(ref version) if version == expected => Ok(()),
+                    _ => Err(error::precondition_failed(path.clone(), None)),
+                }
+            }
+        }
+    }
+}
+
 /// Information used to upload a file.
 ///
 /// This allows attempting to set various properties of a file on upload. Not
@@ -177,12 +301,65 @@ pub trait ObjectInfo {
 /// have `Into` implementations for this object so you may not need to create
 /// one of these manually enless there are specific properties you wish to
 /// change.
-#[derive(Clone, Debug)]
+///
+/// The content type, cache control, content disposition and custom metadata are
+/// together referred to as the file's metadata. When copying a file the source
+/// file's metadata is kept unless any of it is set for the target.
+#[derive(Clone, Debug, Default)]
 pub struct UploadInfo {
     /// The path to upload to.
     pub path: ObjectPath,
     /// Sets the last modified time for the file.
     pub modified: Option<SystemTime>,
+    /// Sets the mimetype of the file.
+    pub content_type: Option<String>,
+    /// Sets a `Cache-Control` value for backends that serve files over http.
+    pub cache_control: Option<String>,
+    /// Sets a `Content-Disposition` value for backends that serve files over
+    /// http.
+    pub content_disposition: Option<String>,
+    /// Custom metadata to store with the file.
+    pub metadata: HashMap<String, String>,
+    /// A condition that must hold for the write to go ahead.
+    pub precondition: WritePrecondition,
+    /// The expected checksum of the data being written. If the data does not
+    /// match the write fails with a [`SourceError`](enum.TransferError.html#variant.SourceError)
+    /// of kind [`InvalidData`](enum.StorageErrorKind.html#variant.InvalidData)
+    /// before anything is committed. Only SHA1 checksums are supported.
+    pub checksum: Option<Checksum>,
+    /// The length of the data being written, if known. If the data is a
+    /// different length the write fails with a [`SourceError`](enum.TransferError.html#variant.SourceError)
+    /// of kind [`InvalidData`](enum.StorageErrorKind.html#variant.InvalidData).
+    /// Some backends can use this to avoid buffering the data.
+    pub size: Option<u64>,
+    /// Receives events as the upload progresses.
+    pub progress: Option<TransferObserver>,
+}
+
+impl UploadInfo {
+    /// Checks whether any of the file's metadata has been set.
+    pub fn has_metadata(&self) -> bool {
+        self.content_type.is_some()
+            || self.cache_control.is_some()
+            || self.content_disposition.is_some()
+            || !self.metadata.is_empty()
+    }
+
+    /// Copies the metadata from an existing object unless some is already set.
+    ///
+    /// Backends that copy files themselves should call this with the source
+    /// object so copies keep the source file's metadata.
+    pub fn inherit_metadata<O>(&mut self, object: &O)
+    where
+        O: ObjectInfo,
+    {
+        if !self.has_metadata() {
+            self.content_type = object.content_type();
+            self.cache_control = object.cache_control();
+            self.content_disposition = object.content_disposition();
+            self.metadata = object.metadata();
+        }
+    }
 }
 
 impl<I> From<I> for UploadInfo
@@ -193,6 +370,14 @@ where
         UploadInfo {
             path: info.path(),
             modified: info.modified(),
+            content_type: info.content_type(),
+            cache_control: info.cache_control(),
+            content_disposition: info.content_disposition(),
+            metadata: info.metadata(),
+            precondition: Default::default(),
+            checksum: None,
+            size: None,
+            progress: None,
         }
     }
 }
@@ -201,7 +386,7 @@ impl From<ObjectPath> for UploadInfo {
     fn from(path: ObjectPath) -> UploadInfo {
         UploadInfo {
             path,
-            modified: None,
+            ..Default::default()
         }
     }
 }
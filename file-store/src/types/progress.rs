@@ -0,0 +1,104 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Types for reporting the progress of transfers.
+use std::fmt;
+use std::sync::Arc;
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+
+use super::StorageError;
+
+/// An event in the progress of a transfer.
+#[derive(Clone, Debug)]
+pub enum TransferEvent {
+    /// More data has been transferred. Uploads count data as it is read from
+    /// the source stream and downloads as it is returned to the caller.
+    Progress {
+        /// The number of bytes transferred so far.
+        transferred: u64,
+        /// The total number of bytes to transfer, if known.
+        total: Option<u64>,
+    },
+    /// A part of a multi-part transfer has started.
+    PartStarted {
+        /// The part number, starting at 1.
+        part: usize,
+        /// The length of the part in bytes.
+        length: u64,
+    },
+    /// A part of a multi-part transfer has completed.
+    PartCompleted {
+        /// The part number, starting at 1.
+        part: usize,
+        /// The length of the part in bytes.
+        length: u64,
+    },
+    /// A request for a part failed and is about to be retried.
+    PartRetried {
+        /// The part number, starting at 1.
+        part: usize,
+        /// The error that caused the retry.
+        error: StorageError,
+    },
+    /// A part of a multi-part transfer failed.
+    PartFailed {
+        /// The part number, starting at 1.
+        part: usize,
+        /// The error that the part failed with.
+        error: StorageError,
+    },
+}
+
+/// Receives the [`TransferEvent`s](enum.TransferEvent.html) for a transfer.
+///
+/// Events are delivered from whichever task is performing that part of the
+/// transfer so the callback should return quickly.
+#[derive(Clone)]
+pub struct TransferObserver {
+    callback: Arc<dyn Fn(TransferEvent) + Send + Sync>,
+}
+
+impl TransferObserver {
+    /// Creates an observer that calls `callback` for every event.
+    pub fn new<F>(callback: F) -> TransferObserver
+    where
+        F: Fn(TransferEvent) + Send + Sync + 'static,
+    {
+        TransferObserver {
+            callback: Arc::new(callback),
+        }
+    }
+
+    /// Creates an observer along with a stream of its events. The stream ends
+    /// once every clone of the observer has been dropped.
+    pub fn channel() -> (TransferObserver, UnboundedReceiver<TransferEvent>) {
+        let (sender, receiver) = unbounded();
+        let observer = TransferObserver::new(move |event| {
+            // The receiver may have been dropped, that is fine.
+            let _ = sender.unbounded_send(event);
+        });
+        (observer, receiver)
+    }
+
+    pub(crate) fn notify(&self, event: TransferEvent) {
+        (self.callback)(event)
+    }
+}
+
+impl fmt::Debug for TransferObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TransferObserver")
+    }
+}
@@ -13,12 +13,15 @@
 // limitations under the License.
 
 //! A module with some useful tools for working with streams.
+use std::cmp::min;
+use std::ops::Range;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
 use futures::stream::Stream;
+use sha1::Sha1;
 
-use super::StorageResult;
+use super::{error, Data, StorageError, StorageResult, TransferEvent, TransferObserver};
 
 pub(crate) type StreamPoll<R> = Poll<Option<R>>;
 pub(crate) type ResultStreamPoll<R> = StreamPoll<StorageResult<R>>;
@@ -183,3 +186,206 @@ where
         result
     }
 }
+
+/// Limits a stream of data to a range of bytes within it.
+///
+/// Data before the start of the range is discarded and the stream ends once
+/// the end of the range is reached.
+pub(crate) struct RangeStream<S>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + 'static,
+{
+    inner: Pin<Box<S>>,
+    position: u64,
+    range: Range<u64>,
+}
+
+impl<S> RangeStream<S>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + 'static,
+{
+    pub fn new(stream: S, range: Range<u64>) -> RangeStream<S> {
+        RangeStream {
+            inner: Box::pin(stream),
+            position: 0,
+            range,
+        }
+    }
+}
+
+impl<S> Stream for RangeStream<S>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + 'static,
+{
+    type Item = StorageResult<Data>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> ResultStreamPoll<Data> {
+        loop {
+            if self.position >= self.range.end {
+                return Poll::Ready(None);
+            }
+
+            match self.inner.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(data))) => {
+                    let start = self.position;
+                    let end = start + data.len() as u64;
+                    self.position = end;
+
+                    if end <= self.range.start {
+                        continue;
+                    }
+
+                    let from = self.range.start.saturating_sub(start) as usize;
+                    let to = (min(end, self.range.end) - start) as usize;
+                    if from < to {
+                        return Poll::Ready(Some(Ok(data.slice(from, to))));
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Verifies the SHA1 hash and length of a stream of data.
+///
+/// Data is passed through unchanged. Once the inner stream ends, if the hash or
+/// length of the data does not match what was expected an `InvalidData` error
+/// is emitted before this stream ends. Data beyond the expected length is
+/// replaced by an error as soon as it is seen.
+pub(crate) struct ChecksumStream<S>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + 'static,
+{
+    inner: Pin<Box<S>>,
+    hasher: Sha1,
+    expected: Option<String>,
+    expected_size: Option<u64>,
+    length: u64,
+}
+
+impl<S> ChecksumStream<S>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + 'static,
+{
+    pub fn new(
+        stream: S,
+        expected: Option<String>,
+        expected_size: Option<u64>,
+    ) -> ChecksumStream<S> {
+        ChecksumStream {
+            inner: Box::pin(stream),
+            hasher: Sha1::new(),
+            expected: expected.map(|hash| hash.to_lowercase()),
+            expected_size,
+            length: 0,
+        }
+    }
+
+    fn size_error(&mut self) -> StorageError {
+        let expected = self.expected_size.take().unwrap_or_default();
+        self.expected = None;
+        error::invalid_data(Some(&format!(
+            "Expected {} bytes of data but got {}.",
+            expected, self.length
+        )))
+    }
+}
+
+impl<S> Stream for ChecksumStream<S>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + 'static,
+{
+    type Item = StorageResult<Data>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> ResultStreamPoll<Data> {
+        match self.inner.as_mut().poll_next(cx) {
+            Poll::Ready(Some(Ok(data))) => {
+                self.length += data.len() as u64;
+                if let Some(size) = self.expected_size {
+                    if self.length > size {
+                        return Poll::Ready(Some(Err(self.size_error())));
+                    }
+                }
+
+                if self.expected.is_some() {
+                    self.hasher.update(&data);
+                }
+                Poll::Ready(Some(Ok(data)))
+            }
+            Poll::Ready(None) => {
+                if let Some(size) = self.expected_size {
+                    if size != self.length {
+                        return Poll::Ready(Some(Err(self.size_error())));
+                    }
+                }
+
+                match self.expected.take() {
+                    Some(expected) => {
+                        let actual = self.hasher.hexdigest();
+                        if actual == expected {
+                            Poll::Ready(None)
+                        } else {
+                            Poll::Ready(Some(Err(error::invalid_data(Some(&format!(
+                                "Expected data with SHA1 {} but got {}.",
+                                expected, actual
+                            ))))))
+                        }
+                    }
+                    None => Poll::Ready(None),
+                }
+            }
+            result => result,
+        }
+    }
+}
+
+/// Reports the amount of data that has passed through a stream to an observer.
+pub(crate) struct ProgressStream<S>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + 'static,
+{
+    inner: Pin<Box<S>>,
+    observer: Option<TransferObserver>,
+    total: Option<u64>,
+    transferred: u64,
+}
+
+impl<S> ProgressStream<S>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + 'static,
+{
+    pub fn new(
+        stream: S,
+        observer: Option<TransferObserver>,
+        total: Option<u64>,
+    ) -> ProgressStream<S> {
+        ProgressStream {
+            inner: Box::pin(stream),
+            observer,
+            total,
+            transferred: 0,
+        }
+    }
+}
+
+impl<S> Stream for ProgressStream<S>
+where
+    S: Stream<Item = StorageResult<Data>> + Send + 'static,
+{
+    type Item = StorageResult<Data>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> ResultStreamPoll<Data> {
+        let result = self.inner.as_mut().poll_next(cx);
+        if let Poll::Ready(Some(Ok(ref data))) = result {
+            self.transferred += data.len() as u64;
+            if let Some(ref observer) = self.observer {
+                observer.notify(TransferEvent::Progress {
+                    transferred: self.transferred,
+                    total: self.total,
+                });
+            }
+        }
+        result
+    }
+}
@@ -14,10 +14,12 @@
 
 //! A set of useful utilities for converting between the different asynchronous
 //! types that this crate uses.
+use std::cmp::max;
 use std::convert::Infallible;
 use std::fmt;
 use std::future::Future;
 use std::io;
+use std::mem::replace;
 use std::ops::{Deref, DerefMut};
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
@@ -25,12 +27,18 @@ use std::task::{Context, Poll, Waker};
 
 use bytes::buf::FromBuf;
 use bytes::{BytesMut, IntoBuf};
-use futures::future::FutureExt;
-use futures::stream::{Stream, StreamExt};
+use futures::future::{ready, FutureExt};
+use futures::stream::{iter, once, Stream, StreamExt};
 use tokio_io::{AsyncRead, BufReader};
 
 use crate::future::WrappedFuture;
-use crate::types::{Data, StorageError};
+use crate::types::stream::{ChecksumStream, ProgressStream};
+use crate::types::{
+    error, Checksum, CopyStream, Data, DeleteResult, DeleteStream, GetStream, Object, ObjectInfo,
+    ObjectPath, ObjectStream, ObjectType, OperationCompleteFuture, StorageError, TransferError,
+    UploadInfo,
+};
+use crate::StorageBackend;
 
 /// Converts an AsyncRead into a stream that emits [`Data`](../type.Data.html).
 pub struct ReaderStream<R>
@@ -124,6 +132,181 @@ where
     })
 }
 
+/// Converts a stream of data to be written into a stream that verifies the
+/// upload's expected checksum and size once all the data has been read and
+/// reports its progress.
+pub(crate) fn into_upload_stream<S, I, E>(
+    info: &UploadInfo,
+    stream: S,
+) -> Result<impl Stream<Item = Result<Data, StorageError>>, TransferError>
+where
+    S: Stream<Item = Result<I, E>> + Send + 'static,
+    I: IntoBuf + 'static,
+    E: Into<StorageError> + 'static,
+{
+    let expected = match info.checksum {
+        Some(Checksum::Sha1(ref hash)) => Some(hash.clone()),
+        Some(_) => {
+            return Err(TransferError::TargetError(error::invalid_settings(Some(
+                "Only SHA1 checksums can be verified.",
+            ))))
+        }
+        None => None,
+    };
+
+    Ok(ProgressStream::new(
+        ChecksumStream::new(into_data_stream(stream), expected, info.size),
+        info.progress.clone(),
+        info.size,
+    ))
+}
+
+/// Deletes the listed objects, running up to `limit` deletes at a time, and
+/// returns a stream of the result for each. Errors from the listing itself are
+/// reported against `prefix`.
+///
+/// Directories are deleted once everything else has been, deepest first, so
+/// that they are empty by the time they are deleted.
+pub(crate) fn delete_objects<D>(
+    objects: ObjectStream,
+    prefix: ObjectPath,
+    limit: usize,
+    delete: D,
+) -> impl Stream<Item = DeleteResult> + Send + 'static
+where
+    D: Fn(Object) -> OperationCompleteFuture + Clone + Send + 'static,
+{
+    async fn result(path: ObjectPath, future: OperationCompleteFuture) -> DeleteResult {
+        match future.await {
+            Ok(()) => Ok(path),
+            Err(e) => Err((path, e)),
+        }
+    }
+
+    let directories: Arc<Mutex<Vec<Object>>> = Default::default();
+    let found = directories.clone();
+    let delete_file = delete.clone();
+    let files = objects
+        .filter_map(move |listed| {
+            ready(match listed {
+                Ok(object) => {
+                    if object.object_type() == ObjectType::Directory {
+                        found.lock().unwrap().push(object);
+                        None
+                    } else {
+                        Some(Ok(object))
+                    }
+                }
+                Err(e) => Some(Err(e)),
+            })
+        })
+        .map(move |listed| match listed {
+            Ok(object) => result(object.path(), delete_file(object)).left_future(),
+            Err(e) => ready(Err((prefix.clone(), e))).right_future(),
+        })
+        .buffer_unordered(max(limit, 1));
+
+    // Only runs once all of the files have been listed and deleted.
+    let directories = once(async move {
+        let mut found = replace(&mut *directories.lock().unwrap(), Vec::new());
+        found.sort_by(|a, b| b.path().parts().len().cmp(&a.path().parts().len()));
+        iter(found)
+    })
+    .flatten()
+    .then(move |object| result(object.path(), delete(object)));
+
+    files.chain(directories)
+}
+
+/// Gets each of the objects, running up to `limit` lookups at a time.
+pub(crate) fn get_each<B>(backend: B, paths: Vec<ObjectPath>, limit: usize) -> GetStream
+where
+    B: StorageBackend,
+{
+    GetStream::from_stream(
+        iter(paths)
+            .map(move |path| {
+                let future = backend.get_object(path.clone());
+                async move { future.await.map_err(|e| (path, e)) }
+            })
+            .buffer_unordered(max(limit, 1)),
+    )
+}
+
+/// Deletes each of the objects, running up to `limit` deletes at a time.
+pub(crate) fn delete_each<B>(backend: B, paths: Vec<ObjectPath>, limit: usize) -> DeleteStream
+where
+    B: StorageBackend,
+{
+    DeleteStream::from_stream(
+        iter(paths)
+            .map(move |path| {
+                let future = backend.delete_object(path.clone());
+                async move {
+                    match future.await {
+                        Ok(()) => Ok(path),
+                        Err(e) => Err((path, e)),
+                    }
+                }
+            })
+            .buffer_unordered(max(limit, 1)),
+    )
+}
+
+/// Copies each of the files, running up to `limit` copies at a time.
+pub(crate) fn copy_each<B>(
+    backend: B,
+    paths: Vec<(ObjectPath, ObjectPath)>,
+    limit: usize,
+) -> CopyStream
+where
+    B: StorageBackend,
+{
+    CopyStream::from_stream(
+        iter(paths)
+            .map(move |(source, target)| {
+                let future = backend.copy_file(source.clone(), target.clone());
+                async move {
+                    match future.await {
+                        Ok(()) => Ok((source, target)),
+                        Err(e) => Err((source, target, e)),
+                    }
+                }
+            })
+            .buffer_unordered(max(limit, 1)),
+    )
+}
+
+/// Spawns the tasks for the parts of a large file transfer, aborting any that
+/// are still running when dropped so that a cancelled transfer stops sending
+/// data.
+#[cfg(any(feature = "b2", feature = "s3"))]
+#[derive(Default)]
+pub(crate) struct PartTasks {
+    handles: Vec<futures::future::AbortHandle>,
+}
+
+#[cfg(any(feature = "b2", feature = "s3"))]
+impl PartTasks {
+    pub(crate) fn spawn<F>(&mut self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let (future, handle) = futures::future::abortable(future);
+        self.handles.push(handle);
+        tokio_executor::spawn(future.map(|_| ()));
+    }
+}
+
+#[cfg(any(feature = "b2", feature = "s3"))]
+impl Drop for PartTasks {
+    fn drop(&mut self) {
+        for handle in &self.handles {
+            handle.abort();
+        }
+    }
+}
+
 struct PoolState<C, T, E>
 where
     C: fmt::Debug,
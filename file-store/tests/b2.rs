@@ -27,8 +27,8 @@ mod test1 {
     use file_store::backends::Backend;
     use file_store::FileStore;
 
-    use crate::mocks::b2_server::start_server;
-    use crate::runner::{TestContext, TestError, TestResult};
+    use crate::mocks::b2_server::{shutdown, start_server};
+    use crate::runner::{TestContext, TestResult};
 
     async fn build_fs(context: &TestContext) -> TestResult<(FileStore, Sender<()>)> {
         let (addr, sender) = start_server(context.get_fs_root(), 20000)?;
@@ -37,15 +37,14 @@ mod test1 {
             .host(&format!("http://{}", addr))
             .limit_small_file_size(20 * 1024 * 1024)
             .limit_requests(5)
+            .limit_upload_parts(2)
             .connect()
             .await?;
         Ok((fs, sender))
     }
 
     async fn cleanup(sender: Sender<()>) -> TestResult<()> {
-        sender.send(()).map_err(|()| {
-            TestError::HarnessFailure(String::from("Failed to send shutdown to mock b2 server."))
-        })
+        shutdown(sender)
     }
 
     build_tests!("test1", Backend::B2, build_fs, cleanup);
@@ -58,8 +57,8 @@ mod retries {
     use file_store::backends::Backend;
     use file_store::FileStore;
 
-    use crate::mocks::b2_server::start_server;
-    use crate::runner::{TestContext, TestError, TestResult};
+    use crate::mocks::b2_server::{shutdown, start_server};
+    use crate::runner::{TestContext, TestResult};
 
     async fn build_fs(context: &TestContext) -> TestResult<(FileStore, Sender<()>)> {
         let (addr, sender) = start_server(context.get_fs_root(), 3)?;
@@ -74,10 +73,1287 @@ mod retries {
     }
 
     async fn cleanup(sender: Sender<()>) -> TestResult<()> {
-        sender.send(()).map_err(|()| {
-            TestError::HarnessFailure(String::from("Failed to send shutdown to mock b2 server."))
-        })
+        shutdown(sender)
+    }
+
+    build_tests!("test1", Backend::B2, build_fs, cleanup);
+}
+
+mod parallel {
+    use futures::channel::oneshot::Sender;
+
+    use file_store::backends::b2::B2Backend;
+    use file_store::backends::Backend;
+    use file_store::FileStore;
+
+    use crate::mocks::b2_server::{shutdown, start_server};
+    use crate::runner::{TestContext, TestResult};
+
+    async fn build_fs(context: &TestContext) -> TestResult<(FileStore, Sender<()>)> {
+        let (addr, sender) = start_server(context.get_fs_root(), 20000)?;
+
+        let fs = B2Backend::builder("foo", "bar")
+            .host(&format!("http://{}", addr))
+            .limit_small_file_size(20 * 1024 * 1024)
+            .parallel_downloads(32 * 1024 * 1024, 2)
+            .connect()
+            .await?;
+        Ok((fs, sender))
+    }
+
+    async fn cleanup(sender: Sender<()>) -> TestResult<()> {
+        shutdown(sender)
     }
 
     build_tests!("test1", Backend::B2, build_fs, cleanup);
 }
+
+mod resume {
+    use std::io::{Error, ErrorKind};
+    use std::time::Duration;
+
+    use futures::channel::oneshot::Sender;
+    use futures::future::ready;
+    use futures::stream::{iter, once, StreamExt};
+
+    use file_store::backends::b2::B2Backend;
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    use crate::mocks::b2_server::{collect, connect_backend, shutdown, start_server};
+    use crate::runner::{prepare_test, run, TestContext, TestResult, INIT};
+
+    const FILE_SIZE: usize = 5000;
+
+    async fn build_backend(context: &TestContext) -> TestResult<(B2Backend, Sender<()>)> {
+        let (addr, sender) = start_server(context.get_fs_root(), 20000)?;
+
+        let backend = connect_backend(addr, |builder| builder.limit_small_file_size(1000)).await?;
+        Ok((backend, sender))
+    }
+
+    fn content(seed: u8) -> Vec<u8> {
+        (0..FILE_SIZE)
+            .map(|i| (i as u8).wrapping_add(seed))
+            .collect()
+    }
+
+    fn data_stream(data: Vec<u8>) -> impl futures::stream::Stream<Item = Result<Data, Error>> {
+        let chunks: Vec<Result<Data, Error>> = data
+            .chunks(100)
+            .map(|chunk| Ok(Data::from(chunk.to_vec())))
+            .collect();
+        iter(chunks)
+    }
+
+    /// Attempts a large upload where the source fails part way through.
+    async fn failed_upload(backend: &B2Backend, path: &ObjectPath) -> TestResult<()> {
+        let mut data = content(0);
+        data.truncate(2500);
+        let stream = data_stream(data).chain(once(ready(Err(Error::new(
+            ErrorKind::Other,
+            "Source failed.",
+        )))));
+
+        match backend.write_file_from_stream(path.clone(), stream).await {
+            Err(TransferError::SourceError(_)) => Ok(()),
+            Err(e) => test_fail!("Should have seen a source error but saw {}.", e),
+            Ok(()) => test_fail!("Expected to fail to write {}.", path),
+        }
+    }
+
+    #[test]
+    fn test_resume_upload() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (backend, sender) = build_backend(&context).await?;
+            let path = ObjectPath::new("dir1/resumed")?;
+
+            failed_upload(&backend, &path).await?;
+
+            let mut uploads = backend.list_unfinished_uploads("dir1/").await?;
+            test_assert_eq!(uploads.len(), 1, "Should have seen one unfinished upload.");
+            let upload = uploads.remove(0);
+            test_assert_eq!(upload.path(), path, "Should have seen the right path.");
+
+            backend
+                .resume_upload(&upload, data_stream(content(0)))
+                .await?;
+            test_assert_eq!(
+                collect(backend.get_file_stream(path.clone()).await?).await?,
+                content(0),
+                "Should have seen the right file contents."
+            );
+
+            let uploads = backend.list_unfinished_uploads("dir1/").await?;
+            test_assert!(uploads.is_empty(), "Upload should have been finished.");
+
+            // Resuming with different data replaces all of the parts.
+            let other = ObjectPath::new("dir1/replaced")?;
+            failed_upload(&backend, &other).await?;
+            let mut uploads = backend.list_unfinished_uploads(other.clone()).await?;
+            test_assert_eq!(uploads.len(), 1, "Should have seen one unfinished upload.");
+
+            backend
+                .resume_upload(&uploads.remove(0), data_stream(content(7)))
+                .await?;
+            test_assert_eq!(
+                collect(backend.get_file_stream(other.clone()).await?).await?,
+                content(7),
+                "Should have seen the right file contents."
+            );
+
+            shutdown(sender)
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_cancel_stale_uploads() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (backend, sender) = build_backend(&context).await?;
+            let path = ObjectPath::new("dir1/cancelled")?;
+
+            failed_upload(&backend, &path).await?;
+
+            let cancelled = backend
+                .cancel_stale_uploads(Duration::from_secs(3600))
+                .await?;
+            test_assert!(
+                cancelled.is_empty(),
+                "Should not have cancelled a new upload."
+            );
+            test_assert_eq!(
+                backend.list_unfinished_uploads("").await?.len(),
+                1,
+                "Should have seen one unfinished upload."
+            );
+
+            let cancelled = backend.cancel_stale_uploads(Duration::from_secs(0)).await?;
+            test_assert_eq!(cancelled.len(), 1, "Should have cancelled the upload.");
+            test_assert_eq!(
+                cancelled[0].path(),
+                path,
+                "Should have seen the right path."
+            );
+            test_assert!(
+                backend.list_unfinished_uploads("").await?.is_empty(),
+                "Should be no unfinished uploads left."
+            );
+
+            match backend.get_object(path.clone()).await {
+                Ok(_) => test_fail!("Should not have created {}.", path),
+                Err(e) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::NotFound(path.clone()),
+                    "Should have seen a not found error."
+                ),
+            }
+
+            shutdown(sender)
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod versions {
+    use futures::channel::oneshot::Sender;
+    use futures::stream::StreamExt;
+
+    use file_store::backends::b2::{B2Backend, FileAction};
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    use crate::mocks::b2_server::{collect, connect_backend, shutdown, start_server};
+    use crate::runner::{prepare_test, run, TestContext, TestResult, INIT};
+
+    async fn build_backend(context: &TestContext) -> TestResult<(B2Backend, Sender<()>)> {
+        let (addr, sender) = start_server(context.get_fs_root(), 20000)?;
+
+        let backend = connect_backend(addr, |builder| builder).await?;
+        Ok((backend, sender))
+    }
+
+    #[test]
+    fn test_file_versions() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (backend, sender) = build_backend(&context).await?;
+            let path = ObjectPath::new("dir1/versioned")?;
+            let content: Vec<u8> = b"Some versioned content.".to_vec();
+
+            backend
+                .write_file_from_stream(
+                    path.clone(),
+                    futures::stream::iter(vec![Ok(Data::from(content.clone()))]),
+                )
+                .await?;
+
+            let versions = backend.list_versions(path.clone()).await?;
+            test_assert_eq!(versions.len(), 1, "Should have seen one version.");
+            test_assert_eq!(versions[0].path(), path, "Should have seen the right path.");
+            test_assert_eq!(
+                versions[0].action(),
+                &FileAction::Upload,
+                "Should have seen an upload."
+            );
+            test_assert_eq!(
+                versions[0].len(),
+                content.len() as u64,
+                "Should have seen the right length."
+            );
+            test_assert_eq!(
+                collect(backend.get_version_stream(&versions[0]).await?).await?,
+                content,
+                "Should have seen the right content."
+            );
+
+            backend.hide_file(path.clone()).await?;
+
+            match backend.get_object(path.clone()).await {
+                Ok(_) => test_fail!("Should not have seen hidden file {}.", path),
+                Err(e) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::NotFound(path.clone()),
+                    "Should have seen a not found error."
+                ),
+            }
+
+            let versions = backend.list_versions(path.clone()).await?;
+            test_assert_eq!(versions.len(), 2, "Should have seen two versions.");
+            test_assert_eq!(
+                versions[0].action(),
+                &FileAction::Upload,
+                "Should have seen the upload first."
+            );
+            test_assert_eq!(
+                versions[1].action(),
+                &FileAction::Hide,
+                "Should have seen the hide marker last."
+            );
+            test_assert!(
+                backend.get_version_stream(&versions[1]).await.is_err(),
+                "Should not be able to download a hide marker."
+            );
+
+            // Removing the hide marker reveals the file again.
+            backend.delete_version(&versions[1]).await?;
+            let object = backend.get_object(path.clone()).await?;
+            test_assert_eq!(
+                object.len(),
+                content.len() as u64,
+                "Should have seen the right length."
+            );
+
+            // Deleting a hidden file removes all of its versions.
+            backend.hide_file(path.clone()).await?;
+            backend.delete_object(path.clone()).await?;
+            match backend.list_versions(path.clone()).await {
+                Ok(versions) => test_fail!("Should have deleted {} versions.", versions.len()),
+                Err(e) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::NotFound(path.clone()),
+                    "Should have seen a not found error."
+                ),
+            }
+
+            shutdown(sender)
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_delete_hidden_prefix() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (backend, sender) = build_backend(&context).await?;
+            let path = ObjectPath::new("dir1/hidden/file")?;
+
+            backend
+                .write_file_from_stream(
+                    path.clone(),
+                    futures::stream::iter(vec![Ok(Data::from(b"Hidden content.".to_vec()))]),
+                )
+                .await?;
+            backend.hide_file(path.clone()).await?;
+
+            let mut deleted: Vec<ObjectPath> = Vec::new();
+            let mut results = backend
+                .delete_prefix(ObjectPath::new("dir1/hidden/")?)
+                .await?;
+            while let Some(result) = results.next().await {
+                match result {
+                    Ok(path) => deleted.push(path),
+                    Err((path, e)) => test_fail!("Failed to delete {}: {}", path, e),
+                }
+            }
+            test_assert_eq!(
+                deleted,
+                vec![path.clone()],
+                "Should have deleted the hidden file."
+            );
+
+            match backend.list_versions(path.clone()).await {
+                Ok(versions) => test_fail!("Should have deleted {} versions.", versions.len()),
+                Err(e) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::NotFound(path.clone()),
+                    "Should have seen a not found error."
+                ),
+            }
+
+            shutdown(sender)
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+mod buckets {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use futures::channel::oneshot::Sender;
+    use futures::stream::StreamExt;
+
+    use file_store::backends::b2::{B2Backend, BucketType, CorsRule, LifecycleRule};
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    use crate::mocks::b2_server::{
+        connect_backend, connect_backend_as, shutdown, start_counting_server, start_server,
+    };
+    use crate::runner::{prepare_test, run, TestContext, TestResult, INIT};
+
+    async fn build_backend(
+        context: &TestContext,
+        key_id: &str,
+    ) -> TestResult<(B2Backend, Sender<()>)> {
+        let (addr, sender) = start_server(context.get_fs_root(), 20000)?;
+
+        let backend = connect_backend_as(addr, key_id, |builder| builder).await?;
+        Ok((backend, sender))
+    }
+
+    #[test]
+    fn test_manage_buckets() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (backend, sender) = build_backend(&context, "foo").await?;
+            let path = ObjectPath::new("newbucket/file")?;
+
+            let bucket = backend
+                .create_bucket("newbucket", BucketType::Private)
+                .await?;
+            test_assert_eq!(
+                bucket.bucket_name,
+                "newbucket",
+                "Should have seen the right name."
+            );
+            test_assert_eq!(
+                bucket.bucket_type,
+                BucketType::Private,
+                "Should have seen the right type."
+            );
+
+            match backend
+                .create_bucket("newbucket", BucketType::Private)
+                .await
+            {
+                Ok(_) => test_fail!("Should not have been able to create the bucket twice."),
+                Err(e) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::AlreadyExists(ObjectPath::new("newbucket")?),
+                    "Should have seen an already exists error."
+                ),
+            }
+
+            let bucket = backend
+                .set_lifecycle_rules(
+                    "newbucket",
+                    vec![LifecycleRule {
+                        days_from_hiding_to_deleting: Some(1),
+                        days_from_uploading_to_hiding: None,
+                        file_name_prefix: String::from("logs/"),
+                    }],
+                )
+                .await?;
+            test_assert_eq!(
+                bucket.lifecycle_rules.len(),
+                1,
+                "Should have seen the lifecycle rule."
+            );
+
+            let bucket = backend
+                .set_cors_rules(
+                    "newbucket",
+                    vec![CorsRule {
+                        cors_rule_name: String::from("downloads"),
+                        allowed_origins: vec![String::from("*")],
+                        allowed_operations: vec![String::from("b2_download_file_by_name")],
+                        allowed_headers: None,
+                        expose_headers: None,
+                        max_age_seconds: 3600,
+                    }],
+                )
+                .await?;
+            test_assert_eq!(
+                bucket.cors_rules.len(),
+                1,
+                "Should have seen the CORS rule."
+            );
+            test_assert_eq!(
+                bucket.lifecycle_rules.len(),
+                1,
+                "Should have kept the lifecycle rule."
+            );
+
+            let mut info = HashMap::new();
+            info.insert(String::from("owner"), String::from("tests"));
+            let bucket = backend.set_bucket_info("newbucket", info).await?;
+            test_assert_eq!(
+                bucket.bucket_info.get("owner").and_then(|v| v.as_str()),
+                Some("tests"),
+                "Should have seen the bucket info."
+            );
+
+            backend
+                .write_file_from_stream(
+                    path.clone(),
+                    futures::stream::iter(vec![Ok(Data::from(b"Hello".to_vec()))]),
+                )
+                .await?;
+
+            match backend.delete_bucket("newbucket").await {
+                Ok(_) => test_fail!("Should not have been able to delete a non-empty bucket."),
+                Err(e) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::PreconditionFailed(ObjectPath::new("newbucket")?),
+                    "Should have seen a precondition failed error."
+                ),
+            }
+
+            backend.delete_object(path.clone()).await?;
+            backend.delete_bucket("newbucket").await?;
+
+            match backend.get_object(path.clone()).await {
+                Ok(_) => test_fail!("Should not have seen {}.", path),
+                Err(e) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::NotFound(path.clone()),
+                    "Should have seen a not found error."
+                ),
+            }
+
+            shutdown(sender)
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_restricted_key() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            // The mock server refuses to list buckets for this key.
+            let (backend, sender) = build_backend(&context, "restricted").await?;
+            let path = ObjectPath::new("dir1/restricted")?;
+
+            backend
+                .write_file_from_stream(
+                    path.clone(),
+                    futures::stream::iter(vec![Ok(Data::from(b"Hello".to_vec()))]),
+                )
+                .await?;
+
+            let object = backend.get_object(path.clone()).await?;
+            test_assert_eq!(object.len(), 5, "Should have seen the right length.");
+
+            let data = collect(backend.get_file_stream(path.clone()).await?).await?;
+            test_assert_eq!(data, b"Hello".to_vec(), "Should have seen the right data.");
+
+            let objects = backend
+                .list_objects("dir1/restricted")
+                .await?
+                .try_collect::<Vec<Object>>()
+                .await?;
+            test_assert_eq!(objects.len(), 1, "Should have listed the file.");
+
+            backend.delete_object(path.clone()).await?;
+
+            shutdown(sender)
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_batch_bucket_lookups() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (addr, sender, calls) = start_counting_server(context.get_fs_root(), 20000)?;
+
+            let uncached = connect_backend(addr, |builder| {
+                builder.cache_buckets(Duration::from_secs(0))
+            })
+            .await?;
+
+            let paths = vec![
+                ObjectPath::new("dir1/smallfile.txt")?,
+                ObjectPath::new("dir1/mediumfile")?,
+                ObjectPath::new("dir1/missing")?,
+            ];
+
+            // Each batch looks the bucket up once.
+            let results = uncached
+                .get_objects(paths.clone())
+                .collect::<Vec<_>>()
+                .await;
+            test_assert_eq!(results.len(), 3, "Should have seen every path.");
+            test_assert_eq!(
+                calls.count("b2_list_buckets").await,
+                1,
+                "Should have looked up the bucket once."
+            );
+
+            uncached
+                .get_objects(paths.clone())
+                .collect::<Vec<_>>()
+                .await;
+            test_assert_eq!(
+                calls.count("b2_list_buckets").await,
+                2,
+                "Should have looked up the bucket again without a cache."
+            );
+
+            // Batches share the bucket cache.
+            let cached = connect_backend(addr, |builder| builder).await?;
+
+            cached.get_objects(paths.clone()).collect::<Vec<_>>().await;
+            cached.get_objects(paths.clone()).collect::<Vec<_>>().await;
+            test_assert_eq!(
+                calls.count("b2_list_buckets").await,
+                3,
+                "Should have used the cached bucket."
+            );
+
+            shutdown(sender)
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod upload_urls {
+    use futures::stream::iter;
+
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    use crate::mocks::b2_server::{collect, connect_backend, shutdown, start_server};
+    use crate::runner::{prepare_test, run, TestResult, INIT};
+
+    #[test]
+    fn test_reuse_upload_urls() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (addr, sender) = start_server(context.get_fs_root(), 20000)?;
+
+            let backend =
+                connect_backend(addr, |builder| builder.limit_small_file_size(1000)).await?;
+
+            // The mock server expires upload URLs after a few uploads so these
+            // must be replaced part way through.
+            for i in 0..10u8 {
+                let path = ObjectPath::new(format!("dir1/upload{}", i))?;
+                let size = if i % 2 == 0 { 100 } else { 3500 };
+                let content: Vec<u8> = (0..size).map(|b| (b as u8).wrapping_add(i)).collect();
+                let chunks: Vec<StorageResult<Data>> = content
+                    .chunks(100)
+                    .map(|chunk| Ok(Data::from(chunk.to_vec())))
+                    .collect();
+
+                backend
+                    .write_file_from_stream(path.clone(), iter(chunks))
+                    .await?;
+
+                let data = collect(backend.get_file_stream(path.clone()).await?).await?;
+                test_assert_eq!(data, content, "Should have seen the right content.");
+            }
+
+            shutdown(sender)
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod streaming {
+    use futures::stream::iter;
+
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    use crate::mocks::b2_server::{collect, connect_backend, shutdown, start_server};
+    use crate::runner::{prepare_test, run, TestResult, INIT};
+
+    fn chunks(content: &[u8]) -> Vec<StorageResult<Data>> {
+        content
+            .chunks(150)
+            .map(|chunk| Ok(Data::from(chunk.to_vec())))
+            .collect()
+    }
+
+    #[test]
+    fn test_streaming_uploads() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (addr, sender) = start_server(context.get_fs_root(), 20000)?;
+
+            let backend = connect_backend(addr, |builder| {
+                builder.limit_small_file_size(1000).stream_uploads(true)
+            })
+            .await?;
+
+            // Both a regular file and a large file with parts that don't
+            // line up with the chunks of data.
+            for size in &[700, 2500] {
+                let path = ObjectPath::new(format!("dir1/stream{}", size))?;
+                let content: Vec<u8> = (0..*size).map(|b| (b % 251) as u8).collect();
+                let info = UploadInfo {
+                    path: path.clone(),
+                    size: Some(*size),
+                    ..Default::default()
+                };
+
+                backend
+                    .write_file_from_stream(info, iter(chunks(&content)))
+                    .await?;
+
+                let data = collect(backend.get_file_stream(path.clone()).await?).await?;
+                test_assert_eq!(data, content, "Should have seen the right content.");
+            }
+
+            // Data of the wrong size fails without writing anything.
+            for (size, length) in &[(700, 600), (700, 800), (2500, 2400), (2500, 2600)] {
+                let path = ObjectPath::new(format!("dir1/bad{}-{}", size, length))?;
+                let content: Vec<u8> = (0..*length).map(|b| (b % 251) as u8).collect();
+                let info = UploadInfo {
+                    path: path.clone(),
+                    size: Some(*size),
+                    ..Default::default()
+                };
+
+                match backend
+                    .write_file_from_stream(info, iter(chunks(&content)))
+                    .await
+                {
+                    Err(TransferError::SourceError(e)) => test_assert_eq!(
+                        e.kind(),
+                        StorageErrorKind::InvalidData,
+                        "Should have seen an invalid data error writing {}.",
+                        path
+                    ),
+                    Err(e) => test_fail!("Should have seen a source error but saw {}.", e),
+                    Ok(()) => test_fail!("Expected to fail to write {}.", path),
+                }
+
+                match backend.get_object(path.clone()).await {
+                    Err(e) => test_assert_eq!(
+                        e.kind(),
+                        StorageErrorKind::NotFound(path.clone()),
+                        "Should not have found {}.",
+                        path
+                    ),
+                    Ok(_) => test_fail!("Should not have written {}.", path),
+                }
+            }
+
+            shutdown(sender)
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod progress {
+    use std::cmp::max;
+    use std::sync::{Arc, Mutex};
+
+    use futures::stream::{iter, StreamExt};
+
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    use crate::mocks::b2_server::{collect, connect_backend, shutdown, start_server};
+    use crate::runner::{prepare_test, run, TestResult, INIT};
+
+    #[test]
+    fn test_part_events() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (addr, sender) = start_server(context.get_fs_root(), 20000)?;
+
+            // Parts are reported whether or not they are buffered.
+            for streaming in &[false, true] {
+                let backend = connect_backend(addr, |builder| {
+                    builder
+                        .limit_small_file_size(1000)
+                        .stream_uploads(*streaming)
+                })
+                .await?;
+
+                let events: Arc<Mutex<Vec<TransferEvent>>> = Default::default();
+                let observer = {
+                    let events = events.clone();
+                    TransferObserver::new(move |event| events.lock().unwrap().push(event))
+                };
+
+                let content: Vec<u8> = (0..2500).map(|b| (b % 251) as u8).collect();
+                let chunks: Vec<StorageResult<Data>> = content
+                    .chunks(100)
+                    .map(|chunk| Ok(Data::from(chunk.to_vec())))
+                    .collect();
+                backend
+                    .write_file_from_stream(
+                        UploadInfo {
+                            path: ObjectPath::new(format!("dir1/progress{}", streaming))?,
+                            size: Some(2500),
+                            progress: Some(observer),
+                            ..Default::default()
+                        },
+                        iter(chunks),
+                    )
+                    .await?;
+
+                let mut started: Vec<usize> = Vec::new();
+                let mut completed: Vec<usize> = Vec::new();
+                let mut length: u64 = 0;
+                for event in events.lock().unwrap().iter() {
+                    match event {
+                        TransferEvent::PartStarted { part, .. } => started.push(*part),
+                        TransferEvent::PartCompleted { part, length: l } => {
+                            completed.push(*part);
+                            length += l;
+                        }
+                        TransferEvent::PartFailed { part, error } => {
+                            test_fail!("Part {} failed: {}", part, error)
+                        }
+                        _ => (),
+                    }
+                }
+                started.sort();
+                completed.sort();
+
+                test_assert_eq!(started, vec![1, 2, 3], "Should have started 3 parts.");
+                test_assert_eq!(completed, vec![1, 2, 3], "Should have completed 3 parts.");
+                test_assert_eq!(length, 2500, "Should have uploaded all of the data.");
+            }
+
+            shutdown(sender)
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_limited_read_ahead() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (addr, sender) = start_server(context.get_fs_root(), 20000)?;
+            let backend = connect_backend(addr, |builder| {
+                builder.limit_small_file_size(1000).limit_upload_parts(2)
+            })
+            .await?;
+
+            let completed: Arc<Mutex<usize>> = Default::default();
+            let observer = {
+                let completed = completed.clone();
+                TransferObserver::new(move |event| {
+                    if let TransferEvent::PartCompleted { .. } = event {
+                        *completed.lock().unwrap() += 1;
+                    }
+                })
+            };
+
+            // Tracks the most parts that have been read from the source but
+            // not yet uploaded. The parts are 1000 bytes.
+            let read_ahead: Arc<Mutex<usize>> = Default::default();
+            let content: Vec<u8> = (0..10000).map(|b| (b % 251) as u8).collect();
+            let chunks: Vec<Data> = content
+                .chunks(100)
+                .map(|chunk| Data::from(chunk.to_vec()))
+                .collect();
+            let stream = {
+                let completed = completed.clone();
+                let read_ahead = read_ahead.clone();
+                iter(chunks.into_iter().enumerate()).map(move |(index, chunk)| {
+                    let part = index / 10 + 1;
+                    let ahead = part.saturating_sub(*completed.lock().unwrap());
+                    let mut most = read_ahead.lock().unwrap();
+                    *most = max(*most, ahead);
+                    Ok::<Data, StorageError>(chunk)
+                })
+            };
+
+            let path = ObjectPath::new("dir1/readahead")?;
+            backend
+                .write_file_from_stream(
+                    UploadInfo {
+                        path: path.clone(),
+                        progress: Some(observer),
+                        ..Default::default()
+                    },
+                    stream,
+                )
+                .await?;
+
+            test_assert_eq!(
+                *completed.lock().unwrap(),
+                10,
+                "Should have uploaded 10 parts."
+            );
+            let most = *read_ahead.lock().unwrap();
+            test_assert!(
+                most <= 2,
+                "Should not have read more than 2 parts ahead of the uploads but read {}.",
+                most
+            );
+            test_assert_eq!(
+                collect(backend.get_file_stream(path.clone()).await?).await?,
+                content,
+                "Should have seen the right content."
+            );
+
+            shutdown(sender)
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod retry_policy {
+    use std::time::{Duration, Instant};
+
+    use chrono::Utc;
+    use futures::channel::oneshot::Sender;
+    use http::StatusCode;
+
+    use file_store::backends::b2::{B2Backend, RetryPolicy};
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    use crate::mocks::b2_server::{
+        connect_backend, shutdown, start_server_with_failures, MockFailure,
+    };
+    use crate::runner::{prepare_test, run, TestContext, TestResult, INIT};
+
+    async fn build_backend(
+        context: &TestContext,
+        failures: Vec<MockFailure>,
+        policy: RetryPolicy,
+    ) -> TestResult<(B2Backend, Sender<()>)> {
+        let (addr, sender) = start_server_with_failures(context.get_fs_root(), 20000, failures)?;
+
+        let backend = connect_backend(addr, |builder| builder.retry_policy(policy)).await?;
+        Ok((backend, sender))
+    }
+
+    fn failures(count: usize, status: StatusCode, code: &'static str) -> Vec<MockFailure> {
+        (0..count)
+            .map(|_| MockFailure {
+                status,
+                code,
+                retry_after: None,
+                method: None,
+                message: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_backoff() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let policy = RetryPolicy::new()
+                .backoff(Duration::from_millis(200), Duration::from_secs(1))
+                .jitter(false);
+            let failures = failures(2, StatusCode::SERVICE_UNAVAILABLE, "service_unavailable");
+            let (backend, sender) = build_backend(&context, failures, policy).await?;
+            let path = ObjectPath::new("dir1/missing")?;
+
+            let start = Instant::now();
+            match backend.get_object(path.clone()).await {
+                Ok(_) => test_fail!("Should not have found {}.", path),
+                Err(e) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::NotFound(path.clone()),
+                    "Should have seen a not found error."
+                ),
+            }
+
+            // Retries after 200ms then 400ms.
+            let elapsed = start.elapsed();
+            test_assert!(
+                elapsed >= Duration::from_millis(600),
+                "Should have waited before retrying but only took {:?}.",
+                elapsed
+            );
+
+            shutdown(sender)
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_retry_after() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let policy = RetryPolicy::new()
+                .backoff(Duration::from_millis(10), Duration::from_millis(50))
+                .jitter(false);
+            let failures = vec![MockFailure {
+                status: StatusCode::TOO_MANY_REQUESTS,
+                code: "too_many_requests",
+                retry_after: Some(String::from("1")),
+                method: None,
+                message: None,
+            }];
+            let (backend, sender) = build_backend(&context, failures, policy).await?;
+            let path = ObjectPath::new("dir1/missing")?;
+
+            let start = Instant::now();
+            match backend.get_file_stream(path.clone()).await {
+                Ok(_) => test_fail!("Should not have found {}.", path),
+                Err(e) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::NotFound(path.clone()),
+                    "Should have seen a not found error."
+                ),
+            }
+
+            let elapsed = start.elapsed();
+            test_assert!(
+                elapsed >= Duration::from_secs(1),
+                "Should have waited for the Retry-After time but only took {:?}.",
+                elapsed
+            );
+
+            shutdown(sender)
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_retry_after_date() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let policy = RetryPolicy::new()
+                .backoff(Duration::from_millis(10), Duration::from_millis(50))
+                .jitter(false);
+            let retry_at = Utc::now() + chrono::Duration::seconds(3);
+            let failures = vec![MockFailure {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                code: "service_unavailable",
+                retry_after: Some(retry_at.format("%a, %d %b %Y %H:%M:%S GMT").to_string()),
+                method: None,
+                message: None,
+            }];
+            let (backend, sender) = build_backend(&context, failures, policy).await?;
+            let path = ObjectPath::new("dir1/missing")?;
+
+            let start = Instant::now();
+            match backend.get_object(path.clone()).await {
+                Ok(_) => test_fail!("Should not have found {}.", path),
+                Err(e) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::NotFound(path.clone()),
+                    "Should have seen a not found error."
+                ),
+            }
+
+            // The date only has a precision of seconds.
+            let elapsed = start.elapsed();
+            test_assert!(
+                elapsed >= Duration::from_secs(2),
+                "Should have waited until the Retry-After date but only took {:?}.",
+                elapsed
+            );
+
+            shutdown(sender)
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_max_attempts() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let policy = RetryPolicy::new()
+                .max_attempts(3)
+                .backoff(Duration::from_millis(10), Duration::from_millis(50));
+            let failures = failures(3, StatusCode::SERVICE_UNAVAILABLE, "service_unavailable");
+            let (backend, sender) = build_backend(&context, failures, policy).await?;
+            let path = ObjectPath::new("dir1/missing")?;
+
+            match backend.get_object(path.clone()).await {
+                Ok(_) => test_fail!("Should not have found {}.", path),
+                Err(e) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::ServiceError,
+                    "Should have given up after three attempts."
+                ),
+            }
+
+            // The server has stopped failing.
+            match backend.get_object(path.clone()).await {
+                Ok(_) => test_fail!("Should not have found {}.", path),
+                Err(e) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::NotFound(path.clone()),
+                    "Should have seen a not found error."
+                ),
+            }
+
+            shutdown(sender)
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_oversized_response() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let policy =
+                RetryPolicy::new().backoff(Duration::from_millis(10), Duration::from_millis(50));
+            let failures = vec![MockFailure {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                code: "service_unavailable",
+                retry_after: None,
+                method: None,
+                message: Some("x".repeat(17 * 1024 * 1024)),
+            }];
+            let (backend, sender) = build_backend(&context, failures, policy).await?;
+            let path = ObjectPath::new("dir1/missing")?;
+
+            // Would be not found if the request had been retried.
+            match backend.get_object(path.clone()).await {
+                Ok(_) => test_fail!("Should not have found {}.", path),
+                Err(e) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::InvalidData,
+                    "Should have refused to read the response."
+                ),
+            }
+
+            shutdown(sender)
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod timeouts {
+    use std::time::{Duration, Instant};
+
+    use futures::channel::mpsc::unbounded;
+    use futures::channel::oneshot::Sender;
+    use futures::stream::{iter, StreamExt};
+    use tokio::timer::Delay;
+
+    use file_store::backends::b2::B2Backend;
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    use crate::mocks::b2_server::{connect_backend, shutdown, start_server};
+    use crate::runner::{prepare_test, run, TestContext, TestResult, INIT};
+
+    async fn build_backend(context: &TestContext) -> TestResult<(B2Backend, Sender<()>)> {
+        let (addr, sender) = start_server(context.get_fs_root(), 20000)?;
+
+        let backend = connect_backend(addr, |builder| {
+            builder
+                .limit_small_file_size(1000)
+                .operation_timeout(Duration::from_millis(500))
+        })
+        .await?;
+        Ok((backend, sender))
+    }
+
+    #[test]
+    fn test_cancel_timed_out_upload() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let (backend, sender) = build_backend(&context).await?;
+            let path = ObjectPath::new("dir1/stalled")?;
+
+            // Sends two parts worth of data and then never ends.
+            let (_source, stalled) = unbounded::<StorageResult<Data>>();
+            let chunks: Vec<StorageResult<Data>> = (0..25)
+                .map(|i| Ok(Data::from(vec![i as u8; 100])))
+                .collect();
+            let stream = iter(chunks).chain(stalled);
+
+            match backend.write_file_from_stream(path.clone(), stream).await {
+                Err(TransferError::TargetError(e)) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::TimedOut,
+                    "Should have seen a timeout."
+                ),
+                Err(e) => test_fail!("Should have seen a target error but saw {}.", e),
+                Ok(()) => test_fail!("Should have failed to write {}.", path),
+            }
+
+            // The large file is cancelled in the background.
+            let mut attempts = 0;
+            while !backend.list_unfinished_uploads("").await?.is_empty() {
+                attempts += 1;
+                test_assert!(attempts < 20, "The large file should have been cancelled.");
+                Delay::new(Instant::now() + Duration::from_millis(50)).await;
+            }
+
+            match backend.get_object(path.clone()).await {
+                Ok(_) => test_fail!("Should not have created {}.", path),
+                Err(e) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::NotFound(path.clone()),
+                    "Should have seen a not found error."
+                ),
+            }
+
+            shutdown(sender)
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod copies {
+    use futures::stream::iter;
+    use http::StatusCode;
+
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    use crate::mocks::b2_server::{
+        connect_backend, shutdown, start_server_with_failures, MockFailure,
+    };
+    use crate::runner::{prepare_test, run, TestResult, INIT};
+
+    #[test]
+    fn test_cancel_failed_copy() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::B2, "test1")?;
+            let failures = vec![MockFailure {
+                status: StatusCode::BAD_REQUEST,
+                code: "bad_request",
+                retry_after: None,
+                method: Some("b2_copy_part"),
+                message: None,
+            }];
+            let (addr, sender) =
+                start_server_with_failures(context.get_fs_root(), 20000, failures)?;
+
+            let backend =
+                connect_backend(addr, |builder| builder.limit_small_file_size(1000)).await?;
+
+            let source = ObjectPath::new("dir1/source")?;
+            let target = ObjectPath::new("dir1/target")?;
+            let chunks: Vec<StorageResult<Data>> = (0..50)
+                .map(|i| Ok(Data::from(vec![i as u8; 100])))
+                .collect();
+            backend
+                .write_file_from_stream(source.clone(), iter(chunks))
+                .await?;
+
+            match backend.copy_file(source.clone(), target.clone()).await {
+                Err(TransferError::TargetError(e)) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::InternalError,
+                    "Should have seen the injected failure."
+                ),
+                Err(e) => test_fail!("Should have seen a target error but saw {}.", e),
+                Ok(()) => test_fail!("Should have failed to copy to {}.", target),
+            }
+
+            test_assert!(
+                backend.list_unfinished_uploads("").await?.is_empty(),
+                "The failed copy should have been cancelled."
+            );
+
+            match backend.get_object(target.clone()).await {
+                Ok(_) => test_fail!("Should not have created {}.", target),
+                Err(e) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::NotFound(target.clone()),
+                    "Should have seen a not found error."
+                ),
+            }
+
+            shutdown(sender)
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(feature = "file")]
+
+extern crate file_store;
+
+#[macro_use]
+mod runner;
+
+mod test1 {
+    use std::ops::Range;
+    use std::sync::Arc;
+
+    use file_store::backends::custom::DynStorageBackend;
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    use crate::runner::{TestContext, TestResult};
+
+    const WRAPPED: Backend = Backend::Custom("wrapped");
+
+    /// A custom backend that passes everything through to another `FileStore`
+    /// but relies on the default copy and move implementations.
+    #[derive(Debug)]
+    struct WrappedBackend {
+        fs: FileStore,
+    }
+
+    impl DynStorageBackend for WrappedBackend {
+        fn backend_type(&self) -> Backend {
+            WRAPPED
+        }
+
+        fn list_objects(&self, prefix: ObjectPath) -> ObjectStreamFuture {
+            self.fs.list_objects(prefix)
+        }
+
+        fn list_directory(&self, dir: ObjectPath) -> ObjectStreamFuture {
+            self.fs.list_directory(dir)
+        }
+
+        fn get_object(&self, path: ObjectPath) -> ObjectFuture {
+            self.fs.get_object(path)
+        }
+
+        fn get_file_stream(&self, path: ObjectPath) -> DataStreamFuture {
+            self.fs.get_file_stream(path)
+        }
+
+        fn get_file_range(&self, path: ObjectPath, range: Range<u64>) -> DataStreamFuture {
+            self.fs.get_file_range(path, range)
+        }
+
+        fn delete_object(&self, path: ObjectPath) -> OperationCompleteFuture {
+            self.fs.delete_object(path)
+        }
+
+        fn write_file_from_stream(
+            &self,
+            info: UploadInfo,
+            stream: DataStream,
+        ) -> WriteCompleteFuture {
+            self.fs.write_file_from_stream(info, stream)
+        }
+    }
+
+    async fn build_fs(context: &TestContext) -> TestResult<(FileStore, ())> {
+        let fs = FileBackend::connect(&context.get_fs_root()).await?;
+        let backend: Arc<dyn DynStorageBackend> = Arc::new(WrappedBackend { fs });
+        let fs = FileStore::from(backend);
+
+        if fs.backend_type() != WRAPPED {
+            test_fail!("Should have seen the custom backend type.");
+        }
+
+        Ok((fs, ()))
+    }
+
+    async fn cleanup(_: ()) -> TestResult<()> {
+        Ok(())
+    }
+
+    build_tests!("test1", WRAPPED, build_fs, cleanup);
+}
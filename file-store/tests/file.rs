@@ -43,7 +43,13 @@ mod test1 {
     use file_store::FileStore;
 
     async fn build_fs(context: &TestContext) -> TestResult<(FileStore, ())> {
-        Ok((FileBackend::connect(&context.get_fs_root()).await?, ()))
+        Ok((
+            FileBackend::builder(&context.get_fs_root())
+                .sync_directory(true)
+                .connect()
+                .await?,
+            (),
+        ))
     }
 
     async fn cleanup(_: ()) -> TestResult<()> {
@@ -52,3 +58,230 @@ mod test1 {
 
     build_tests!("test1", Backend::File, build_fs, cleanup);
 }
+
+mod paths {
+    use crate::runner::{prepare_test, run, TestResult, INIT};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    async fn test_rejected(fs: &FileStore, path: &str) -> TestResult<()> {
+        let path = ObjectPath::new(path)?;
+        match fs.get_object(path.clone()).await {
+            Ok(_) => test_fail!("Should not have been able to access {}.", path),
+            Err(e) => test_assert_eq!(
+                e.kind(),
+                StorageErrorKind::InvalidPath(path.clone()),
+                "Should have seen an invalid path error for {}.",
+                path
+            ),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_paths() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1")?;
+            let fs = FileBackend::connect(&context.get_fs_root()).await?;
+
+            test_rejected(&fs, "dir1/../../smallfile.txt").await?;
+            test_rejected(&fs, "dir1/./smallfile.txt").await?;
+            test_rejected(&fs, "dir1/..").await?;
+            test_rejected(&fs, "dir1//smallfile.txt").await?;
+            test_rejected(&fs, "dir1\\smallfile.txt").await?;
+            test_rejected(&fs, "dir1/small\0file.txt").await?;
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_external_symlinks() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1")?;
+            let root = context.get_fs_root();
+            let outside = match root.parent() {
+                Some(p) => p.to_owned(),
+                None => test_fail!("Root should have a parent."),
+            };
+            std::os::unix::fs::symlink(&outside, root.join("outside"))
+                .map_err(|e| crate::runner::TestError::HarnessFailure(e.to_string()))?;
+            std::os::unix::fs::symlink(root.join("dir2"), root.join("inside"))
+                .map_err(|e| crate::runner::TestError::HarnessFailure(e.to_string()))?;
+
+            let fs = FileBackend::connect(&root).await?;
+            fs.get_object("outside/dir1/smallfile.txt").await?;
+            fs.get_object("inside/daz").await?;
+
+            let fs = FileBackend::builder(&root)
+                .follow_external_symlinks(false)
+                .connect()
+                .await?;
+            test_rejected(&fs, "outside").await?;
+            test_rejected(&fs, "outside/dir1/smallfile.txt").await?;
+            fs.get_object("inside/daz").await?;
+            fs.get_object("smallfile.txt").await?;
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod checksums {
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+
+    use filetime::{set_file_mtime, FileTime};
+    use futures::stream::{iter, TryStreamExt};
+
+    use crate::runner::{prepare_test, run, TestError, TestResult, INIT, SMALL_FILE_MODIFIED};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    #[test]
+    fn test_modified_file() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1")?;
+            let fs = FileBackend::connect(&context.get_fs_root()).await?;
+
+            let info = UploadInfo {
+                path: ObjectPath::new("dir1/checksummed")?,
+                modified: Some(SMALL_FILE_MODIFIED()),
+                ..Default::default()
+            };
+            let content: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+            fs.write_file_from_stream(
+                info.clone(),
+                iter(vec![Ok::<Data, StorageError>(Data::from(content))]),
+            )
+            .await?;
+
+            match fs.get_object(info.path.clone()).await?.checksum() {
+                Some(Checksum::Sha1(_)) => (),
+                checksum => test_fail!("Should have seen a SHA1 checksum but saw {:?}.", checksum),
+            }
+
+            // Change the content without changing the file's size or
+            // modification time.
+            let harness_error = |e: std::io::Error| TestError::HarnessFailure(e.to_string());
+            let target = context.get_fs_root().join("dir1").join("checksummed");
+            let mut file = OpenOptions::new()
+                .write(true)
+                .open(&target)
+                .map_err(harness_error)?;
+            file.seek(SeekFrom::Start(500)).map_err(harness_error)?;
+            file.write_all(b"corrupt").map_err(harness_error)?;
+            file.sync_all().map_err(harness_error)?;
+            set_file_mtime(&target, FileTime::from_system_time(SMALL_FILE_MODIFIED()))
+                .map_err(harness_error)?;
+
+            match fs
+                .get_verified_file_stream(info.path.clone())
+                .await?
+                .try_collect::<Vec<Data>>()
+                .await
+            {
+                Err(e) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::InvalidData,
+                    "Should have seen an invalid data error reading {}.",
+                    info.path
+                ),
+                Ok(_) => test_fail!("Should have failed to verify {}.", info.path),
+            }
+
+            // Once the modification time changes the stored checksum no longer
+            // applies.
+            set_file_mtime(&target, FileTime::now()).map_err(harness_error)?;
+            test_assert_eq!(
+                fs.get_object(info.path.clone()).await?.checksum(),
+                None,
+                "Should not have seen a checksum for {}.",
+                info.path
+            );
+            fs.get_verified_file_stream(info.path.clone())
+                .await?
+                .try_collect::<Vec<Data>>()
+                .await?;
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
+
+mod timeouts {
+    use std::time::Duration;
+
+    use futures::channel::mpsc::unbounded;
+    use futures::stream::{iter, StreamExt};
+
+    use crate::runner::{prepare_test, run, TestError, TestResult, INIT};
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    #[test]
+    fn test_timed_out_write() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1")?;
+            let fs = FileBackend::builder(&context.get_fs_root())
+                .operation_timeout(Duration::from_millis(200))
+                .connect()
+                .await?;
+            let path = ObjectPath::new("dir1/stalled")?;
+
+            // Sends some data and then never ends.
+            let (_source, stalled) = unbounded::<StorageResult<Data>>();
+            let stream = iter(vec![Ok(Data::from(vec![5; 1000]))]).chain(stalled);
+
+            match fs.write_file_from_stream(path.clone(), stream).await {
+                Err(TransferError::TargetError(e)) => test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::TimedOut,
+                    "Should have seen a timeout."
+                ),
+                Err(e) => test_fail!("Should have seen a target error but saw {}.", e),
+                Ok(()) => test_fail!("Should have failed to write {}.", path),
+            }
+
+            // The partially written temporary file is removed.
+            let harness_error = |e: std::io::Error| TestError::HarnessFailure(e.to_string());
+            for entry in
+                std::fs::read_dir(context.get_fs_root().join("dir1")).map_err(harness_error)?
+            {
+                let name = entry.map_err(harness_error)?.file_name();
+                test_assert!(
+                    !name.to_string_lossy().contains("stalled"),
+                    "Should not have left {:?} behind.",
+                    name
+                );
+            }
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(feature = "memory")]
+
+extern crate file_store;
+
+#[macro_use]
+mod runner;
+
+mod test1 {
+    use std::fs::{metadata, read, read_dir};
+    use std::path::{Path, PathBuf};
+
+    use futures::stream::iter;
+
+    use file_store::backends::memory::MemoryBackend;
+    use file_store::backends::Backend;
+    use file_store::*;
+
+    use crate::runner::{TestContext, TestError, TestResult};
+
+    fn harness_error<E: ToString>(error: E) -> TestError {
+        TestError::HarnessFailure(error.to_string())
+    }
+
+    fn find_files(
+        dir: &Path,
+        path: ObjectPath,
+        files: &mut Vec<(ObjectPath, PathBuf)>,
+    ) -> TestResult<()> {
+        for entry in read_dir(dir).map_err(harness_error)? {
+            let entry = entry.map_err(harness_error)?;
+            let mut child = path.clone();
+            child.push_part(&entry.file_name().to_string_lossy());
+
+            if entry.file_type().map_err(harness_error)?.is_dir() {
+                find_files(&entry.path(), child, files)?;
+            } else {
+                files.push((child, entry.path()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copies the test files from disk into a new memory store.
+    async fn build_fs(context: &TestContext) -> TestResult<(FileStore, ())> {
+        let fs = MemoryBackend::connect().await?;
+
+        let mut files = Vec::new();
+        find_files(&context.get_fs_root(), ObjectPath::empty(), &mut files)?;
+
+        for (path, file) in files {
+            let modified = metadata(&file)
+                .and_then(|m| m.modified())
+                .map_err(harness_error)?;
+            let data = Data::from(read(&file).map_err(harness_error)?);
+
+            fs.write_file_from_stream(
+                UploadInfo {
+                    path,
+                    modified: Some(modified),
+                    ..Default::default()
+                },
+                iter(vec![Ok::<Data, StorageError>(data)]),
+            )
+            .await?;
+        }
+
+        Ok((fs, ()))
+    }
+
+    async fn cleanup(_: ()) -> TestResult<()> {
+        Ok(())
+    }
+
+    build_tests!("test1", Backend::Memory, build_fs, cleanup);
+}
+
+#[cfg(feature = "file")]
+mod sync {
+    use futures::future::ready;
+    use futures::stream::{iter, TryStreamExt};
+
+    use file_store::backends::file::FileBackend;
+    use file_store::backends::memory::MemoryBackend;
+    use file_store::backends::Backend;
+    use file_store::sync::{SyncAction, SyncBuilder, SyncReport};
+    use file_store::*;
+
+    use crate::runner::{prepare_test, run, TestResult, INIT};
+
+    fn sorted(report: &SyncReport) -> Vec<SyncAction> {
+        let mut actions = report.actions.clone();
+        actions.sort_by_key(|action| match action {
+            SyncAction::Create { target, .. } => target.to_string(),
+            SyncAction::Update { target, .. } => target.to_string(),
+            SyncAction::Delete { target } => target.to_string(),
+        });
+        actions
+    }
+
+    fn create(source: &str, target: &str) -> TestResult<SyncAction> {
+        Ok(SyncAction::Create {
+            source: ObjectPath::new(source)?,
+            target: ObjectPath::new(target)?,
+        })
+    }
+
+    async fn write(fs: &FileStore, path: &str, data: &[u8]) -> TestResult<()> {
+        fs.write_file_from_stream(
+            path,
+            iter(vec![Ok::<Data, StorageError>(Data::from(data.to_vec()))]),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn read(fs: &FileStore, path: &str) -> TestResult<Vec<u8>> {
+        Ok(fs
+            .get_file_stream(path)
+            .await?
+            .try_fold(Vec::new(), |mut data, chunk| {
+                data.extend_from_slice(&chunk);
+                ready(Ok(data))
+            })
+            .await?)
+    }
+
+    async fn count_files(fs: &FileStore) -> TestResult<usize> {
+        Ok(fs
+            .list_objects("")
+            .await?
+            .try_filter(|o| ready(o.object_type() == ObjectType::File))
+            .try_collect::<Vec<Object>>()
+            .await?
+            .len())
+    }
+
+    #[test]
+    fn test_sync() {
+        INIT.call_once(env_logger::init);
+        let result: TestResult<()> = run(async {
+            let context = prepare_test(Backend::File, "test1/dir1/dir2")?;
+            let source = FileBackend::connect(&context.get_fs_root()).await?;
+            let target = MemoryBackend::connect().await?;
+            write(&target, "backup/extra", b"Not in the source.").await?;
+
+            let prefix = ObjectPath::new("backup")?;
+
+            // A dry run should change nothing.
+            let report = SyncBuilder::new(&source, ObjectPath::empty(), &target, prefix.clone())
+                .dry_run(true)
+                .delete_extra(true)
+                .run()
+                .await?;
+            test_assert_eq!(report.actions.len(), 9);
+            test_assert_eq!(report.unchanged, 0);
+            test_assert!(report.is_success());
+            test_assert_eq!(count_files(&target).await?, 1);
+
+            let report = SyncBuilder::new(&source, ObjectPath::empty(), &target, prefix.clone())
+                .limit_concurrency(2)
+                .run()
+                .await?;
+            test_assert!(report.is_success());
+            test_assert_eq!(report.actions.len(), 8);
+            test_assert_eq!(sorted(&report)[0].clone(), create("0foo", "backup/0foo")?);
+            test_assert_eq!(count_files(&target).await?, 9);
+
+            test_assert_eq!(
+                read(&target, "backup/daz").await?,
+                read(&source, "daz").await?
+            );
+
+            // Nothing has changed so nothing should be copied.
+            let report = SyncBuilder::new(&source, ObjectPath::empty(), &target, prefix.clone())
+                .compare_contents(true)
+                .run()
+                .await?;
+            test_assert_eq!(report.actions.len(), 0);
+            test_assert_eq!(report.unchanged, 8);
+
+            // Changing the size of a file is noticed.
+            write(&target, "backup/daz", b"Different.").await?;
+            let report = SyncBuilder::new(&source, ObjectPath::empty(), &target, prefix.clone())
+                .delete_extra(true)
+                .run()
+                .await?;
+            test_assert!(report.is_success());
+            test_assert_eq!(
+                sorted(&report),
+                vec![
+                    SyncAction::Update {
+                        source: ObjectPath::new("daz")?,
+                        target: ObjectPath::new("backup/daz")?,
+                    },
+                    SyncAction::Delete {
+                        target: ObjectPath::new("backup/extra")?,
+                    },
+                ]
+            );
+            test_assert_eq!(count_files(&target).await?, 8);
+
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            panic!(error.to_string());
+        }
+    }
+}
@@ -17,21 +17,22 @@
 use std::cmp::Ord;
 use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
 use std::fmt;
 use std::fmt::Display;
-use std::fs::{metadata, read, read_dir, remove_file, DirEntry, File};
+use std::fs::{create_dir_all, metadata, read, read_dir, remove_dir, remove_file, DirEntry, File};
 use std::io;
 use std::io::{BufWriter, Write};
 use std::net::SocketAddr;
 use std::net::TcpListener;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::{Duration, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use base64::encode;
 use filetime::{set_file_mtime, FileTime};
 use futures::channel::oneshot::{channel, Sender};
-use futures::future::FutureExt;
+use futures::future::{ready, FutureExt};
 use futures::lock::Mutex;
 use futures::stream::{iter, TryStreamExt};
 use http::header;
@@ -46,31 +47,51 @@ use sha1::Sha1;
 use tokio::spawn;
 use uuid::Uuid;
 
+use file_store::backends::b2::{B2Backend, B2BackendBuilder};
+use file_store::DataStream;
+
 use storage_types::b2::v2::requests::*;
 use storage_types::b2::v2::responses::*;
 use storage_types::b2::v2::{
     percent_decode, BucketType, FileAction, Int, UserFileInfo, B2_HEADER_CONTENT_SHA1,
-    B2_HEADER_FILE_INFO_PREFIX, B2_HEADER_FILE_NAME, B2_HEADER_PART_NUMBER, LAST_MODIFIED_KEY,
+    B2_HEADER_FILE_ID, B2_HEADER_FILE_INFO_PREFIX, B2_HEADER_FILE_NAME, B2_HEADER_PART_NUMBER,
+    CONTENT_SHA1_AT_END, LAST_MODIFIED_KEY,
 };
 
-use crate::runner::TestResult;
+use super::byte_range;
+use crate::runner::{TestError, TestResult};
 
 const TEST_KEY_ID: &str = "foo";
 const TEST_KEY: &str = "bar";
 const TEST_ACCOUNT_ID: &str = "foobarbaz";
 
+/// A key that can only access a single bucket.
+///
+/// Unlike B2 the mock will not list buckets for this key so that tests can
+/// check that no lookups are needed.
+const TEST_RESTRICTED_KEY_ID: &str = "restricted";
+const TEST_RESTRICTED_BUCKET: &str = "dir1";
+
+/// How many uploads can use an upload auth token before it expires?
+const UPLOAD_AUTH_USES: usize = 3;
+
 /// How many uses can an auth token see before it expires?
 const DEFAULT_FILE_COUNT: usize = 2;
 const BUCKET_ID_PREFIX: &str = "bkt_";
 const FILE_ID_PREFIX: &str = "id_";
+const HIDE_ID_PREFIX: &str = "hide_";
 
 type B2Result = Result<Response<Body>, B2Error>;
 
+/// The content type and file info stored for a file.
+type FileMetadata = (String, UserFileInfo);
+
 #[derive(Debug)]
 struct B2Error {
     status: StatusCode,
     code: String,
     message: String,
+    retry_after: Option<String>,
 }
 
 impl B2Error {
@@ -83,6 +104,7 @@ impl B2Error {
             status,
             code: code.to_string(),
             message: message.to_string(),
+            retry_after: None,
         }
     }
 
@@ -180,8 +202,13 @@ impl From<B2Error> for Response<Body> {
             message: error.message.clone(),
         };
 
-        Response::builder()
-            .status(error.status)
+        let mut builder = Response::builder();
+        builder.status(error.status);
+        if let Some(retry_after) = error.retry_after {
+            builder.header(header::RETRY_AFTER, retry_after);
+        }
+
+        builder
             .body(
                 to_string_pretty(&response)
                     .expect("Failed to serialize error.")
@@ -239,6 +266,67 @@ where
     }
 }
 
+/// Reads the data uploaded in a request, checking its length and hash. Returns
+/// the data and its hash, which may have been sent after the data.
+async fn read_upload(head: &Parts, mut body: Body) -> Result<(Vec<u8>, String), B2Error> {
+    let expected_sha1 = header_or_error(&head.headers, B2_HEADER_CONTENT_SHA1)?;
+    let expected_length: u64 = match header_or_error(&head.headers, header::CONTENT_LENGTH)?.parse()
+    {
+        Ok(len) => len,
+        Err(_) => {
+            return Err(B2Error::invalid_parameters(
+                "Content-Length header could not be parsed.",
+            ))
+        }
+    };
+
+    let mut data: Vec<u8> = Vec::new();
+    loop {
+        match body.next().await {
+            Some(Ok(chunk)) => data.extend_from_slice(&chunk),
+            Some(Err(e)) => {
+                return Err(B2Error::request_timeout(format!(
+                    "Failed to upload data: {}",
+                    e
+                )))
+            }
+            None => break,
+        }
+    }
+
+    if data.len() as u64 != expected_length {
+        return Err(B2Error::invalid_parameters(
+            "Content-Length header was not set correctly.",
+        ));
+    }
+
+    let expected_sha1 = if expected_sha1 == CONTENT_SHA1_AT_END {
+        if data.len() < 40 {
+            return Err(B2Error::invalid_parameters(
+                "Data was too short to include a hash.",
+            ));
+        }
+
+        let hash = data.split_off(data.len() - 40);
+        match String::from_utf8(hash) {
+            Ok(s) => s,
+            Err(_) => return Err(B2Error::invalid_parameters("Hash was not valid utf-8.")),
+        }
+    } else {
+        expected_sha1
+    };
+
+    let mut hasher = Sha1::new();
+    hasher.update(&data);
+    if expected_sha1 != hasher.hexdigest() {
+        return Err(B2Error::invalid_parameters(
+            "Expected hash did not match data.",
+        ));
+    }
+
+    Ok((data, expected_sha1))
+}
+
 macro_rules! api_response {
     ($body:expr) => {
         Ok(Response::builder()
@@ -274,6 +362,8 @@ struct FileLister {
     delimiter: Option<String>,
     state: Vec<(String, Vec<Result<DirEntry, B2Error>>)>,
     last_name: Option<String>,
+    metadata: HashMap<PathBuf, FileMetadata>,
+    hashes: HashMap<PathBuf, String>,
 }
 
 impl FileLister {
@@ -282,6 +372,8 @@ impl FileLister {
         dir: &Path,
         prefix: &str,
         delimiter: &Option<String>,
+        metadata: HashMap<PathBuf, FileMetadata>,
+        hashes: HashMap<PathBuf, String>,
     ) -> Result<FileLister, B2Error> {
         let mut lister = FileLister {
             bucket_id: bucket_id.to_owned(),
@@ -289,6 +381,8 @@ impl FileLister {
             delimiter: delimiter.to_owned(),
             state: Default::default(),
             last_name: None,
+            metadata,
+            hashes,
         };
 
         lister.push_state("", dir)?;
@@ -358,7 +452,12 @@ impl FileLister {
                             }
                         }
 
-                        let mut info = UserFileInfo::new();
+                        let (content_type, mut info) = match self.metadata.get(&entry.path()) {
+                            Some((content_type, info)) => {
+                                (Some(content_type.clone()), info.clone())
+                            }
+                            None => (None, UserFileInfo::new()),
+                        };
                         if let Ok(time) = meta.modified() {
                             if let Ok(dur) = time.duration_since(UNIX_EPOCH) {
                                 info.insert(
@@ -373,8 +472,8 @@ impl FileLister {
                             action: FileAction::Upload,
                             bucket_id: self.bucket_id.clone(),
                             content_length: meta.len(),
-                            content_sha1: None,
-                            content_type: None,
+                            content_sha1: self.hashes.get(&entry.path()).cloned(),
+                            content_type,
                             file_id: Some(format!("{}{}", FILE_ID_PREFIX, entry.path().display())),
                             file_info: info,
                             file_name: file_path,
@@ -424,32 +523,142 @@ impl Iterator for FileLister {
 struct LargeUpload {
     file_name: String,
     bucket_id: String,
+    metadata: FileMetadata,
     auth: HashSet<String>,
     parts: HashMap<usize, (Vec<Chunk>, String)>,
+    started: Int,
 }
 
 impl LargeUpload {
-    fn new(file_name: &str, bucket_id: &str) -> LargeUpload {
+    fn new(file_name: &str, bucket_id: &str, metadata: FileMetadata) -> LargeUpload {
         LargeUpload {
             file_name: file_name.to_owned(),
             bucket_id: bucket_id.to_owned(),
+            metadata,
             auth: Default::default(),
             parts: Default::default(),
+            started: now_millis(),
         }
     }
 }
 
+/// An error that the mock server returns instead of handling a request.
+#[derive(Debug, Clone)]
+pub struct MockFailure {
+    pub status: StatusCode,
+    pub code: &'static str,
+    /// The value to send in a `Retry-After` header, either a number of seconds
+    /// or an HTTP date.
+    pub retry_after: Option<String>,
+    /// Only fail calls to this API method, any request when `None`.
+    pub method: Option<&'static str>,
+    /// The error message to send, a short default when `None`.
+    pub message: Option<String>,
+}
+
+impl From<MockFailure> for B2Error {
+    fn from(failure: MockFailure) -> B2Error {
+        let message = failure
+            .message
+            .unwrap_or_else(|| String::from("Injected failure."));
+        let mut error = B2Error::new(failure.status, failure.code, message);
+        error.retry_after = failure.retry_after;
+        error
+    }
+}
+
 #[derive(Default)]
 struct B2ServerState {
     authorizations: HashMap<String, usize>,
     upload_authorizations: HashMap<String, String>,
+    /// How many uploads have used each upload auth token.
+    upload_uses: HashMap<String, usize>,
     large_uploads: HashMap<String, LargeUpload>,
+    file_metadata: HashMap<PathBuf, FileMetadata>,
+    /// The SHA1 of files uploaded in a single part.
+    content_sha1: HashMap<PathBuf, String>,
+    /// The times that hidden files were hidden.
+    hidden: HashMap<PathBuf, Int>,
+    /// Buckets whose settings have been changed from the defaults.
+    buckets: HashMap<String, Bucket>,
+    /// Authorizations for the restricted key.
+    restricted: HashSet<String>,
+    /// Failures to return for the next matching requests, other than
+    /// authorizing.
+    failures: Vec<MockFailure>,
+    /// How many times each API method has been called.
+    calls: HashMap<String, usize>,
 }
 
 impl B2ServerState {
     fn new() -> B2ServerState {
         Default::default()
     }
+
+    /// Counts a use of an upload auth token, failing once it has expired.
+    fn use_upload_auth(&mut self, auth: &str) -> Result<(), B2Error> {
+        let uses = self.upload_uses.entry(auth.to_owned()).or_insert(0);
+        if *uses >= UPLOAD_AUTH_USES {
+            return Err(B2Error::new(
+                StatusCode::UNAUTHORIZED,
+                "expired_auth_token",
+                "Upload auth token has expired.",
+            ));
+        }
+
+        *uses += 1;
+        Ok(())
+    }
+
+    /// Gets the settings for the named bucket.
+    fn bucket(&self, name: &str) -> Bucket {
+        match self.buckets.get(name) {
+            Some(bucket) => bucket.clone(),
+            None => Bucket {
+                account_id: String::from(TEST_ACCOUNT_ID),
+                bucket_id: format!("{}{}", BUCKET_ID_PREFIX, name),
+                bucket_name: name.to_owned(),
+                bucket_type: BucketType::Public,
+                bucket_info: Default::default(),
+                cors_rules: Default::default(),
+                lifecycle_rules: Default::default(),
+                revision: 0,
+            },
+        }
+    }
+
+    /// Records a new version of a file, replacing any hidden marker.
+    fn file_written(&mut self, path: &Path, metadata: FileMetadata, sha1: Option<String>) {
+        self.file_metadata.insert(path.to_owned(), metadata);
+        match sha1 {
+            Some(hash) => self.content_sha1.insert(path.to_owned(), hash),
+            None => self.content_sha1.remove(path),
+        };
+        self.hidden.remove(path);
+    }
+}
+
+fn now_millis() -> Int {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as Int)
+        .unwrap_or(0)
+}
+
+/// Builds the version that hides a file.
+fn hide_marker(bucket_id: &str, file_name: &str, path: &Path, hidden: Int) -> FileInfo {
+    FileInfo {
+        account_id: TEST_ACCOUNT_ID.to_owned(),
+        action: FileAction::Hide,
+        bucket_id: bucket_id.to_owned(),
+        content_length: 0,
+        content_sha1: None,
+        content_type: None,
+        file_id: Some(format!("{}{}", HIDE_ID_PREFIX, path.display())),
+        file_info: Default::default(),
+        file_name: file_name.to_owned(),
+        upload_timestamp: hidden,
+    }
 }
 
 #[derive(Clone)]
@@ -464,24 +673,36 @@ impl B2Server {
     async fn b2_authorize_account(self, auth: &str) -> B2Result {
         let mut state = self.state.lock().await;
         let expected = format!("Basic {}", encode(&format!("{}:{}", TEST_KEY_ID, TEST_KEY)));
+        let restricted = format!(
+            "Basic {}",
+            encode(&format!("{}:{}", TEST_RESTRICTED_KEY_ID, TEST_KEY))
+        );
 
         let base = format!("http://{}", self.addr);
         let api_url = format!("{}/api", base);
         let download_url = format!("{}/download", base);
 
-        if expected == auth {
+        if expected == auth || restricted == auth {
             let uuid = Uuid::new_v4().to_string();
             state.authorizations.insert(uuid.clone(), 0);
 
+            let mut allowed = AuthorizeAccountAllowed {
+                capabilities: vec![],
+                bucket_id: None,
+                bucket_name: None,
+                name_prefix: None,
+            };
+
+            if restricted == auth {
+                state.restricted.insert(uuid.clone());
+                allowed.bucket_id = Some(format!("{}{}", BUCKET_ID_PREFIX, TEST_RESTRICTED_BUCKET));
+                allowed.bucket_name = Some(TEST_RESTRICTED_BUCKET.to_owned());
+            }
+
             api_response!(AuthorizeAccountResponse {
                 account_id: String::from(TEST_ACCOUNT_ID),
                 authorization_token: uuid,
-                allowed: AuthorizeAccountAllowed {
-                    capabilities: vec![],
-                    bucket_id: None,
-                    bucket_name: None,
-                    name_prefix: None,
-                },
+                allowed,
                 api_url,
                 download_url,
                 recommended_part_size: 1000,
@@ -496,8 +717,14 @@ impl B2Server {
         }
     }
 
-    async fn b2_list_buckets(self, _head: Parts, body: ListBucketsRequest) -> B2Result {
-        if body.account_id != TEST_ACCOUNT_ID {
+    async fn b2_list_buckets(self, head: Parts, body: ListBucketsRequest) -> B2Result {
+        let token = head
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("");
+        if body.account_id != TEST_ACCOUNT_ID || self.state.lock().await.restricted.contains(token)
+        {
             return Err(B2Error::new(
                 StatusCode::UNAUTHORIZED,
                 "unauthorized",
@@ -505,12 +732,6 @@ impl B2Server {
             ));
         }
 
-        if !body.bucket_types.includes(BucketType::Public) {
-            return api_response!(ListBucketsResponse {
-                buckets: Vec::new()
-            });
-        }
-
         let name = match (body.bucket_id, body.bucket_name) {
             (Some(id), None) => {
                 if !id.starts_with(BUCKET_ID_PREFIX) {
@@ -527,7 +748,7 @@ impl B2Server {
             }
         };
 
-        if let Some(name) = name {
+        let names: Vec<String> = if let Some(name) = name {
             let mut path = self.root.clone();
             path.push(&name);
 
@@ -537,58 +758,160 @@ impl B2Server {
                 return Err(B2Error::not_found(&path));
             }
 
-            return api_response!(ListBucketsResponse {
-                buckets: vec![Bucket {
-                    account_id: String::from(TEST_ACCOUNT_ID),
-                    bucket_id: format!("{}{}", BUCKET_ID_PREFIX, &name),
-                    bucket_name: name.to_owned(),
-                    bucket_type: BucketType::Public,
-                    bucket_info: Default::default(),
-                    cors_rules: Default::default(),
-                    lifecycle_rules: Default::default(),
-                    revision: 0,
-                }]
-            });
-        }
-
-        let buckets: Vec<Bucket> = read_dir(&self.root)
-            .into_path_err(&self.root)?
-            .filter_map(|result| {
-                let entry = match result {
-                    Ok(e) => e,
-                    _ => return None,
-                };
+            vec![name]
+        } else {
+            read_dir(&self.root)
+                .into_path_err(&self.root)?
+                .filter_map(|result| {
+                    let entry = match result {
+                        Ok(e) => e,
+                        _ => return None,
+                    };
 
-                match entry.metadata() {
-                    Ok(m) => {
-                        if !m.is_dir() {
-                            return None;
+                    match entry.metadata() {
+                        Ok(m) => {
+                            if !m.is_dir() {
+                                return None;
+                            }
                         }
+                        _ => return None,
                     }
-                    _ => return None,
-                }
 
-                let name = match entry.file_name().into_string() {
-                    Ok(s) => s,
-                    _ => panic!("Path at {} uses an invalid name.", entry.path().display()),
-                };
-
-                Some(Bucket {
-                    account_id: String::from(TEST_ACCOUNT_ID),
-                    bucket_id: format!("{}{}", BUCKET_ID_PREFIX, name),
-                    bucket_name: name.to_owned(),
-                    bucket_type: BucketType::Public,
-                    bucket_info: Default::default(),
-                    cors_rules: Default::default(),
-                    lifecycle_rules: Default::default(),
-                    revision: 0,
+                    match entry.file_name().into_string() {
+                        Ok(s) => Some(s),
+                        _ => panic!("Path at {} uses an invalid name.", entry.path().display()),
+                    }
                 })
-            })
+                .collect()
+        };
+
+        let state = self.state.lock().await;
+        let buckets: Vec<Bucket> = names
+            .iter()
+            .map(|name| state.bucket(name))
+            .filter(|bucket| body.bucket_types.includes(bucket.bucket_type.clone()))
             .collect();
 
         api_response!(ListBucketsResponse { buckets })
     }
 
+    async fn b2_create_bucket(self, _head: Parts, body: CreateBucketRequest) -> B2Result {
+        if body.account_id != TEST_ACCOUNT_ID {
+            return Err(B2Error::new(
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+                "This key cannot create buckets in the requested account.",
+            ));
+        }
+
+        if body.bucket_name.is_empty() || body.bucket_name.contains('/') {
+            return Err(B2Error::invalid_parameters("Invalid bucket name."));
+        }
+
+        let mut path = self.root.clone();
+        path.push(&body.bucket_name);
+        if metadata(&path).is_ok() {
+            return Err(B2Error::new(
+                StatusCode::BAD_REQUEST,
+                "duplicate_bucket_name",
+                format!("Bucket name is already in use: {}", body.bucket_name),
+            ));
+        }
+
+        create_dir_all(&path).into_path_err(&path)?;
+
+        let bucket = Bucket {
+            account_id: body.account_id,
+            bucket_id: format!("{}{}", BUCKET_ID_PREFIX, body.bucket_name),
+            bucket_name: body.bucket_name.clone(),
+            bucket_type: body.bucket_type,
+            bucket_info: body.bucket_info.unwrap_or_default(),
+            cors_rules: body.cors_rules.unwrap_or_default(),
+            lifecycle_rules: body.lifecycle_rules.unwrap_or_default(),
+            revision: 1,
+        };
+
+        self.state
+            .lock()
+            .await
+            .buckets
+            .insert(body.bucket_name, bucket.clone());
+
+        api_response!(bucket)
+    }
+
+    /// Finds the name of an existing bucket from its id.
+    fn bucket_name(&self, bucket_id: &str) -> Result<String, B2Error> {
+        if !bucket_id.starts_with(BUCKET_ID_PREFIX) {
+            return Err(B2Error::invalid_bucket_id(bucket_id));
+        }
+
+        let name = &bucket_id[BUCKET_ID_PREFIX.len()..];
+        match metadata(self.root.join(name)) {
+            Ok(ref meta) if meta.is_dir() => Ok(name.to_owned()),
+            _ => Err(B2Error::invalid_bucket_id(bucket_id)),
+        }
+    }
+
+    async fn b2_update_bucket(self, _head: Parts, body: UpdateBucketRequest) -> B2Result {
+        let name = self.bucket_name(&body.bucket_id)?;
+        let mut state = self.state.lock().await;
+        let mut bucket = state.bucket(&name);
+
+        if let Some(revision) = body.if_revision_is {
+            if revision != bucket.revision {
+                return Err(B2Error::new(
+                    StatusCode::CONFLICT,
+                    "conflict",
+                    format!("Bucket revision is {}", bucket.revision),
+                ));
+            }
+        }
+
+        if let Some(bucket_type) = body.bucket_type {
+            bucket.bucket_type = bucket_type;
+        }
+        if let Some(bucket_info) = body.bucket_info {
+            bucket.bucket_info = bucket_info;
+        }
+        if let Some(cors_rules) = body.cors_rules {
+            bucket.cors_rules = cors_rules;
+        }
+        if let Some(lifecycle_rules) = body.lifecycle_rules {
+            bucket.lifecycle_rules = lifecycle_rules;
+        }
+        bucket.revision += 1;
+
+        state.buckets.insert(name, bucket.clone());
+
+        api_response!(bucket)
+    }
+
+    async fn b2_delete_bucket(self, _head: Parts, body: DeleteBucketRequest) -> B2Result {
+        let name = self.bucket_name(&body.bucket_id)?;
+        let path = self.root.join(&name);
+
+        let mut state = self.state.lock().await;
+        let has_uploads = state
+            .large_uploads
+            .values()
+            .any(|upload| upload.bucket_id == body.bucket_id);
+        let mut entries = read_dir(&path).into_path_err(&path)?;
+        if has_uploads || entries.next().is_some() {
+            return Err(B2Error::new(
+                StatusCode::BAD_REQUEST,
+                "cannot_delete_non_empty_bucket",
+                format!("Bucket is not empty: {}", name),
+            ));
+        }
+
+        let bucket = state.bucket(&name);
+        remove_dir(&path).into_path_err(&path)?;
+        state.buckets.remove(&name);
+
+        api_response!(bucket)
+    }
+
     async fn b2_list_file_names(self, _head: Parts, body: ListFileNamesRequest) -> B2Result {
         if !body.bucket_id.starts_with(BUCKET_ID_PREFIX) {
             return Err(B2Error::invalid_bucket_id(&body.bucket_id));
@@ -597,15 +920,25 @@ impl B2Server {
         let mut dir = self.root.clone();
         dir.push(&body.bucket_id[BUCKET_ID_PREFIX.len()..]);
         let start = body.start_file_name.unwrap_or_else(String::new);
+        let (metadata, hashes, hidden) = {
+            let state = self.state.lock().await;
+            (
+                state.file_metadata.clone(),
+                state.content_sha1.clone(),
+                state.hidden.clone(),
+            )
+        };
 
         let lister = FileLister::new(
             &body.bucket_id,
             dir.as_path(),
             &body.prefix.unwrap_or_else(String::new),
             &body.delimiter,
+            metadata,
+            hashes,
         )?
         .filter(|result| match result {
-            Ok(info) => info.file_name >= start,
+            Ok(info) => info.file_name >= start && !hidden.contains_key(&dir.join(&info.file_name)),
             Err(_) => true,
         });
 
@@ -636,12 +969,22 @@ impl B2Server {
         let mut dir = self.root.clone();
         dir.push(&body.bucket_id[BUCKET_ID_PREFIX.len()..]);
         let start = body.start_file_name.unwrap_or_else(String::new);
+        let (metadata, hashes, hidden) = {
+            let state = self.state.lock().await;
+            (
+                state.file_metadata.clone(),
+                state.content_sha1.clone(),
+                state.hidden.clone(),
+            )
+        };
 
         let lister = FileLister::new(
             &body.bucket_id,
             dir.as_path(),
             &body.prefix.unwrap_or_else(String::new),
             &body.delimiter,
+            metadata,
+            hashes,
         )?
         .filter(|result| match result {
             Ok(info) => info.file_name >= start,
@@ -658,6 +1001,16 @@ impl B2Server {
             let info = result?;
 
             if response.files.len() < DEFAULT_FILE_COUNT {
+                // Newer versions are listed first.
+                let path = dir.join(&info.file_name);
+                if let Some(time) = hidden.get(&path) {
+                    response.files.push(hide_marker(
+                        &body.bucket_id,
+                        &info.file_name,
+                        &path,
+                        *time,
+                    ));
+                }
                 response.files.push(info);
             } else if response.files.len() == DEFAULT_FILE_COUNT {
                 response.next_file_name = Some(info.file_name);
@@ -674,6 +1027,21 @@ impl B2Server {
         _head: Parts,
         body: DeleteFileVersionRequest,
     ) -> B2Result {
+        if body.file_id.starts_with(HIDE_ID_PREFIX) {
+            let path = Path::new(&body.file_id[HIDE_ID_PREFIX.len()..]);
+            return match self.state.lock().await.hidden.remove(path) {
+                Some(_) => api_response!(DeleteFileVersionResponse {
+                    file_id: body.file_id,
+                    file_name: body.file_name,
+                }),
+                None => Err(B2Error::new(
+                    StatusCode::BAD_REQUEST,
+                    "file_not_present",
+                    format!("File not present: {} {}", body.file_name, body.file_id),
+                )),
+            };
+        }
+
         if !body.file_id.starts_with(FILE_ID_PREFIX) {
             return Err(B2Error::new(
                 StatusCode::BAD_REQUEST,
@@ -695,6 +1063,9 @@ impl B2Server {
                 }
 
                 remove_file(path)?;
+                let mut state = self.state.lock().await;
+                state.file_metadata.remove(Path::new(path));
+                state.content_sha1.remove(Path::new(path));
 
                 api_response!(DeleteFileVersionResponse {
                     file_id: body.file_id,
@@ -715,7 +1086,55 @@ impl B2Server {
         }
     }
 
-    async fn b2_download_file(self, path: &str) -> B2Result {
+    async fn b2_hide_file(self, _head: Parts, body: HideFileRequest) -> B2Result {
+        if !body.bucket_id.starts_with(BUCKET_ID_PREFIX) {
+            return Err(B2Error::invalid_bucket_id(&body.bucket_id));
+        }
+
+        let mut path = self.root.clone();
+        path.push(&body.bucket_id[BUCKET_ID_PREFIX.len()..]);
+        path.push(&body.file_name);
+
+        match metadata(&path) {
+            Ok(ref meta) if meta.is_file() => (),
+            _ => {
+                return Err(B2Error::new(
+                    StatusCode::BAD_REQUEST,
+                    "file_not_present",
+                    format!("File not present: {}", body.file_name),
+                ))
+            }
+        }
+
+        let time = now_millis();
+        self.state.lock().await.hidden.insert(path.clone(), time);
+
+        api_response!(hide_marker(&body.bucket_id, &body.file_name, &path, time))
+    }
+
+    async fn b2_download_file_by_id(self, head: &Parts) -> B2Result {
+        let file_id = head
+            .uri
+            .query()
+            .unwrap_or("")
+            .split('&')
+            .find(|param| param.starts_with("fileId="))
+            .map(|param| percent_decode(&param[7..]))
+            .ok_or_else(|| B2Error::invalid_parameters("No file id provided."))?
+            .map_err(|_| B2Error::invalid_parameters("File id was invalid utf-8."))?;
+
+        if !file_id.starts_with(FILE_ID_PREFIX) {
+            return Err(B2Error::new(
+                StatusCode::BAD_REQUEST,
+                "file_not_present",
+                format!("File not present: {}", file_id),
+            ));
+        }
+
+        self.send_file(head, PathBuf::from(&file_id[FILE_ID_PREFIX.len()..]))
+    }
+
+    async fn b2_download_file(self, head: &Parts, path: &str) -> B2Result {
         let path = match percent_decode(path) {
             Ok(s) => s,
             Err(_) => return Err(B2Error::invalid_parameters("File path was invalid utf-8.")),
@@ -724,12 +1143,47 @@ impl B2Server {
         let mut file = self.root.clone();
         file.push(path);
 
+        if self.state.lock().await.hidden.contains_key(&file) {
+            return Err(B2Error::not_found(&file));
+        }
+
+        self.send_file(head, file)
+    }
+
+    fn send_file(&self, head: &Parts, file: PathBuf) -> B2Result {
         let meta = metadata(&file).into_path_err(&file)?;
         if !meta.is_file() {
             return Err(B2Error::not_found(&file));
         }
 
-        let source = read(&file).into_path_err(file)?;
+        let mut source = read(&file).into_path_err(file)?;
+        let size = source.len();
+        let mut builder = Response::builder();
+        builder.status(StatusCode::OK).header(
+            B2_HEADER_FILE_ID,
+            format!("{}{}", FILE_ID_PREFIX, file.display()),
+        );
+
+        if let Some(value) = head.headers.get(header::RANGE) {
+            let range = value
+                .to_str()
+                .ok()
+                .and_then(|v| byte_range(v, size))
+                .ok_or_else(|| {
+                    B2Error::new(
+                        StatusCode::RANGE_NOT_SATISFIABLE,
+                        "range_not_satisfiable",
+                        format!("Invalid range {:?} for a file of {} bytes.", value, size),
+                    )
+                })?;
+
+            builder.status(StatusCode::PARTIAL_CONTENT).header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", range.start, range.end - 1, size),
+            );
+            source = source[range].to_vec();
+        }
+
         let mut len = source.len() / 5;
         if len == 0 {
             len = 1;
@@ -744,8 +1198,7 @@ impl B2Server {
             })
             .collect();
 
-        Ok(Response::builder()
-            .status(StatusCode::OK)
+        Ok(builder
             .body(Body::wrap_stream(iter(blocks)))
             .expect("Failed to build response."))
     }
@@ -794,21 +1247,11 @@ impl B2Server {
         })
     }
 
-    async fn b2_upload_file(self, bucket_id: &str, head: Parts, mut body: Body) -> B2Result {
+    async fn b2_upload_file(self, bucket_id: &str, head: Parts, body: Body) -> B2Result {
         let file = match percent_decode(&header_or_error(&head.headers, B2_HEADER_FILE_NAME)?) {
             Ok(s) => s,
             Err(_) => return Err(B2Error::invalid_parameters("Filename was not valid utf-8.")),
         };
-        let expected_sha1 = header_or_error(&head.headers, B2_HEADER_CONTENT_SHA1)?;
-        let expected_length: u64 =
-            match header_or_error(&head.headers, header::CONTENT_LENGTH)?.parse() {
-                Ok(len) => len,
-                Err(_) => {
-                    return Err(B2Error::invalid_parameters(
-                        "Content-Length header could not be parsed.",
-                    ))
-                }
-            };
 
         let last_modified = head
             .headers
@@ -820,42 +1263,26 @@ impl B2Server {
             .and_then(|t| t.parse::<u64>().ok())
             .map(|d| UNIX_EPOCH + Duration::from_millis(d));
 
-        let mut path = self.root.clone();
-        path.push(&bucket_id[BUCKET_ID_PREFIX.len()..]);
-        path.push(&file);
-        let mut writer = File::create(&path)?;
-
-        let mut length: Int = 0;
-        let mut hasher = Sha1::new();
-
-        loop {
-            match body.next().await {
-                Some(Ok(chunk)) => {
-                    writer.write_all(&chunk)?;
-                    hasher.update(&chunk);
-                    length += chunk.len() as Int;
-                }
-                Some(Err(e)) => {
-                    return Err(B2Error::request_timeout(format!(
-                        "Failed to upload data: {}",
-                        e
-                    )))
-                }
-                None => break,
+        let content_type = header_or_error(&head.headers, header::CONTENT_TYPE)?;
+        let mut file_info = UserFileInfo::new();
+        let prefix = B2_HEADER_FILE_INFO_PREFIX.to_lowercase();
+        for (name, value) in head.headers.iter() {
+            if name.as_str().starts_with(&prefix) {
+                let value = value
+                    .to_str()
+                    .ok()
+                    .and_then(|v| percent_decode(v).ok())
+                    .ok_or_else(|| B2Error::invalid_parameters("File info was not valid utf-8."))?;
+                file_info.insert(name.as_str()[prefix.len()..].to_owned(), value);
             }
         }
 
-        if length != expected_length {
-            return Err(B2Error::invalid_parameters(
-                "Content-Length header was not set correctly.",
-            ));
-        }
+        let (data, sha1) = read_upload(&head, body).await?;
 
-        if expected_sha1 != hasher.hexdigest() {
-            return Err(B2Error::invalid_parameters(
-                "Expected hash did not match data.",
-            ));
-        }
+        let mut path = self.root.clone();
+        path.push(&bucket_id[BUCKET_ID_PREFIX.len()..]);
+        path.push(&file);
+        File::create(&path)?.write_all(&data)?;
 
         if let Some(time) = last_modified {
             if let Err(e) = set_file_mtime(&path, FileTime::from_system_time(time)) {
@@ -866,15 +1293,21 @@ impl B2Server {
             }
         }
 
+        self.state.lock().await.file_written(
+            &path,
+            (content_type.clone(), file_info.clone()),
+            Some(sha1.clone()),
+        );
+
         api_response!(UploadFileResponse {
             account_id: TEST_ACCOUNT_ID.to_owned(),
             action: FileAction::Upload,
             bucket_id: bucket_id.to_owned(),
-            content_length: length,
-            content_sha1: Some(expected_sha1.to_owned()),
-            content_type: Some(String::from("application/octet-stream")),
-            file_id: Some(format!("{}", path.display())),
-            file_info: Default::default(),
+            content_length: data.len() as Int,
+            content_sha1: Some(sha1),
+            content_type: Some(content_type),
+            file_id: Some(format!("{}{}", FILE_ID_PREFIX, path.display())),
+            file_info,
             file_name: file.to_owned(),
             upload_timestamp: 0,
         })
@@ -899,9 +1332,14 @@ impl B2Server {
             )));
         }
 
+        let file_info = body.file_info.unwrap_or_default();
         state.large_uploads.insert(
             file_id.clone(),
-            LargeUpload::new(&body.file_name, &body.bucket_id),
+            LargeUpload::new(
+                &body.file_name,
+                &body.bucket_id,
+                (body.content_type.clone(), file_info.clone()),
+            ),
         );
 
         api_response!(StartLargeFileResponse {
@@ -912,7 +1350,7 @@ impl B2Server {
             content_sha1: None,
             content_type: Some(body.content_type),
             file_id: Some(file_id),
-            file_info: Default::default(),
+            file_info,
             file_name: body.file_name,
             upload_timestamp: 0,
         })
@@ -935,17 +1373,7 @@ impl B2Server {
         }
     }
 
-    async fn b2_upload_part(self, file_id: String, head: Parts, mut body: Body) -> B2Result {
-        let expected_sha1 = header_or_error(&head.headers, B2_HEADER_CONTENT_SHA1)?;
-        let expected_length: u64 =
-            match header_or_error(&head.headers, header::CONTENT_LENGTH)?.parse() {
-                Ok(len) => len,
-                Err(_) => {
-                    return Err(B2Error::invalid_parameters(
-                        "Content-Length header could not be parsed.",
-                    ))
-                }
-            };
+    async fn b2_upload_part(self, file_id: String, head: Parts, body: Body) -> B2Result {
         let part_number: usize =
             match header_or_error(&head.headers, B2_HEADER_PART_NUMBER)?.parse() {
                 Ok(len) => len,
@@ -962,38 +1390,8 @@ impl B2Server {
             ));
         }
 
-        let mut length: Int = 0;
-        let mut hasher = Sha1::new();
-        let mut data: Vec<Chunk> = Default::default();
-
-        loop {
-            match body.next().await {
-                Some(Ok(chunk)) => {
-                    hasher.update(&chunk);
-                    length += chunk.len() as Int;
-                    data.push(chunk);
-                }
-                Some(Err(e)) => {
-                    return Err(B2Error::request_timeout(format!(
-                        "Failed to upload data: {}",
-                        e
-                    )))
-                }
-                None => break,
-            }
-        }
-
-        if length != expected_length {
-            return Err(B2Error::invalid_parameters(
-                "Content-Length header was not set correctly.",
-            ));
-        }
-
-        if expected_sha1 != hasher.hexdigest() {
-            return Err(B2Error::invalid_parameters(
-                "Expected hash did not match data.",
-            ));
-        }
+        let (data, sha1) = read_upload(&head, body).await?;
+        let length = data.len() as Int;
 
         let mut state = self.state.lock().await;
         let upload = match state.large_uploads.get_mut(&file_id) {
@@ -1007,13 +1405,13 @@ impl B2Server {
 
         upload
             .parts
-            .insert(part_number - 1, (data, expected_sha1.clone()));
+            .insert(part_number - 1, (vec![Chunk::from(data)], sha1.clone()));
 
         api_response!(UploadPartResponse {
             file_id,
             part_number,
-            content_length: expected_length,
-            content_sha1: expected_sha1,
+            content_length: length,
+            content_sha1: sha1,
             upload_timestamp: 0,
         })
     }
@@ -1054,16 +1452,262 @@ impl B2Server {
             }
         }
 
+        let (content_type, file_info) = upload.metadata;
+        self.state.lock().await.file_written(
+            &path,
+            (content_type.clone(), file_info.clone()),
+            None,
+        );
+
         api_response!(FinishLargeFileResponse {
             account_id: String::from(TEST_ACCOUNT_ID),
             action: FileAction::Upload,
             bucket_id: upload.bucket_id,
             content_length: length,
             content_sha1: None,
-            content_type: None,
+            content_type: Some(content_type),
             file_id: Some(body.file_id),
-            file_info: Default::default(),
+            file_info,
+            file_name: upload.file_name,
+            upload_timestamp: 0,
+        })
+    }
+
+    async fn b2_list_unfinished_large_files(
+        self,
+        _head: Parts,
+        body: ListUnfinishedLargeFilesRequest,
+    ) -> B2Result {
+        if !body.bucket_id.starts_with(BUCKET_ID_PREFIX) {
+            return Err(B2Error::invalid_bucket_id(&body.bucket_id));
+        }
+
+        let prefix = body.name_prefix.unwrap_or_default();
+        let state = self.state.lock().await;
+        let mut files: Vec<FileInfo> = state
+            .large_uploads
+            .iter()
+            .filter(|(_, upload)| {
+                upload.bucket_id == body.bucket_id && upload.file_name.starts_with(&prefix)
+            })
+            .map(|(file_id, upload)| FileInfo {
+                account_id: TEST_ACCOUNT_ID.to_owned(),
+                action: FileAction::Start,
+                bucket_id: upload.bucket_id.clone(),
+                content_length: 0,
+                content_sha1: None,
+                content_type: Some(upload.metadata.0.clone()),
+                file_id: Some(file_id.clone()),
+                file_info: upload.metadata.1.clone(),
+                file_name: upload.file_name.clone(),
+                upload_timestamp: upload.started,
+            })
+            .collect();
+        files.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+
+        api_response!(ListUnfinishedLargeFilesResponse {
+            files,
+            next_file_id: None,
+        })
+    }
+
+    async fn b2_list_parts(self, _head: Parts, body: ListPartsRequest) -> B2Result {
+        let state = self.state.lock().await;
+        let upload = match state.large_uploads.get(&body.file_id) {
+            Some(upload) => upload,
+            None => return Err(B2Error::invalid_parameters("Unknown file id.")),
+        };
+
+        let mut parts: Vec<UploadPartResponse> = upload
+            .parts
+            .iter()
+            .map(|(index, (data, hash))| UploadPartResponse {
+                file_id: body.file_id.clone(),
+                part_number: index + 1,
+                content_length: data.iter().map(|chunk| chunk.len() as Int).sum(),
+                content_sha1: hash.clone(),
+                upload_timestamp: upload.started,
+            })
+            .filter(|part| part.part_number >= body.start_part_number.unwrap_or(1))
+            .collect();
+        parts.sort_by(|a, b| a.part_number.cmp(&b.part_number));
+
+        api_response!(ListPartsResponse {
+            parts,
+            next_part_number: None,
+        })
+    }
+
+    async fn b2_cancel_large_file(self, _head: Parts, body: CancelLargeFileRequest) -> B2Result {
+        let upload = match self.state.lock().await.large_uploads.remove(&body.file_id) {
+            Some(upload) => upload,
+            None => return Err(B2Error::invalid_parameters("Unknown file id.")),
+        };
+
+        api_response!(CancelLargeFileResponse {
+            file_id: body.file_id,
+            account_id: TEST_ACCOUNT_ID.to_owned(),
+            bucket_id: upload.bucket_id,
             file_name: upload.file_name,
+        })
+    }
+
+    /// Reads the requested range of the file with the given id.
+    fn read_copy_source(
+        &self,
+        file_id: &str,
+        range: &Option<String>,
+    ) -> Result<(PathBuf, Vec<u8>), B2Error> {
+        if !file_id.starts_with(FILE_ID_PREFIX) {
+            return Err(B2Error::new(
+                StatusCode::BAD_REQUEST,
+                "file_not_present",
+                format!("File not present: {}", file_id),
+            ));
+        }
+
+        let path = PathBuf::from(&file_id[FILE_ID_PREFIX.len()..]);
+        if !path.starts_with(&self.root) || !path.is_file() {
+            return Err(B2Error::new(
+                StatusCode::BAD_REQUEST,
+                "file_not_present",
+                format!("File not present: {}", file_id),
+            ));
+        }
+
+        let data = read(&path).into_path_err(&path)?;
+        match range {
+            Some(range) => match byte_range(range, data.len()) {
+                Some(r) => Ok((path, data[r].to_vec())),
+                None => Err(B2Error::invalid_parameters(format!(
+                    "Invalid range {} for a file of {} bytes.",
+                    range,
+                    data.len()
+                ))),
+            },
+            None => Ok((path, data)),
+        }
+    }
+
+    async fn b2_copy_file(self, _head: Parts, body: CopyFileRequest) -> B2Result {
+        let (source, data) = self.read_copy_source(&body.source_file_id, &body.range)?;
+
+        let source_bucket = source
+            .strip_prefix(&self.root)
+            .ok()
+            .and_then(|p| p.iter().next())
+            .and_then(|b| b.to_str())
+            .map(|b| format!("{}{}", BUCKET_ID_PREFIX, b))
+            .ok_or_else(|| B2Error::server_error("Unable to find the source bucket."))?;
+        let bucket_id = body.destination_bucket_id.unwrap_or(source_bucket);
+
+        let (content_type, file_info) = match body.metadata_directive {
+            MetadataDirective::Copy => {
+                if body.content_type.is_some() || body.file_info.is_some() {
+                    return Err(B2Error::invalid_parameters(
+                        "contentType and fileInfo must not be set when copying metadata.",
+                    ));
+                }
+
+                let (content_type, mut info) =
+                    match self.state.lock().await.file_metadata.get(&source) {
+                        Some(stored) => stored.clone(),
+                        None => (
+                            String::from("application/octet-stream"),
+                            UserFileInfo::new(),
+                        ),
+                    };
+                let modified = metadata(&source)?.modified()?;
+                if let Ok(dur) = modified.duration_since(UNIX_EPOCH) {
+                    info.insert(LAST_MODIFIED_KEY.to_owned(), dur.as_millis().to_string());
+                }
+                (content_type, info)
+            }
+            MetadataDirective::Replace => match body.content_type {
+                Some(content_type) => (content_type, body.file_info.unwrap_or_default()),
+                None => {
+                    return Err(B2Error::invalid_parameters(
+                        "contentType must be set when replacing metadata.",
+                    ))
+                }
+            },
+        };
+
+        let mut path = self.root.clone();
+        path.push(&bucket_id[BUCKET_ID_PREFIX.len()..]);
+        path.push(&body.file_name);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+
+        let mut writer = File::create(&path)?;
+        writer.write_all(&data)?;
+        drop(writer);
+
+        let last_modified = file_info
+            .get(LAST_MODIFIED_KEY)
+            .and_then(|t| t.parse::<u64>().ok())
+            .map(|d| UNIX_EPOCH + Duration::from_millis(d));
+        if let Some(time) = last_modified {
+            if let Err(e) = set_file_mtime(&path, FileTime::from_system_time(time)) {
+                return Err(B2Error::server_error(format!(
+                    "Failed to set file modification time: {}.",
+                    e
+                )));
+            }
+        }
+
+        let mut hasher = Sha1::new();
+        hasher.update(&data);
+        let hash = hasher.hexdigest();
+
+        self.state.lock().await.file_written(
+            &path,
+            (content_type.clone(), file_info.clone()),
+            Some(hash.clone()),
+        );
+
+        api_response!(CopyFileResponse {
+            account_id: TEST_ACCOUNT_ID.to_owned(),
+            action: FileAction::Upload,
+            bucket_id,
+            content_length: data.len() as Int,
+            content_sha1: Some(hash),
+            content_type: Some(content_type),
+            file_id: Some(format!("{}{}", FILE_ID_PREFIX, path.display())),
+            file_info,
+            file_name: body.file_name,
+            upload_timestamp: 0,
+        })
+    }
+
+    async fn b2_copy_part(self, _head: Parts, body: CopyPartRequest) -> B2Result {
+        let (_, data) = self.read_copy_source(&body.source_file_id, &body.range)?;
+
+        if body.part_number < 1 {
+            return Err(B2Error::invalid_parameters("Invalid part number."));
+        }
+
+        let mut hasher = Sha1::new();
+        hasher.update(&data);
+        let hash = hasher.hexdigest();
+        let length = data.len() as Int;
+
+        let mut state = self.state.lock().await;
+        let upload = match state.large_uploads.get_mut(&body.large_file_id) {
+            Some(u) => u,
+            None => return Err(B2Error::invalid_parameters("Unknown file id.")),
+        };
+
+        upload
+            .parts
+            .insert(body.part_number - 1, (vec![data.into()], hash.clone()));
+
+        api_response!(CopyPartResponse {
+            file_id: body.large_file_id,
+            part_number: body.part_number,
+            content_length: length,
+            content_sha1: hash,
             upload_timestamp: 0,
         })
     }
@@ -1095,13 +1739,22 @@ impl B2Server {
 
     async fn call_api(self, method: &str, head: Parts, data: Chunk) -> B2Result {
         api_method!(b2_list_buckets, self, method, head, data);
+        api_method!(b2_create_bucket, self, method, head, data);
+        api_method!(b2_update_bucket, self, method, head, data);
+        api_method!(b2_delete_bucket, self, method, head, data);
         api_method!(b2_list_file_names, self, method, head, data);
         api_method!(b2_list_file_versions, self, method, head, data);
         api_method!(b2_delete_file_version, self, method, head, data);
+        api_method!(b2_hide_file, self, method, head, data);
         api_method!(b2_get_upload_url, self, method, head, data);
         api_method!(b2_start_large_file, self, method, head, data);
         api_method!(b2_get_upload_part_url, self, method, head, data);
         api_method!(b2_finish_large_file, self, method, head, data);
+        api_method!(b2_copy_file, self, method, head, data);
+        api_method!(b2_copy_part, self, method, head, data);
+        api_method!(b2_list_unfinished_large_files, self, method, head, data);
+        api_method!(b2_list_parts, self, method, head, data);
+        api_method!(b2_cancel_large_file, self, method, head, data);
 
         Err(B2Error::invalid_parameters("Invalid API method requested."))
     }
@@ -1163,21 +1816,42 @@ impl B2Server {
             }
         };
 
+        if !path.starts_with("/b2api/v2/b2_authorize_account") {
+            let mut state = self.state.lock().await;
+            let failure = state.failures.iter().position(|f| match f.method {
+                Some(method) => path.ends_with(method),
+                None => true,
+            });
+            if let Some(index) = failure {
+                return Err(state.failures.remove(index).into());
+            }
+        }
+
         if path.starts_with("/b2api/v2/b2_authorize_account") {
             self.b2_authorize_account(&auth).await
         } else if path.starts_with("/api/b2api/v2/") {
             self.check_auth(&auth).await?;
 
             let method = &path[14..];
+            *self
+                .state
+                .lock()
+                .await
+                .calls
+                .entry(method.to_owned())
+                .or_insert(0) += 1;
 
             let data = body.try_concat().await.map_err(|e| {
                 B2Error::invalid_parameters(format!("Failed to receive entire body: {}", e))
             })?;
             self.call_api(method, head, data).await
+        } else if path.starts_with("/download/b2api/v2/b2_download_file_by_id") {
+            self.check_auth(&auth).await?;
+            self.b2_download_file_by_id(&head).await
         } else if path.starts_with("/download/file/") {
             let target = &path[15..];
             self.check_auth(&auth).await?;
-            self.b2_download_file(target).await
+            self.b2_download_file(&head, target).await
         } else if path.starts_with("/upload/file/") {
             if head.method != "POST" {
                 return Err(B2Error::method_not_allowed(
@@ -1210,6 +1884,9 @@ impl B2Server {
                         ));
                     }
                 }
+
+                // An expired token is not re-added.
+                state.use_upload_auth(&auth)?;
             }
 
             let result = self.b2_upload_file(&bucket_id, head, body).await;
@@ -1248,6 +1925,9 @@ impl B2Server {
                         "Unknown part auth token.",
                     ));
                 }
+
+                // An expired token is not re-added.
+                state.use_upload_auth(&auth)?;
             }
 
             let result = self.b2_upload_part(file_id.clone(), head, body).await;
@@ -1268,6 +1948,96 @@ impl B2Server {
 }
 
 pub fn start_server(root: PathBuf, auth_timeout: usize) -> TestResult<(SocketAddr, Sender<()>)> {
+    start_server_with_failures(root, auth_timeout, Vec::new())
+}
+
+/// Stops a mock server.
+pub fn shutdown(sender: Sender<()>) -> TestResult<()> {
+    sender.send(()).map_err(|()| {
+        TestError::HarnessFailure(String::from("Failed to send shutdown to mock b2 server."))
+    })
+}
+
+/// Connects a backend to the mock server at `addr`. `configure` can change the
+/// builder's settings before connecting.
+pub async fn connect_backend<F>(addr: SocketAddr, configure: F) -> TestResult<B2Backend>
+where
+    F: FnOnce(B2BackendBuilder) -> B2BackendBuilder,
+{
+    connect_backend_as(addr, TEST_KEY_ID, configure).await
+}
+
+/// Connects a backend to the mock server at `addr` with a specific key id.
+pub async fn connect_backend_as<F>(
+    addr: SocketAddr,
+    key_id: &str,
+    configure: F,
+) -> TestResult<B2Backend>
+where
+    F: FnOnce(B2BackendBuilder) -> B2BackendBuilder,
+{
+    let builder = B2Backend::builder(key_id, "bar").host(&format!("http://{}", addr));
+    let fs = configure(builder).connect().await?;
+
+    match fs.try_into() {
+        Ok(backend) => Ok(backend),
+        Err(_) => test_fail!("Expected a B2 backend."),
+    }
+}
+
+/// Reads all of the data from a stream.
+pub async fn collect(stream: DataStream) -> TestResult<Vec<u8>> {
+    Ok(stream
+        .try_fold(Vec::new(), |mut result, data| {
+            result.extend_from_slice(&data);
+            ready(Ok(result))
+        })
+        .await?)
+}
+
+/// Counts the calls made to a mock server's API methods.
+#[derive(Clone)]
+pub struct ApiCalls {
+    state: Arc<Mutex<B2ServerState>>,
+}
+
+impl ApiCalls {
+    /// The number of times the named API method has been called.
+    pub async fn count(&self, method: &str) -> usize {
+        self.state
+            .lock()
+            .await
+            .calls
+            .get(method)
+            .cloned()
+            .unwrap_or(0)
+    }
+}
+
+/// Starts a server that counts the calls made to its API methods.
+pub fn start_counting_server(
+    root: PathBuf,
+    auth_timeout: usize,
+) -> TestResult<(SocketAddr, Sender<()>, ApiCalls)> {
+    start(root, auth_timeout, Vec::new())
+}
+
+/// Starts a server that fails the first requests after authorizing with the
+/// given failures.
+pub fn start_server_with_failures(
+    root: PathBuf,
+    auth_timeout: usize,
+    failures: Vec<MockFailure>,
+) -> TestResult<(SocketAddr, Sender<()>)> {
+    let (addr, sender, _) = start(root, auth_timeout, failures)?;
+    Ok((addr, sender))
+}
+
+fn start(
+    root: PathBuf,
+    auth_timeout: usize,
+    failures: Vec<MockFailure>,
+) -> TestResult<(SocketAddr, Sender<()>, ApiCalls)> {
     let (shutdown_sender, shutdown_receiver) = channel::<()>();
 
     let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
@@ -1279,9 +2049,15 @@ pub fn start_server(root: PathBuf, auth_timeout: usize) -> TestResult<(SocketAdd
     let b2_server = B2Server {
         addr,
         auth_timeout,
-        state: Arc::new(Mutex::new(B2ServerState::new())),
+        state: Arc::new(Mutex::new(B2ServerState {
+            failures,
+            ..B2ServerState::new()
+        })),
         root,
     };
+    let calls = ApiCalls {
+        state: b2_server.state.clone(),
+    };
 
     let http_server = Server::from_tcp(listener)
         .expect("Failed to attach to tcp stream.")
@@ -1306,5 +2082,5 @@ pub fn start_server(root: PathBuf, auth_timeout: usize) -> TestResult<(SocketAdd
 
     spawn(server_future);
 
-    Ok((addr, shutdown_sender))
+    Ok((addr, shutdown_sender, calls))
 }
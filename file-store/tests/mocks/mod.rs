@@ -12,5 +12,36 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#![cfg(feature = "b2")]
+use std::ops::Range;
+
+// Each test only uses one of the mock servers.
+#[cfg(feature = "b2")]
+#[allow(dead_code)]
 pub mod b2_server;
+#[cfg(feature = "s3")]
+#[allow(dead_code)]
+pub mod s3_server;
+
+/// Finds the bytes of a file of the given length requested by a `Range` header
+/// of the form `bytes=<first>-<last>`.
+///
+/// Returns `None` if the header is invalid or the range cannot be satisfied.
+#[allow(dead_code)]
+pub fn byte_range(header: &str, len: usize) -> Option<Range<usize>> {
+    if !header.starts_with("bytes=") {
+        return None;
+    }
+
+    let mut parts = header[6..].splitn(2, '-');
+    let first: usize = parts.next()?.parse().ok()?;
+    let last: usize = match parts.next()? {
+        "" => len.saturating_sub(1),
+        s => s.parse().ok()?,
+    };
+
+    if first >= len || last < first {
+        return None;
+    }
+
+    Some(first..std::cmp::min(last + 1, len))
+}
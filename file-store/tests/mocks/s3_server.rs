@@ -0,0 +1,1002 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(feature = "s3")]
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fmt::Display;
+use std::fs::{create_dir_all, metadata, read, read_dir, remove_file, File};
+use std::io;
+use std::io::{BufWriter, Write};
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use filetime::{set_file_mtime, FileTime};
+use futures::channel::oneshot::{channel, Sender};
+use futures::future::FutureExt;
+use futures::lock::Mutex;
+use futures::stream::{iter, TryStreamExt};
+use http::header;
+use http::method::Method;
+use http::request::Parts;
+use http::StatusCode;
+use hyper::server::Server;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Chunk, Request, Response};
+use serde_xml_rs::from_reader;
+use tokio::spawn;
+use uuid::Uuid;
+
+use storage_types::s3::requests::*;
+use storage_types::s3::signing::{canonical_request, sha256_hex, signature, ALGORITHM};
+use storage_types::s3::{
+    canonical_query, format_http_date, format_iso8601, uri_decode, xml_escape, LAST_MODIFIED_KEY,
+    S3_HEADER_CONTENT_SHA256, S3_HEADER_DATE, S3_HEADER_META_PREFIX,
+};
+
+use super::byte_range;
+use crate::runner::TestResult;
+
+const TEST_ACCESS_KEY_ID: &str = "foo";
+const TEST_SECRET_KEY: &str = "bar";
+pub const TEST_REGION: &str = "mock-region";
+
+/// Keep pages small to exercise continuation.
+const MAX_KEYS: usize = 3;
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+type S3Result = Result<Response<Body>, S3Error>;
+
+#[derive(Debug)]
+struct S3Error {
+    status: StatusCode,
+    code: String,
+    message: String,
+}
+
+impl S3Error {
+    fn new<C, M>(status: StatusCode, code: C, message: M) -> S3Error
+    where
+        C: Display,
+        M: Display,
+    {
+        S3Error {
+            status,
+            code: code.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    fn no_such_key<P>(path: P) -> S3Error
+    where
+        P: AsRef<Path>,
+    {
+        S3Error::new(
+            StatusCode::NOT_FOUND,
+            "NoSuchKey",
+            format!("{} did not exist.", path.as_ref().display()),
+        )
+    }
+
+    fn no_such_bucket<D>(bucket: D) -> S3Error
+    where
+        D: Display,
+    {
+        S3Error::new(
+            StatusCode::NOT_FOUND,
+            "NoSuchBucket",
+            format!("Bucket {} did not exist.", bucket),
+        )
+    }
+
+    fn invalid_argument<D>(message: D) -> S3Error
+    where
+        D: Display,
+    {
+        S3Error::new(StatusCode::BAD_REQUEST, "InvalidArgument", message)
+    }
+
+    fn access_denied<D>(message: D) -> S3Error
+    where
+        D: Display,
+    {
+        S3Error::new(StatusCode::FORBIDDEN, "AccessDenied", message)
+    }
+
+    fn signature_mismatch<D>(message: D) -> S3Error
+    where
+        D: Display,
+    {
+        S3Error::new(StatusCode::FORBIDDEN, "SignatureDoesNotMatch", message)
+    }
+
+    fn server_error<D>(message: D) -> S3Error
+    where
+        D: Display,
+    {
+        S3Error::new(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", message)
+    }
+}
+
+impl Display for S3Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(&format!("{:?}", self))
+    }
+}
+
+impl From<S3Error> for Response<Body> {
+    fn from(error: S3Error) -> Response<Body> {
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<Error><Code>{}</Code><Message>{}</Message></Error>",
+            xml_escape(&error.code),
+            xml_escape(&error.message)
+        );
+
+        Response::builder()
+            .status(error.status)
+            .header(header::CONTENT_TYPE, "application/xml")
+            .body(body.into())
+            .expect("Failed to build error response.")
+    }
+}
+
+impl From<io::Error> for S3Error {
+    fn from(error: io::Error) -> S3Error {
+        if error.kind() == io::ErrorKind::NotFound {
+            S3Error::new(StatusCode::NOT_FOUND, "NoSuchKey", format!("{}", error))
+        } else {
+            S3Error::server_error(error)
+        }
+    }
+}
+
+fn header_value(head: &Parts, name: &str) -> Option<String> {
+    head.headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_owned())
+}
+
+fn header_or_error(head: &Parts, name: &str) -> Result<String, S3Error> {
+    match header_value(head, name) {
+        Some(v) => Ok(v),
+        None => Err(S3Error::invalid_argument(format!(
+            "No header {} provided.",
+            name
+        ))),
+    }
+}
+
+fn millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0)
+}
+
+fn last_modified(head: &Parts) -> Option<SystemTime> {
+    header_value(
+        head,
+        &format!("{}{}", S3_HEADER_META_PREFIX, LAST_MODIFIED_KEY),
+    )
+    .and_then(|t| t.parse::<u64>().ok())
+    .map(|d| UNIX_EPOCH + Duration::from_millis(d))
+}
+
+/// The headers from a request that are stored with an object.
+fn stored_headers(head: &Parts) -> Vec<(String, String)> {
+    let last_modified = format!("{}{}", S3_HEADER_META_PREFIX, LAST_MODIFIED_KEY);
+
+    head.headers
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.as_str();
+            name == header::CONTENT_TYPE.as_str()
+                || name == header::CACHE_CONTROL.as_str()
+                || name == header::CONTENT_DISPOSITION.as_str()
+                || (name.starts_with(S3_HEADER_META_PREFIX) && name != last_modified)
+        })
+        .filter_map(|(name, value)| {
+            value
+                .to_str()
+                .ok()
+                .map(|v| (name.as_str().to_owned(), v.to_owned()))
+        })
+        .collect()
+}
+
+fn xml_response(body: String) -> S3Result {
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/xml")
+        .body(format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}", body).into())
+        .expect("Failed to build response."))
+}
+
+fn empty_response(status: StatusCode) -> S3Result {
+    Ok(Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .expect("Failed to build response."))
+}
+
+/// Finds all the files within a directory, returned as keys relative to the
+/// directory.
+fn find_keys(dir: &Path, prefix: &str, keys: &mut Vec<(String, PathBuf)>) -> Result<(), S3Error> {
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+
+        if entry.file_type()?.is_dir() {
+            find_keys(&entry.path(), &format!("{}/", name), keys)?;
+        } else {
+            keys.push((name, entry.path()));
+        }
+    }
+
+    Ok(())
+}
+
+enum ListEntry {
+    Key(String, PathBuf),
+    Prefix(String),
+}
+
+impl ListEntry {
+    fn name(&self) -> &str {
+        match self {
+            ListEntry::Key(key, _) => key,
+            ListEntry::Prefix(prefix) => prefix,
+        }
+    }
+}
+
+struct MultipartUpload {
+    bucket: String,
+    key: String,
+    modified: Option<SystemTime>,
+    headers: Vec<(String, String)>,
+    parts: HashMap<u64, (Chunk, String)>,
+}
+
+#[derive(Default)]
+struct S3ServerState {
+    uploads: HashMap<String, MultipartUpload>,
+    headers: HashMap<PathBuf, Vec<(String, String)>>,
+    etags: HashMap<PathBuf, String>,
+}
+
+#[derive(Clone)]
+struct S3Server {
+    root: PathBuf,
+    state: Arc<Mutex<S3ServerState>>,
+}
+
+impl S3Server {
+    fn bucket_dir(&self, bucket: &str) -> Result<PathBuf, S3Error> {
+        let mut dir = self.root.clone();
+        dir.push(bucket);
+
+        match metadata(&dir) {
+            Ok(ref meta) if meta.is_dir() => Ok(dir),
+            _ => Err(S3Error::no_such_bucket(bucket)),
+        }
+    }
+
+    fn file_path(&self, bucket: &str, key: &str) -> Result<PathBuf, S3Error> {
+        let mut path = self.bucket_dir(bucket)?;
+        for part in key.split('/') {
+            path.push(part);
+        }
+        Ok(path)
+    }
+
+    fn check_signature(&self, head: &Parts) -> Result<(), S3Error> {
+        let auth = match header_value(head, header::AUTHORIZATION.as_str()) {
+            Some(a) => a,
+            None => {
+                return Err(S3Error::access_denied(
+                    "Request contained no authorization.",
+                ))
+            }
+        };
+
+        let mut credential: Option<&str> = None;
+        let mut signed_headers: Option<&str> = None;
+        let mut expected_signature: Option<&str> = None;
+
+        if !auth.starts_with(ALGORITHM) {
+            return Err(S3Error::access_denied(
+                "Unsupported authorization algorithm.",
+            ));
+        }
+
+        for part in auth[ALGORITHM.len()..].split(',') {
+            let part = part.trim();
+            if part.starts_with("Credential=") {
+                credential = Some(&part[11..]);
+            } else if part.starts_with("SignedHeaders=") {
+                signed_headers = Some(&part[14..]);
+            } else if part.starts_with("Signature=") {
+                expected_signature = Some(&part[10..]);
+            }
+        }
+
+        let (credential, signed_headers, expected_signature) =
+            match (credential, signed_headers, expected_signature) {
+                (Some(c), Some(h), Some(s)) => (c, h, s),
+                _ => return Err(S3Error::access_denied("Authorization header was invalid.")),
+            };
+
+        let credential: Vec<&str> = credential.split('/').collect();
+        if credential.len() != 5 || credential[0] != TEST_ACCESS_KEY_ID {
+            return Err(S3Error::new(
+                StatusCode::FORBIDDEN,
+                "InvalidAccessKeyId",
+                "Unknown access key id.",
+            ));
+        }
+
+        if credential[2] != TEST_REGION {
+            return Err(S3Error::access_denied("Incorrect region."));
+        }
+
+        let amz_date = header_or_error(head, S3_HEADER_DATE)?;
+        let payload_hash = header_or_error(head, S3_HEADER_CONTENT_SHA256)?;
+
+        let mut headers: Vec<(String, String)> = Vec::new();
+        for name in signed_headers.split(';') {
+            headers.push((name.to_owned(), header_or_error(head, name)?));
+        }
+
+        let mut query: Vec<(String, String)> = Vec::new();
+        for (key, value) in parse_query(head)? {
+            query.push((key, value));
+        }
+
+        let (canonical, _) = canonical_request(
+            head.method.as_str(),
+            head.uri.path(),
+            &canonical_query(&query),
+            &headers,
+            &payload_hash,
+        );
+
+        if signature(TEST_SECRET_KEY, &amz_date, TEST_REGION, &canonical) != expected_signature {
+            return Err(S3Error::signature_mismatch(
+                "The request signature did not match.",
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn list_buckets(self) -> S3Result {
+        let mut buckets = String::new();
+        for entry in read_dir(&self.root)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                buckets.push_str(&format!(
+                    "<Bucket><Name>{}</Name><CreationDate>{}</CreationDate></Bucket>",
+                    xml_escape(&entry.file_name().to_string_lossy()),
+                    format_iso8601(UNIX_EPOCH)
+                ));
+            }
+        }
+
+        xml_response(format!(
+            "<ListAllMyBucketsResult><Owner><ID>{}</ID></Owner><Buckets>{}</Buckets></ListAllMyBucketsResult>",
+            TEST_ACCESS_KEY_ID, buckets
+        ))
+    }
+
+    async fn list_objects(self, bucket: &str, query: HashMap<String, String>) -> S3Result {
+        if query.get("list-type").map(|s| s.as_str()) != Some("2") {
+            return Err(S3Error::invalid_argument(
+                "Only ListObjectsV2 is supported.",
+            ));
+        }
+
+        let dir = self.bucket_dir(bucket)?;
+        let prefix = query.get("prefix").cloned().unwrap_or_default();
+        let delimiter = query.get("delimiter").cloned();
+        let start = query.get("continuation-token").cloned();
+
+        let mut keys = Vec::new();
+        find_keys(&dir, "", &mut keys)?;
+
+        let mut entries: Vec<ListEntry> = Vec::new();
+        let mut common_prefixes: HashSet<String> = HashSet::new();
+        for (key, path) in keys {
+            if !key.starts_with(&prefix) {
+                continue;
+            }
+
+            if let Some(ref delimiter) = delimiter {
+                if let Some(pos) = key[prefix.len()..].find(delimiter.as_str()) {
+                    let common = key[0..prefix.len() + pos + delimiter.len()].to_owned();
+                    if common_prefixes.insert(common.clone()) {
+                        entries.push(ListEntry::Prefix(common));
+                    }
+                    continue;
+                }
+            }
+
+            entries.push(ListEntry::Key(key, path));
+        }
+        entries.sort_by(|a, b| a.name().cmp(b.name()));
+
+        let mut remaining: Vec<ListEntry> = entries
+            .into_iter()
+            .filter(|e| match start {
+                Some(ref token) => e.name() > token.as_str(),
+                None => true,
+            })
+            .collect();
+
+        let is_truncated = remaining.len() > MAX_KEYS;
+        remaining.truncate(MAX_KEYS);
+
+        let mut contents = String::new();
+        for entry in remaining.iter() {
+            match entry {
+                ListEntry::Key(key, path) => {
+                    let meta = metadata(path)?;
+                    contents.push_str(&format!(
+                        "<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>{}</ETag><Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                        xml_escape(key),
+                        format_iso8601(meta.modified()?),
+                        xml_escape(&self.etag(path, meta.modified()?).await),
+                        meta.len()
+                    ));
+                }
+                ListEntry::Prefix(prefix) => {
+                    contents.push_str(&format!(
+                        "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+                        xml_escape(prefix)
+                    ));
+                }
+            }
+        }
+
+        let next_token = match (is_truncated, remaining.last()) {
+            (true, Some(entry)) => format!(
+                "<NextContinuationToken>{}</NextContinuationToken>",
+                xml_escape(entry.name())
+            ),
+            _ => String::new(),
+        };
+
+        xml_response(format!(
+            "<ListBucketResult><Name>{}</Name><Prefix>{}</Prefix><KeyCount>{}</KeyCount><MaxKeys>{}</MaxKeys><IsTruncated>{}</IsTruncated>{}{}</ListBucketResult>",
+            xml_escape(bucket),
+            xml_escape(&prefix),
+            remaining.len(),
+            MAX_KEYS,
+            is_truncated,
+            contents,
+            next_token
+        ))
+    }
+
+    /// Gets the quoted ETag for a file. Files not written through the server
+    /// use their modification time.
+    async fn etag(&self, path: &Path, modified: SystemTime) -> String {
+        match self.state.lock().await.etags.get(path) {
+            Some(etag) => format!("\"{}\"", etag),
+            None => format!("\"{}\"", millis(modified)),
+        }
+    }
+
+    /// Checks the conditional headers of a write against the current file.
+    async fn check_precondition(&self, path: &Path, head: &Parts) -> Result<(), S3Error> {
+        let current = match metadata(path) {
+            Ok(ref meta) if meta.is_file() => Some(self.etag(path, meta.modified()?).await),
+            _ => None,
+        };
+
+        let failed = match (
+            header_value(head, header::IF_NONE_MATCH.as_str()),
+            header_value(head, header::IF_MATCH.as_str()),
+        ) {
+            (Some(_), _) if current.is_some() => true,
+            (_, Some(expected)) => current != Some(expected),
+            _ => false,
+        };
+
+        if failed {
+            Err(S3Error::new(
+                StatusCode::PRECONDITION_FAILED,
+                "PreconditionFailed",
+                "At least one of the pre-conditions you specified did not hold.",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Records a new version of a file that has been written.
+    async fn written(&self, path: PathBuf, headers: Vec<(String, String)>) -> String {
+        let etag = Uuid::new_v4().to_string();
+        let mut state = self.state.lock().await;
+        state.etags.insert(path.clone(), etag.clone());
+        state.headers.insert(path, headers);
+        format!("\"{}\"", etag)
+    }
+
+    async fn object_response(&self, path: &Path) -> Result<http::response::Builder, S3Error> {
+        let meta = metadata(path).map_err(|_| S3Error::no_such_key(path))?;
+        if !meta.is_file() {
+            return Err(S3Error::no_such_key(path));
+        }
+
+        let modified = meta.modified()?;
+        let mut builder = Response::builder();
+        builder
+            .status(StatusCode::OK)
+            .header(header::LAST_MODIFIED, format_http_date(modified))
+            .header(header::ETAG, self.etag(path, modified).await)
+            .header(
+                format!("{}{}", S3_HEADER_META_PREFIX, LAST_MODIFIED_KEY).as_str(),
+                millis(modified).to_string(),
+            );
+
+        if let Some(headers) = self.state.lock().await.headers.get(path) {
+            for (name, value) in headers {
+                builder.header(name.as_str(), value.as_str());
+            }
+        }
+
+        Ok(builder)
+    }
+
+    async fn head_object(self, bucket: &str, key: &str) -> S3Result {
+        let path = self.file_path(bucket, key)?;
+        let mut builder = self.object_response(&path).await?;
+        Ok(builder
+            .header(header::CONTENT_LENGTH, metadata(&path)?.len())
+            .body(Body::empty())
+            .expect("Failed to build response."))
+    }
+
+    async fn get_object(self, bucket: &str, key: &str, head: Parts) -> S3Result {
+        let path = self.file_path(bucket, key)?;
+        let mut builder = self.object_response(&path).await?;
+
+        let mut source = read(&path)?;
+        let size = source.len();
+        if let Some(value) = head.headers.get(header::RANGE) {
+            let range = value
+                .to_str()
+                .ok()
+                .and_then(|v| byte_range(v, size))
+                .ok_or_else(|| {
+                    S3Error::new(
+                        StatusCode::RANGE_NOT_SATISFIABLE,
+                        "InvalidRange",
+                        format!("Invalid range {:?} for an object of {} bytes.", value, size),
+                    )
+                })?;
+
+            builder.status(StatusCode::PARTIAL_CONTENT).header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", range.start, range.end - 1, size),
+            );
+            source = source[range].to_vec();
+        }
+        builder.header(header::CONTENT_LENGTH, source.len());
+
+        let mut len = source.len() / 5;
+        if len == 0 {
+            len = 1;
+        }
+
+        let blocks: Vec<io::Result<Chunk>> = source
+            .chunks(len)
+            .map(|s| {
+                let mut result: Vec<u8> = Default::default();
+                result.extend_from_slice(s);
+                io::Result::<Chunk>::Ok(result.into())
+            })
+            .collect();
+
+        Ok(builder
+            .body(Body::wrap_stream(iter(blocks)))
+            .expect("Failed to build response."))
+    }
+
+    /// Reads the request body checking that it matches the signed length and
+    /// hash.
+    async fn read_body(head: &Parts, body: Body) -> Result<Chunk, S3Error> {
+        let expected_hash = header_or_error(head, S3_HEADER_CONTENT_SHA256)?;
+        let expected_length = header_or_error(head, header::CONTENT_LENGTH.as_str())?;
+
+        let data = body.try_concat().await.map_err(|e| {
+            S3Error::new(
+                StatusCode::BAD_REQUEST,
+                "RequestTimeout",
+                format!("Failed to receive entire body: {}", e),
+            )
+        })?;
+
+        if expected_length != data.len().to_string() {
+            return Err(S3Error::invalid_argument(
+                "Content-Length header was not set correctly.",
+            ));
+        }
+
+        if expected_hash != sha256_hex(&data) {
+            return Err(S3Error::new(
+                StatusCode::BAD_REQUEST,
+                "XAmzContentSHA256Mismatch",
+                "Expected hash did not match data.",
+            ));
+        }
+
+        Ok(data)
+    }
+
+    fn write_file(
+        &self,
+        path: &Path,
+        data: &[&[u8]],
+        modified: Option<SystemTime>,
+    ) -> Result<(), S3Error> {
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        for chunk in data {
+            writer.write_all(chunk)?;
+        }
+        writer.flush()?;
+        drop(writer);
+
+        if let Some(time) = modified {
+            if let Err(e) = set_file_mtime(path, FileTime::from_system_time(time)) {
+                return Err(S3Error::server_error(format!(
+                    "Failed to set file modification time: {}.",
+                    e
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn put_object(self, bucket: &str, key: &str, head: Parts, body: Body) -> S3Result {
+        let path = self.file_path(bucket, key)?;
+        let data = S3Server::read_body(&head, body).await?;
+        self.check_precondition(&path, &head).await?;
+
+        self.write_file(&path, &[data.as_ref()], last_modified(&head))?;
+        let etag = self.written(path, stored_headers(&head)).await;
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::ETAG, etag)
+            .body(Body::empty())
+            .expect("Failed to build response."))
+    }
+
+    async fn create_multipart_upload(self, bucket: &str, key: &str, head: Parts) -> S3Result {
+        self.bucket_dir(bucket)?;
+
+        let upload_id = Uuid::new_v4().to_string();
+        let mut state = self.state.lock().await;
+        state.uploads.insert(
+            upload_id.clone(),
+            MultipartUpload {
+                bucket: bucket.to_owned(),
+                key: key.to_owned(),
+                modified: last_modified(&head),
+                headers: stored_headers(&head),
+                parts: Default::default(),
+            },
+        );
+
+        xml_response(format!(
+            "<InitiateMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+            xml_escape(bucket),
+            xml_escape(key),
+            upload_id
+        ))
+    }
+
+    async fn upload_part(
+        self,
+        upload_id: &str,
+        part_number: &str,
+        head: Parts,
+        body: Body,
+    ) -> S3Result {
+        let part_number = match part_number.parse::<u64>() {
+            Ok(p) if p >= 1 && p <= 10000 => p,
+            _ => return Err(S3Error::invalid_argument("Invalid part number.")),
+        };
+
+        let data = S3Server::read_body(&head, body).await?;
+        let etag = format!("\"{}\"", sha256_hex(&data));
+
+        let mut state = self.state.lock().await;
+        match state.uploads.get_mut(upload_id) {
+            Some(upload) => {
+                upload.parts.insert(part_number, (data, etag.clone()));
+            }
+            None => {
+                return Err(S3Error::new(
+                    StatusCode::NOT_FOUND,
+                    "NoSuchUpload",
+                    "Unknown upload id.",
+                ))
+            }
+        }
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::ETAG, etag)
+            .body(Body::empty())
+            .expect("Failed to build response."))
+    }
+
+    async fn complete_multipart_upload(self, upload_id: &str, head: Parts, body: Body) -> S3Result {
+        let data = S3Server::read_body(&head, body).await?;
+        let request: CompleteMultipartUpload = from_reader(data.as_ref()).map_err(|e| {
+            S3Error::new(
+                StatusCode::BAD_REQUEST,
+                "MalformedXML",
+                format!("Failed to parse request: {}", e),
+            )
+        })?;
+
+        let mut upload = {
+            let mut state = self.state.lock().await;
+            match state.uploads.remove(upload_id) {
+                Some(upload) => upload,
+                None => {
+                    return Err(S3Error::new(
+                        StatusCode::NOT_FOUND,
+                        "NoSuchUpload",
+                        "Unknown upload id.",
+                    ))
+                }
+            }
+        };
+
+        let path = self.file_path(&upload.bucket, &upload.key)?;
+        if let Err(e) = self.check_precondition(&path, &head).await {
+            // The upload can still be aborted.
+            self.state
+                .lock()
+                .await
+                .uploads
+                .insert(upload_id.to_owned(), upload);
+            return Err(e);
+        }
+
+        let mut chunks: Vec<Chunk> = Vec::new();
+        let mut last_part: u64 = 0;
+        for (index, part) in request.parts.iter().enumerate() {
+            if part.part_number <= last_part {
+                return Err(S3Error::new(
+                    StatusCode::BAD_REQUEST,
+                    "InvalidPartOrder",
+                    "Parts were not in ascending order.",
+                ));
+            }
+            last_part = part.part_number;
+
+            let (data, etag) = match upload.parts.remove(&part.part_number) {
+                Some(p) => p,
+                None => {
+                    return Err(S3Error::new(
+                        StatusCode::BAD_REQUEST,
+                        "InvalidPart",
+                        format!("Part {} was not uploaded.", part.part_number),
+                    ))
+                }
+            };
+
+            if etag != part.e_tag {
+                return Err(S3Error::new(
+                    StatusCode::BAD_REQUEST,
+                    "InvalidPart",
+                    format!("Part {} had the wrong ETag.", part.part_number),
+                ));
+            }
+
+            if index + 1 < request.parts.len() && data.len() < MIN_PART_SIZE {
+                return Err(S3Error::new(
+                    StatusCode::BAD_REQUEST,
+                    "EntityTooSmall",
+                    format!("Part {} was too small.", part.part_number),
+                ));
+            }
+
+            chunks.push(data);
+        }
+
+        let slices: Vec<&[u8]> = chunks.iter().map(|c| c.as_ref()).collect();
+        self.write_file(&path, &slices, upload.modified)?;
+        let etag = self.written(path, upload.headers).await;
+
+        xml_response(format!(
+            "<CompleteMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key><ETag>{}</ETag></CompleteMultipartUploadResult>",
+            xml_escape(&upload.bucket),
+            xml_escape(&upload.key),
+            xml_escape(&etag)
+        ))
+    }
+
+    async fn abort_multipart_upload(self, upload_id: &str) -> S3Result {
+        let mut state = self.state.lock().await;
+        match state.uploads.remove(upload_id) {
+            Some(_) => empty_response(StatusCode::NO_CONTENT),
+            None => Err(S3Error::new(
+                StatusCode::NOT_FOUND,
+                "NoSuchUpload",
+                "Unknown upload id.",
+            )),
+        }
+    }
+
+    async fn delete_object(self, bucket: &str, key: &str) -> S3Result {
+        let path = self.file_path(bucket, key)?;
+        {
+            let mut state = self.state.lock().await;
+            state.headers.remove(&path);
+            state.etags.remove(&path);
+        }
+        match remove_file(&path) {
+            Ok(()) => empty_response(StatusCode::NO_CONTENT),
+            // S3 does not complain about deleting objects that don't exist.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                empty_response(StatusCode::NO_CONTENT)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn serve(self, request: Request<Body>) -> S3Result {
+        let (head, body) = request.into_parts();
+
+        match header_value(&head, header::USER_AGENT.as_str()) {
+            Some(ua) => {
+                if !ua.starts_with(env!("CARGO_PKG_NAME")) {
+                    return Err(S3Error::access_denied(format!(
+                        "User-Agent was invalid: {}",
+                        ua
+                    )));
+                }
+            }
+            None => return Err(S3Error::access_denied("User-Agent was not sent")),
+        }
+
+        self.check_signature(&head)?;
+
+        let path = head.uri.path()[1..].to_owned();
+        let (bucket, key) = match path.find('/') {
+            Some(pos) => (path[0..pos].to_owned(), path[pos + 1..].to_owned()),
+            None => (path, String::new()),
+        };
+
+        let bucket = uri_decode(&bucket)
+            .map_err(|_| S3Error::invalid_argument("Bucket was not valid utf-8."))?;
+        let key =
+            uri_decode(&key).map_err(|_| S3Error::invalid_argument("Key was not valid utf-8."))?;
+        let query: HashMap<String, String> = parse_query(&head)?.into_iter().collect();
+
+        if bucket.is_empty() {
+            return match head.method {
+                Method::GET => self.list_buckets().await,
+                _ => Err(S3Error::invalid_argument("Invalid method.")),
+            };
+        }
+
+        if key.is_empty() {
+            return match head.method {
+                Method::GET => self.list_objects(&bucket, query).await,
+                _ => Err(S3Error::invalid_argument("Invalid method.")),
+            };
+        }
+
+        match (head.method.clone(), query.get("uploadId").cloned()) {
+            (Method::HEAD, None) => self.head_object(&bucket, &key).await,
+            (Method::GET, None) => self.get_object(&bucket, &key, head).await,
+            (Method::PUT, None) => self.put_object(&bucket, &key, head, body).await,
+            (Method::DELETE, None) => self.delete_object(&bucket, &key).await,
+            (Method::POST, None) => {
+                if query.contains_key("uploads") {
+                    self.create_multipart_upload(&bucket, &key, head).await
+                } else {
+                    Err(S3Error::invalid_argument("Invalid request."))
+                }
+            }
+            (Method::PUT, Some(upload_id)) => match query.get("partNumber") {
+                Some(part) => self.upload_part(&upload_id, part, head, body).await,
+                None => Err(S3Error::invalid_argument("No part number provided.")),
+            },
+            (Method::POST, Some(upload_id)) => {
+                self.complete_multipart_upload(&upload_id, head, body).await
+            }
+            (Method::DELETE, Some(upload_id)) => self.abort_multipart_upload(&upload_id).await,
+            _ => Err(S3Error::invalid_argument("Invalid method.")),
+        }
+    }
+}
+
+fn parse_query(head: &Parts) -> Result<Vec<(String, String)>, S3Error> {
+    let mut pairs = Vec::new();
+
+    if let Some(query) = head.uri.query() {
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = match pair.find('=') {
+                Some(pos) => (&pair[0..pos], &pair[pos + 1..]),
+                None => (pair, ""),
+            };
+
+            let key = uri_decode(key)
+                .map_err(|_| S3Error::invalid_argument("Query was not valid utf-8."))?;
+            let value = uri_decode(value)
+                .map_err(|_| S3Error::invalid_argument("Query was not valid utf-8."))?;
+            pairs.push((key, value));
+        }
+    }
+
+    Ok(pairs)
+}
+
+pub fn start_server(root: PathBuf) -> TestResult<(SocketAddr, Sender<()>)> {
+    let (shutdown_sender, shutdown_receiver) = channel::<()>();
+
+    let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
+    let listener = TcpListener::bind(addr).expect("Failed to bind to server socket.");
+    let addr = listener
+        .local_addr()
+        .expect("Failed to bind to server socket.");
+
+    let s3_server = S3Server {
+        state: Arc::new(Mutex::new(Default::default())),
+        root,
+    };
+
+    let http_server = Server::from_tcp(listener)
+        .expect("Failed to attach to tcp stream.")
+        .serve(make_service_fn(move |_| {
+            let server = s3_server.clone();
+            async {
+                Ok::<_, io::Error>(service_fn(move |request: Request<Body>| {
+                    server
+                        .clone()
+                        .serve(request)
+                        .map(|r| r.or_else(|e| Ok::<Response<Body>, io::Error>(e.into())))
+                }))
+            }
+        }));
+
+    let server_future = http_server
+        .with_graceful_shutdown(shutdown_receiver.map(|_| ()))
+        .map(|r| match r {
+            Ok(()) => (),
+            Err(e) => panic!(e.to_string()),
+        });
+
+    spawn(server_future);
+
+    Ok((addr, shutdown_sender))
+}
@@ -18,7 +18,7 @@ pub mod read;
 pub mod write;
 
 use std::fmt;
-use std::fs::create_dir_all;
+use std::fs::{create_dir_all, remove_file, File};
 use std::future::Future;
 use std::iter::empty;
 use std::path::PathBuf;
@@ -176,6 +176,54 @@ impl TestContext {
     }
 }
 
+/// Whether the backend has physical directories.
+pub fn has_directories(backend: Backend) -> bool {
+    match backend {
+        Backend::File => true,
+        #[cfg(feature = "memory")]
+        Backend::Memory => true,
+        // The custom backend tests wrap the file backend.
+        Backend::Custom(_) => true,
+        _ => false,
+    }
+}
+
+/// Whether the backend stores its files in the test's temporary directory.
+pub fn stores_on_disk(backend: Backend) -> bool {
+    match backend {
+        #[cfg(feature = "memory")]
+        Backend::Memory => false,
+        _ => true,
+    }
+}
+
+/// Whether the backend keeps the metadata that files are written with. The
+/// file backend stores it in extended attributes which not every platform or
+/// filesystem supports.
+pub fn stores_metadata(backend: Backend, context: &TestContext) -> bool {
+    match backend {
+        // The custom backend tests wrap the file backend.
+        Backend::File | Backend::Custom(_) => {
+            let probe = context.root.join("xattr-probe");
+            let supported = File::create(&probe).is_ok()
+                && xattr::set(&probe, "user.file-store.probe", b"probe").is_ok();
+            let _ = remove_file(&probe);
+            supported
+        }
+        _ => true,
+    }
+}
+
+/// Whether the backend can report the SHA1 of the files that it writes. S3
+/// only knows the MD5 of objects uploaded in a single part.
+pub fn stores_sha1(backend: Backend, context: &TestContext) -> bool {
+    match backend {
+        #[cfg(feature = "s3")]
+        Backend::S3 => false,
+        _ => stores_metadata(backend, context),
+    }
+}
+
 /// Creates a filesystem used for testing.
 pub fn prepare_test(backend: Backend, test_root: &str) -> TestResult<TestContext> {
     let temp = tempdir().into_test_result()?;
@@ -202,7 +250,7 @@ pub fn prepare_test(backend: Backend, test_root: &str) -> TestResult<TestContext
     set_file_mtime(file, FileTime::from_system_time(LARGE_FILE_MODIFIED())).into_test_result()?;
     write_file(&dir, "mediumfile", ContentIterator::new(58, 5 * MB))?;
 
-    if backend == Backend::File {
+    if has_directories(backend) {
         let mut em = dir.clone();
         em.push("maybedir");
         create_dir_all(&em).into_test_result()?;
@@ -266,6 +314,7 @@ macro_rules! build_tests {
             $setup,
             $cleanup
         );
+        make_test!($root, $backend, read, test_get_file_range, $setup, $cleanup);
         make_test!(
             $root,
             $backend,
@@ -277,6 +326,15 @@ macro_rules! build_tests {
         make_test!($root, $backend, write, test_copy_file, $setup, $cleanup);
         make_test!($root, $backend, write, test_move_file, $setup, $cleanup);
         make_test!($root, $backend, write, test_delete_object, $setup, $cleanup);
+        make_test!($root, $backend, write, test_delete_prefix, $setup, $cleanup);
+        make_test!(
+            $root,
+            $backend,
+            write,
+            test_batch_operations,
+            $setup,
+            $cleanup
+        );
         make_test!(
             $root,
             $backend,
@@ -285,5 +343,39 @@ macro_rules! build_tests {
             $setup,
             $cleanup
         );
+        make_test!(
+            $root,
+            $backend,
+            write,
+            test_upload_metadata,
+            $setup,
+            $cleanup
+        );
+        make_test!(
+            $root,
+            $backend,
+            write,
+            test_write_precondition,
+            $setup,
+            $cleanup
+        );
+        make_test!($root, $backend, write, test_failed_write, $setup, $cleanup);
+        make_test!(
+            $root,
+            $backend,
+            write,
+            test_write_checksum,
+            $setup,
+            $cleanup
+        );
+        make_test!($root, $backend, write, test_write_size, $setup, $cleanup);
+        make_test!(
+            $root,
+            $backend,
+            write,
+            test_transfer_progress,
+            $setup,
+            $cleanup
+        );
     };
 }
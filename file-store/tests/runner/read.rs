@@ -15,6 +15,7 @@
 use std::fs::symlink_metadata;
 use std::future::Future;
 use std::iter::empty;
+use std::ops::Range;
 use std::path::Path;
 use std::pin::Pin;
 use std::task::{Context, Poll};
@@ -162,7 +163,7 @@ pub async fn test_list_objects(fs: &FileStore, context: &TestContext) -> TestRes
         "test1/dir1/dir2/yu",
     ];
 
-    if fs.backend_type() == Backend::File {
+    if has_directories(fs.backend_type()) {
         allfiles.extend(vec![
             "test1",
             "test1/dir1",
@@ -194,7 +195,7 @@ pub async fn test_list_objects(fs: &FileStore, context: &TestContext) -> TestRes
 
     test_list(fs, context, "test1/dir1/dir2/", prefixed.clone()).await?;
 
-    if fs.backend_type() == Backend::File {
+    if has_directories(fs.backend_type()) {
         prefixed.extend(vec!["test1/dir1/dir2"]);
     }
 
@@ -315,7 +316,7 @@ pub async fn test_get_object(fs: &FileStore, context: &TestContext) -> TestResul
     test_fail(fs, context, "test1/dir1/daz").await?;
     test_fail(fs, context, "test1/dir1/foo/bar").await?;
 
-    if fs.backend_type() == Backend::File {
+    if has_directories(fs.backend_type()) {
         test_pass(fs, context, "test1/dir1/maybedir").await?;
     } else {
         test_fail(fs, context, "test1/dir1/dir2").await?;
@@ -416,6 +417,122 @@ pub async fn test_get_file_stream(fs: &FileStore, context: &TestContext) -> Test
     Ok(())
 }
 
+pub async fn test_get_file_range(fs: &FileStore, context: &TestContext) -> TestResult<()> {
+    async fn test_pass<I>(
+        fs: &FileStore,
+        context: &TestContext,
+        path: &str,
+        range: Range<u64>,
+        mut data: I,
+    ) -> TestResult<()>
+    where
+        I: Iterator<Item = u8>,
+    {
+        let target = context.get_path(path);
+        let mut stream = Box::pin(fs.get_file_range(target, range.clone()).await?);
+
+        let mut pos: u64 = range.start;
+        loop {
+            let buf = stream.next().await;
+            match buf {
+                Some(Ok(buffer)) => {
+                    for x in 0..buffer.len() {
+                        match data.next() {
+                            Some(b) => test_assert_eq!(
+                                buffer[x],
+                                b,
+                                "Data should have matched at position {}.",
+                                pos
+                            ),
+                            None => test_fail!("Ran out of expected data as position {}.", pos),
+                        }
+                        pos += 1;
+                    }
+                }
+                Some(Err(e)) => {
+                    return Err(e.into());
+                }
+                None => {
+                    test_assert_eq!(
+                        data.next(),
+                        None,
+                        "Expected data should have ended at position {}.",
+                        pos
+                    );
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn test_fail(fs: &FileStore, context: &TestContext, path: &str) -> TestResult<()> {
+        let target = context.get_path(path);
+        let result = fs.get_file_range(target.clone(), 0..10).await;
+        test_assert!(result.is_err());
+        if let Err(e) = result {
+            test_assert_eq!(e.kind(), StorageErrorKind::NotFound(target));
+        }
+
+        Ok(())
+    }
+
+    test_pass(
+        fs,
+        context,
+        "test1/dir1/smallfile.txt",
+        5..15,
+        b"is quite a".iter().cloned(),
+    )
+    .await?;
+    test_pass(
+        fs,
+        context,
+        "test1/dir1/smallfile.txt",
+        20..100,
+        b"t file.".iter().cloned(),
+    )
+    .await?;
+    test_pass(fs, context, "test1/dir1/smallfile.txt", 27..40, empty()).await?;
+    test_pass(fs, context, "test1/dir1/smallfile.txt", 50..60, empty()).await?;
+    test_pass(fs, context, "test1/dir1/smallfile.txt", 10..10, empty()).await?;
+    test_pass(
+        fs,
+        context,
+        "test1/dir1/largefile",
+        (20 * MB)..(20 * MB + 5000),
+        ContentIterator::new(0, 100 * MB)
+            .skip(20 * MB as usize)
+            .take(5000),
+    )
+    .await?;
+    test_pass(
+        fs,
+        context,
+        "test1/dir1/largefile",
+        (99 * MB)..(200 * MB),
+        ContentIterator::new(0, 100 * MB).skip(99 * MB as usize),
+    )
+    .await?;
+    test_pass(fs, context, "test1/dir1/dir2/bar", 0..10, empty()).await?;
+    test_pass(
+        fs,
+        context,
+        "test1/dir1/dir2/daz",
+        0..300,
+        ContentIterator::new(72, 300),
+    )
+    .await?;
+
+    test_fail(fs, context, "test1/dir1/dir2").await?;
+    test_fail(fs, context, "test1/dir1/daz").await?;
+    test_fail(fs, context, "test1/dir1/foo/bar").await?;
+    test_fail(fs, context, "test1/dir1/dir2/gaz").await?;
+
+    Ok(())
+}
+
 struct Wrapper {
     inner: Pin<Box<dyn Future<Output = TestResult<()>> + Send + 'static>>,
 }
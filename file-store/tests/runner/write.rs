@@ -12,17 +12,58 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::HashMap;
 use std::fs::{symlink_metadata, File};
 use std::io::{BufReader, ErrorKind, Read};
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use futures::future::ready;
+use futures::stream::{once, StreamExt, TryStreamExt};
+use sha1::Sha1;
 
 use super::utils::*;
 use super::*;
 
-use file_store::backends::Backend;
 use file_store::*;
 
-fn test_file_matches<I>(target: &Path, info: UploadInfo, mut expected: I) -> TestResult<()>
+/// Finds the type of the object stored at the path, if there is one.
+async fn stored_type(
+    fs: &FileStore,
+    context: &TestContext,
+    path: &ObjectPath,
+) -> TestResult<Option<ObjectType>> {
+    if stores_on_disk(fs.backend_type()) {
+        match symlink_metadata(context.get_target(path)) {
+            Ok(m) => {
+                if m.is_file() {
+                    Ok(Some(ObjectType::File))
+                } else if m.is_dir() {
+                    Ok(Some(ObjectType::Directory))
+                } else {
+                    Ok(Some(ObjectType::Symlink))
+                }
+            }
+            Err(e) => {
+                if e.kind() == ErrorKind::NotFound {
+                    Ok(None)
+                } else {
+                    Err(TestError::from_error(e))
+                }
+            }
+        }
+    } else {
+        match fs.get_object(path.clone()).await {
+            Ok(object) => Ok(Some(object.object_type())),
+            Err(e) => match e.kind() {
+                StorageErrorKind::NotFound(_) => Ok(None),
+                _ => Err(e.into()),
+            },
+        }
+    }
+}
+
+fn test_local_file_matches<I>(target: &Path, info: UploadInfo, mut expected: I) -> TestResult<()>
 where
     I: Iterator<Item = u8>,
 {
@@ -68,6 +109,73 @@ where
     Ok(())
 }
 
+async fn test_stored_file_matches<I>(
+    fs: &FileStore,
+    info: UploadInfo,
+    mut expected: I,
+) -> TestResult<()>
+where
+    I: Iterator<Item = u8>,
+{
+    let object = fs.get_object(info.path.clone()).await?;
+    test_assert_eq!(
+        object.object_type(),
+        ObjectType::File,
+        "Should have seen a file at {}.",
+        info.path
+    );
+
+    let mut stream = fs.get_file_stream(info.path.clone()).await?;
+    let mut pos = 0;
+    while let Some(result) = stream.next().await {
+        for f in result?.iter() {
+            match expected.next() {
+                Some(e) => test_assert_eq!(
+                    *f,
+                    e,
+                    "File content of {} at {} should have matched expected.",
+                    info.path,
+                    pos
+                ),
+                None => test_fail!("Found too many bytes in {}.", info.path),
+            }
+
+            pos += 1;
+        }
+    }
+
+    if expected.next().is_some() {
+        test_fail!("Found too few bytes in {}.", info.path);
+    }
+
+    if let Some(time) = info.modified {
+        test_assert_eq!(
+            object.modified(),
+            Some(time),
+            "Should have seen the right modification time for {}.",
+            info.path
+        );
+    }
+
+    Ok(())
+}
+
+async fn test_file_matches<I>(
+    fs: &FileStore,
+    context: &TestContext,
+    info: UploadInfo,
+    expected: I,
+) -> TestResult<()>
+where
+    I: Iterator<Item = u8>,
+{
+    if stores_on_disk(fs.backend_type()) {
+        test_local_file_matches(&context.get_target(&info.path), info, expected)
+    } else {
+        test_stored_file_matches(fs, info, expected).await
+    }
+}
+
 pub async fn test_copy_file(fs: &FileStore, context: &TestContext) -> TestResult<()> {
     async fn test_pass(
         fs: &FileStore,
@@ -78,14 +186,16 @@ pub async fn test_copy_file(fs: &FileStore, context: &TestContext) -> TestResult
         length: u64,
     ) -> TestResult<()> {
         let remote_current = context.get_path(path);
-        let local_current = context.get_target(&remote_current);
-        let local_target = context.get_target(&target.path);
 
         fs.copy_file(remote_current.clone(), target.clone()).await?;
 
-        let result = symlink_metadata(local_current.clone());
-        if let Ok(m) = result {
-            test_assert!(m.is_file(), "File {} should still exist.", remote_current);
+        if let Some(t) = stored_type(fs, context, &remote_current).await? {
+            test_assert_eq!(
+                t,
+                ObjectType::File,
+                "File {} should still exist.",
+                remote_current
+            );
         } else {
             test_fail!(
                 "Should not have seen an error getting metadata for the old file {}",
@@ -93,7 +203,7 @@ pub async fn test_copy_file(fs: &FileStore, context: &TestContext) -> TestResult
             );
         }
 
-        test_file_matches(&local_target, target, ContentIterator::new(seed, length))?;
+        test_file_matches(fs, context, target, ContentIterator::new(seed, length)).await?;
 
         Ok(())
     }
@@ -106,7 +216,6 @@ pub async fn test_copy_file(fs: &FileStore, context: &TestContext) -> TestResult
     ) -> TestResult<()> {
         let remote_current = context.get_path(path);
         let remote_target = context.get_path(target);
-        let local_target = context.get_target(&remote_target);
 
         let result = fs
             .copy_file(remote_current.clone(), remote_target.clone())
@@ -127,15 +236,8 @@ pub async fn test_copy_file(fs: &FileStore, context: &TestContext) -> TestResult
             test_fail!("Expected to fail to copy {}.", remote_current);
         }
 
-        let result = symlink_metadata(local_target);
-        if let Err(e) = result {
-            test_assert_eq!(
-                e.kind(),
-                ErrorKind::NotFound,
-                "File {} should not exist.",
-                remote_target
-            );
-        }
+        // The target may exist from an earlier pass but must not be broken.
+        stored_type(fs, context, &remote_target).await?;
 
         Ok(())
     }
@@ -147,6 +249,7 @@ pub async fn test_copy_file(fs: &FileStore, context: &TestContext) -> TestResult
         UploadInfo {
             path: context.get_path("test1/dir1/testfile"),
             modified: None,
+            ..Default::default()
         },
         58,
         5 * MB,
@@ -159,6 +262,7 @@ pub async fn test_copy_file(fs: &FileStore, context: &TestContext) -> TestResult
         UploadInfo {
             path: context.get_path("test1/dir1/dir2/hop"),
             modified: None,
+            ..Default::default()
         },
         0,
         100 * MB,
@@ -171,6 +275,7 @@ pub async fn test_copy_file(fs: &FileStore, context: &TestContext) -> TestResult
         UploadInfo {
             path: context.get_path("test1/dir1/bazza"),
             modified: Some(UNIX_EPOCH + Duration::from_millis(1_703_257_714)),
+            ..Default::default()
         },
         72,
         300,
@@ -193,17 +298,14 @@ pub async fn test_move_file(fs: &FileStore, context: &TestContext) -> TestResult
         length: u64,
     ) -> TestResult<()> {
         let remote_current = context.get_path(path);
-        let local_current = context.get_target(&remote_current);
-        let local_target = context.get_target(&target.path);
 
         fs.move_file(remote_current.clone(), target.clone()).await?;
 
-        let result = symlink_metadata(local_current.clone());
-        if result.is_ok() {
+        if stored_type(fs, context, &remote_current).await?.is_some() {
             test_fail!("File {} should no longer exist.", remote_current);
         }
 
-        test_file_matches(&local_target, target, ContentIterator::new(seed, length))?;
+        test_file_matches(fs, context, target, ContentIterator::new(seed, length)).await?;
 
         Ok(())
     }
@@ -216,7 +318,6 @@ pub async fn test_move_file(fs: &FileStore, context: &TestContext) -> TestResult
     ) -> TestResult<()> {
         let remote_current = context.get_path(path);
         let remote_target = context.get_path(target);
-        let local_target = context.get_target(&remote_target);
 
         let result = fs
             .move_file(remote_current.clone(), remote_target.clone())
@@ -237,15 +338,8 @@ pub async fn test_move_file(fs: &FileStore, context: &TestContext) -> TestResult
             test_fail!("Expected to fail to copy {}.", remote_current);
         }
 
-        let result = symlink_metadata(local_target);
-        if let Err(e) = result {
-            test_assert_eq!(
-                e.kind(),
-                ErrorKind::NotFound,
-                "File {} should not exist.",
-                remote_target
-            );
-        }
+        // The target may exist from an earlier pass but must not be broken.
+        stored_type(fs, context, &remote_target).await?;
 
         Ok(())
     }
@@ -257,6 +351,7 @@ pub async fn test_move_file(fs: &FileStore, context: &TestContext) -> TestResult
         UploadInfo {
             path: context.get_path("test1/dir1/testfile"),
             modified: Some(UNIX_EPOCH + Duration::from_millis(1_703_257_714)),
+            ..Default::default()
         },
         58,
         5 * MB,
@@ -269,6 +364,7 @@ pub async fn test_move_file(fs: &FileStore, context: &TestContext) -> TestResult
         UploadInfo {
             path: context.get_path("test1/dir1/dir2/hop"),
             modified: None,
+            ..Default::default()
         },
         0,
         100 * MB,
@@ -281,6 +377,7 @@ pub async fn test_move_file(fs: &FileStore, context: &TestContext) -> TestResult
         UploadInfo {
             path: context.get_path("test1/dir1/bazza"),
             modified: None,
+            ..Default::default()
         },
         72,
         300,
@@ -296,22 +393,11 @@ pub async fn test_move_file(fs: &FileStore, context: &TestContext) -> TestResult
 pub async fn test_delete_object(fs: &FileStore, context: &TestContext) -> TestResult<()> {
     async fn test_pass(fs: &FileStore, context: &TestContext, path: &str) -> TestResult<()> {
         let remote = context.get_path(path);
-        let target = context.get_target(&remote);
 
-        fs.delete_object(remote).await?;
+        fs.delete_object(remote.clone()).await?;
 
-        match symlink_metadata(target.clone()) {
-            Ok(m) => {
-                test_assert!(m.is_file(), "Failed to delete {}", target.display());
-            }
-            Err(e) => {
-                test_assert_eq!(
-                    e.kind(),
-                    ErrorKind::NotFound,
-                    "Should have failed to find {}",
-                    target.display()
-                );
-            }
+        if let Some(t) = stored_type(fs, context, &remote).await? {
+            test_assert_eq!(t, ObjectType::File, "Failed to delete {}", remote);
         }
 
         Ok(())
@@ -319,7 +405,6 @@ pub async fn test_delete_object(fs: &FileStore, context: &TestContext) -> TestRe
 
     async fn test_fail(fs: &FileStore, context: &TestContext, path: &str) -> TestResult<()> {
         let fspath = context.get_path(path);
-        let target = context.get_target(&fspath);
 
         match fs.delete_object(fspath.clone()).await {
             Ok(()) => test_fail!("Should have failed to delete {}", fspath),
@@ -331,8 +416,13 @@ pub async fn test_delete_object(fs: &FileStore, context: &TestContext) -> TestRe
             ),
         }
 
-        if let Ok(m) = symlink_metadata(target) {
-            test_assert!(m.is_dir(), "Shouldn't have deleted {}.", fspath);
+        if let Some(t) = stored_type(fs, context, &fspath).await? {
+            test_assert_eq!(
+                t,
+                ObjectType::Directory,
+                "Shouldn't have deleted {}.",
+                fspath
+            );
         }
 
         Ok(())
@@ -343,7 +433,7 @@ pub async fn test_delete_object(fs: &FileStore, context: &TestContext) -> TestRe
     test_pass(fs, context, "test1/dir1/dir2/daz").await?;
     test_pass(fs, context, "test1/dir1/maybedir").await?;
 
-    if fs.backend_type() == Backend::File {
+    if has_directories(fs.backend_type()) {
         test_pass(fs, context, "test1/dir1/dir2").await?;
     } else {
         test_fail(fs, context, "test1/dir1/dir2").await?;
@@ -354,6 +444,194 @@ pub async fn test_delete_object(fs: &FileStore, context: &TestContext) -> TestRe
     Ok(())
 }
 
+pub async fn test_delete_prefix(fs: &FileStore, context: &TestContext) -> TestResult<()> {
+    async fn test_delete(fs: &FileStore, context: &TestContext, prefix: &str) -> TestResult<()> {
+        let prefix = context.get_path(prefix);
+        let expected: Vec<ObjectPath> = fs
+            .list_objects(prefix.clone())
+            .await?
+            .try_collect::<Vec<Object>>()
+            .await?
+            .into_iter()
+            .filter(|object| object.object_type() != ObjectType::Directory)
+            .map(|object| object.path())
+            .collect();
+
+        let mut deleted: Vec<ObjectPath> = Vec::new();
+        let mut results = fs.delete_prefix(prefix.clone()).await?;
+        while let Some(result) = results.next().await {
+            match result {
+                Ok(path) => deleted.push(path),
+                Err((path, e)) => test_fail!("Failed to delete {}: {}", path, e),
+            }
+        }
+
+        for path in expected {
+            test_assert!(
+                deleted.contains(&path),
+                "Should have deleted {} under {}.",
+                path,
+                prefix
+            );
+            test_assert_eq!(
+                stored_type(fs, context, &path).await?,
+                None,
+                "Should have deleted {}.",
+                path
+            );
+        }
+
+        let remaining = fs
+            .list_objects(prefix.clone())
+            .await?
+            .try_collect::<Vec<Object>>()
+            .await?;
+        test_assert!(
+            remaining.is_empty(),
+            "Should have deleted everything under {}.",
+            prefix
+        );
+
+        Ok(())
+    }
+
+    test_delete(fs, context, "test1/dir1/dir2/").await?;
+    test_delete(fs, context, "test1/dir1/maybedir").await?;
+    test_delete(fs, context, "test1/dir1/nothing").await?;
+
+    // Only the objects under the prefixes are deleted.
+    test_assert_eq!(
+        stored_type(fs, context, &context.get_path("test1/dir1/smallfile.txt")).await?,
+        Some(ObjectType::File),
+        "Should not have deleted anything else."
+    );
+
+    if has_directories(fs.backend_type()) {
+        test_assert_eq!(
+            stored_type(fs, context, &context.get_path("test1/dir1/dir2")).await?,
+            Some(ObjectType::Directory),
+            "Should have left the directory in place."
+        );
+    }
+
+    Ok(())
+}
+
+pub async fn test_batch_operations(fs: &FileStore, context: &TestContext) -> TestResult<()> {
+    let small = context.get_path("test1/dir1/smallfile.txt");
+    let large = context.get_path("test1/dir1/largefile");
+    let missing = context.get_path("test1/dir1/biz");
+    let copied_small = context.get_path("test1/dir1/copiedsmall");
+    let copied_large = context.get_path("test1/dir1/copiedlarge");
+    let copied_missing = context.get_path("test1/dir1/copiedmissing");
+
+    let mut found: Vec<ObjectPath> = Vec::new();
+    let mut results = fs.get_objects(vec![small.clone(), missing.clone(), large.clone()]);
+    while let Some(result) = results.next().await {
+        match result {
+            Ok(object) => found.push(object.path()),
+            Err((path, e)) => {
+                test_assert_eq!(
+                    path,
+                    missing,
+                    "Should only have failed to find {}.",
+                    missing
+                );
+                test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::NotFound(missing.clone()),
+                    "Should have seen a not found error."
+                );
+            }
+        }
+    }
+    found.sort();
+    let mut expected = vec![small.clone(), large.clone()];
+    expected.sort();
+    test_assert_eq!(found, expected, "Should have found the existing objects.");
+
+    let mut copied: Vec<ObjectPath> = Vec::new();
+    let mut results = fs.copy_files(vec![
+        (small.clone(), copied_small.clone()),
+        (missing.clone(), copied_missing.clone()),
+        (large.clone(), copied_large.clone()),
+    ]);
+    while let Some(result) = results.next().await {
+        match result {
+            Ok((_, target)) => copied.push(target),
+            Err((source, _, e)) => {
+                test_assert_eq!(
+                    source,
+                    missing,
+                    "Should only have failed to copy {}.",
+                    missing
+                );
+                match e {
+                    TransferError::SourceError(_) => (),
+                    e => test_fail!("Should have seen a source error but saw {}.", e),
+                }
+            }
+        }
+    }
+    copied.sort();
+    let mut expected = vec![copied_small.clone(), copied_large.clone()];
+    expected.sort();
+    test_assert_eq!(copied, expected, "Should have copied the existing files.");
+
+    for path in &copied {
+        test_assert_eq!(
+            stored_type(fs, context, path).await?,
+            Some(ObjectType::File),
+            "Should have created {}.",
+            path
+        );
+    }
+    test_assert_eq!(
+        stored_type(fs, context, &copied_missing).await?,
+        None,
+        "Should not have created {}.",
+        copied_missing
+    );
+
+    let mut deleted: Vec<ObjectPath> = Vec::new();
+    let mut results = fs.delete_objects(vec![
+        copied_small.clone(),
+        missing.clone(),
+        copied_large.clone(),
+    ]);
+    while let Some(result) = results.next().await {
+        match result {
+            Ok(path) => deleted.push(path),
+            Err((path, e)) => {
+                test_assert_eq!(
+                    path,
+                    missing,
+                    "Should only have failed to delete {}.",
+                    missing
+                );
+                test_assert_eq!(
+                    e.kind(),
+                    StorageErrorKind::NotFound(missing.clone()),
+                    "Should have seen a not found error."
+                );
+            }
+        }
+    }
+    deleted.sort();
+    test_assert_eq!(deleted, copied, "Should have deleted the copies.");
+
+    for path in &copied {
+        test_assert_eq!(
+            stored_type(fs, context, path).await?,
+            None,
+            "Should have deleted {}.",
+            path
+        );
+    }
+
+    Ok(())
+}
+
 pub async fn test_write_file_from_stream(fs: &FileStore, context: &TestContext) -> TestResult<()> {
     async fn test_write(
         fs: &FileStore,
@@ -362,15 +640,13 @@ pub async fn test_write_file_from_stream(fs: &FileStore, context: &TestContext)
         seed: u8,
         length: u64,
     ) -> TestResult<()> {
-        let local_target = context.get_target(&target.path);
-
         fs.write_file_from_stream(
             target.clone(),
             stream_iterator(ContentIterator::new(seed, length), (length / 10) as usize),
         )
         .await?;
 
-        test_file_matches(&local_target, target, ContentIterator::new(seed, length))?;
+        test_file_matches(fs, context, target, ContentIterator::new(seed, length)).await?;
 
         Ok(())
     }
@@ -381,6 +657,7 @@ pub async fn test_write_file_from_stream(fs: &FileStore, context: &TestContext)
         UploadInfo {
             path: context.get_path("test1/dir1/foobar"),
             modified: Some(UNIX_EPOCH + Duration::from_millis(1_703_257_714)),
+            ..Default::default()
         },
         58,
         300,
@@ -392,6 +669,7 @@ pub async fn test_write_file_from_stream(fs: &FileStore, context: &TestContext)
         UploadInfo {
             path: context.get_path("test1/dir1/maybedir"),
             modified: None,
+            ..Default::default()
         },
         27,
         500,
@@ -403,6 +681,7 @@ pub async fn test_write_file_from_stream(fs: &FileStore, context: &TestContext)
         UploadInfo {
             path: context.get_path("test1/dir1/dir2/daz"),
             modified: None,
+            ..Default::default()
         },
         27,
         100 * MB,
@@ -411,3 +690,437 @@ pub async fn test_write_file_from_stream(fs: &FileStore, context: &TestContext)
 
     Ok(())
 }
+
+pub async fn test_upload_metadata(fs: &FileStore, context: &TestContext) -> TestResult<()> {
+    async fn test_metadata(fs: &FileStore, stored: bool, expected: &UploadInfo) -> TestResult<()> {
+        let object = fs.get_object(expected.path.clone()).await?;
+
+        // Writing still succeeds where the metadata cannot be kept.
+        let expected = if stored {
+            expected.clone()
+        } else {
+            UploadInfo::from(expected.path.clone())
+        };
+
+        test_assert_eq!(
+            object.content_type(),
+            expected.content_type,
+            "Should have seen the right content type for {}.",
+            expected.path
+        );
+        test_assert_eq!(
+            object.cache_control(),
+            expected.cache_control,
+            "Should have seen the right cache control for {}.",
+            expected.path
+        );
+        test_assert_eq!(
+            object.content_disposition(),
+            expected.content_disposition,
+            "Should have seen the right content disposition for {}.",
+            expected.path
+        );
+        test_assert_eq!(
+            object.metadata(),
+            expected.metadata,
+            "Should have seen the right metadata for {}.",
+            expected.path
+        );
+
+        Ok(())
+    }
+
+    let stored = stores_metadata(fs.backend_type(), context);
+
+    let mut metadata = HashMap::new();
+    metadata.insert(String::from("colour"), String::from("blue"));
+    metadata.insert(String::from("size"), String::from("large"));
+
+    let source = UploadInfo {
+        path: context.get_path("test1/dir1/metadata"),
+        content_type: Some(String::from("text/plain")),
+        cache_control: Some(String::from("max-age=3600")),
+        content_disposition: Some(String::from("attachment; filename=\"foo.txt\"")),
+        metadata,
+        ..Default::default()
+    };
+
+    fs.write_file_from_stream(
+        source.clone(),
+        stream_iterator(ContentIterator::new(12, 300), 30),
+    )
+    .await?;
+    test_metadata(fs, stored, &source).await?;
+
+    // Copying without setting any metadata should keep the source's.
+    let copied = UploadInfo {
+        path: context.get_path("test1/dir1/copied"),
+        ..source.clone()
+    };
+    fs.copy_file(source.path.clone(), copied.path.clone())
+        .await?;
+    test_metadata(fs, stored, &copied).await?;
+
+    // Metadata on the target should replace all of the source's.
+    let mut metadata = HashMap::new();
+    metadata.insert(String::from("shape"), String::from("round"));
+
+    let replaced = UploadInfo {
+        path: context.get_path("test1/dir1/replaced"),
+        content_type: Some(String::from("application/json")),
+        metadata,
+        ..Default::default()
+    };
+    fs.copy_file(source.path.clone(), replaced.clone()).await?;
+    test_metadata(fs, stored, &replaced).await?;
+
+    Ok(())
+}
+
+pub async fn test_write_precondition(fs: &FileStore, context: &TestContext) -> TestResult<()> {
+    async fn test_rejected(
+        fs: &FileStore,
+        info: UploadInfo,
+        expected: StorageErrorKind,
+    ) -> TestResult<()> {
+        let result = fs
+            .write_file_from_stream(
+                info.clone(),
+                stream_iterator(ContentIterator::new(99, 200), 20),
+            )
+            .await;
+
+        if let Err(e) = result {
+            if let TransferError::TargetError(t) = e {
+                test_assert_eq!(
+                    t.kind(),
+                    expected,
+                    "Should have seen the right error writing {}.",
+                    info.path
+                );
+            } else {
+                test_fail!("Should have received a target error.");
+            }
+        } else {
+            test_fail!("Expected to fail to write {}.", info.path);
+        }
+
+        Ok(())
+    }
+
+    let path = context.get_path("test1/dir1/precondition");
+    let create = UploadInfo {
+        path: path.clone(),
+        precondition: WritePrecondition::DoesNotExist,
+        ..Default::default()
+    };
+
+    fs.write_file_from_stream(
+        create.clone(),
+        stream_iterator(ContentIterator::new(5, 300), 30),
+    )
+    .await?;
+    test_file_matches(fs, context, create.clone(), ContentIterator::new(5, 300)).await?;
+
+    // Creating again should fail and leave the file alone.
+    test_rejected(
+        fs,
+        create.clone(),
+        StorageErrorKind::AlreadyExists(path.clone()),
+    )
+    .await?;
+    test_file_matches(fs, context, create.clone(), ContentIterator::new(5, 300)).await?;
+
+    let version = match fs.get_object(path.clone()).await?.version() {
+        Some(v) => v,
+        None => test_fail!("Should have seen a version for {}.", path),
+    };
+
+    // A mismatched version should fail and leave the file alone.
+    test_rejected(
+        fs,
+        UploadInfo {
+            path: path.clone(),
+            precondition: WritePrecondition::MatchesVersion(String::from("not-a-version")),
+            ..Default::default()
+        },
+        StorageErrorKind::PreconditionFailed(path.clone()),
+    )
+    .await?;
+    test_file_matches(fs, context, create.clone(), ContentIterator::new(5, 300)).await?;
+
+    // The current version should allow replacing the file.
+    let replace = UploadInfo {
+        path: path.clone(),
+        precondition: WritePrecondition::MatchesVersion(version),
+        ..Default::default()
+    };
+    fs.write_file_from_stream(
+        replace.clone(),
+        stream_iterator(ContentIterator::new(7, 400), 40),
+    )
+    .await?;
+    test_file_matches(fs, context, replace, ContentIterator::new(7, 400)).await?;
+
+    // A version never matches a missing file.
+    let missing = context.get_path("test1/dir1/missing");
+    test_rejected(
+        fs,
+        UploadInfo {
+            path: missing.clone(),
+            precondition: WritePrecondition::MatchesVersion(String::from("not-a-version")),
+            ..Default::default()
+        },
+        StorageErrorKind::PreconditionFailed(missing.clone()),
+    )
+    .await?;
+
+    // A rejected write shouldn't create any missing directories.
+    let nested = context.get_path("test1/dir1/newdir/missing");
+    test_rejected(
+        fs,
+        UploadInfo {
+            path: nested.clone(),
+            precondition: WritePrecondition::MatchesVersion(String::from("not-a-version")),
+            ..Default::default()
+        },
+        StorageErrorKind::PreconditionFailed(nested.clone()),
+    )
+    .await?;
+    test_assert!(
+        stored_type(fs, context, &context.get_path("test1/dir1/newdir"))
+            .await?
+            .is_none(),
+        "Should not have created the parent directory."
+    );
+
+    Ok(())
+}
+
+pub async fn test_failed_write(fs: &FileStore, context: &TestContext) -> TestResult<()> {
+    let dir = context.get_path("test1/dir1/dir2");
+    let existing = fs
+        .list_directory(dir.clone())
+        .await?
+        .try_collect::<Vec<Object>>()
+        .await?
+        .len();
+
+    let info: UploadInfo = context.get_path("test1/dir1/dir2/daz").into();
+    let stream = stream_iterator(ContentIterator::new(3, 500), 50).chain(once(ready(Err(
+        std::io::Error::new(ErrorKind::Other, "Source failed."),
+    ))));
+
+    match fs.write_file_from_stream(info.clone(), stream).await {
+        Err(TransferError::SourceError(_)) => (),
+        Err(e) => test_fail!("Should have seen a source error but saw {}.", e),
+        Ok(()) => test_fail!("Expected to fail to write {}.", info.path),
+    }
+
+    // The original file should be untouched.
+    test_file_matches(fs, context, info, ContentIterator::new(72, 300)).await?;
+
+    // Nothing should have been left behind.
+    let found = fs
+        .list_directory(dir.clone())
+        .await?
+        .try_collect::<Vec<Object>>()
+        .await?
+        .len();
+    test_assert_eq!(
+        found,
+        existing,
+        "Should have seen the same number of files in {}.",
+        dir
+    );
+
+    Ok(())
+}
+
+pub async fn test_write_checksum(fs: &FileStore, context: &TestContext) -> TestResult<()> {
+    let path = context.get_path("test1/dir1/dir2/daz");
+    let mut hasher = Sha1::new();
+    hasher.update(&ContentIterator::new(19, 400).collect::<Vec<u8>>());
+    let hash = hasher.hexdigest();
+
+    // Data that doesn't match should fail and leave the original file alone.
+    let info = UploadInfo {
+        path: path.clone(),
+        checksum: Some(Checksum::Sha1(hash.clone())),
+        ..Default::default()
+    };
+    match fs
+        .write_file_from_stream(
+            info.clone(),
+            stream_iterator(ContentIterator::new(20, 400), 40),
+        )
+        .await
+    {
+        Err(TransferError::SourceError(e)) => test_assert_eq!(
+            e.kind(),
+            StorageErrorKind::InvalidData,
+            "Should have seen an invalid data error writing {}.",
+            path
+        ),
+        Err(e) => test_fail!("Should have seen a source error but saw {}.", e),
+        Ok(()) => test_fail!("Expected to fail to write {}.", path),
+    }
+    test_file_matches(fs, context, info.clone(), ContentIterator::new(72, 300)).await?;
+
+    // Only SHA1 checksums can be verified.
+    match fs
+        .write_file_from_stream(
+            UploadInfo {
+                checksum: Some(Checksum::Md5(String::from("not-a-hash"))),
+                ..info.clone()
+            },
+            stream_iterator(ContentIterator::new(19, 400), 40),
+        )
+        .await
+    {
+        Err(TransferError::TargetError(e)) => test_assert_eq!(
+            e.kind(),
+            StorageErrorKind::InvalidSettings,
+            "Should have seen an invalid settings error writing {}.",
+            path
+        ),
+        Err(e) => test_fail!("Should have seen a target error but saw {}.", e),
+        Ok(()) => test_fail!("Expected to fail to write {}.", path),
+    }
+    test_file_matches(fs, context, info.clone(), ContentIterator::new(72, 300)).await?;
+
+    // Matching data is written. Hashes are not case sensitive.
+    fs.write_file_from_stream(
+        UploadInfo {
+            checksum: Some(Checksum::Sha1(hash.to_uppercase())),
+            ..info.clone()
+        },
+        stream_iterator(ContentIterator::new(19, 400), 40),
+    )
+    .await?;
+    test_file_matches(fs, context, info.clone(), ContentIterator::new(19, 400)).await?;
+
+    let object = fs.get_object(path.clone()).await?;
+    match object.checksum() {
+        Some(Checksum::Sha1(found)) => {
+            test_assert_eq!(found, hash, "Should have seen the right SHA1 for {}.", path)
+        }
+        _ => test_assert!(
+            !stores_sha1(fs.backend_type(), context),
+            "Should have seen a SHA1 for {}.",
+            path
+        ),
+    }
+
+    let data = fs
+        .get_verified_file_stream(path.clone())
+        .await?
+        .try_collect::<Vec<Data>>()
+        .await?;
+    test_assert_eq!(
+        data.iter()
+            .flat_map(|d| d.iter().cloned())
+            .collect::<Vec<u8>>(),
+        ContentIterator::new(19, 400).collect::<Vec<u8>>(),
+        "Should have read the right data from {}.",
+        path
+    );
+
+    Ok(())
+}
+
+pub async fn test_write_size(fs: &FileStore, context: &TestContext) -> TestResult<()> {
+    let path = context.get_path("test1/dir1/dir2/daz");
+    let info = UploadInfo {
+        path: path.clone(),
+        size: Some(400),
+        ..Default::default()
+    };
+
+    // Data that is too short or too long should fail and leave the original
+    // file alone.
+    for length in &[300, 500] {
+        match fs
+            .write_file_from_stream(
+                info.clone(),
+                stream_iterator(ContentIterator::new(20, *length), 40),
+            )
+            .await
+        {
+            Err(TransferError::SourceError(e)) => test_assert_eq!(
+                e.kind(),
+                StorageErrorKind::InvalidData,
+                "Should have seen an invalid data error writing {}.",
+                path
+            ),
+            Err(e) => test_fail!("Should have seen a source error but saw {}.", e),
+            Ok(()) => test_fail!("Expected to fail to write {}.", path),
+        }
+        test_file_matches(fs, context, info.clone(), ContentIterator::new(72, 300)).await?;
+    }
+
+    fs.write_file_from_stream(
+        info.clone(),
+        stream_iterator(ContentIterator::new(19, 400), 40),
+    )
+    .await?;
+    test_file_matches(fs, context, info, ContentIterator::new(19, 400)).await?;
+
+    Ok(())
+}
+
+/// Checks that progress events count up to the expected total.
+fn check_progress(events: &[TransferEvent], total: u64) -> TestResult<()> {
+    let mut last: u64 = 0;
+    for event in events {
+        if let TransferEvent::Progress {
+            transferred,
+            total: expected,
+        } = event
+        {
+            test_assert!(*transferred > last, "Progress should always increase.");
+            test_assert_eq!(*expected, Some(total), "Should have seen the right total.");
+            last = *transferred;
+        }
+    }
+
+    test_assert_eq!(last, total, "Should have seen all of the data transferred.");
+    Ok(())
+}
+
+pub async fn test_transfer_progress(fs: &FileStore, context: &TestContext) -> TestResult<()> {
+    let path = context.get_path("test1/dir1/dir2/daz");
+    let events: Arc<Mutex<Vec<TransferEvent>>> = Default::default();
+    let observer = {
+        let events = events.clone();
+        TransferObserver::new(move |event| events.lock().unwrap().push(event))
+    };
+
+    fs.write_file_from_stream(
+        UploadInfo {
+            path: path.clone(),
+            size: Some(400),
+            progress: Some(observer.clone()),
+            ..Default::default()
+        },
+        stream_iterator(ContentIterator::new(19, 400), 40),
+    )
+    .await?;
+    check_progress(&events.lock().unwrap(), 400)?;
+
+    events.lock().unwrap().clear();
+    let data = fs
+        .get_file_stream_with_progress(path.clone(), observer)
+        .await?
+        .try_collect::<Vec<Data>>()
+        .await?;
+    test_assert_eq!(
+        data.iter().map(|d| d.len() as u64).sum::<u64>(),
+        400,
+        "Should have read all of {}.",
+        path
+    );
+    check_progress(&events.lock().unwrap(), 400)?;
+
+    Ok(())
+}
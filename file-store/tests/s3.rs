@@ -0,0 +1,54 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(feature = "s3")]
+
+extern crate file_store;
+
+#[macro_use]
+mod runner;
+mod mocks;
+
+mod test1 {
+    use futures::channel::oneshot::Sender;
+
+    use file_store::backends::s3::S3Backend;
+    use file_store::backends::Backend;
+    use file_store::FileStore;
+
+    use crate::mocks::s3_server::{start_server, TEST_REGION};
+    use crate::runner::{TestContext, TestError, TestResult};
+
+    async fn build_fs(context: &TestContext) -> TestResult<(FileStore, Sender<()>)> {
+        let (addr, sender) = start_server(context.get_fs_root())?;
+
+        let fs = S3Backend::builder("foo", "bar")
+            .endpoint(&format!("http://{}", addr))
+            .region(TEST_REGION)
+            .path_style(true)
+            .limit_small_file_size(20 * 1024 * 1024)
+            .limit_requests(5)
+            .connect()
+            .await?;
+        Ok((fs, sender))
+    }
+
+    async fn cleanup(sender: Sender<()>) -> TestResult<()> {
+        sender.send(()).map_err(|()| {
+            TestError::HarnessFailure(String::from("Failed to send shutdown to mock s3 server."))
+        })
+    }
+
+    build_tests!("test1", Backend::S3, build_fs, cleanup);
+}
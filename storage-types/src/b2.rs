@@ -31,10 +31,19 @@ pub mod v2 {
 
     pub const B2_HEADER_FILE_INFO_PREFIX: &str = "X-Bz-Info-";
     pub const B2_HEADER_FILE_NAME: &str = "X-Bz-File-Name";
+    pub const B2_HEADER_FILE_ID: &str = "X-Bz-File-Id";
     pub const B2_HEADER_CONTENT_SHA1: &str = "X-Bz-Content-Sha1";
     pub const B2_HEADER_PART_NUMBER: &str = "X-Bz-Part-Number";
 
     pub const LAST_MODIFIED_KEY: &str = "src_last_modified_millis";
+    pub const LARGE_FILE_SHA1_KEY: &str = "large_file_sha1";
+    pub const CACHE_CONTROL_KEY: &str = "b2-cache-control";
+    pub const CONTENT_DISPOSITION_KEY: &str = "b2-content-disposition";
+    pub const RESERVED_KEY_PREFIX: &str = "b2-";
+
+    pub const CONTENT_TYPE_AUTO: &str = "b2/x-auto";
+    /// Used in place of a SHA1 when the hash follows the uploaded data.
+    pub const CONTENT_SHA1_AT_END: &str = "hex_digits_at_end";
 
     /// The set of characters to percent encode.
     ///
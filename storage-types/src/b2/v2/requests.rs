@@ -14,7 +14,8 @@
 
 use serde::{Deserialize, Serialize};
 
-use super::{BucketTypes, Int, UserFileInfo};
+use super::responses::{CorsRule, LifecycleRule};
+use super::{BucketType, BucketTypes, Int, Map, UserFileInfo};
 
 pub const B2_API_HOST: &str = "https://api.backblazeb2.com";
 pub const B2_VERSION: &str = "v2";
@@ -28,6 +29,44 @@ pub struct ListBucketsRequest {
     pub bucket_types: BucketTypes,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateBucketRequest {
+    pub account_id: String,
+    pub bucket_name: String,
+    pub bucket_type: BucketType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bucket_info: Option<Map>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cors_rules: Option<Vec<CorsRule>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lifecycle_rules: Option<Vec<LifecycleRule>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateBucketRequest {
+    pub account_id: String,
+    pub bucket_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bucket_type: Option<BucketType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bucket_info: Option<Map>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cors_rules: Option<Vec<CorsRule>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lifecycle_rules: Option<Vec<LifecycleRule>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub if_revision_is: Option<Int>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteBucketRequest {
+    pub account_id: String,
+    pub bucket_id: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GetFileInfoRequest {
@@ -62,6 +101,13 @@ pub struct DeleteFileVersionRequest {
     pub file_id: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HideFileRequest {
+    pub bucket_id: String,
+    pub file_name: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GetUploadUrlRequest {
@@ -89,3 +135,65 @@ pub struct FinishLargeFileRequest {
     pub file_id: String,
     pub part_sha1_array: Vec<String>,
 }
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MetadataDirective {
+    #[serde(rename = "COPY")]
+    Copy,
+    #[serde(rename = "REPLACE")]
+    Replace,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CopyFileRequest {
+    pub source_file_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination_bucket_id: Option<String>,
+    pub file_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<String>,
+    pub metadata_directive: MetadataDirective,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_info: Option<UserFileInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CopyPartRequest {
+    pub source_file_id: String,
+    pub large_file_id: String,
+    pub part_number: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListUnfinishedLargeFilesRequest {
+    pub bucket_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name_prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_file_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_file_count: Option<Int>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListPartsRequest {
+    pub file_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_part_number: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_part_count: Option<Int>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelLargeFileRequest {
+    pub file_id: String,
+}
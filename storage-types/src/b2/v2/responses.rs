@@ -56,12 +56,12 @@ pub struct LifecycleRule {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CorsRule {
-    cors_rule_name: String,
-    allowed_origins: Vec<String>,
-    allowed_operations: Vec<String>,
-    allowed_headers: Option<Vec<String>>,
-    expose_headers: Option<Vec<String>>,
-    max_age_seconds: Int,
+    pub cors_rule_name: String,
+    pub allowed_origins: Vec<String>,
+    pub allowed_operations: Vec<String>,
+    pub allowed_headers: Option<Vec<String>>,
+    pub expose_headers: Option<Vec<String>>,
+    pub max_age_seconds: Int,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -83,6 +83,12 @@ pub struct ListBucketsResponse {
     pub buckets: Vec<Bucket>,
 }
 
+pub type CreateBucketResponse = Bucket;
+
+pub type UpdateBucketResponse = Bucket;
+
+pub type DeleteBucketResponse = Bucket;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct FileInfo {
@@ -133,6 +139,8 @@ pub struct DeleteFileVersionResponse {
     pub file_id: String,
 }
 
+pub type HideFileResponse = FileInfo;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GetUploadUrlResponse {
@@ -164,3 +172,30 @@ pub struct UploadPartResponse {
 }
 
 pub type FinishLargeFileResponse = FileInfo;
+
+pub type CopyFileResponse = FileInfo;
+
+pub type CopyPartResponse = UploadPartResponse;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListUnfinishedLargeFilesResponse {
+    pub files: Vec<FileInfo>,
+    pub next_file_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListPartsResponse {
+    pub parts: Vec<UploadPartResponse>,
+    pub next_part_number: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelLargeFileResponse {
+    pub file_id: String,
+    pub account_id: String,
+    pub bucket_id: String,
+    pub file_name: String,
+}
@@ -13,3 +13,5 @@
 // limitations under the License.
 
 pub mod b2;
+#[cfg(feature = "s3")]
+pub mod s3;
@@ -0,0 +1,135 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod requests;
+pub mod responses;
+pub mod signing;
+
+use std::collections::HashMap;
+use std::str::Utf8Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, TimeZone, Utc};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+pub type Int = u64;
+pub type UserMetadata = HashMap<String, String>;
+
+pub const S3_HEADER_DATE: &str = "x-amz-date";
+pub const S3_HEADER_CONTENT_SHA256: &str = "x-amz-content-sha256";
+pub const S3_HEADER_SECURITY_TOKEN: &str = "x-amz-security-token";
+pub const S3_HEADER_META_PREFIX: &str = "x-amz-meta-";
+
+pub const LAST_MODIFIED_KEY: &str = "src-last-modified-millis";
+
+/// The characters left alone when URI encoding for SigV4. Everything other
+/// than the unreserved characters must be encoded.
+const ENCODE_SET: AsciiSet = NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+const PATH_ENCODE_SET: AsciiSet = ENCODE_SET.remove(b'/');
+
+/// Encodes a value to be used in a query string.
+pub fn uri_encode(value: &str) -> String {
+    utf8_percent_encode(value, &ENCODE_SET).collect()
+}
+
+/// Encodes an object key to be used in a path, `/` characters are left alone.
+pub fn uri_encode_path(value: &str) -> String {
+    utf8_percent_encode(value, &PATH_ENCODE_SET).collect()
+}
+
+pub fn uri_decode(value: &str) -> Result<String, Utf8Error> {
+    Ok(percent_decode_str(value).decode_utf8()?.into_owned())
+}
+
+/// Builds a query string from the given pairs sorted in the order that SigV4
+/// requires.
+pub fn canonical_query(pairs: &[(String, String)]) -> String {
+    let mut encoded: Vec<(String, String)> = pairs
+        .iter()
+        .map(|(k, v)| (uri_encode(k), uri_encode(v)))
+        .collect();
+    encoded.sort();
+
+    encoded
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<String>>()
+        .join("&")
+}
+
+fn to_datetime(time: SystemTime) -> DateTime<Utc> {
+    let duration = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    Utc.timestamp_opt(duration.as_secs() as i64, duration.subsec_nanos())
+        .unwrap()
+}
+
+fn from_datetime<T: TimeZone>(time: DateTime<T>) -> Option<SystemTime> {
+    let millis = time.timestamp_millis();
+    if millis < 0 {
+        None
+    } else {
+        Some(UNIX_EPOCH + Duration::from_millis(millis as u64))
+    }
+}
+
+/// Formats a time as used in the `x-amz-date` header.
+pub fn format_amz_date(time: SystemTime) -> String {
+    to_datetime(time).format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Formats a time as used in the XML responses.
+pub fn format_iso8601(time: SystemTime) -> String {
+    to_datetime(time)
+        .format("%Y-%m-%dT%H:%M:%S%.3fZ")
+        .to_string()
+}
+
+/// Formats a time as used in the `Last-Modified` header.
+pub fn format_http_date(time: SystemTime) -> String {
+    to_datetime(time)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+pub fn parse_iso8601(value: &str) -> Option<SystemTime> {
+    DateTime::parse_from_rfc3339(value)
+        .ok()
+        .and_then(from_datetime)
+}
+
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    DateTime::parse_from_rfc2822(value)
+        .ok()
+        .and_then(from_datetime)
+}
+
+pub fn xml_escape(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&apos;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
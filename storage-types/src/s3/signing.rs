@@ -0,0 +1,131 @@
+// Copyright 2019 Dave Townsend
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! AWS Signature Version 4 support.
+//!
+//! See https://docs.aws.amazon.com/general/latest/gr/signature-version-4.html
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+pub const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+pub const SERVICE: &str = "s3";
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_varkey(key).expect("HMAC accepts keys of any length.");
+    mac.input(data.as_bytes());
+    mac.result().code().to_vec()
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Generates the hex encoded SHA256 hash of a payload supplied in pieces.
+#[derive(Clone, Default)]
+pub struct PayloadHasher {
+    hasher: Sha256,
+}
+
+impl PayloadHasher {
+    pub fn new() -> PayloadHasher {
+        Default::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.input(data);
+    }
+
+    pub fn hexdigest(self) -> String {
+        hex::encode(self.hasher.result())
+    }
+}
+
+/// The credential scope, `amz_date` is the `x-amz-date` header value.
+pub fn scope(amz_date: &str, region: &str) -> String {
+    format!("{}/{}/{}/aws4_request", &amz_date[0..8], region, SERVICE)
+}
+
+/// Builds the canonical request and returns it along with the list of signed
+/// headers.
+///
+/// `path` and `query` must already be encoded as they are sent. The headers
+/// passed are the headers to sign.
+pub fn canonical_request(
+    method: &str,
+    path: &str,
+    query: &str,
+    headers: &[(String, String)],
+    payload_hash: &str,
+) -> (String, String) {
+    let mut headers: Vec<(String, String)> = headers
+        .iter()
+        .map(|(k, v)| (k.to_lowercase(), v.trim().to_owned()))
+        .collect();
+    headers.sort();
+
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(k, v)| format!("{}:{}\n", k, v))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(k, _)| k.as_str())
+        .collect::<Vec<&str>>()
+        .join(";");
+
+    let request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method, path, query, canonical_headers, signed_headers, payload_hash
+    );
+
+    (request, signed_headers)
+}
+
+/// Generates the signature for a canonical request.
+pub fn signature(secret: &str, amz_date: &str, region: &str, canonical_request: &str) -> String {
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}",
+        ALGORITHM,
+        amz_date,
+        scope(amz_date, region),
+        sha256_hex(canonical_request.as_bytes())
+    );
+
+    let key = hmac(format!("AWS4{}", secret).as_bytes(), &amz_date[0..8]);
+    let key = hmac(&key, region);
+    let key = hmac(&key, SERVICE);
+    let key = hmac(&key, "aws4_request");
+
+    hex::encode(hmac(&key, &string_to_sign))
+}
+
+/// Generates the value for the `Authorization` header.
+pub fn authorization(
+    access_key_id: &str,
+    amz_date: &str,
+    region: &str,
+    signed_headers: &str,
+    signature: &str,
+) -> String {
+    format!(
+        "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+        ALGORITHM,
+        access_key_id,
+        scope(amz_date, region),
+        signed_headers,
+        signature
+    )
+}